@@ -17,10 +17,11 @@ use governor::{
 use tracing::error;
 use r2d2::{self, Pool};
 use r2d2_sqlite::SqliteConnectionManager;
-use std::{ops::Deref, sync::Arc};
+use std::{ops::Deref, path::PathBuf, sync::Arc};
+use svg2pdf::usvg::fontdb;
 use tokio::sync::Mutex;
 
-use crate::errors::AppError;
+use crate::{errors::AppError, incompatibility::IncompatibilityRules, regulatory::RegulatoryLists};
 
 #[derive(Clone)]
 pub struct AppState {
@@ -35,6 +36,32 @@ pub struct AppState {
     pub keycloak_realm: String,
 
     pub pkce_store: Arc<Mutex<DashMap<String, String>>>,
+
+    // Directory of the GHS pictogram images (<code>.svg) used for labels.
+    pub ghs_pictograms_dir: Option<String>,
+    // System fonts used to render the PDF documents, loaded once at startup.
+    pub font_database: Arc<fontdb::Database>,
+
+    // Directory of the safety data sheet files.
+    pub sds_dir: PathBuf,
+    // Safety data sheets older than this number of days are flagged as outdated.
+    pub sds_max_age_days: i64,
+    // Storages expiring within this number of days are alerted.
+    pub expiry_warning_days: i64,
+    // Requests replayed with the same idempotency key within this number of hours
+    // return the original response.
+    pub idempotency_window_hours: i64,
+
+    // PubChem base URL, overridable to use a local PubChem stand-in.
+    pub pubchem_base_url: String,
+    // HTTP client shared by the PubChem structure requests.
+    pub pubchem_http_client: reqwest::Client,
+    // Directory of the cached 2D structure images.
+    pub structure_cache_dir: PathBuf,
+
+    pub incompatibility_rules: Arc<IncompatibilityRules>,
+    // CAS number lists of the regulated products.
+    pub regulatory_lists: Arc<RegulatoryLists>,
 }
 
 pub async fn init_casbin_enforcer(
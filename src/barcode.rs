@@ -0,0 +1,369 @@
+use barcoders::sym::code128::Code128;
+use datamatrix::{DataMatrix, SymbolList};
+use qrcode::{Color, QrCode};
+use serde::Deserialize;
+use std::fmt::Write;
+
+use crate::{
+    errors::AppError,
+    ghs_label::{GLYPH_WIDTH_RATIO, LINE_HEIGHT_RATIO, wrap, xml_escape},
+};
+
+// Height of the linear barcodes, in modules.
+const LINEAR_BARCODE_HEIGHT: usize = 40;
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BarcodeSymbology {
+    #[default]
+    Code128,
+    DataMatrix,
+    Qr,
+}
+
+impl BarcodeSymbology {
+    // Minimum quiet zone around the symbol, in modules.
+    fn quiet_zone(&self) -> usize {
+        match self {
+            BarcodeSymbology::Code128 => 10,
+            BarcodeSymbology::DataMatrix => 1,
+            BarcodeSymbology::Qr => 4,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BarcodeFormat {
+    #[default]
+    Png,
+    Svg,
+}
+
+impl BarcodeFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            BarcodeFormat::Png => "image/png",
+            BarcodeFormat::Svg => "image/svg+xml",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            BarcodeFormat::Png => "png",
+            BarcodeFormat::Svg => "svg",
+        }
+    }
+}
+
+// Code 128 is encoded with the code set B only: the data must be
+// printable ASCII characters.
+fn validate_code128_data(data: &str) -> Result<(), AppError> {
+    if data.is_empty() {
+        return Err(AppError::InputValidation(String::from(
+            "empty Code 128 barcode data",
+        )));
+    }
+
+    match data.chars().find(|c| !(' '..='~').contains(c)) {
+        Some(c) => Err(AppError::InputValidation(format!(
+            "invalid Code 128 barcode character {:?} in {}",
+            c, data
+        ))),
+        None => Ok(()),
+    }
+}
+
+// The dark and light modules of an encoded barcode, row by row,
+// quiet zone included.
+#[derive(Debug, Clone, Default)]
+pub struct Barcode {
+    pub width: usize,
+    pub height: usize,
+    modules: Vec<bool>,
+    // Linear barcodes have identical rows.
+    pub linear: bool,
+}
+
+impl Barcode {
+    fn new(width: usize, height: usize, quiet_zone: usize, linear: bool) -> Self {
+        let width = width + 2 * quiet_zone;
+        let height = if linear {
+            height
+        } else {
+            height + 2 * quiet_zone
+        };
+
+        Barcode {
+            width,
+            height,
+            modules: vec![false; width * height],
+            linear,
+        }
+    }
+
+    fn set_dark(&mut self, x: usize, y: usize) {
+        self.modules[y * self.width + x] = true;
+    }
+
+    pub fn is_dark(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.width + x]
+    }
+
+    pub fn encode(symbology: BarcodeSymbology, data: &str) -> Result<Self, AppError> {
+        let quiet_zone = symbology.quiet_zone();
+
+        match symbology {
+            BarcodeSymbology::Code128 => {
+                // Start with the code set B, that handles the printable ASCII characters.
+                validate_code128_data(data)?;
+                let code128 = match Code128::new(format!("Ɓ{}", data)) {
+                    Ok(code128) => code128,
+                    Err(err) => return Err(AppError::Barcode(err.to_string())),
+                };
+                let bars = code128.encode();
+
+                let mut barcode = Barcode::new(bars.len(), LINEAR_BARCODE_HEIGHT, quiet_zone, true);
+                for (x, bar) in bars.iter().enumerate() {
+                    if *bar == 1 {
+                        for y in 0..LINEAR_BARCODE_HEIGHT {
+                            barcode.set_dark(x + quiet_zone, y);
+                        }
+                    }
+                }
+
+                Ok(barcode)
+            }
+            BarcodeSymbology::DataMatrix => {
+                let data_matrix = match DataMatrix::encode(data.as_bytes(), SymbolList::default()) {
+                    Ok(data_matrix) => data_matrix,
+                    Err(err) => return Err(AppError::Barcode(format!("{:?}", err))),
+                };
+                let bitmap = data_matrix.bitmap();
+
+                let mut barcode = Barcode::new(bitmap.width(), bitmap.height(), quiet_zone, false);
+                for (x, y) in bitmap.pixels() {
+                    barcode.set_dark(x + quiet_zone, y + quiet_zone);
+                }
+
+                Ok(barcode)
+            }
+            BarcodeSymbology::Qr => {
+                let qr_code = match QrCode::new(data.as_bytes()) {
+                    Ok(qr_code) => qr_code,
+                    Err(err) => return Err(AppError::Barcode(err.to_string())),
+                };
+                let size = qr_code.width();
+
+                let mut barcode = Barcode::new(size, size, quiet_zone, false);
+                for (i, color) in qr_code.to_colors().iter().enumerate() {
+                    if *color == Color::Dark {
+                        barcode.set_dark(i % size + quiet_zone, i / size + quiet_zone);
+                    }
+                }
+
+                Ok(barcode)
+            }
+        }
+    }
+
+    // Return the SVG path data drawing the dark modules, one horizontal
+    // run per path segment, with the given module size in user units.
+    pub fn svg_path(&self, x: f64, y: f64, module_width: f64, module_height: f64) -> String {
+        let mut path = String::new();
+        for row in 0..self.height {
+            let mut column = 0;
+            while column < self.width {
+                if !self.is_dark(column, row) {
+                    column += 1;
+                    continue;
+                }
+                let start = column;
+                while column < self.width && self.is_dark(column, row) {
+                    column += 1;
+                }
+
+                let _ = write!(
+                    path,
+                    "M{:.3},{:.3}h{:.3}v{:.3}h{:.3}z",
+                    x + start as f64 * module_width,
+                    y + row as f64 * module_height,
+                    (column - start) as f64 * module_width,
+                    module_height,
+                    -((column - start) as f64) * module_width
+                );
+            }
+        }
+
+        path
+    }
+
+    // Render the barcode as a standalone SVG document, scale pixels per module.
+    pub fn to_svg(&self, scale: u32) -> String {
+        let scale = scale as f64;
+        let (width, height) = (self.width as f64 * scale, self.height as f64 * scale);
+
+        format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}" shape-rendering="crispEdges"><rect width="{width}" height="{height}" fill="white"/><path fill="black" d="{}"/></svg>"#,
+            self.svg_path(0.0, 0.0, scale, scale)
+        )
+    }
+
+    // Render the barcode as a grayscale PNG image, scale pixels per module.
+    pub fn to_png(&self, scale: u32) -> Result<Vec<u8>, AppError> {
+        let scale = scale as usize;
+        let (width, height) = (self.width * scale, self.height * scale);
+
+        let mut pixels: Vec<u8> = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                pixels.push(if self.is_dark(x / scale, y / scale) {
+                    0
+                } else {
+                    255
+                });
+            }
+        }
+
+        let mut png: Vec<u8> = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, width as u32, height as u32);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let mut writer = match encoder.write_header() {
+            Ok(writer) => writer,
+            Err(err) => return Err(AppError::Barcode(err.to_string())),
+        };
+        if let Err(err) = writer.write_image_data(&pixels) {
+            return Err(AppError::Barcode(err.to_string()));
+        }
+        if let Err(err) = writer.finish() {
+            return Err(AppError::Barcode(err.to_string()));
+        }
+
+        Ok(png)
+    }
+}
+
+// A4 label sheet layout, in millimeters: 3 columns of 8 labels of 70x37.
+pub const LABEL_SHEET_COLUMNS: usize = 3;
+pub const LABEL_SHEET_ROWS: usize = 8;
+const LABEL_SHEET_WIDTH: f64 = 210.0;
+const LABEL_SHEET_HEIGHT: f64 = 297.0;
+const SHEET_LABEL_MARGIN: f64 = 3.0;
+const SHEET_LABEL_FONT_SIZE: f64 = 3.0;
+
+// A label of a label sheet.
+#[derive(Debug, Clone, Default)]
+pub struct SheetLabel {
+    pub barcode: Barcode,
+    pub caption: String,
+    pub lines: Vec<String>,
+}
+
+// Render an A4 label sheet as an SVG document in millimeters,
+// the labels being laid out row by row from the given first position
+// so that a partially used sheet can be printed again.
+pub fn render_label_sheet_svg(labels: &[SheetLabel], first_position: usize) -> String {
+    let label_width = LABEL_SHEET_WIDTH / LABEL_SHEET_COLUMNS as f64;
+    let label_height = LABEL_SHEET_HEIGHT / LABEL_SHEET_ROWS as f64;
+    let line_height = SHEET_LABEL_FONT_SIZE * LINE_HEIGHT_RATIO;
+
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{LABEL_SHEET_WIDTH}" height="{LABEL_SHEET_HEIGHT}" viewBox="0 0 {LABEL_SHEET_WIDTH} {LABEL_SHEET_HEIGHT}">"#
+    );
+
+    for (i, label) in labels.iter().enumerate() {
+        let position = first_position + i;
+        let label_x = (position % LABEL_SHEET_COLUMNS) as f64 * label_width;
+        let label_y = (position / LABEL_SHEET_COLUMNS) as f64 * label_height;
+        let barcode = &label.barcode;
+
+        // Linear barcodes take the label width above the texts,
+        // square ones the label height left to the texts.
+        let (module_width, module_height, text_x, text_y, text_width) = if barcode.linear {
+            let module_width = (label_width - 2.0 * SHEET_LABEL_MARGIN) / barcode.width as f64;
+            let module_height = (label_height / 2.0) / barcode.height as f64;
+            (
+                module_width,
+                module_height,
+                label_x + SHEET_LABEL_MARGIN,
+                label_y + SHEET_LABEL_MARGIN + label_height / 2.0 + line_height,
+                label_width - 2.0 * SHEET_LABEL_MARGIN,
+            )
+        } else {
+            let size = label_height - 2.0 * SHEET_LABEL_MARGIN;
+            let module_size = size / barcode.width.max(barcode.height) as f64;
+            (
+                module_size,
+                module_size,
+                label_x + SHEET_LABEL_MARGIN + size + SHEET_LABEL_MARGIN,
+                label_y + SHEET_LABEL_MARGIN + line_height,
+                label_width - 3.0 * SHEET_LABEL_MARGIN - size,
+            )
+        };
+
+        let _ = write!(
+            svg,
+            r#"<path fill="black" d="{}"/>"#,
+            barcode.svg_path(
+                label_x + SHEET_LABEL_MARGIN,
+                label_y + SHEET_LABEL_MARGIN,
+                module_width,
+                module_height
+            )
+        );
+
+        let max_chars = (text_width / (SHEET_LABEL_FONT_SIZE * GLYPH_WIDTH_RATIO)).floor() as usize;
+        let mut text_lines: Vec<String> = vec![label.caption.clone()];
+        for line in label.lines.iter() {
+            text_lines.extend(wrap(line, max_chars.max(1)));
+        }
+
+        let mut y = text_y;
+        for (j, line) in text_lines.iter().enumerate() {
+            if y > label_y + label_height - SHEET_LABEL_MARGIN {
+                break;
+            }
+            let _ = write!(
+                svg,
+                r#"<text x="{text_x:.2}" y="{y:.2}" font-size="{SHEET_LABEL_FONT_SIZE:.2}" font-family="{}"{}>{}</text>"#,
+                if j == 0 { "monospace" } else { "sans-serif" },
+                if j == 0 { r#" font-weight="bold""# } else { "" },
+                xml_escape(line)
+            );
+            y += line_height;
+        }
+    }
+
+    svg.push_str("</svg>");
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn code128_accepts_printable_ascii() {
+        assert!(validate_code128_data("CHIM-0042 a~z").is_ok());
+    }
+
+    #[test]
+    fn code128_rejects_invalid_data() {
+        for data in ["", "Éthanol", "Ɓ12", "tab\there", "µL"] {
+            assert!(
+                matches!(
+                    validate_code128_data(data),
+                    Err(AppError::InputValidation(_))
+                ),
+                "{:?}",
+                data
+            );
+            assert!(matches!(
+                Barcode::encode(BarcodeSymbology::Code128, data),
+                Err(AppError::InputValidation(_))
+            ));
+        }
+    }
+}
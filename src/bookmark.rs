@@ -0,0 +1,218 @@
+use rusqlite::{Connection, TransactionBehavior, params};
+use std::collections::BTreeMap;
+
+use crate::errors::AppError;
+
+// Create the bookmark labels table.
+// The bookmarks themselves are the chimitheque_db bookmark rows, so that
+// the products keep their bookmark flag.
+pub fn init_bookmark(db_connection: &Connection) -> Result<(), AppError> {
+    match db_connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS bookmark_label (
+            bookmark_label_person_id INTEGER NOT NULL,
+            bookmark_label_product_id INTEGER NOT NULL,
+            bookmark_label_label TEXT NOT NULL,
+            PRIMARY KEY (bookmark_label_person_id, bookmark_label_product_id, bookmark_label_label)
+        );",
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// chimitheque_db only toggles a bookmark, which is undone by a retried
+// request: a bookmark and its labels are written below, in one transaction.
+
+// Bookmark a product with the given labels, replacing the previous ones.
+pub fn create_product_bookmark(
+    db_connection: &mut Connection,
+    person_id: u64,
+    product_id: u64,
+    labels: &[String],
+) -> Result<(), AppError> {
+    let tx = match db_connection.transaction_with_behavior(TransactionBehavior::Immediate) {
+        Ok(tx) => tx,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    if let Err(err) = tx.execute(
+        "INSERT INTO bookmark (person, product)
+        SELECT ?1, ?2
+        WHERE NOT EXISTS (SELECT 1 FROM bookmark WHERE person = ?1 AND product = ?2)",
+        params![person_id, product_id],
+    ) {
+        return Err(AppError::Database(err.to_string()));
+    }
+
+    if let Err(err) = tx.execute(
+        "DELETE FROM bookmark_label WHERE bookmark_label_person_id = ?1 AND bookmark_label_product_id = ?2",
+        params![person_id, product_id],
+    ) {
+        return Err(AppError::Database(err.to_string()));
+    }
+
+    for label in labels {
+        if let Err(err) = tx.execute(
+            "INSERT OR IGNORE INTO bookmark_label (bookmark_label_person_id, bookmark_label_product_id, bookmark_label_label) VALUES (?1, ?2, ?3)",
+            params![person_id, product_id, label],
+        ) {
+            return Err(AppError::Database(err.to_string()));
+        }
+    }
+
+    match tx.commit() {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// Remove a product bookmark and its labels, doing nothing if the product
+// is not bookmarked.
+pub fn delete_product_bookmark(
+    db_connection: &mut Connection,
+    person_id: u64,
+    product_id: u64,
+) -> Result<(), AppError> {
+    let tx = match db_connection.transaction_with_behavior(TransactionBehavior::Immediate) {
+        Ok(tx) => tx,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    if let Err(err) = tx.execute(
+        "DELETE FROM bookmark WHERE person = ?1 AND product = ?2",
+        params![person_id, product_id],
+    ) {
+        return Err(AppError::Database(err.to_string()));
+    }
+
+    if let Err(err) = tx.execute(
+        "DELETE FROM bookmark_label WHERE bookmark_label_person_id = ?1 AND bookmark_label_product_id = ?2",
+        params![person_id, product_id],
+    ) {
+        return Err(AppError::Database(err.to_string()));
+    }
+
+    match tx.commit() {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// The bookmarked product IDs of a person with their sorted labels,
+// most recent first.
+pub fn get_product_bookmarks(
+    db_connection: &Connection,
+    person_id: u64,
+) -> Result<Vec<(u64, Vec<String>)>, AppError> {
+    let mut labels: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+    {
+        let mut stmt = match db_connection.prepare(
+            "SELECT bookmark_label_product_id, bookmark_label_label FROM bookmark_label
+            WHERE bookmark_label_person_id = ?1
+            ORDER BY bookmark_label_label",
+        ) {
+            Ok(stmt) => stmt,
+            Err(err) => return Err(AppError::Database(err.to_string())),
+        };
+        let rows = match stmt.query_map(params![person_id], |row| {
+            Ok((row.get::<_, u64>(0)?, row.get::<_, String>(1)?))
+        }) {
+            Ok(rows) => rows,
+            Err(err) => return Err(AppError::Database(err.to_string())),
+        };
+        for row in rows {
+            match row {
+                Ok((product_id, label)) => labels.entry(product_id).or_default().push(label),
+                Err(err) => return Err(AppError::Database(err.to_string())),
+            }
+        }
+    }
+
+    let mut stmt = match db_connection
+        .prepare("SELECT product FROM bookmark WHERE person = ?1 ORDER BY bookmark_id DESC")
+    {
+        Ok(stmt) => stmt,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    match stmt.query_map(params![person_id], |row| row.get::<_, u64>(0)) {
+        Ok(rows) => match rows.collect::<Result<Vec<u64>, rusqlite::Error>>() {
+            Ok(product_ids) => Ok(product_ids
+                .into_iter()
+                .map(|product_id| (product_id, labels.remove(&product_id).unwrap_or_default()))
+                .collect()),
+            Err(err) => Err(AppError::Database(err.to_string())),
+        },
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn init_test_db() -> Connection {
+        let db_connection = Connection::open_in_memory().unwrap();
+        db_connection
+            .execute_batch(
+                "CREATE TABLE bookmark (
+                    bookmark_id INTEGER PRIMARY KEY,
+                    person INTEGER NOT NULL,
+                    product INTEGER NOT NULL
+                );",
+            )
+            .unwrap();
+        init_bookmark(&db_connection).unwrap();
+        db_connection
+    }
+
+    fn labels(labels: &[&str]) -> Vec<String> {
+        labels.iter().map(|label| label.to_string()).collect()
+    }
+
+    #[test]
+    fn create_product_bookmark_is_idempotent() {
+        let mut db_connection = init_test_db();
+
+        create_product_bookmark(&mut db_connection, 1, 10, &labels(&["project"])).unwrap();
+        create_product_bookmark(&mut db_connection, 1, 10, &labels(&["project"])).unwrap();
+        create_product_bookmark(&mut db_connection, 1, 20, &[]).unwrap();
+        create_product_bookmark(&mut db_connection, 2, 10, &labels(&["other"])).unwrap();
+
+        assert_eq!(
+            get_product_bookmarks(&db_connection, 1).unwrap(),
+            vec![(20, vec![]), (10, labels(&["project"]))]
+        );
+    }
+
+    #[test]
+    fn create_product_bookmark_replaces_the_labels() {
+        let mut db_connection = init_test_db();
+
+        create_product_bookmark(&mut db_connection, 1, 10, &labels(&["a", "b"])).unwrap();
+        create_product_bookmark(&mut db_connection, 1, 10, &labels(&["c", "b"])).unwrap();
+
+        assert_eq!(
+            get_product_bookmarks(&db_connection, 1).unwrap(),
+            vec![(10, labels(&["b", "c"]))]
+        );
+    }
+
+    #[test]
+    fn delete_product_bookmark_removes_the_labels() {
+        let mut db_connection = init_test_db();
+
+        create_product_bookmark(&mut db_connection, 1, 10, &labels(&["project"])).unwrap();
+        delete_product_bookmark(&mut db_connection, 1, 10).unwrap();
+        // Deleting twice does nothing.
+        delete_product_bookmark(&mut db_connection, 1, 10).unwrap();
+        assert!(get_product_bookmarks(&db_connection, 1).unwrap().is_empty());
+
+        // A new bookmark does not get the previous labels back.
+        create_product_bookmark(&mut db_connection, 1, 10, &[]).unwrap();
+        assert_eq!(
+            get_product_bookmarks(&db_connection, 1).unwrap(),
+            vec![(10, vec![])]
+        );
+    }
+}
@@ -0,0 +1,194 @@
+use rusqlite::{Connection, OptionalExtension, Row, params};
+use serde::Serialize;
+
+use crate::errors::AppError;
+
+pub const BORROWING_DATETIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+pub const BORROWING_DATE_FORMAT: &str = "%Y-%m-%d";
+
+// A storage borrowing, open until the storage is returned.
+// Dates are formatted with BORROWING_DATETIME_FORMAT, the due date
+// with BORROWING_DATE_FORMAT.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct BorrowingLog {
+    pub borrowing_log_id: u64,
+    pub storage_id: u64,
+    pub borrower_id: u64,
+    // The person who recorded the borrowing.
+    pub lender_id: u64,
+    // The storage owner when it was borrowed.
+    pub owner_id: Option<u64>,
+    pub comment: Option<String>,
+    pub borrowed_at: String,
+    pub due_date: Option<String>,
+    pub returned_at: Option<String>,
+    pub returned_by: Option<u64>,
+}
+
+const BORROWING_LOG_COLUMNS: &str = "borrowing_log_id, borrowing_log_storage_id, borrowing_log_borrower_id, borrowing_log_lender_id, borrowing_log_owner_id, borrowing_log_comment, borrowing_log_borrowed_at, borrowing_log_due_date, borrowing_log_returned_at, borrowing_log_returned_by";
+
+fn borrowing_log_from_row(row: &Row) -> Result<BorrowingLog, rusqlite::Error> {
+    Ok(BorrowingLog {
+        borrowing_log_id: row.get(0)?,
+        storage_id: row.get(1)?,
+        borrower_id: row.get(2)?,
+        lender_id: row.get(3)?,
+        owner_id: row.get(4)?,
+        comment: row.get(5)?,
+        borrowed_at: row.get(6)?,
+        due_date: row.get(7)?,
+        returned_at: row.get(8)?,
+        returned_by: row.get(9)?,
+    })
+}
+
+fn query_borrowing_logs(
+    db_connection: &Connection,
+    filter: &str,
+    params: impl rusqlite::Params,
+) -> Result<Vec<BorrowingLog>, AppError> {
+    let sql = format!(
+        "SELECT {} FROM borrowing_log WHERE {} ORDER BY borrowing_log_borrowed_at DESC, borrowing_log_id DESC",
+        BORROWING_LOG_COLUMNS, filter
+    );
+
+    let mut stmt = match db_connection.prepare(&sql) {
+        Ok(stmt) => stmt,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    match stmt.query_map(params, borrowing_log_from_row) {
+        Ok(rows) => match rows.collect::<Result<Vec<BorrowingLog>, rusqlite::Error>>() {
+            Ok(borrowing_logs) => Ok(borrowing_logs),
+            Err(err) => Err(AppError::Database(err.to_string())),
+        },
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// Create the borrowing history table.
+pub fn init_borrowing_log(db_connection: &Connection) -> Result<(), AppError> {
+    match db_connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS borrowing_log (
+            borrowing_log_id INTEGER PRIMARY KEY,
+            borrowing_log_storage_id INTEGER NOT NULL,
+            borrowing_log_borrower_id INTEGER NOT NULL,
+            borrowing_log_lender_id INTEGER NOT NULL,
+            borrowing_log_owner_id INTEGER,
+            borrowing_log_comment TEXT,
+            borrowing_log_borrowed_at TEXT NOT NULL,
+            borrowing_log_due_date TEXT,
+            borrowing_log_returned_at TEXT,
+            borrowing_log_returned_by INTEGER
+        );
+        CREATE INDEX IF NOT EXISTS idx_borrowing_log_storage ON borrowing_log(borrowing_log_storage_id);",
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+pub fn insert_borrowing_log(
+    db_connection: &Connection,
+    borrowing_log: &BorrowingLog,
+) -> Result<u64, AppError> {
+    match db_connection.execute(
+        "INSERT INTO borrowing_log (borrowing_log_storage_id, borrowing_log_borrower_id, borrowing_log_lender_id, borrowing_log_owner_id, borrowing_log_comment, borrowing_log_borrowed_at, borrowing_log_due_date)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            borrowing_log.storage_id,
+            borrowing_log.borrower_id,
+            borrowing_log.lender_id,
+            borrowing_log.owner_id,
+            borrowing_log.comment,
+            borrowing_log.borrowed_at,
+            borrowing_log.due_date
+        ],
+    ) {
+        Ok(_) => Ok(db_connection.last_insert_rowid() as u64),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// Close the open borrowing of a storage, if any.
+pub fn close_borrowing_log(
+    db_connection: &Connection,
+    storage_id: u64,
+    returned_at: &str,
+    returned_by: u64,
+) -> Result<(), AppError> {
+    match db_connection.execute(
+        "UPDATE borrowing_log SET borrowing_log_returned_at = ?1, borrowing_log_returned_by = ?2
+        WHERE borrowing_log_storage_id = ?3 AND borrowing_log_returned_at IS NULL",
+        params![returned_at, returned_by, storage_id],
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+pub fn get_open_borrowing_log(
+    db_connection: &Connection,
+    storage_id: u64,
+) -> Result<Option<BorrowingLog>, AppError> {
+    let sql = format!(
+        "SELECT {} FROM borrowing_log WHERE borrowing_log_storage_id = ?1 AND borrowing_log_returned_at IS NULL",
+        BORROWING_LOG_COLUMNS
+    );
+
+    match db_connection
+        .query_row(&sql, params![storage_id], borrowing_log_from_row)
+        .optional()
+    {
+        Ok(borrowing_log) => Ok(borrowing_log),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// The borrowing history of a storage, most recent first.
+pub fn get_storage_borrowing_logs(
+    db_connection: &Connection,
+    storage_id: u64,
+) -> Result<Vec<BorrowingLog>, AppError> {
+    query_borrowing_logs(
+        db_connection,
+        "borrowing_log_storage_id = ?1",
+        params![storage_id],
+    )
+}
+
+// The open borrowings of a borrower.
+pub fn get_borrower_borrowing_logs(
+    db_connection: &Connection,
+    borrower_id: u64,
+) -> Result<Vec<BorrowingLog>, AppError> {
+    query_borrowing_logs(
+        db_connection,
+        "borrowing_log_borrower_id = ?1 AND borrowing_log_returned_at IS NULL",
+        params![borrower_id],
+    )
+}
+
+// The open borrowings of the storages of an owner, by other people.
+pub fn get_owner_borrowing_logs(
+    db_connection: &Connection,
+    owner_id: u64,
+) -> Result<Vec<BorrowingLog>, AppError> {
+    query_borrowing_logs(
+        db_connection,
+        "borrowing_log_owner_id = ?1 AND borrowing_log_borrower_id != ?1 AND borrowing_log_returned_at IS NULL",
+        params![owner_id],
+    )
+}
+
+// The open borrowings with a due date before the given date.
+pub fn get_overdue_borrowing_logs(
+    db_connection: &Connection,
+    date: &str,
+) -> Result<Vec<BorrowingLog>, AppError> {
+    query_borrowing_logs(
+        db_connection,
+        "borrowing_log_due_date < ?1 AND borrowing_log_returned_at IS NULL",
+        params![date],
+    )
+}
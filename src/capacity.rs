@@ -0,0 +1,595 @@
+use chimitheque_types::{product::Product, storage::Storage};
+use rusqlite::{Connection, Row, params};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+use crate::{
+    consumption::convert_quantity,
+    errors::AppError,
+    waste::{HazardClassTotal, UNCLASSIFIED_HAZARD_CLASS, normalize_quantity},
+};
+
+// A maximum quantity of the storages of a store location and its
+// sub-locations, for a hazard class (GHS pictogram code such as SGH02)
+// or for all the storages.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CapacityLimit {
+    #[serde(default)]
+    pub capacity_limit_id: u64,
+    #[serde(default)]
+    pub store_location_id: u64,
+    #[serde(default)]
+    pub hazard_class: Option<String>,
+    pub max_quantity: f64,
+    // A mass or volume unit, such as kg or L.
+    pub unit_label: String,
+    // Reject the storages exceeding the limit instead of warning.
+    #[serde(default)]
+    pub strict: bool,
+}
+
+impl CapacityLimit {
+    pub fn sanitize_and_validate(&mut self) -> Result<(), AppError> {
+        self.hazard_class = self
+            .hazard_class
+            .as_ref()
+            .map(|hazard_class| hazard_class.trim().to_uppercase())
+            .filter(|hazard_class| !hazard_class.is_empty());
+        self.unit_label = self.unit_label.trim().to_string();
+
+        if !self.max_quantity.is_finite() || self.max_quantity <= 0.0 {
+            return Err(AppError::InputValidation(format!(
+                "invalid maximum quantity {}",
+                self.max_quantity
+            )));
+        }
+        if convert_quantity(1.0, &self.unit_label, "kg").is_none()
+            && convert_quantity(1.0, &self.unit_label, "L").is_none()
+        {
+            return Err(AppError::InputValidation(format!(
+                "unit {} is not a mass or volume unit",
+                self.unit_label
+            )));
+        }
+
+        Ok(())
+    }
+
+    // Whether the limit counts the storages of the product.
+    pub fn applies_to(&self, product: &Product) -> bool {
+        match &self.hazard_class {
+            Some(hazard_class) => product
+                .symbols
+                .iter()
+                .flatten()
+                .any(|symbol| symbol.symbol_label.eq_ignore_ascii_case(hazard_class)),
+            None => true,
+        }
+    }
+}
+
+// The current total of the storages counted by a limit, in the limit unit.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct CapacityTotal {
+    pub capacity_limit: CapacityLimit,
+    pub nb_storages: usize,
+    pub total: f64,
+    // Storages without quantity or with a unit that can not be converted
+    // to the limit unit, not included in the total.
+    pub nb_unconverted: usize,
+    pub exceeded: bool,
+}
+
+// The current storages of a store location and its sub-locations
+// with the same hazard class and quantity unit.
+#[derive(Debug, Clone, Default)]
+pub struct StoredQuantity {
+    // None for all the storages, or for the storages without hazard class
+    // when grouped by hazard class.
+    pub hazard_class: Option<String>,
+    pub unit_label: Option<String>,
+    pub nb_storages: usize,
+    // The storages with a quantity, and the sum of their quantities.
+    pub nb_quantities: usize,
+    pub quantity: f64,
+}
+
+// A limit that a storage to be created or moved would exceed.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct CapacityExcess {
+    pub store_location_id: u64,
+    pub store_location_name: String,
+    pub hazard_class: Option<String>,
+    pub max_quantity: f64,
+    pub unit_label: String,
+    // The total including the storage.
+    pub total: f64,
+    pub strict: bool,
+}
+
+impl std::fmt::Display for CapacityExcess {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} would exceed the {} {} limit of {}",
+            self.total,
+            self.unit_label,
+            self.hazard_class.as_deref().unwrap_or("overall"),
+            self.max_quantity,
+            self.unit_label,
+            self.store_location_name
+        )
+    }
+}
+
+// The quantity of a storage converted to the given unit, if possible.
+pub fn storage_quantity_in(storage: &Storage, unit_label: &str) -> Option<f64> {
+    let quantity = storage.storage_quantity?;
+    let storage_unit_label = storage
+        .unit_quantity
+        .as_ref()
+        .map(|unit| unit.unit_label.as_str())?;
+
+    convert_quantity(quantity, storage_unit_label, unit_label)
+}
+
+// Whether an updated storage holds more than before. A quantity that can not
+// be compared with the previous one is considered increased.
+pub fn storage_quantity_increased(previous_storage: &Storage, storage: &Storage) -> bool {
+    if storage.storage_quantity.is_none() {
+        return false;
+    }
+    let Some(previous_quantity) = previous_storage.storage_quantity else {
+        return true;
+    };
+    let Some(previous_unit_label) = previous_storage
+        .unit_quantity
+        .as_ref()
+        .map(|unit| unit.unit_label.as_str())
+    else {
+        return true;
+    };
+
+    match storage_quantity_in(storage, previous_unit_label) {
+        Some(quantity) => quantity > previous_quantity,
+        None => true,
+    }
+}
+
+// Sum the stored quantities counted by a limit, see get_stored_quantities.
+pub fn capacity_total(
+    capacity_limit: &CapacityLimit,
+    stored_quantities: &[StoredQuantity],
+) -> CapacityTotal {
+    let mut capacity_total = CapacityTotal {
+        capacity_limit: capacity_limit.clone(),
+        ..Default::default()
+    };
+
+    for stored_quantity in stored_quantities {
+        capacity_total.nb_storages += stored_quantity.nb_storages;
+        match stored_quantity
+            .unit_label
+            .as_deref()
+            .and_then(|unit_label| {
+                convert_quantity(
+                    stored_quantity.quantity,
+                    unit_label,
+                    &capacity_limit.unit_label,
+                )
+            }) {
+            Some(quantity) => {
+                capacity_total.total += quantity;
+                capacity_total.nb_unconverted +=
+                    stored_quantity.nb_storages - stored_quantity.nb_quantities;
+            }
+            None => capacity_total.nb_unconverted += stored_quantity.nb_storages,
+        }
+    }
+    capacity_total.exceeded = capacity_total.total > capacity_limit.max_quantity;
+
+    capacity_total
+}
+
+// The storages and quantities per hazard class, see get_stored_quantities_by_hazard_class.
+pub fn stored_hazard_class_totals(stored_quantities: &[StoredQuantity]) -> Vec<HazardClassTotal> {
+    let mut totals: BTreeMap<String, HazardClassTotal> = BTreeMap::new();
+
+    for stored_quantity in stored_quantities {
+        let hazard_class = stored_quantity
+            .hazard_class
+            .clone()
+            .unwrap_or_else(|| UNCLASSIFIED_HAZARD_CLASS.to_string());
+        let total = totals
+            .entry(hazard_class.clone())
+            .or_insert_with(|| HazardClassTotal {
+                hazard_class,
+                ..Default::default()
+            });
+        total.nb_storages += stored_quantity.nb_storages;
+        if let (Some(unit_label), true) = (
+            stored_quantity.unit_label.as_deref(),
+            stored_quantity.nb_quantities > 0,
+        ) {
+            let (unit_label, quantity) = normalize_quantity(stored_quantity.quantity, unit_label);
+            *total.quantities.entry(unit_label).or_default() += quantity;
+        }
+    }
+
+    totals.into_values().collect()
+}
+
+const CAPACITY_LIMIT_COLUMNS: &str = "capacity_limit_id, capacity_limit_store_location_id, capacity_limit_hazard_class, capacity_limit_max_quantity, capacity_limit_unit_label, capacity_limit_strict";
+
+fn capacity_limit_from_row(row: &Row) -> Result<CapacityLimit, rusqlite::Error> {
+    Ok(CapacityLimit {
+        capacity_limit_id: row.get(0)?,
+        store_location_id: row.get(1)?,
+        hazard_class: row.get(2)?,
+        max_quantity: row.get(3)?,
+        unit_label: row.get(4)?,
+        strict: row.get(5)?,
+    })
+}
+
+fn query_capacity_limits(
+    db_connection: &Connection,
+    filter: &str,
+    params: impl rusqlite::Params,
+) -> Result<Vec<CapacityLimit>, AppError> {
+    let sql = format!(
+        "SELECT {} FROM capacity_limit WHERE {} ORDER BY capacity_limit_store_location_id, capacity_limit_id",
+        CAPACITY_LIMIT_COLUMNS, filter
+    );
+
+    let mut stmt = match db_connection.prepare(&sql) {
+        Ok(stmt) => stmt,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    match stmt.query_map(params, capacity_limit_from_row) {
+        Ok(rows) => match rows.collect::<Result<Vec<CapacityLimit>, rusqlite::Error>>() {
+            Ok(capacity_limits) => Ok(capacity_limits),
+            Err(err) => Err(AppError::Database(err.to_string())),
+        },
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// Create the store location capacity limits table.
+pub fn init_capacity(db_connection: &Connection) -> Result<(), AppError> {
+    match db_connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS capacity_limit (
+            capacity_limit_id INTEGER PRIMARY KEY,
+            capacity_limit_store_location_id INTEGER NOT NULL,
+            capacity_limit_hazard_class TEXT,
+            capacity_limit_max_quantity REAL NOT NULL,
+            capacity_limit_unit_label TEXT NOT NULL,
+            capacity_limit_strict INTEGER NOT NULL DEFAULT 0
+        );
+        CREATE INDEX IF NOT EXISTS idx_capacity_limit_store_location ON capacity_limit(capacity_limit_store_location_id);",
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// Replace the capacity limits of a store location.
+pub fn set_capacity_limits(
+    db_connection: &mut Connection,
+    store_location_id: u64,
+    capacity_limits: &[CapacityLimit],
+) -> Result<(), AppError> {
+    let tx = match db_connection.transaction() {
+        Ok(tx) => tx,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    if let Err(err) = tx.execute(
+        "DELETE FROM capacity_limit WHERE capacity_limit_store_location_id = ?1",
+        params![store_location_id],
+    ) {
+        return Err(AppError::Database(err.to_string()));
+    }
+
+    for capacity_limit in capacity_limits.iter() {
+        if let Err(err) = tx.execute(
+            "INSERT INTO capacity_limit (capacity_limit_store_location_id, capacity_limit_hazard_class, capacity_limit_max_quantity, capacity_limit_unit_label, capacity_limit_strict)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                store_location_id,
+                capacity_limit.hazard_class,
+                capacity_limit.max_quantity,
+                capacity_limit.unit_label,
+                capacity_limit.strict
+            ],
+        ) {
+            return Err(AppError::Database(err.to_string()));
+        }
+    }
+
+    match tx.commit() {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+pub fn get_capacity_limits(
+    db_connection: &Connection,
+    store_location_id: u64,
+) -> Result<Vec<CapacityLimit>, AppError> {
+    query_capacity_limits(
+        db_connection,
+        "capacity_limit_store_location_id = ?1",
+        params![store_location_id],
+    )
+}
+
+pub fn get_all_capacity_limits(db_connection: &Connection) -> Result<Vec<CapacityLimit>, AppError> {
+    query_capacity_limits(db_connection, "1 = 1", [])
+}
+
+// The store location ?1 and all its sub-locations.
+const STORE_LOCATION_SUBTREE: &str = "WITH RECURSIVE subtree(store_location_id) AS (
+        SELECT ?1
+        UNION
+        SELECT store_location.store_location_id FROM store_location
+        JOIN subtree ON store_location.store_location = subtree.store_location_id
+    )";
+
+// The current storages, the archived storages and the storage history excluded.
+const CURRENT_STORAGES_IN_SUBTREE: &str =
+    "storage.store_location IN (SELECT store_location_id FROM subtree)
+    AND storage.storage_archive = 0 AND storage.storage IS NULL";
+
+// The store location and its parents, closest first, with their names.
+pub fn get_store_location_ancestors(
+    db_connection: &Connection,
+    store_location_id: u64,
+) -> Result<Vec<(u64, String)>, AppError> {
+    let mut stmt = match db_connection.prepare(
+        "WITH RECURSIVE ancestor(store_location_id, store_location_name, parent_id, depth) AS (
+            SELECT store_location_id, store_location_name, store_location, 0
+            FROM store_location WHERE store_location_id = ?1
+            UNION
+            SELECT store_location.store_location_id, store_location.store_location_name, store_location.store_location, ancestor.depth + 1
+            FROM store_location JOIN ancestor ON store_location.store_location_id = ancestor.parent_id
+            WHERE ancestor.depth < 64
+        )
+        SELECT store_location_id, store_location_name FROM ancestor ORDER BY depth",
+    ) {
+        Ok(stmt) => stmt,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    match stmt.query_map(params![store_location_id], |row| {
+        Ok((row.get(0)?, row.get(1)?))
+    }) {
+        Ok(rows) => match rows.collect::<Result<Vec<(u64, String)>, rusqlite::Error>>() {
+            Ok(ancestors) => Ok(ancestors),
+            Err(err) => Err(AppError::Database(err.to_string())),
+        },
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// The store location and all its sub-locations.
+pub fn get_store_location_subtree_ids(
+    db_connection: &Connection,
+    store_location_id: u64,
+) -> Result<HashSet<u64>, AppError> {
+    let sql = format!(
+        "{} SELECT store_location_id FROM subtree",
+        STORE_LOCATION_SUBTREE
+    );
+
+    let mut stmt = match db_connection.prepare(&sql) {
+        Ok(stmt) => stmt,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    match stmt.query_map(params![store_location_id], |row| row.get(0)) {
+        Ok(rows) => match rows.collect::<Result<HashSet<u64>, rusqlite::Error>>() {
+            Ok(subtree_ids) => Ok(subtree_ids),
+            Err(err) => Err(AppError::Database(err.to_string())),
+        },
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+fn query_stored_quantities(
+    db_connection: &Connection,
+    sql: &str,
+    params: impl rusqlite::Params,
+) -> Result<Vec<StoredQuantity>, AppError> {
+    let mut stmt = match db_connection.prepare(sql) {
+        Ok(stmt) => stmt,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    match stmt.query_map(params, |row| {
+        Ok(StoredQuantity {
+            hazard_class: row.get(0)?,
+            unit_label: row.get(1)?,
+            nb_storages: row.get(2)?,
+            nb_quantities: row.get(3)?,
+            quantity: row.get(4)?,
+        })
+    }) {
+        Ok(rows) => match rows.collect::<Result<Vec<StoredQuantity>, rusqlite::Error>>() {
+            Ok(stored_quantities) => Ok(stored_quantities),
+            Err(err) => Err(AppError::Database(err.to_string())),
+        },
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// The current storages of a store location and its sub-locations per quantity unit,
+// of the products with the given hazard class if any, whoever can see them.
+// The excluded storages are the ones being moved or updated, counted by the caller.
+pub fn get_stored_quantities(
+    db_connection: &Connection,
+    store_location_id: u64,
+    hazard_class: Option<&str>,
+    excluded_storage_ids: &[u64],
+) -> Result<Vec<StoredQuantity>, AppError> {
+    let excluded_storages = if excluded_storage_ids.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "AND storage.storage_id NOT IN ({})",
+            excluded_storage_ids
+                .iter()
+                .map(|storage_id| storage_id.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        )
+    };
+    let sql = format!(
+        "{} SELECT ?2, unit.unit_label, COUNT(*), COUNT(storage.storage_quantity), TOTAL(storage.storage_quantity)
+        FROM storage
+        LEFT JOIN unit ON storage.unit_quantity = unit.unit_id
+        WHERE {} {}
+        AND (?2 IS NULL OR EXISTS (
+            SELECT 1 FROM productsymbols
+            JOIN symbol ON productsymbols.productsymbols_symbol_id = symbol.symbol_id
+            WHERE productsymbols.productsymbols_product_id = storage.product
+            AND UPPER(symbol.symbol_label) = UPPER(?2)
+        ))
+        GROUP BY unit.unit_label",
+        STORE_LOCATION_SUBTREE, CURRENT_STORAGES_IN_SUBTREE, excluded_storages
+    );
+
+    query_stored_quantities(
+        db_connection,
+        &sql,
+        params![store_location_id, hazard_class],
+    )
+}
+
+// The current storages of a store location and its sub-locations per hazard class
+// and quantity unit, whoever can see them.
+// A storage is counted in each hazard class of its product.
+pub fn get_stored_quantities_by_hazard_class(
+    db_connection: &Connection,
+    store_location_id: u64,
+) -> Result<Vec<StoredQuantity>, AppError> {
+    let sql = format!(
+        "{} SELECT symbol.symbol_label, unit.unit_label, COUNT(*), COUNT(storage.storage_quantity), TOTAL(storage.storage_quantity)
+        FROM storage
+        LEFT JOIN unit ON storage.unit_quantity = unit.unit_id
+        LEFT JOIN productsymbols ON productsymbols.productsymbols_product_id = storage.product
+        LEFT JOIN symbol ON productsymbols.productsymbols_symbol_id = symbol.symbol_id
+        WHERE {}
+        GROUP BY symbol.symbol_label, unit.unit_label",
+        STORE_LOCATION_SUBTREE, CURRENT_STORAGES_IN_SUBTREE
+    );
+
+    query_stored_quantities(db_connection, &sql, params![store_location_id])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn init_test_db() -> Connection {
+        let db_connection = Connection::open_in_memory().unwrap();
+        db_connection
+            .execute_batch(
+                "CREATE TABLE store_location (
+                    store_location_id INTEGER PRIMARY KEY,
+                    store_location_name TEXT NOT NULL,
+                    store_location INTEGER
+                );
+                CREATE TABLE unit (unit_id INTEGER PRIMARY KEY, unit_label TEXT NOT NULL);
+                CREATE TABLE symbol (symbol_id INTEGER PRIMARY KEY, symbol_label TEXT NOT NULL);
+                CREATE TABLE productsymbols (
+                    productsymbols_product_id INTEGER NOT NULL,
+                    productsymbols_symbol_id INTEGER NOT NULL
+                );
+                CREATE TABLE storage (
+                    storage_id INTEGER PRIMARY KEY,
+                    store_location INTEGER NOT NULL,
+                    product INTEGER NOT NULL,
+                    unit_quantity INTEGER,
+                    storage_quantity REAL,
+                    storage_archive INTEGER NOT NULL DEFAULT 0,
+                    storage INTEGER
+                );
+                INSERT INTO store_location VALUES (1, 'room', NULL), (2, 'cabinet', 1), (3, 'shelf', 2), (4, 'other room', NULL);
+                INSERT INTO unit VALUES (1, 'g'), (2, 'kg');
+                INSERT INTO symbol VALUES (1, 'SGH02'), (2, 'SGH07');
+                INSERT INTO productsymbols VALUES (10, 1), (10, 2), (11, 2);
+                INSERT INTO storage VALUES
+                    (1, 3, 10, 1, 500, 0, NULL),
+                    (2, 2, 10, 2, 1, 0, NULL),
+                    (3, 1, 11, 2, 2, 0, NULL),
+                    (4, 3, 10, NULL, NULL, 0, NULL),
+                    -- Archived, history and other store location storages.
+                    (5, 3, 10, 2, 9, 1, NULL),
+                    (6, 3, 10, 2, 9, 0, 1),
+                    (7, 4, 10, 2, 9, 0, NULL);",
+            )
+            .unwrap();
+        db_connection
+    }
+
+    fn limit(hazard_class: Option<&str>) -> CapacityLimit {
+        CapacityLimit {
+            store_location_id: 1,
+            hazard_class: hazard_class.map(|hazard_class| hazard_class.to_string()),
+            max_quantity: 1.0,
+            unit_label: String::from("kg"),
+            ..Default::default()
+        }
+    }
+
+    fn capacity_total_of(
+        db_connection: &Connection,
+        store_location_id: u64,
+        hazard_class: Option<&str>,
+        excluded_storage_ids: &[u64],
+    ) -> CapacityTotal {
+        capacity_total(
+            &limit(hazard_class),
+            &get_stored_quantities(
+                db_connection,
+                store_location_id,
+                hazard_class,
+                excluded_storage_ids,
+            )
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn get_stored_quantities_of_the_sub_locations() {
+        let db_connection = init_test_db();
+
+        // The hazard class is not case sensitive.
+        let capacity_total = capacity_total_of(&db_connection, 1, Some("sgh02"), &[]);
+        assert_eq!(capacity_total.nb_storages, 3);
+        assert_eq!(capacity_total.nb_unconverted, 1);
+        assert_eq!(capacity_total.total, 1.5);
+        assert!(capacity_total.exceeded);
+
+        let capacity_total = capacity_total_of(&db_connection, 2, None, &[]);
+        assert_eq!(capacity_total.nb_storages, 3);
+        assert_eq!(capacity_total.total, 1.5);
+    }
+
+    #[test]
+    fn get_stored_quantities_without_the_excluded_storages() {
+        let db_connection = init_test_db();
+
+        let capacity_total = capacity_total_of(&db_connection, 1, None, &[2]);
+        assert_eq!(capacity_total.nb_storages, 3);
+        assert_eq!(capacity_total.total, 2.5);
+
+        let capacity_total = capacity_total_of(&db_connection, 1, None, &[2, 3, 4]);
+        assert_eq!(capacity_total.nb_storages, 1);
+        assert_eq!(capacity_total.nb_unconverted, 0);
+        assert_eq!(capacity_total.total, 0.5);
+        assert!(!capacity_total.exceeded);
+    }
+}
@@ -1,3 +1,30 @@
 pub const REQUEST_ID_HEADER: &str = "x-request-id";
 pub const CHIMITHEQUE_PERSON_ID_HEADER: &str = "x-chimitheque-person-id";
 pub const CHIMITHEQUE_PERSON_EMAIL_HEADER: &str = "x-chimitheque-person-email";
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+pub const CHIMITHEQUE_IDEMPOTENT_REPLAY_HEADER: &str = "x-chimitheque-idempotent-replay";
+pub const CHIMITHEQUE_WARNINGS_HEADER: &str = "x-chimitheque-warnings";
+pub const CHIMITHEQUE_WARNINGS_COUNT_HEADER: &str = "x-chimitheque-warnings-count";
+
+// Maximum size of the warnings header value, in bytes.
+pub const CHIMITHEQUE_WARNINGS_HEADER_MAX_SIZE: usize = 4096;
+// Maximum size of an uploaded safety data sheet, in bytes.
+pub const SDS_MAX_SIZE: usize = 20 * 1024 * 1024;
+// Default maximum age of a safety data sheet before it is flagged as outdated.
+pub const DEFAULT_SDS_MAX_AGE_DAYS: i64 = 3 * 365;
+// Default number of days before the expiry of a storage to alert.
+pub const DEFAULT_EXPIRY_WARNING_DAYS: i64 = 30;
+// Default interval between two runs of the expiry alerts job, in hours.
+pub const DEFAULT_EXPIRY_ALERTS_INTERVAL_HOURS: u64 = 24;
+// Default number of hours during which an idempotency key replays the original response.
+pub const DEFAULT_IDEMPOTENCY_WINDOW_HOURS: i64 = 24;
+// Maximum size of a request body recorded with an idempotency key, in bytes.
+// A larger body, such as a safety data sheet upload, is processed without the key.
+pub const IDEMPOTENCY_MAX_REQUEST_SIZE: usize = 2 * 1024 * 1024;
+// Maximum size of a response body recorded to be replayed, in bytes.
+pub const IDEMPOTENCY_MAX_RESPONSE_SIZE: usize = 2 * 1024 * 1024;
+// Number of minutes after which a key still pending, left by a crashed
+// request, can be reserved again.
+pub const IDEMPOTENCY_PENDING_TIMEOUT_MINUTES: i64 = 10;
+
+pub const DEFAULT_PUBCHEM_BASE_URL: &str = "https://pubchem.ncbi.nlm.nih.gov";
@@ -0,0 +1,248 @@
+use rusqlite::{Connection, OptionalExtension, Row, params};
+use serde::Serialize;
+
+use crate::errors::AppError;
+
+pub const USAGE_DATETIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+// The quantity units that can be converted, with their dimension and
+// multiplier to the dimension reference unit (g for masses, L for volumes).
+const QUANTITY_UNITS: [(&str, &str, f64); 8] = [
+    ("kg", "mass", 1e3),
+    ("g", "mass", 1.0),
+    ("mg", "mass", 1e-3),
+    ("µg", "mass", 1e-6),
+    ("L", "volume", 1.0),
+    ("mL", "volume", 1e-3),
+    ("µL", "volume", 1e-6),
+    ("m3", "volume", 1e3),
+];
+
+fn quantity_unit(unit_label: &str) -> Option<(&'static str, f64)> {
+    // Accept the "u" prefix for micro.
+    let unit_label = unit_label.trim().replacen('u', "µ", 1);
+
+    QUANTITY_UNITS
+        .iter()
+        .find(|(label, _, _)| label.eq_ignore_ascii_case(&unit_label))
+        .map(|(_, dimension, multiplier)| (*dimension, *multiplier))
+}
+
+// Convert a quantity between two units of the same dimension.
+pub fn convert_quantity(quantity: f64, from_unit_label: &str, to_unit_label: &str) -> Option<f64> {
+    if from_unit_label == to_unit_label {
+        return Some(quantity);
+    }
+
+    let (from_dimension, from_multiplier) = quantity_unit(from_unit_label)?;
+    let (to_dimension, to_multiplier) = quantity_unit(to_unit_label)?;
+    if from_dimension != to_dimension {
+        return None;
+    }
+
+    Some(quantity * from_multiplier / to_multiplier)
+}
+
+// A quantity taken from a storage, in the storage unit.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct StorageUsage {
+    pub storage_usage_id: u64,
+    pub storage_id: u64,
+    pub product_id: u64,
+    pub person_id: u64,
+    pub quantity: f64,
+    pub unit_label: String,
+    // The quantity left in the storage after the usage.
+    pub remaining_quantity: f64,
+    pub comment: Option<String>,
+    pub used_at: String,
+}
+
+const STORAGE_USAGE_COLUMNS: &str = "storage_usage_id, storage_usage_storage_id, storage_usage_product_id, storage_usage_person_id, storage_usage_quantity, storage_usage_unit_label, storage_usage_remaining_quantity, storage_usage_comment, storage_usage_used_at";
+
+fn storage_usage_from_row(row: &Row) -> Result<StorageUsage, rusqlite::Error> {
+    Ok(StorageUsage {
+        storage_usage_id: row.get(0)?,
+        storage_id: row.get(1)?,
+        product_id: row.get(2)?,
+        person_id: row.get(3)?,
+        quantity: row.get(4)?,
+        unit_label: row.get(5)?,
+        remaining_quantity: row.get(6)?,
+        comment: row.get(7)?,
+        used_at: row.get(8)?,
+    })
+}
+
+// Create the storage usages table.
+pub fn init_consumption_log(db_connection: &Connection) -> Result<(), AppError> {
+    match db_connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS storage_usage (
+            storage_usage_id INTEGER PRIMARY KEY,
+            storage_usage_storage_id INTEGER NOT NULL,
+            storage_usage_product_id INTEGER NOT NULL,
+            storage_usage_person_id INTEGER NOT NULL,
+            storage_usage_quantity REAL NOT NULL,
+            storage_usage_unit_label TEXT NOT NULL,
+            storage_usage_remaining_quantity REAL NOT NULL,
+            storage_usage_comment TEXT,
+            storage_usage_used_at TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS idx_storage_usage_storage ON storage_usage(storage_usage_storage_id);
+        CREATE INDEX IF NOT EXISTS idx_storage_usage_product ON storage_usage(storage_usage_product_id);",
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+pub fn insert_storage_usage(
+    db_connection: &Connection,
+    storage_usage: &StorageUsage,
+) -> Result<u64, AppError> {
+    match db_connection.execute(
+        "INSERT INTO storage_usage (storage_usage_storage_id, storage_usage_product_id, storage_usage_person_id, storage_usage_quantity, storage_usage_unit_label, storage_usage_remaining_quantity, storage_usage_comment, storage_usage_used_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            storage_usage.storage_id,
+            storage_usage.product_id,
+            storage_usage.person_id,
+            storage_usage.quantity,
+            storage_usage.unit_label,
+            storage_usage.remaining_quantity,
+            storage_usage.comment,
+            storage_usage.used_at
+        ],
+    ) {
+        Ok(_) => Ok(db_connection.last_insert_rowid() as u64),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// chimitheque_db writes a storage in its own transaction, which can not
+// be nested: the quantity and archive of a used storage are written below,
+// in one transaction with its usage, so that concurrent usages can not
+// overwrite each other.
+
+// The quantity of a storage, None if it has none.
+pub fn get_storage_quantity(
+    db_connection: &Connection,
+    storage_id: u64,
+) -> Result<Option<f64>, AppError> {
+    match db_connection
+        .query_row(
+            "SELECT storage_quantity FROM storage WHERE storage_id = ?1",
+            params![storage_id],
+            |row| row.get::<_, Option<f64>>(0),
+        )
+        .optional()
+    {
+        Ok(storage_quantity) => Ok(storage_quantity.flatten()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+pub fn set_storage_quantity(
+    db_connection: &Connection,
+    storage_id: u64,
+    storage_quantity: f64,
+) -> Result<(), AppError> {
+    match db_connection.execute(
+        "UPDATE storage SET storage_quantity = ?1 WHERE storage_id = ?2",
+        params![storage_quantity, storage_id],
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// Archive an emptied storage.
+pub fn archive_used_storage(db_connection: &Connection, storage_id: u64) -> Result<(), AppError> {
+    match db_connection.execute(
+        "UPDATE storage SET storage_archive = 1 WHERE storage_id = ?1",
+        params![storage_id],
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+fn query_storage_usages(
+    db_connection: &Connection,
+    filter: &str,
+    id: u64,
+) -> Result<Vec<StorageUsage>, AppError> {
+    let sql = format!(
+        "SELECT {} FROM storage_usage WHERE {} = ?1 ORDER BY storage_usage_used_at DESC, storage_usage_id DESC",
+        STORAGE_USAGE_COLUMNS, filter
+    );
+
+    let mut stmt = match db_connection.prepare(&sql) {
+        Ok(stmt) => stmt,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    match stmt.query_map(params![id], storage_usage_from_row) {
+        Ok(rows) => match rows.collect::<Result<Vec<StorageUsage>, rusqlite::Error>>() {
+            Ok(storage_usages) => Ok(storage_usages),
+            Err(err) => Err(AppError::Database(err.to_string())),
+        },
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// The usages of a storage, most recent first.
+pub fn get_storage_usages(
+    db_connection: &Connection,
+    storage_id: u64,
+) -> Result<Vec<StorageUsage>, AppError> {
+    query_storage_usages(db_connection, "storage_usage_storage_id", storage_id)
+}
+
+// The usages of all the storages of a product, most recent first.
+pub fn get_product_usages(
+    db_connection: &Connection,
+    product_id: u64,
+) -> Result<Vec<StorageUsage>, AppError> {
+    query_storage_usages(db_connection, "storage_usage_product_id", product_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_quantity(quantity: Option<f64>, expected: f64) {
+        let quantity = quantity.expect("convertible units");
+        assert!(
+            (quantity - expected).abs() < 1e-9,
+            "{} != {}",
+            quantity,
+            expected
+        );
+    }
+
+    #[test]
+    fn convert_quantity_same_dimension() {
+        assert_quantity(convert_quantity(1.5, "kg", "g"), 1500.0);
+        assert_quantity(convert_quantity(250.0, "mL", "L"), 0.25);
+        assert_quantity(convert_quantity(2.0, "m3", "mL"), 2e6);
+        assert_quantity(convert_quantity(500.0, "mg", "g"), 0.5);
+    }
+
+    #[test]
+    fn convert_quantity_unit_labels() {
+        // The same label is returned as is, even when it is unknown.
+        assert_quantity(convert_quantity(3.0, "bottle", "bottle"), 3.0);
+        // The "u" prefix stands for micro, and the case is ignored.
+        assert_quantity(convert_quantity(10.0, "uL", "µL"), 10.0);
+        assert_quantity(convert_quantity(1000.0, "ug", "mg"), 1.0);
+        assert_quantity(convert_quantity(1.0, " l ", "ml"), 1000.0);
+    }
+
+    #[test]
+    fn convert_quantity_incompatible_units() {
+        assert_eq!(convert_quantity(1.0, "g", "mL"), None);
+        assert_eq!(convert_quantity(1.0, "g", "bottle"), None);
+        assert_eq!(convert_quantity(1.0, "bottle", "g"), None);
+    }
+}
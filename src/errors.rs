@@ -53,6 +53,36 @@ pub enum AppError {
     DecodeJWKS(String),
     #[error("refresh jwks error: {0}")]
     RefreshJWKS(String),
+    #[error("export error: {0}")]
+    Export(String),
+    #[error("label error: {0}")]
+    Label(String),
+    #[error("barcode error: {0}")]
+    Barcode(String),
+    #[error("not found: {0}")]
+    NotFound(String),
+    #[error("safety data sheet storage: {0}")]
+    SafetyDataSheetStorage(String),
+    #[error("structure cache: {0}")]
+    StructureCache(String),
+    #[error("incompatibility rules: {0}")]
+    IncompatibilityRules(String),
+    #[error("incompatible storage: {0}")]
+    IncompatibleStorage(String),
+    #[error("regulatory lists: {0}")]
+    RegulatoryLists(String),
+    #[error("borrowing: {0}")]
+    Borrowing(String),
+    #[error("inventory: {0}")]
+    Inventory(String),
+    #[error("waste: {0}")]
+    Waste(String),
+    #[error("idempotency: {0}")]
+    Idempotency(String),
+    #[error("reservation: {0}")]
+    Reservation(String),
+    #[error("capacity exceeded: {0}")]
+    CapacityExceeded(String),
 }
 
 impl IntoResponse for AppError {
@@ -213,6 +243,93 @@ impl IntoResponse for AppError {
                     AppError::RefreshJWKS(s).to_string(),
                 )
             }
+            AppError::Export(s) => {
+                error!("Export: {}", s);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    AppError::Export(s).to_string(),
+                )
+            }
+            AppError::Label(s) => {
+                error!("Label: {}", s);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    AppError::Label(s).to_string(),
+                )
+            }
+            AppError::Barcode(s) => {
+                error!("Barcode: {}", s);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    AppError::Barcode(s).to_string(),
+                )
+            }
+            AppError::NotFound(s) => {
+                // We do not log not found errors.
+                (StatusCode::NOT_FOUND, AppError::NotFound(s).to_string())
+            }
+            AppError::SafetyDataSheetStorage(s) => {
+                error!("SafetyDataSheetStorage: {}", s);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    AppError::SafetyDataSheetStorage(s).to_string(),
+                )
+            }
+            AppError::StructureCache(s) => {
+                error!("StructureCache: {}", s);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    AppError::StructureCache(s).to_string(),
+                )
+            }
+            AppError::IncompatibilityRules(s) => {
+                error!("IncompatibilityRules: {}", s);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    AppError::IncompatibilityRules(s).to_string(),
+                )
+            }
+            AppError::IncompatibleStorage(s) => {
+                error!("IncompatibleStorage: {}", s);
+                (
+                    StatusCode::CONFLICT,
+                    AppError::IncompatibleStorage(s).to_string(),
+                )
+            }
+            AppError::RegulatoryLists(s) => {
+                error!("RegulatoryLists: {}", s);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    AppError::RegulatoryLists(s).to_string(),
+                )
+            }
+            AppError::Borrowing(s) => {
+                error!("Borrowing: {}", s);
+                (StatusCode::CONFLICT, AppError::Borrowing(s).to_string())
+            }
+            AppError::Inventory(s) => {
+                error!("Inventory: {}", s);
+                (StatusCode::CONFLICT, AppError::Inventory(s).to_string())
+            }
+            AppError::Waste(s) => {
+                error!("Waste: {}", s);
+                (StatusCode::CONFLICT, AppError::Waste(s).to_string())
+            }
+            AppError::Idempotency(s) => {
+                error!("Idempotency: {}", s);
+                (StatusCode::CONFLICT, AppError::Idempotency(s).to_string())
+            }
+            AppError::Reservation(s) => {
+                error!("Reservation: {}", s);
+                (StatusCode::CONFLICT, AppError::Reservation(s).to_string())
+            }
+            AppError::CapacityExceeded(s) => {
+                error!("CapacityExceeded: {}", s);
+                (
+                    StatusCode::CONFLICT,
+                    AppError::CapacityExceeded(s).to_string(),
+                )
+            }
         };
         (status, body).into_response()
     }
@@ -0,0 +1,535 @@
+use chimitheque_types::requestfilter::RequestFilter;
+use chrono::{Local, NaiveDate};
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{Connection, OptionalExtension, Row, params};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, ops::Deref, sync::Arc};
+use tracing::{error, info, warn};
+
+use crate::errors::AppError;
+
+pub const EXPIRY_DATE_FORMAT: &str = "%Y-%m-%d";
+
+// The opening date and shelf life of a storage, and its expiration date.
+// Dates are formatted with EXPIRY_DATE_FORMAT.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ShelfLife {
+    #[serde(default)]
+    pub storage_id: u64,
+    pub opened_date: Option<String>,
+    // Number of days the product can be used after opening,
+    // typically short for peroxide formers.
+    pub shelf_life_days: Option<u32>,
+    pub expiration_date: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExpiryReason {
+    // Past the expiration date.
+    Expiration,
+    // Past the shelf life after opening.
+    ShelfLife,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ExpiryAlert {
+    pub storage_id: u64,
+    pub expiry_date: String,
+    pub reason: ExpiryReason,
+    pub expired: bool,
+    // Negative when expired.
+    pub days_left: i64,
+}
+
+fn parse_date(date: &str) -> Result<NaiveDate, AppError> {
+    match NaiveDate::parse_from_str(date.trim(), EXPIRY_DATE_FORMAT) {
+        Ok(date) => Ok(date),
+        Err(err) => Err(AppError::InputValidation(format!(
+            "invalid date {}: {}",
+            date, err
+        ))),
+    }
+}
+
+impl ShelfLife {
+    pub fn sanitize_and_validate(&mut self) -> Result<(), AppError> {
+        for date in [&mut self.opened_date, &mut self.expiration_date] {
+            *date = match date.as_deref().map(str::trim) {
+                Some("") | None => None,
+                Some(value) => Some(parse_date(value)?.format(EXPIRY_DATE_FORMAT).to_string()),
+            };
+        }
+
+        if self.shelf_life_days == Some(0) {
+            return Err(AppError::InputValidation(String::from(
+                "the shelf life must be positive",
+            )));
+        }
+
+        Ok(())
+    }
+
+    // The earliest of the expiration date and the end of the shelf life.
+    pub fn expiry(&self) -> Option<(NaiveDate, ExpiryReason)> {
+        let expiration = self
+            .expiration_date
+            .as_deref()
+            .and_then(|date| parse_date(date).ok())
+            .map(|date| (date, ExpiryReason::Expiration));
+        let shelf_life = match (self.opened_date.as_deref(), self.shelf_life_days) {
+            (Some(opened_date), Some(shelf_life_days)) => parse_date(opened_date)
+                .ok()
+                .map(|date| date + chrono::Duration::days(shelf_life_days.into()))
+                .map(|date| (date, ExpiryReason::ShelfLife)),
+            _ => None,
+        };
+
+        match (expiration, shelf_life) {
+            (Some(expiration), Some(shelf_life)) => Some(if shelf_life.0 < expiration.0 {
+                shelf_life
+            } else {
+                expiration
+            }),
+            (expiration, shelf_life) => expiration.or(shelf_life),
+        }
+    }
+
+    // The expiry alert, if the storage is expired or expires within warning_days.
+    pub fn alert(&self, today: NaiveDate, warning_days: i64) -> Option<ExpiryAlert> {
+        let (expiry_date, reason) = self.expiry()?;
+        let days_left = (expiry_date - today).num_days();
+        if days_left > warning_days {
+            return None;
+        }
+
+        Some(ExpiryAlert {
+            storage_id: self.storage_id,
+            expiry_date: expiry_date.format(EXPIRY_DATE_FORMAT).to_string(),
+            reason,
+            expired: days_left < 0,
+            days_left,
+        })
+    }
+}
+
+// An expiry alert recorded by the expiry alerts job.
+#[derive(Serialize, Debug, Clone)]
+pub struct RecordedExpiryAlert {
+    pub storage_id: u64,
+    pub entity_id: u64,
+    pub expiry_date: String,
+    pub expired: bool,
+    // When the alert was recorded, or became expired.
+    pub alerted_at: String,
+}
+
+const SHELF_LIFE_COLUMNS: &str = "storage_shelf_life_storage_id, storage_shelf_life_opened_date, storage_shelf_life_days, storage_shelf_life_expiration_date";
+
+fn shelf_life_from_row(row: &Row) -> Result<ShelfLife, rusqlite::Error> {
+    Ok(ShelfLife {
+        storage_id: row.get(0)?,
+        opened_date: row.get(1)?,
+        shelf_life_days: row.get(2)?,
+        expiration_date: row.get(3)?,
+    })
+}
+
+// Create the storage shelf lives and expiry alerts tables.
+pub fn init_expiry(db_connection: &Connection) -> Result<(), AppError> {
+    match db_connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS storage_shelf_life (
+            storage_shelf_life_storage_id INTEGER PRIMARY KEY,
+            storage_shelf_life_opened_date TEXT,
+            storage_shelf_life_days INTEGER,
+            storage_shelf_life_expiration_date TEXT
+        );
+        CREATE TABLE IF NOT EXISTS storage_expiry_alert (
+            storage_expiry_alert_storage_id INTEGER PRIMARY KEY,
+            storage_expiry_alert_entity_id INTEGER NOT NULL,
+            storage_expiry_alert_expiry_date TEXT NOT NULL,
+            storage_expiry_alert_expired INTEGER NOT NULL,
+            storage_expiry_alert_alerted_at TEXT NOT NULL
+        );",
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+pub fn get_shelf_life(
+    db_connection: &Connection,
+    storage_id: u64,
+) -> Result<Option<ShelfLife>, AppError> {
+    let sql = format!(
+        "SELECT {} FROM storage_shelf_life WHERE storage_shelf_life_storage_id = ?1",
+        SHELF_LIFE_COLUMNS
+    );
+
+    match db_connection
+        .query_row(&sql, params![storage_id], shelf_life_from_row)
+        .optional()
+    {
+        Ok(shelf_life) => Ok(shelf_life),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+pub fn get_shelf_lives(db_connection: &Connection) -> Result<HashMap<u64, ShelfLife>, AppError> {
+    let sql = format!("SELECT {} FROM storage_shelf_life", SHELF_LIFE_COLUMNS);
+
+    let mut stmt = match db_connection.prepare(&sql) {
+        Ok(stmt) => stmt,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    match stmt.query_map([], shelf_life_from_row) {
+        Ok(rows) => match rows.collect::<Result<Vec<ShelfLife>, rusqlite::Error>>() {
+            Ok(shelf_lives) => Ok(shelf_lives
+                .into_iter()
+                .map(|shelf_life| (shelf_life.storage_id, shelf_life))
+                .collect()),
+            Err(err) => Err(AppError::Database(err.to_string())),
+        },
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+pub fn set_shelf_life(db_connection: &Connection, shelf_life: &ShelfLife) -> Result<(), AppError> {
+    match db_connection.execute(
+        "INSERT OR REPLACE INTO storage_shelf_life (storage_shelf_life_storage_id, storage_shelf_life_opened_date, storage_shelf_life_days, storage_shelf_life_expiration_date)
+        VALUES (?1, ?2, ?3, ?4)",
+        params![
+            shelf_life.storage_id,
+            shelf_life.opened_date,
+            shelf_life.shelf_life_days,
+            shelf_life.expiration_date
+        ],
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// The expiry alerts recorded for an entity, soonest first.
+pub fn get_recorded_expiry_alerts(
+    db_connection: &Connection,
+    entity_id: u64,
+) -> Result<Vec<RecordedExpiryAlert>, AppError> {
+    let mut stmt = match db_connection.prepare(
+        "SELECT storage_expiry_alert_storage_id, storage_expiry_alert_entity_id, storage_expiry_alert_expiry_date, storage_expiry_alert_expired, storage_expiry_alert_alerted_at
+        FROM storage_expiry_alert
+        WHERE storage_expiry_alert_entity_id = ?1
+        ORDER BY storage_expiry_alert_expiry_date, storage_expiry_alert_storage_id",
+    ) {
+        Ok(stmt) => stmt,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    match stmt.query_map(params![entity_id], |row| {
+        Ok(RecordedExpiryAlert {
+            storage_id: row.get(0)?,
+            entity_id: row.get(1)?,
+            expiry_date: row.get(2)?,
+            expired: row.get(3)?,
+            alerted_at: row.get(4)?,
+        })
+    }) {
+        Ok(rows) => match rows.collect::<Result<Vec<RecordedExpiryAlert>, rusqlite::Error>>() {
+            Ok(recorded_expiry_alerts) => Ok(recorded_expiry_alerts),
+            Err(err) => Err(AppError::Database(err.to_string())),
+        },
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// The expiry alerts of the given storages, soonest first.
+pub fn get_expiry_alerts(
+    shelf_lives: &HashMap<u64, ShelfLife>,
+    storage_ids: impl Iterator<Item = u64>,
+    warning_days: i64,
+) -> Vec<ExpiryAlert> {
+    let today = Local::now().date_naive();
+
+    let mut expiry_alerts: Vec<ExpiryAlert> = storage_ids
+        .filter_map(|storage_id| shelf_lives.get(&storage_id))
+        .filter_map(|shelf_life| shelf_life.alert(today, warning_days))
+        .collect();
+    expiry_alerts.sort_by_key(|expiry_alert| expiry_alert.days_left);
+
+    expiry_alerts
+}
+
+// Compute the expiry alerts of every entity, as the given admin, and record them.
+// New alerts are logged once, alerts no longer relevant (storage archived,
+// dates changed) are removed.
+fn produce_expiry_alerts(
+    db_connection: &Connection,
+    chimitheque_person_id: u64,
+    warning_days: i64,
+) -> Result<(), AppError> {
+    let entities = match chimitheque_db::entity::get_entities(
+        db_connection,
+        RequestFilter::default(),
+        chimitheque_person_id,
+    ) {
+        Ok((entities, _)) => entities,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    let shelf_lives = get_shelf_lives(db_connection)?;
+    let alerted_at = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+
+    let mut alerted_storage_ids: Vec<u64> = Vec::new();
+    for entity in entities.iter() {
+        let Some(entity_id) = entity.entity_id else {
+            continue;
+        };
+
+        let storage_ids: Vec<u64> = match chimitheque_db::storage::get_storages(
+            db_connection,
+            RequestFilter {
+                entity: Some(entity_id),
+                ..Default::default()
+            },
+            chimitheque_person_id,
+        ) {
+            Ok((storages, _)) => storages
+                .iter()
+                .filter_map(|storage| storage.storage_id)
+                .collect(),
+            Err(err) => return Err(AppError::Database(err.to_string())),
+        };
+
+        for expiry_alert in get_expiry_alerts(&shelf_lives, storage_ids.into_iter(), warning_days) {
+            alerted_storage_ids.push(expiry_alert.storage_id);
+
+            // Only the new alerts, or the alerts becoming expired, are logged.
+            let nb_changed = match db_connection.execute(
+                "INSERT INTO storage_expiry_alert (storage_expiry_alert_storage_id, storage_expiry_alert_entity_id, storage_expiry_alert_expiry_date, storage_expiry_alert_expired, storage_expiry_alert_alerted_at)
+                VALUES (?1, ?2, ?3, ?4, ?5)
+                ON CONFLICT(storage_expiry_alert_storage_id) DO UPDATE SET
+                    storage_expiry_alert_entity_id = excluded.storage_expiry_alert_entity_id,
+                    storage_expiry_alert_expiry_date = excluded.storage_expiry_alert_expiry_date,
+                    storage_expiry_alert_expired = excluded.storage_expiry_alert_expired,
+                    storage_expiry_alert_alerted_at = excluded.storage_expiry_alert_alerted_at
+                WHERE storage_expiry_alert_expiry_date != excluded.storage_expiry_alert_expiry_date
+                    OR storage_expiry_alert_expired != excluded.storage_expiry_alert_expired",
+                params![
+                    expiry_alert.storage_id,
+                    entity_id,
+                    expiry_alert.expiry_date,
+                    expiry_alert.expired,
+                    alerted_at
+                ],
+            ) {
+                Ok(nb_changed) => nb_changed,
+                Err(err) => return Err(AppError::Database(err.to_string())),
+            };
+
+            if nb_changed > 0 {
+                warn!(
+                    "storage {} of entity {} {} on {} ({:?})",
+                    expiry_alert.storage_id,
+                    entity.entity_name,
+                    if expiry_alert.expired {
+                        "expired"
+                    } else {
+                        "expires"
+                    },
+                    expiry_alert.expiry_date,
+                    expiry_alert.reason
+                );
+            }
+        }
+    }
+
+    let mut stmt = match db_connection
+        .prepare("SELECT storage_expiry_alert_storage_id FROM storage_expiry_alert")
+    {
+        Ok(stmt) => stmt,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+    let recorded_storage_ids: Vec<u64> = match stmt.query_map([], |row| row.get(0)) {
+        Ok(rows) => match rows.collect::<Result<Vec<u64>, rusqlite::Error>>() {
+            Ok(recorded_storage_ids) => recorded_storage_ids,
+            Err(err) => return Err(AppError::Database(err.to_string())),
+        },
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+    for storage_id in recorded_storage_ids
+        .iter()
+        .filter(|storage_id| !alerted_storage_ids.contains(storage_id))
+    {
+        if let Err(err) = db_connection.execute(
+            "DELETE FROM storage_expiry_alert WHERE storage_expiry_alert_storage_id = ?1",
+            params![storage_id],
+        ) {
+            return Err(AppError::Database(err.to_string()));
+        }
+    }
+
+    info!(
+        "expiry alerts: {} storages expired or expiring",
+        alerted_storage_ids.len()
+    );
+
+    Ok(())
+}
+
+// Produce the expiry alerts every interval_hours.
+pub async fn run_expiry_alerts(
+    db_connection_pool: Arc<Pool<SqliteConnectionManager>>,
+    chimitheque_person_id: u64,
+    warning_days: i64,
+    interval_hours: u64,
+) {
+    let mut interval =
+        tokio::time::interval(std::time::Duration::from_secs(interval_hours * 60 * 60));
+
+    loop {
+        interval.tick().await;
+
+        // The alerts are produced with blocking database calls.
+        let db_connection_pool = db_connection_pool.clone();
+        let mayerr_produced = tokio::task::spawn_blocking(move || {
+            let db_connection = match db_connection_pool.get() {
+                Ok(db_connection) => db_connection,
+                Err(err) => return Err(AppError::DatabasePool(err.to_string())),
+            };
+
+            produce_expiry_alerts(db_connection.deref(), chimitheque_person_id, warning_days)
+        })
+        .await;
+
+        match mayerr_produced {
+            Ok(Ok(())) => (),
+            Ok(Err(err)) => error!("expiry alerts: {}", err),
+            Err(err) => error!("expiry alerts: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(date: &str) -> NaiveDate {
+        parse_date(date).unwrap()
+    }
+
+    #[test]
+    fn expiry_without_dates() {
+        assert_eq!(ShelfLife::default().expiry(), None);
+
+        // A shelf life without opening date does not expire.
+        let shelf_life = ShelfLife {
+            shelf_life_days: Some(30),
+            ..Default::default()
+        };
+        assert_eq!(shelf_life.expiry(), None);
+    }
+
+    #[test]
+    fn expiry_from_expiration_date_or_shelf_life() {
+        let shelf_life = ShelfLife {
+            expiration_date: Some(String::from("2025-06-30")),
+            ..Default::default()
+        };
+        assert_eq!(
+            shelf_life.expiry(),
+            Some((date("2025-06-30"), ExpiryReason::Expiration))
+        );
+
+        let shelf_life = ShelfLife {
+            opened_date: Some(String::from("2025-01-31")),
+            shelf_life_days: Some(30),
+            ..Default::default()
+        };
+        assert_eq!(
+            shelf_life.expiry(),
+            Some((date("2025-03-02"), ExpiryReason::ShelfLife))
+        );
+    }
+
+    #[test]
+    fn expiry_is_the_earliest_date() {
+        let mut shelf_life = ShelfLife {
+            opened_date: Some(String::from("2025-01-01")),
+            shelf_life_days: Some(90),
+            expiration_date: Some(String::from("2025-12-31")),
+            ..Default::default()
+        };
+        assert_eq!(
+            shelf_life.expiry(),
+            Some((date("2025-04-01"), ExpiryReason::ShelfLife))
+        );
+
+        shelf_life.expiration_date = Some(String::from("2025-02-01"));
+        assert_eq!(
+            shelf_life.expiry(),
+            Some((date("2025-02-01"), ExpiryReason::Expiration))
+        );
+    }
+
+    #[test]
+    fn expiry_ignores_invalid_dates() {
+        let shelf_life = ShelfLife {
+            opened_date: Some(String::from("yesterday")),
+            shelf_life_days: Some(7),
+            expiration_date: Some(String::from("2025-02-01")),
+            ..Default::default()
+        };
+        assert_eq!(
+            shelf_life.expiry(),
+            Some((date("2025-02-01"), ExpiryReason::Expiration))
+        );
+    }
+
+    #[test]
+    fn recorded_expiry_alerts_of_an_entity() {
+        let db_connection = Connection::open_in_memory().unwrap();
+        init_expiry(&db_connection).unwrap();
+        db_connection
+            .execute_batch(
+                "INSERT INTO storage_expiry_alert VALUES (1, 10, '2025-03-01', 0, '2025-02-01 08:00:00');
+                INSERT INTO storage_expiry_alert VALUES (2, 10, '2025-01-15', 1, '2025-01-16 08:00:00');
+                INSERT INTO storage_expiry_alert VALUES (3, 20, '2025-01-01', 1, '2025-01-02 08:00:00');",
+            )
+            .unwrap();
+
+        let recorded_expiry_alerts = get_recorded_expiry_alerts(&db_connection, 10).unwrap();
+
+        assert_eq!(
+            recorded_expiry_alerts
+                .iter()
+                .map(|recorded_expiry_alert| (
+                    recorded_expiry_alert.storage_id,
+                    recorded_expiry_alert.expired
+                ))
+                .collect::<Vec<(u64, bool)>>(),
+            vec![(2, true), (1, false)]
+        );
+    }
+
+    #[test]
+    fn alert_within_warning_days() {
+        let shelf_life = ShelfLife {
+            storage_id: 3,
+            expiration_date: Some(String::from("2025-02-10")),
+            ..Default::default()
+        };
+
+        assert!(shelf_life.alert(date("2025-01-01"), 30).is_none());
+
+        let expiry_alert = shelf_life.alert(date("2025-02-01"), 30).unwrap();
+        assert_eq!(expiry_alert.storage_id, 3);
+        assert_eq!(expiry_alert.days_left, 9);
+        assert!(!expiry_alert.expired);
+
+        let expiry_alert = shelf_life.alert(date("2025-02-12"), 30).unwrap();
+        assert_eq!(expiry_alert.days_left, -2);
+        assert!(expiry_alert.expired);
+    }
+}
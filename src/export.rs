@@ -0,0 +1,620 @@
+use axum::{
+    body::Body,
+    http::header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+    response::{IntoResponse, Response},
+};
+use chimitheque_types::requestfilter::RequestFilter;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::Connection;
+use rust_xlsxwriter::{Format, Workbook};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use spreadsheet_ods::{Sheet, WorkBook};
+use std::{
+    io::{Seek, SeekFrom, Write},
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::error;
+
+use crate::errors::AppError;
+
+// Separator of the CSV produced by the chimitheque_db export functions.
+const DB_EXPORT_SEPARATOR: u8 = b',';
+
+// Size of the chunks of the streamed export body.
+const EXPORT_CHUNK_SIZE: usize = 64 * 1024;
+
+// Number of rows read from the database per export page.
+const EXPORT_PAGE_SIZE: u64 = 1000;
+
+// Number of encoded chunks buffered between the encoder and the client.
+const EXPORT_CHANNEL_CAPACITY: usize = 4;
+
+// UTF-8 byte order mark, required by Excel to detect the CSV encoding.
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Xlsx,
+    Ods,
+    Json,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Xlsx => {
+                "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"
+            }
+            ExportFormat::Ods => "application/vnd.oasis.opendocument.spreadsheet",
+            ExportFormat::Json => "application/json",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Xlsx => "xlsx",
+            ExportFormat::Ods => "ods",
+            ExportFormat::Json => "json",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ExportQueryParameters {
+    #[serde(default)]
+    pub format: ExportFormat,
+    #[serde(default = "default_separator")]
+    pub separator: char,
+    #[serde(default = "default_bom")]
+    pub bom: bool,
+    // Columns to export, in order. All columns are exported if empty.
+    #[serde(default)]
+    pub columns: Vec<String>,
+}
+
+fn default_separator() -> char {
+    ','
+}
+
+fn default_bom() -> bool {
+    true
+}
+
+// An export as a header line and its rows.
+#[derive(Debug, Clone, Default)]
+pub struct ExportTable {
+    pub headers: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+impl ExportTable {
+    // Parse the CSV returned by the chimitheque_db export functions.
+    pub fn from_db_csv(csv: &str) -> Result<Self, AppError> {
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(DB_EXPORT_SEPARATOR)
+            .flexible(true)
+            .from_reader(csv.as_bytes());
+
+        let headers: Vec<String> = match reader.headers() {
+            Ok(headers) => headers.iter().map(|h| h.to_string()).collect(),
+            Err(err) => return Err(AppError::Export(err.to_string())),
+        };
+
+        let mut rows: Vec<Vec<String>> = Vec::new();
+        for record in reader.records() {
+            match record {
+                Ok(record) => rows.push(record.iter().map(|f| f.to_string()).collect()),
+                Err(err) => return Err(AppError::Export(err.to_string())),
+            }
+        }
+
+        Ok(ExportTable { headers, rows })
+    }
+
+    // Keep only the given columns, in the given order.
+    pub fn select_columns(self, columns: &[String]) -> Result<Self, AppError> {
+        if columns.is_empty() {
+            return Ok(self);
+        }
+
+        let mut indexes: Vec<usize> = Vec::with_capacity(columns.len());
+        for column in columns {
+            match self.headers.iter().position(|h| h == column) {
+                Some(index) => indexes.push(index),
+                None => {
+                    return Err(AppError::InputValidation(format!(
+                        "unknown export column: {}",
+                        column
+                    )));
+                }
+            }
+        }
+
+        let headers = indexes.iter().map(|i| self.headers[*i].clone()).collect();
+        let rows = self
+            .rows
+            .into_iter()
+            .map(|row| {
+                indexes
+                    .iter()
+                    .map(|i| row.get(*i).cloned().unwrap_or_default())
+                    .collect()
+            })
+            .collect();
+
+        Ok(ExportTable { headers, rows })
+    }
+
+    // Write the export as CSV, with the optional UTF-8 BOM.
+    pub fn write_csv<W: Write + Send>(
+        &self,
+        writer: W,
+        separator: char,
+        bom: bool,
+    ) -> Result<(), AppError> {
+        let mut encoder =
+            ExportEncoder::new(writer, ExportFormat::Csv, separator, bom, &self.headers, "")?;
+        for row in self.rows.iter() {
+            encoder.write_row(row)?;
+        }
+
+        encoder.finish().map(|_| ())
+    }
+}
+
+// An export being encoded in one of the export formats, one row at a time.
+enum ExportEncoder<W: Write + Send> {
+    Csv(Box<csv::Writer<W>>),
+    Json {
+        writer: W,
+        headers: Vec<String>,
+        rows: u32,
+    },
+    // The worksheet rows are flushed to a temporary file as they are
+    // written instead of being kept in memory.
+    Xlsx {
+        workbook: Box<Workbook>,
+        writer: W,
+        rows: u32,
+    },
+    // The ODS writer has no streaming API: the sheet is kept in memory and
+    // written to a temporary file when the export is finished, because
+    // the writer also needs a seekable output.
+    Ods {
+        workbook: Box<WorkBook>,
+        sheet: Box<Sheet>,
+        writer: W,
+        rows: u32,
+    },
+}
+
+impl<W: Write + Send> ExportEncoder<W> {
+    // Start the export and write its header line.
+    // sheet_name is the spreadsheet sheet name.
+    fn new(
+        mut writer: W,
+        format: ExportFormat,
+        separator: char,
+        bom: bool,
+        headers: &[String],
+        sheet_name: &str,
+    ) -> Result<Self, AppError> {
+        match format {
+            ExportFormat::Csv => {
+                if !separator.is_ascii() {
+                    return Err(AppError::InputValidation(format!(
+                        "invalid export separator: {}",
+                        separator
+                    )));
+                }
+
+                let bom = if bom { UTF8_BOM } else { &[] };
+                if let Err(err) = writer.write_all(bom) {
+                    return Err(AppError::Export(err.to_string()));
+                }
+
+                let mut csv_writer = csv::WriterBuilder::new()
+                    .delimiter(separator as u8)
+                    .from_writer(writer);
+                if let Err(err) = csv_writer.write_record(headers) {
+                    return Err(AppError::Export(err.to_string()));
+                }
+
+                Ok(ExportEncoder::Csv(Box::new(csv_writer)))
+            }
+            ExportFormat::Json => {
+                if let Err(err) = writer.write_all(b"[") {
+                    return Err(AppError::Export(err.to_string()));
+                }
+
+                Ok(ExportEncoder::Json {
+                    writer,
+                    headers: headers.to_vec(),
+                    rows: 0,
+                })
+            }
+            ExportFormat::Xlsx => {
+                let mut workbook = Workbook::new();
+                let header_format = Format::new().set_bold();
+
+                let worksheet = workbook.add_worksheet_with_constant_memory();
+                if let Err(err) = worksheet.set_name(sheet_name) {
+                    return Err(AppError::Export(err.to_string()));
+                }
+                for (col, header) in headers.iter().enumerate() {
+                    if let Err(err) =
+                        worksheet.write_string_with_format(0, col as u16, header, &header_format)
+                    {
+                        return Err(AppError::Export(err.to_string()));
+                    }
+                }
+
+                Ok(ExportEncoder::Xlsx {
+                    workbook: Box::new(workbook),
+                    writer,
+                    rows: 0,
+                })
+            }
+            ExportFormat::Ods => {
+                let mut sheet = Sheet::new(sheet_name);
+                for (col, header) in headers.iter().enumerate() {
+                    sheet.set_value(0, col as u32, header.as_str());
+                }
+
+                Ok(ExportEncoder::Ods {
+                    workbook: Box::new(WorkBook::new_empty()),
+                    sheet: Box::new(sheet),
+                    writer,
+                    rows: 0,
+                })
+            }
+        }
+    }
+
+    fn write_row(&mut self, row: &[String]) -> Result<(), AppError> {
+        match self {
+            ExportEncoder::Csv(csv_writer) => match csv_writer.write_record(row) {
+                Ok(_) => Ok(()),
+                Err(err) => Err(AppError::Export(err.to_string())),
+            },
+            ExportEncoder::Json {
+                writer,
+                headers,
+                rows,
+            } => {
+                let mut object = Map::new();
+                for (i, header) in headers.iter().enumerate() {
+                    object.insert(
+                        header.clone(),
+                        Value::String(row.get(i).cloned().unwrap_or_default()),
+                    );
+                }
+
+                let mut bytes: Vec<u8> = if *rows > 0 { vec![b','] } else { Vec::new() };
+                if let Err(err) = serde_json::to_writer(&mut bytes, &Value::Object(object)) {
+                    return Err(AppError::Export(err.to_string()));
+                }
+                *rows += 1;
+
+                match writer.write_all(&bytes) {
+                    Ok(_) => Ok(()),
+                    Err(err) => Err(AppError::Export(err.to_string())),
+                }
+            }
+            ExportEncoder::Xlsx {
+                workbook,
+                writer: _,
+                rows,
+            } => {
+                *rows += 1;
+                let worksheet = match workbook.worksheet_from_index(0) {
+                    Ok(worksheet) => worksheet,
+                    Err(err) => return Err(AppError::Export(err.to_string())),
+                };
+                for (col, value) in row.iter().enumerate() {
+                    if let Err(err) = worksheet.write_string(*rows, col as u16, value) {
+                        return Err(AppError::Export(err.to_string()));
+                    }
+                }
+
+                Ok(())
+            }
+            ExportEncoder::Ods {
+                workbook: _,
+                sheet,
+                writer: _,
+                rows,
+            } => {
+                *rows += 1;
+                for (col, value) in row.iter().enumerate() {
+                    sheet.set_value(*rows, col as u32, value.as_str());
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    // Write the end of the export and return the flushed writer.
+    fn finish(self) -> Result<W, AppError> {
+        let mut writer = match self {
+            ExportEncoder::Csv(csv_writer) => match csv_writer.into_inner() {
+                Ok(writer) => writer,
+                Err(err) => return Err(AppError::Export(err.to_string())),
+            },
+            ExportEncoder::Json { mut writer, .. } => {
+                if let Err(err) = writer.write_all(b"]") {
+                    return Err(AppError::Export(err.to_string()));
+                }
+                writer
+            }
+            ExportEncoder::Xlsx {
+                mut workbook,
+                mut writer,
+                ..
+            } => {
+                if let Err(err) = workbook.save_to_writer(&mut writer) {
+                    return Err(AppError::Export(err.to_string()));
+                }
+                writer
+            }
+            ExportEncoder::Ods {
+                mut workbook,
+                sheet,
+                mut writer,
+                ..
+            } => {
+                workbook.push_sheet(*sheet);
+
+                let mut file = match tempfile::tempfile() {
+                    Ok(file) => file,
+                    Err(err) => return Err(AppError::Export(err.to_string())),
+                };
+                if let Err(err) = spreadsheet_ods::write_ods_to(&mut workbook, &mut file) {
+                    return Err(AppError::Export(err.to_string()));
+                }
+                // Release the sheet before copying the archive.
+                drop(workbook);
+
+                if let Err(err) = file.seek(SeekFrom::Start(0)) {
+                    return Err(AppError::Export(err.to_string()));
+                }
+                if let Err(err) = std::io::copy(&mut file, &mut writer) {
+                    return Err(AppError::Export(err.to_string()));
+                }
+                writer
+            }
+        };
+
+        match writer.flush() {
+            Ok(_) => Ok(writer),
+            Err(err) => Err(AppError::Export(err.to_string())),
+        }
+    }
+}
+
+// A writer sending its output to the response body by chunks of
+// EXPORT_CHUNK_SIZE bytes. Writes fail once the client is gone.
+struct ChunkWriter {
+    sender: mpsc::Sender<Result<Vec<u8>, std::io::Error>>,
+    buffer: Vec<u8>,
+}
+
+impl ChunkWriter {
+    fn new(sender: mpsc::Sender<Result<Vec<u8>, std::io::Error>>) -> Self {
+        ChunkWriter {
+            sender,
+            buffer: Vec::with_capacity(EXPORT_CHUNK_SIZE),
+        }
+    }
+
+    fn send_buffer(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let chunk = std::mem::replace(&mut self.buffer, Vec::with_capacity(EXPORT_CHUNK_SIZE));
+        match self.sender.blocking_send(Ok(chunk)) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "export client gone",
+            )),
+        }
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= EXPORT_CHUNK_SIZE {
+            self.send_buffer()?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.send_buffer()
+    }
+}
+
+// Build the Content-Disposition header value for an export download.
+pub fn content_disposition(basename: &str, format: ExportFormat) -> String {
+    format!(
+        "attachment; filename=\"{}_{}.{}\"",
+        basename,
+        chrono::Local::now().format("%Y%m%d_%H%M%S"),
+        format.extension()
+    )
+}
+
+// Render the export in the requested format, with the download headers.
+// basename is used for the filename and the spreadsheet sheet name.
+//
+// fetch_page is called with the request filter and the offset and limit
+// of each page, and returns the rows of the page. The pages are read from
+// one connection, in one read transaction so that they all see the same
+// state of the database, and each row is encoded to the response body as
+// soon as its page is read. The connection is released after the last
+// page. The first page is read before answering so that errors such as
+// an unknown column are returned with a proper status code.
+pub async fn export_response<F>(
+    db_connection_pool: Arc<Pool<SqliteConnectionManager>>,
+    request_filter: RequestFilter,
+    basename: &'static str,
+    params: ExportQueryParameters,
+    fetch_page: F,
+) -> Result<Response, AppError>
+where
+    F: FnMut(&Connection, RequestFilter) -> Result<ExportTable, AppError> + Send + 'static,
+{
+    let format = params.format;
+
+    if format == ExportFormat::Csv && !params.separator.is_ascii() {
+        return Err(AppError::InputValidation(format!(
+            "invalid export separator: {}",
+            params.separator
+        )));
+    }
+
+    // The bounded channel keeps at most EXPORT_CHANNEL_CAPACITY chunks
+    // in memory when the client reads slower than the export is encoded.
+    let (sender, receiver) =
+        mpsc::channel::<Result<Vec<u8>, std::io::Error>>(EXPORT_CHANNEL_CAPACITY);
+    let (first_page_sender, first_page_receiver) = oneshot::channel::<Result<(), AppError>>();
+
+    tokio::task::spawn_blocking(move || {
+        let mut first_page_sender = Some(first_page_sender);
+
+        let result = stream_export(
+            &db_connection_pool,
+            request_filter,
+            basename,
+            &params,
+            fetch_page,
+            ChunkWriter::new(sender.clone()),
+            &mut first_page_sender,
+        );
+
+        if let Err(err) = result {
+            match first_page_sender {
+                // Nothing is answered yet: answer with the error.
+                Some(first_page_sender) => {
+                    let _ = first_page_sender.send(Err(err));
+                }
+                // Headers are already sent: abort the body.
+                None => {
+                    error!("export {}: {}", basename, err);
+                    let _ = sender.blocking_send(Err(std::io::Error::other(err.to_string())));
+                }
+            }
+        }
+    });
+
+    match first_page_receiver.await {
+        Ok(Ok(_)) => (),
+        Ok(Err(err)) => return Err(err),
+        Err(err) => return Err(AppError::Export(err.to_string())),
+    }
+
+    Ok((
+        [
+            (CONTENT_TYPE, format.content_type().to_string()),
+            (CONTENT_DISPOSITION, content_disposition(basename, format)),
+        ],
+        Body::from_stream(ReceiverStream::new(receiver)),
+    )
+        .into_response())
+}
+
+// Read the export page by page and encode its rows to writer.
+// first_page_sender is taken and notified once the first page is read.
+fn stream_export<F>(
+    db_connection_pool: &Pool<SqliteConnectionManager>,
+    request_filter: RequestFilter,
+    basename: &str,
+    params: &ExportQueryParameters,
+    mut fetch_page: F,
+    writer: ChunkWriter,
+    first_page_sender: &mut Option<oneshot::Sender<Result<(), AppError>>>,
+) -> Result<(), AppError>
+where
+    F: FnMut(&Connection, RequestFilter) -> Result<ExportTable, AppError>,
+{
+    let mut db_connection = match db_connection_pool.get() {
+        Ok(db_connection) => db_connection,
+        Err(err) => return Err(AppError::DatabasePool(err.to_string())),
+    };
+    let tx = match db_connection.deref_mut().transaction() {
+        Ok(tx) => tx,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    let mut read_page = |offset: u64, limit: u64| {
+        fetch_page(
+            tx.deref(),
+            RequestFilter {
+                offset: Some(offset),
+                limit: Some(limit),
+                ..request_filter.clone()
+            },
+        )
+        .and_then(|page| page.select_columns(&params.columns))
+    };
+
+    let mut offset = request_filter.offset.unwrap_or(0);
+    let mut remaining = request_filter.limit;
+    let mut page_size = remaining.map_or(EXPORT_PAGE_SIZE, |remaining| {
+        remaining.min(EXPORT_PAGE_SIZE)
+    });
+    let mut page = read_page(offset, page_size)?;
+
+    let mut encoder = ExportEncoder::new(
+        writer,
+        params.format,
+        params.separator,
+        params.bom,
+        &page.headers,
+        basename,
+    )?;
+    if let Some(first_page_sender) = first_page_sender.take() {
+        let _ = first_page_sender.send(Ok(()));
+    }
+
+    loop {
+        for row in page.rows.iter() {
+            encoder.write_row(row)?;
+        }
+
+        // A short page is the last one. A page longer than asked means that
+        // the export function ignored the limit and returned all the rows.
+        let page_rows = page.rows.len() as u64;
+        offset += page_rows;
+        remaining = remaining.map(|remaining| remaining.saturating_sub(page_rows));
+        if page_rows != page_size || remaining == Some(0) {
+            break;
+        }
+
+        page_size = remaining.map_or(EXPORT_PAGE_SIZE, |remaining| {
+            remaining.min(EXPORT_PAGE_SIZE)
+        });
+        page = read_page(offset, page_size)?;
+    }
+
+    // Release the connection before writing the end of the export.
+    drop(tx);
+    drop(db_connection);
+
+    encoder.finish().map(|_| ())
+}
@@ -0,0 +1,480 @@
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::Deserialize;
+use std::{fmt::Write, path::Path};
+use svg2pdf::usvg::{PostProcessingSteps, Tree, TreeParsing, TreePostProc, fontdb};
+
+use crate::{
+    barcode::{Barcode, BarcodeSymbology},
+    errors::AppError,
+    i18n::Locale,
+};
+
+// Label dimensions are expressed in millimeters in the SVG user space.
+// 25.4 dots per inch makes one SVG unit one millimeter in the PDF.
+const PDF_DPI: f32 = 25.4;
+
+// Average glyph width relative to the font size, used to wrap text lines.
+pub(crate) const GLYPH_WIDTH_RATIO: f64 = 0.5;
+
+// Line height relative to the font size.
+pub(crate) const LINE_HEIGHT_RATIO: f64 = 1.25;
+
+// Maximum number of pictograms per row, the next ones are wrapped.
+const PICTOGRAMS_PER_ROW: usize = 4;
+
+// Maximum width of a storage barcode module, in millimeters.
+const MAX_BARCODE_MODULE_WIDTH: f64 = 0.4;
+
+// Standard GHS label sizes (width x height in millimeters),
+// depending on the container capacity.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum LabelSize {
+    // Up to 3 liters.
+    #[default]
+    #[serde(rename = "52x74")]
+    Small,
+    // From 3 to 50 liters.
+    #[serde(rename = "74x105")]
+    Medium,
+    // From 50 to 500 liters.
+    #[serde(rename = "105x148")]
+    Large,
+    // More than 500 liters.
+    #[serde(rename = "148x210")]
+    ExtraLarge,
+}
+
+impl LabelSize {
+    // Return the label width and height in millimeters (landscape).
+    pub fn dimensions(&self) -> (f64, f64) {
+        match self {
+            LabelSize::Small => (74.0, 52.0),
+            LabelSize::Medium => (105.0, 74.0),
+            LabelSize::Large => (148.0, 105.0),
+            LabelSize::ExtraLarge => (210.0, 148.0),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LabelFormat {
+    #[default]
+    Pdf,
+    Svg,
+}
+
+impl LabelFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            LabelFormat::Pdf => "application/pdf",
+            LabelFormat::Svg => "image/svg+xml",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            LabelFormat::Pdf => "pdf",
+            LabelFormat::Svg => "svg",
+        }
+    }
+}
+
+// Label texts in the label language.
+impl Locale {
+    // Translate the signal word as stored in the database ("danger" or "warning").
+    pub fn signal_word(&self, signal_word: &str) -> String {
+        match (self, signal_word.to_lowercase().as_str()) {
+            (Locale::En, "danger") => String::from("DANGER"),
+            (Locale::En, "warning") => String::from("WARNING"),
+            (Locale::Fr, "danger") => String::from("DANGER"),
+            (Locale::Fr, "warning") => String::from("ATTENTION"),
+            (_, other) => other.to_uppercase(),
+        }
+    }
+
+    fn cas_number_caption(&self) -> &'static str {
+        match self {
+            Locale::En => "CAS No.",
+            Locale::Fr => "N° CAS",
+        }
+    }
+
+    fn owner_caption(&self) -> &'static str {
+        match self {
+            Locale::En => "Owner",
+            Locale::Fr => "Propriétaire",
+        }
+    }
+}
+
+// A hazard or precautionary statement.
+#[derive(Debug, Clone, Default)]
+pub struct LabelStatement {
+    pub reference: String,
+    pub label: String,
+}
+
+// The label information, extracted from a product and optionally a storage.
+#[derive(Debug, Clone, Default)]
+pub struct LabelContent {
+    pub product_name: String,
+    pub cas_number: Option<String>,
+    pub signal_word: Option<String>,
+    // GHS pictogram codes such as SGH02.
+    pub pictograms: Vec<String>,
+    pub hazard_statements: Vec<LabelStatement>,
+    pub precautionary_statements: Vec<LabelStatement>,
+    pub storage_barecode: Option<String>,
+    pub entity_name: Option<String>,
+}
+
+pub(crate) fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Wrap a text into lines of at most max_chars characters.
+pub(crate) fn wrap(text: &str, max_chars: usize) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut line = String::new();
+
+    for word in text.split_whitespace() {
+        if !line.is_empty() && line.chars().count() + 1 + word.chars().count() > max_chars {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+
+    lines
+}
+
+// Return the pictogram as an SVG element of the given size at (x, y).
+// The pictogram image is read from pictograms_dir/<code>.svg when available,
+// otherwise a red bordered diamond with the pictogram code is drawn.
+fn pictogram(pictograms_dir: Option<&str>, code: &str, x: f64, y: f64, size: f64) -> String {
+    if let Some(pictograms_dir) = pictograms_dir {
+        let path = Path::new(pictograms_dir).join(format!("{}.svg", code));
+        if let Ok(image) = std::fs::read(path) {
+            return format!(
+                r#"<image x="{x:.2}" y="{y:.2}" width="{size:.2}" height="{size:.2}" href="data:image/svg+xml;base64,{}"/>"#,
+                STANDARD.encode(image)
+            );
+        }
+    }
+
+    let half = size / 2.0;
+    let (cx, cy) = (x + half, y + half);
+    format!(
+        r#"<polygon points="{:.2},{:.2} {:.2},{:.2} {:.2},{:.2} {:.2},{:.2}" fill="white" stroke="red" stroke-width="{:.2}"/><text x="{cx:.2}" y="{:.2}" font-size="{:.2}" text-anchor="middle" font-family="sans-serif">{}</text>"#,
+        cx,
+        y,
+        x + size,
+        cy,
+        cx,
+        y + size,
+        x,
+        cy,
+        size * 0.08,
+        cy + size * 0.06,
+        size * 0.16,
+        xml_escape(code),
+    )
+}
+
+// Return the size of the pictograms and their (x, y) positions, laid out
+// in rows of PICTOGRAMS_PER_ROW from (x, y) to fit the given width and height.
+fn pictogram_layout(
+    nb_pictograms: usize,
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+    gap: f64,
+) -> (f64, Vec<(f64, f64)>) {
+    if nb_pictograms == 0 {
+        return (0.0, Vec::new());
+    }
+
+    let nb_columns = nb_pictograms.min(PICTOGRAMS_PER_ROW);
+    let nb_rows = nb_pictograms.div_ceil(PICTOGRAMS_PER_ROW);
+    let size = ((width - (PICTOGRAMS_PER_ROW - 1) as f64 * gap) / PICTOGRAMS_PER_ROW as f64)
+        .min((height - (nb_rows - 1) as f64 * gap) / nb_rows as f64);
+
+    let positions = (0..nb_pictograms)
+        .map(|i| {
+            (
+                x + (i % nb_columns) as f64 * (size + gap),
+                y + (i / nb_columns) as f64 * (size + gap),
+            )
+        })
+        .collect();
+
+    (size, positions)
+}
+
+// Render the label as an SVG document.
+pub fn render_svg(
+    content: &LabelContent,
+    size: LabelSize,
+    language: Locale,
+    pictograms_dir: Option<&str>,
+) -> String {
+    let (width, height) = size.dimensions();
+    let margin = width * 0.04;
+    let inner_width = width - 2.0 * margin;
+
+    let title_font_size = height * 0.065;
+    let signal_word_font_size = height * 0.06;
+
+    let mut svg = String::new();
+    let _ = write!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}mm" height="{height}mm" viewBox="0 0 {width} {height}">"#
+    );
+    let _ = write!(
+        svg,
+        r#"<rect x="0.5" y="0.5" width="{:.2}" height="{:.2}" fill="white" stroke="black" stroke-width="0.5"/>"#,
+        width - 1.0,
+        height - 1.0
+    );
+
+    // Product name and CAS number.
+    let mut y = margin + title_font_size;
+    let _ = write!(
+        svg,
+        r#"<text x="{margin:.2}" y="{y:.2}" font-size="{title_font_size:.2}" font-weight="bold" font-family="sans-serif">{}</text>"#,
+        xml_escape(&content.product_name)
+    );
+    if let Some(cas_number) = &content.cas_number {
+        y += title_font_size * LINE_HEIGHT_RATIO * 0.8;
+        let _ = write!(
+            svg,
+            r#"<text x="{margin:.2}" y="{y:.2}" font-size="{:.2}" font-family="sans-serif">{} {}</text>"#,
+            title_font_size * 0.7,
+            language.cas_number_caption(),
+            xml_escape(cas_number)
+        );
+    }
+
+    // Pictograms and signal word.
+    y += margin;
+    let (pictogram_size, pictogram_positions) = pictogram_layout(
+        content.pictograms.len(),
+        margin,
+        y,
+        inner_width,
+        height * 0.28,
+        margin / 2.0,
+    );
+    for (code, (x, pictogram_y)) in content.pictograms.iter().zip(pictogram_positions.iter()) {
+        svg.push_str(&pictogram(
+            pictograms_dir,
+            code,
+            *x,
+            *pictogram_y,
+            pictogram_size,
+        ));
+    }
+    if let Some((_, last_y)) = pictogram_positions.last() {
+        y = last_y + pictogram_size;
+    }
+    if let Some(signal_word) = &content.signal_word {
+        y += signal_word_font_size * LINE_HEIGHT_RATIO;
+        let _ = write!(
+            svg,
+            r#"<text x="{margin:.2}" y="{y:.2}" font-size="{signal_word_font_size:.2}" font-weight="bold" font-family="sans-serif">{}</text>"#,
+            xml_escape(&language.signal_word(signal_word))
+        );
+    }
+
+    // Footer: owner entity and storage barcode, with its human readable text.
+    // A barcode that can not be encoded as Code 128 is only printed as text.
+    let footer_font_size = height * 0.035;
+    let mut footer_lines: Vec<String> = Vec::new();
+    if let Some(entity_name) = &content.entity_name {
+        footer_lines.push(format!("{}: {}", language.owner_caption(), entity_name));
+    }
+    let barcode = content
+        .storage_barecode
+        .as_ref()
+        .and_then(|storage_barecode| {
+            Barcode::encode(BarcodeSymbology::Code128, storage_barecode).ok()
+        });
+    let barcode_height = match barcode {
+        Some(_) => height * 0.1,
+        None => 0.0,
+    };
+    if let Some(storage_barecode) = &content.storage_barecode {
+        footer_lines.push(storage_barecode.clone());
+    }
+    let footer_height =
+        barcode_height + footer_lines.len() as f64 * footer_font_size * LINE_HEIGHT_RATIO;
+
+    // Statements, shrunk to fit the remaining space.
+    let statements: Vec<String> = content
+        .hazard_statements
+        .iter()
+        .chain(content.precautionary_statements.iter())
+        .map(|statement| format!("{}: {}", statement.reference, statement.label))
+        .collect();
+
+    let available_height = height - y - margin - footer_height;
+    let mut statement_font_size = height * 0.03;
+    let mut lines: Vec<String>;
+    loop {
+        let max_chars = (inner_width / (statement_font_size * GLYPH_WIDTH_RATIO)) as usize;
+        lines = statements
+            .iter()
+            .flat_map(|statement| wrap(statement, max_chars.max(1)))
+            .collect();
+
+        if lines.len() as f64 * statement_font_size * LINE_HEIGHT_RATIO <= available_height
+            || statement_font_size < 1.0
+        {
+            break;
+        }
+        statement_font_size *= 0.9;
+    }
+    y += margin / 2.0;
+    for line in lines {
+        y += statement_font_size * LINE_HEIGHT_RATIO;
+        let _ = write!(
+            svg,
+            r#"<text x="{margin:.2}" y="{y:.2}" font-size="{statement_font_size:.2}" font-family="sans-serif">{}</text>"#,
+            xml_escape(&line)
+        );
+    }
+
+    let mut y = height - margin - footer_height;
+    if let Some(barcode) = &barcode {
+        let module_width = (inner_width / barcode.width as f64).min(MAX_BARCODE_MODULE_WIDTH);
+        let _ = write!(
+            svg,
+            r#"<path fill="black" d="{}"/>"#,
+            barcode.svg_path(
+                margin,
+                y,
+                module_width,
+                barcode_height / barcode.height as f64
+            )
+        );
+        y += barcode_height;
+    }
+    for line in footer_lines {
+        y += footer_font_size * LINE_HEIGHT_RATIO;
+        let _ = write!(
+            svg,
+            r#"<text x="{margin:.2}" y="{y:.2}" font-size="{footer_font_size:.2}" font-family="monospace">{}</text>"#,
+            xml_escape(&line)
+        );
+    }
+
+    svg.push_str("</svg>");
+    svg
+}
+
+// Load the system fonts used to render the SVG texts in PDF documents.
+// Loading them is slow: this is done once at startup.
+pub fn load_font_database() -> fontdb::Database {
+    let mut font_database = fontdb::Database::new();
+    font_database.load_system_fonts();
+
+    font_database
+}
+
+// Convert an SVG document into a PDF document.
+pub fn svg_to_pdf(svg: &str, font_database: &fontdb::Database) -> Result<Vec<u8>, AppError> {
+    let mut tree = match Tree::from_str(svg, &svg2pdf::usvg::Options::default()) {
+        Ok(tree) => tree,
+        Err(err) => return Err(AppError::Label(err.to_string())),
+    };
+
+    tree.postprocess(PostProcessingSteps::default(), font_database);
+
+    Ok(svg2pdf::convert_tree(
+        &tree,
+        svg2pdf::Options {
+            dpi: PDF_DPI,
+            ..Default::default()
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap_splits_on_words() {
+        assert_eq!(
+            wrap("Causes serious eye irritation", 14),
+            vec!["Causes serious", "eye irritation"]
+        );
+    }
+
+    #[test]
+    fn wrap_keeps_long_words_whole() {
+        assert_eq!(
+            wrap("a supercalifragilistic b", 5),
+            vec!["a", "supercalifragilistic", "b"]
+        );
+    }
+
+    #[test]
+    fn wrap_empty_text() {
+        assert!(wrap("   ", 10).is_empty());
+    }
+
+    #[test]
+    fn pictogram_layout_wraps_rows() {
+        let (size, positions) = pictogram_layout(9, 2.0, 10.0, 70.0, 40.0, 1.0);
+
+        assert_eq!(positions.len(), 9);
+        // 3 rows of 4, 4 and 1 pictograms.
+        assert_eq!(positions[4], (2.0, 10.0 + size + 1.0));
+        assert_eq!(positions[8], (2.0, 10.0 + 2.0 * (size + 1.0)));
+        for (x, y) in positions {
+            assert!(x + size <= 72.0 + 1e-9);
+            assert!(y + size <= 50.0 + 1e-9);
+        }
+    }
+
+    #[test]
+    fn render_svg_escapes_texts() {
+        let content = LabelContent {
+            product_name: String::from("Sodium <hydroxide> & co"),
+            signal_word: Some(String::from("warning")),
+            ..Default::default()
+        };
+        let svg = render_svg(&content, LabelSize::Small, Locale::Fr, None);
+
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.ends_with("</svg>"));
+        assert!(svg.contains("Sodium &lt;hydroxide&gt; &amp; co"));
+        assert!(svg.contains("ATTENTION"));
+    }
+
+    #[test]
+    fn render_svg_draws_pictograms_and_barcode() {
+        let content = LabelContent {
+            product_name: String::from("Acetone"),
+            pictograms: (1..=6).map(|i| format!("SGH0{}", i)).collect(),
+            storage_barecode: Some(String::from("S12.1")),
+            ..Default::default()
+        };
+        let svg = render_svg(&content, LabelSize::Medium, Locale::En, None);
+
+        assert_eq!(svg.matches("<polygon").count(), 6);
+        assert!(svg.contains(r#"<path fill="black" d="M"#));
+        assert!(svg.contains(">S12.1</text>"));
+    }
+}
@@ -1,11 +1,27 @@
+pub mod barcode;
 pub mod bookmark;
 pub mod borrowing;
+pub mod bulk;
+pub mod capacity;
+pub mod consumption;
 pub mod entity;
+pub mod expiry;
 pub mod fake;
+pub mod incompatibility;
+pub mod inventory;
+pub mod label;
+pub mod locale;
 pub mod person;
 pub mod product;
 pub mod pubchem;
+pub mod regulatory;
+pub mod reservation;
+pub mod safety_data_sheet;
 pub mod searchable;
 pub mod storage;
+pub mod storage_move;
+pub mod structure;
 pub mod store_location;
+pub mod synonym;
 pub mod validate;
+pub mod waste;
@@ -0,0 +1,301 @@
+use axum::{
+    Json,
+    body::Body,
+    extract::{Path, State},
+    http::{
+        HeaderMap,
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+    },
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::Query;
+use chimitheque_types::{requestfilter::RequestFilter, storage::Storage};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::ops::Deref;
+use tracing::info;
+use url::Url;
+
+use crate::{
+    appstate::AppState,
+    barcode::{
+        Barcode, BarcodeFormat, BarcodeSymbology, LABEL_SHEET_COLUMNS, LABEL_SHEET_ROWS,
+        SheetLabel, render_label_sheet_svg,
+    },
+    errors::AppError,
+    ghs_label::svg_to_pdf,
+    handlers::storage::get_storage,
+    utils::get_chimitheque_person_id_from_headers,
+};
+
+// Maximum number of pixels per module of the barcode images.
+const BARCODE_MAX_SCALE: u32 = 20;
+
+fn default_scale() -> u32 {
+    4
+}
+
+#[derive(Deserialize)]
+pub struct BarcodeQueryParameters {
+    #[serde(default)]
+    symbology: BarcodeSymbology,
+    #[serde(default)]
+    format: BarcodeFormat,
+    // Pixels per module.
+    #[serde(default = "default_scale")]
+    scale: u32,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LabelSheetQueryParameters {
+    // The storage IDs, such as the ones returned by create_update_storage,
+    // given as ids=1&ids=2.
+    #[serde(default)]
+    ids: Vec<u64>,
+    #[serde(default)]
+    symbology: BarcodeSymbology,
+    // Position of the first label on the sheet, starting at 0,
+    // to print on a partially used sheet.
+    #[serde(default)]
+    first_position: usize,
+}
+
+fn storage_barcode_data(storage: &Storage) -> Result<String, AppError> {
+    match storage.storage_barecode.as_deref().map(str::trim) {
+        Some(storage_barecode) if !storage_barecode.is_empty() => Ok(storage_barecode.to_string()),
+        _ => Err(AppError::NotFound(format!(
+            "barcode of storage {}",
+            storage.storage_id.unwrap_or_default()
+        ))),
+    }
+}
+
+pub async fn get_storage_barcode(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+    Query(query_params): Query<BarcodeQueryParameters>,
+) -> Result<Response, AppError> {
+    info!("get_storage_barcode: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    if query_params.scale == 0 || query_params.scale > BARCODE_MAX_SCALE {
+        return Err(AppError::InputValidation(format!(
+            "the scale must be between 1 and {}",
+            BARCODE_MAX_SCALE
+        )));
+    }
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    let storage = get_storage(db_connection.deref(), id, chimitheque_person_id)?;
+    let barcode = Barcode::encode(query_params.symbology, &storage_barcode_data(&storage)?)?;
+
+    let body = match query_params.format {
+        BarcodeFormat::Png => barcode.to_png(query_params.scale)?,
+        BarcodeFormat::Svg => barcode.to_svg(query_params.scale).into_bytes(),
+    };
+
+    Ok((
+        [
+            (CONTENT_TYPE, query_params.format.content_type().to_string()),
+            (
+                CONTENT_DISPOSITION,
+                format!(
+                    "inline; filename=\"storage_{}.{}\"",
+                    id,
+                    query_params.format.extension()
+                ),
+            ),
+        ],
+        Body::from(body),
+    )
+        .into_response())
+}
+
+// Return an A4 PDF sheet of barcode labels for the given storages.
+pub async fn get_storage_label_sheet(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(label_sheet): Query<LabelSheetQueryParameters>,
+) -> Result<Response, AppError> {
+    info!("get_storage_label_sheet: {:?}", label_sheet);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    let nb_positions = LABEL_SHEET_COLUMNS * LABEL_SHEET_ROWS;
+    if label_sheet.ids.is_empty() {
+        return Err(AppError::InputValidation(String::from("no storage IDs")));
+    }
+    if label_sheet.first_position + label_sheet.ids.len() > nb_positions {
+        return Err(AppError::InputValidation(format!(
+            "a sheet holds {} labels, {} requested from position {}",
+            nb_positions,
+            label_sheet.ids.len(),
+            label_sheet.first_position
+        )));
+    }
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    let mut labels: Vec<SheetLabel> = Vec::new();
+    for id in label_sheet.ids.iter() {
+        let storage = get_storage(db_connection.deref(), *id, chimitheque_person_id)?;
+        let barcode_data = storage_barcode_data(&storage)?;
+
+        labels.push(SheetLabel {
+            barcode: Barcode::encode(label_sheet.symbology, &barcode_data)?,
+            caption: barcode_data,
+            lines: vec![
+                storage.product.name.name_label.clone(),
+                storage.store_location.store_location_name.clone(),
+            ],
+        });
+    }
+
+    let svg = render_label_sheet_svg(&labels, label_sheet.first_position);
+
+    Ok((
+        [
+            (CONTENT_TYPE, String::from("application/pdf")),
+            (
+                CONTENT_DISPOSITION,
+                String::from("inline; filename=\"storage_labels.pdf\""),
+            ),
+        ],
+        Body::from(svg_to_pdf(&svg, &state.font_database)?),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ScanQueryParameters {
+    // The scanned code: a storage barcode, or a URL with the storage ID.
+    code: String,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanMatch {
+    Barcode,
+    StorageId,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ScanResponse {
+    code: String,
+    matched_by: ScanMatch,
+    // The matching storages with their product, several for a batch
+    // of storages sharing the same barcode.
+    storages: Vec<Storage>,
+}
+
+// Return the storage ID of a scanned URL, given as a "storage", "storage_id"
+// or "id" query parameter, or as the path segment following "storages".
+fn storage_id_from_url(code: &str) -> Option<u64> {
+    let url = Url::parse(code).ok()?;
+
+    if let Some(storage_id) = url
+        .query_pairs()
+        .find(|(key, _)| key == "storage" || key == "storage_id" || key == "id")
+        .and_then(|(_, value)| value.parse::<u64>().ok())
+    {
+        return Some(storage_id);
+    }
+
+    url.path_segments()?
+        .skip_while(|segment| *segment != "storages")
+        .nth(1)
+        .and_then(|segment| segment.parse::<u64>().ok())
+}
+
+// Return the storages matching a scanned code and how they were matched,
+// no storages if the code is unknown.
+pub(crate) fn find_storages_by_code(
+    db_connection: &Connection,
+    code: &str,
+    chimitheque_person_id: u64,
+) -> Result<(ScanMatch, Vec<Storage>), AppError> {
+    if let Some(storage_id) = storage_id_from_url(code) {
+        return match chimitheque_db::storage::get_storages(
+            db_connection,
+            RequestFilter {
+                id: Some(storage_id),
+                ..Default::default()
+            },
+            chimitheque_person_id,
+        ) {
+            Ok((storages, _)) => Ok((ScanMatch::StorageId, storages)),
+            Err(err) => Err(AppError::Database(err.to_string())),
+        };
+    }
+
+    // The barcode filter may match partially, keep the exact matches only.
+    match chimitheque_db::storage::get_storages(
+        db_connection,
+        RequestFilter {
+            storage_barecode: Some(code.to_string()),
+            ..Default::default()
+        },
+        chimitheque_person_id,
+    ) {
+        Ok((storages, _)) => Ok((
+            ScanMatch::Barcode,
+            storages
+                .into_iter()
+                .filter(|storage| storage.storage_barecode.as_deref().map(str::trim) == Some(code))
+                .collect(),
+        )),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// Resolve a scanned code to its storages.
+pub async fn scan_storage(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query_params): Query<ScanQueryParameters>,
+) -> Result<Json<ScanResponse>, AppError> {
+    info!("scan_storage: {}", query_params.code);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    let code = query_params.code.trim().to_string();
+    if code.is_empty() {
+        return Err(AppError::InputValidation(String::from("empty code")));
+    }
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    let (matched_by, storages) =
+        find_storages_by_code(db_connection.deref(), &code, chimitheque_person_id)?;
+
+    if storages.is_empty() {
+        return Err(AppError::NotFound(format!("storage with code {}", code)));
+    }
+
+    Ok(Json(ScanResponse {
+        code,
+        matched_by,
+        storages,
+    }))
+}
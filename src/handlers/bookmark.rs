@@ -1,38 +1,192 @@
-use axum::extract::{Path, State};
-use chimitheque_db::bookmark::toggle_product_bookmark;
+use axum::{
+    Json,
+    extract::{Path, State},
+};
+use axum_extra::extract::Query;
+use chimitheque_types::{product::Product, requestfilter::RequestFilter};
 use http::HeaderMap;
-use serde::Deserialize;
-use std::ops::DerefMut;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    ops::{Deref, DerefMut},
+};
+use tracing::info;
 
-use crate::{appstate::AppState, errors::AppError, utils::get_chimitheque_person_id_from_headers};
+use crate::{
+    appstate::AppState,
+    bookmark::{create_product_bookmark, delete_product_bookmark, get_product_bookmarks},
+    errors::AppError,
+    utils::get_chimitheque_person_id_from_headers,
+};
 
-#[derive(Deserialize)]
-pub struct ToogleBookmarkPathParameters {
+#[derive(Deserialize, Debug, Default)]
+pub struct CreateUpdateBookmark {
+    // The bookmark labels (folders), such as a project name.
     #[serde(default)]
-    id: u64,
+    labels: Vec<String>,
 }
 
-pub async fn toogle_bookmark(
+fn sanitize_labels(labels: Vec<String>) -> Vec<String> {
+    let mut labels: Vec<String> = labels
+        .into_iter()
+        .map(|label| label.trim().to_string())
+        .filter(|label| !label.is_empty())
+        .collect();
+    labels.sort();
+    labels.dedup();
+
+    labels
+}
+
+// Bookmark a product with the given labels, replacing the previous ones.
+// Bookmarking an already bookmarked product only updates its labels.
+pub async fn create_update_bookmark(
     State(state): State<AppState>,
-    Path(path_params): Path<ToogleBookmarkPathParameters>,
+    Path(id): Path<u64>,
     headers: HeaderMap,
+    body: Option<Json<CreateUpdateBookmark>>,
 ) -> Result<(), AppError> {
+    info!("create_update_bookmark: {}", id);
+
     // Get the chimitheque_person_id.
     let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
         Ok(chimitheque_person_id) => chimitheque_person_id,
         Err(err) => return Err(err),
     };
 
+    let labels = match body {
+        Some(Json(create_update_bookmark)) => sanitize_labels(create_update_bookmark.labels),
+        None => vec![],
+    };
+
     // Get the connection from the database.
     let db_connection_pool = state.db_connection_pool.clone();
     let mut db_connection = db_connection_pool.get().unwrap();
 
-    match toggle_product_bookmark(
+    create_product_bookmark(
         db_connection.deref_mut(),
         chimitheque_person_id,
-        path_params.id,
-    ) {
-        Ok(_) => Ok(()),
-        Err(err) => Err(AppError::Database(err.to_string())),
+        id,
+        &labels,
+    )
+}
+
+// Remove a product bookmark, doing nothing if the product is not bookmarked.
+pub async fn delete_bookmark(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+) -> Result<(), AppError> {
+    info!("delete_bookmark: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let mut db_connection = db_connection_pool.get().unwrap();
+
+    delete_product_bookmark(db_connection.deref_mut(), chimitheque_person_id, id)
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct GetBookmarksQueryParameters {
+    // Only list the bookmarks with this label.
+    label: Option<String>,
+    offset: Option<u64>,
+    limit: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct BookmarkedProduct {
+    product: Product,
+    labels: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct GetBookmarksResponse {
+    rows: Vec<BookmarkedProduct>,
+    total: usize,
+}
+
+// List the connected user bookmarked products, most recent first.
+pub async fn get_bookmarks(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query_params): Query<GetBookmarksQueryParameters>,
+) -> Result<Json<GetBookmarksResponse>, AppError> {
+    info!("get_bookmarks");
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    let mut bookmarks = get_product_bookmarks(db_connection.deref(), chimitheque_person_id)?;
+    if let Some(label) = &query_params.label {
+        bookmarks.retain(|(_, labels)| labels.contains(label));
+    }
+
+    let total = bookmarks.len();
+    let offset = query_params.offset.unwrap_or(0) as usize;
+    let limit = query_params.limit.map_or(total, |limit| limit as usize);
+
+    let mut rows: Vec<BookmarkedProduct> = Vec::new();
+    for (product_id, labels) in bookmarks.into_iter().skip(offset).take(limit) {
+        match chimitheque_db::product::get_products(
+            db_connection.deref(),
+            RequestFilter {
+                id: Some(product_id),
+                ..Default::default()
+            },
+            chimitheque_person_id,
+        ) {
+            Ok((products, _)) => {
+                if let Some(product) = products.first() {
+                    rows.push(BookmarkedProduct {
+                        product: product.to_owned(),
+                        labels,
+                    });
+                }
+            }
+            Err(err) => return Err(AppError::Database(err.to_string())),
+        }
     }
+
+    Ok(Json(GetBookmarksResponse { rows, total }))
+}
+
+// List the connected user bookmark labels with their number of bookmarks.
+pub async fn get_bookmark_labels(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<BTreeMap<String, usize>>, AppError> {
+    info!("get_bookmark_labels");
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    let bookmarks = get_product_bookmarks(db_connection.deref(), chimitheque_person_id)?;
+
+    let mut labels: BTreeMap<String, usize> = BTreeMap::new();
+    for label in bookmarks.iter().flat_map(|(_, labels)| labels.iter()) {
+        *labels.entry(label.clone()).or_default() += 1;
+    }
+
+    Ok(Json(labels))
 }
@@ -1,50 +1,395 @@
-use axum::extract::{Path, State};
-use axum_extra::extract::Query;
+use axum::{
+    Json,
+    extract::{Path, State},
+};
 use chimitheque_db::borrowing::toggle_storage_borrowing;
+use chimitheque_types::{requestfilter::RequestFilter, storage::Storage};
+use chrono::{Local, NaiveDate};
 use http::HeaderMap;
-use serde::Deserialize;
-use std::ops::DerefMut;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    ops::{Deref, DerefMut},
+};
+use tracing::info;
 
-use crate::{appstate::AppState, errors::AppError, utils::get_chimitheque_person_id_from_headers};
+use crate::{
+    appstate::AppState,
+    borrowing::{
+        BORROWING_DATE_FORMAT, BORROWING_DATETIME_FORMAT, BorrowingLog, close_borrowing_log,
+        get_borrower_borrowing_logs, get_open_borrowing_log, get_overdue_borrowing_logs,
+        get_owner_borrowing_logs, get_storage_borrowing_logs, insert_borrowing_log,
+    },
+    errors::AppError,
+    handlers::{reservation::check_not_reserved, storage::get_storage},
+    utils::{enforce, get_chimitheque_person_id_from_headers},
+};
 
-#[derive(Deserialize)]
-pub struct ToogleBorrowingQueryParameters {
-    #[serde(default)]
-    borrower_id: u64,
-    #[serde(default)]
-    borrowing_comment: String,
+#[derive(Deserialize, Debug, Default)]
+pub struct Borrow {
+    // Defaults to the connected user.
+    borrower_id: Option<u64>,
+    // The expected return date, YYYY-MM-DD.
+    due_date: Option<String>,
+    comment: Option<String>,
 }
 
-#[derive(Deserialize)]
-pub struct ToogleBorrowingPathParameters {
-    #[serde(default)]
-    id: u64,
+#[derive(Serialize, Debug, Clone)]
+pub struct BorrowedStorage {
+    borrowing: BorrowingLog,
+    storage: Storage,
+    overdue: bool,
 }
 
-pub async fn toogle_borrowing(
+fn now() -> String {
+    Local::now().format(BORROWING_DATETIME_FORMAT).to_string()
+}
+
+fn today() -> String {
+    Local::now().format(BORROWING_DATE_FORMAT).to_string()
+}
+
+fn is_borrowed(db_connection: &Connection, storage: &Storage) -> Result<bool, AppError> {
+    let Some(storage_id) = storage.storage_id else {
+        return Ok(false);
+    };
+
+    Ok(storage.borrowing.is_some() || get_open_borrowing_log(db_connection, storage_id)?.is_some())
+}
+
+fn borrow_storage(
+    db_connection: &mut Connection,
+    storage: &Storage,
+    borrow: Borrow,
+    chimitheque_person_id: u64,
+) -> Result<BorrowingLog, AppError> {
+    let storage_id = storage.storage_id.unwrap_or_default();
+
+    if is_borrowed(db_connection, storage)? {
+        return Err(AppError::Borrowing(format!(
+            "storage {} is already borrowed",
+            storage_id
+        )));
+    }
+
+    let due_date = match borrow.due_date {
+        Some(due_date) => match NaiveDate::parse_from_str(due_date.trim(), BORROWING_DATE_FORMAT) {
+            Ok(due_date) => Some(due_date.format(BORROWING_DATE_FORMAT).to_string()),
+            Err(err) => {
+                return Err(AppError::InputValidation(format!(
+                    "invalid due date {}: {}",
+                    due_date, err
+                )));
+            }
+        },
+        None => None,
+    };
+    if due_date
+        .as_ref()
+        .is_some_and(|due_date| *due_date < today())
+    {
+        return Err(AppError::InputValidation(String::from(
+            "the due date is in the past",
+        )));
+    }
+
+    let comment = borrow
+        .comment
+        .map(|comment| comment.trim().to_string())
+        .filter(|comment| !comment.is_empty());
+    let borrower_id = borrow.borrower_id.unwrap_or(chimitheque_person_id);
+
+    // A reserved storage can only be borrowed by its reserver,
+    // until the due date if any.
+    let borrowed_at = now();
+    let borrowed_until = match &due_date {
+        Some(due_date) => format!("{} 23:59:59", due_date),
+        None => borrowed_at.clone(),
+    };
+    check_not_reserved(
+        db_connection,
+        storage_id,
+        borrower_id,
+        &borrowed_at,
+        &borrowed_until,
+    )?;
+
+    // Keep the storage borrowing up to date for the storage listings.
+    if let Err(err) = toggle_storage_borrowing(
+        db_connection,
+        chimitheque_person_id,
+        storage_id,
+        borrower_id,
+        comment.clone(),
+    ) {
+        return Err(AppError::Database(err.to_string()));
+    }
+
+    let mut borrowing_log = BorrowingLog {
+        storage_id,
+        borrower_id,
+        lender_id: chimitheque_person_id,
+        owner_id: storage.person.person_id,
+        comment,
+        borrowed_at,
+        due_date,
+        ..Default::default()
+    };
+    borrowing_log.borrowing_log_id = insert_borrowing_log(db_connection, &borrowing_log)?;
+
+    Ok(borrowing_log)
+}
+
+fn return_storage(
+    db_connection: &mut Connection,
+    storage: &Storage,
+    chimitheque_person_id: u64,
+) -> Result<(), AppError> {
+    let storage_id = storage.storage_id.unwrap_or_default();
+
+    if !is_borrowed(db_connection, storage)? {
+        return Err(AppError::Borrowing(format!(
+            "storage {} is not borrowed",
+            storage_id
+        )));
+    }
+
+    if storage.borrowing.is_some() {
+        match toggle_storage_borrowing(db_connection, chimitheque_person_id, storage_id, 0, None) {
+            Ok(_) => (),
+            Err(err) => return Err(AppError::Database(err.to_string())),
+        }
+    }
+
+    close_borrowing_log(db_connection, storage_id, &now(), chimitheque_person_id)
+}
+
+// The person must be able to read the storage, as for the storage
+// borrowing in the casbin matchers.
+async fn check_borrowing_access(
+    state: &AppState,
+    chimitheque_person_id: u64,
+    storage_id: u64,
+) -> Result<(), AppError> {
+    if enforce(state, chimitheque_person_id, "r", "borrows", storage_id).await? {
+        Ok(())
+    } else {
+        Err(AppError::PermissionDenied)
+    }
+}
+
+// Borrow a storage.
+pub async fn create_borrowing(
     State(state): State<AppState>,
     headers: HeaderMap,
-    Query(query_params): Query<ToogleBorrowingQueryParameters>,
-    Path(path_params): Path<ToogleBorrowingPathParameters>,
-) -> Result<(), AppError> {
+    Path(id): Path<u64>,
+    Json(borrow): Json<Borrow>,
+) -> Result<Json<BorrowingLog>, AppError> {
+    info!("create_borrowing: {} {:?}", id, borrow);
+
     // Get the chimitheque_person_id.
     let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
         Ok(chimitheque_person_id) => chimitheque_person_id,
         Err(err) => return Err(err),
     };
 
+    check_borrowing_access(&state, chimitheque_person_id, id).await?;
+
     // Get the connection from the database.
     let db_connection_pool = state.db_connection_pool.clone();
     let mut db_connection = db_connection_pool.get().unwrap();
 
-    match toggle_storage_borrowing(
+    let storage = get_storage(db_connection.deref(), id, chimitheque_person_id)?;
+
+    Ok(Json(borrow_storage(
         db_connection.deref_mut(),
+        &storage,
+        borrow,
         chimitheque_person_id,
-        path_params.id,
-        query_params.borrower_id,
-        Some(query_params.borrowing_comment),
-    ) {
-        Ok(_) => Ok(()),
-        Err(err) => Err(AppError::Database(err.to_string())),
+    )?))
+}
+
+// Return a borrowed storage.
+pub async fn delete_borrowing(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<(), AppError> {
+    info!("delete_borrowing: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    check_borrowing_access(&state, chimitheque_person_id, id).await?;
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let mut db_connection = db_connection_pool.get().unwrap();
+
+    let storage = get_storage(db_connection.deref(), id, chimitheque_person_id)?;
+
+    return_storage(db_connection.deref_mut(), &storage, chimitheque_person_id)
+}
+
+// The borrowing history of a storage, most recent first.
+pub async fn get_borrowing_history(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<Json<Vec<BorrowingLog>>, AppError> {
+    info!("get_borrowing_history: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    check_borrowing_access(&state, chimitheque_person_id, id).await?;
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    // Check that the storage exists.
+    get_storage(db_connection.deref(), id, chimitheque_person_id)?;
+
+    Ok(Json(get_storage_borrowing_logs(db_connection.deref(), id)?))
+}
+
+// Attach the storages to the borrowings, skipping the storages
+// the connected user can not see.
+fn borrowed_storages(
+    db_connection: &Connection,
+    borrowing_logs: Vec<BorrowingLog>,
+    chimitheque_person_id: u64,
+) -> Result<Vec<BorrowedStorage>, AppError> {
+    let today = today();
+
+    let mut borrowed_storages: Vec<BorrowedStorage> = Vec::new();
+    for borrowing_log in borrowing_logs {
+        let storage = match get_storage(
+            db_connection,
+            borrowing_log.storage_id,
+            chimitheque_person_id,
+        ) {
+            Ok(storage) => storage,
+            Err(AppError::NotFound(_)) => continue,
+            Err(err) => return Err(err),
+        };
+        let overdue = borrowing_log
+            .due_date
+            .as_ref()
+            .is_some_and(|due_date| *due_date < today);
+
+        borrowed_storages.push(BorrowedStorage {
+            borrowing: borrowing_log,
+            storage,
+            overdue,
+        });
     }
+
+    Ok(borrowed_storages)
+}
+
+// The storages borrowed by the connected user.
+pub async fn get_connected_user_borrowings(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<BorrowedStorage>>, AppError> {
+    info!("get_connected_user_borrowings");
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    let borrowing_logs = get_borrower_borrowing_logs(db_connection.deref(), chimitheque_person_id)?;
+
+    Ok(Json(borrowed_storages(
+        db_connection.deref(),
+        borrowing_logs,
+        chimitheque_person_id,
+    )?))
+}
+
+// The connected user storages borrowed by other people.
+pub async fn get_connected_user_lendings(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<BorrowedStorage>>, AppError> {
+    info!("get_connected_user_lendings");
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    let borrowing_logs = get_owner_borrowing_logs(db_connection.deref(), chimitheque_person_id)?;
+
+    Ok(Json(borrowed_storages(
+        db_connection.deref(),
+        borrowing_logs,
+        chimitheque_person_id,
+    )?))
+}
+
+// The overdue borrowings of the storages of an entity.
+pub async fn get_entity_overdue_borrowings(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<Json<Vec<BorrowedStorage>>, AppError> {
+    info!("get_entity_overdue_borrowings: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    let entity_storage_ids: HashSet<u64> = match chimitheque_db::storage::get_storages(
+        db_connection.deref(),
+        RequestFilter {
+            entity: Some(id),
+            ..Default::default()
+        },
+        chimitheque_person_id,
+    ) {
+        Ok((storages, _)) => storages
+            .iter()
+            .filter_map(|storage| storage.storage_id)
+            .collect(),
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    let borrowing_logs: Vec<BorrowingLog> =
+        get_overdue_borrowing_logs(db_connection.deref(), &today())?
+            .into_iter()
+            .filter(|borrowing_log| entity_storage_ids.contains(&borrowing_log.storage_id))
+            .collect();
+
+    Ok(Json(borrowed_storages(
+        db_connection.deref(),
+        borrowing_logs,
+        chimitheque_person_id,
+    )?))
 }
@@ -0,0 +1,747 @@
+use axum::{
+    Json,
+    extract::{RawQuery, State},
+    http::HeaderMap,
+};
+use axum_extra::extract::Query;
+use chimitheque_types::{
+    category::Category, product::Product, requestfilter::RequestFilter, storage::Storage,
+    storelocation::StoreLocation, supplier::Supplier, tag::Tag,
+};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::ops::{Deref, DerefMut};
+use tracing::info;
+
+use crate::{
+    appstate::AppState,
+    errors::AppError,
+    handlers::{
+        capacity::check_storage_capacity,
+        incompatibility::{check_storage_incompatibilities, get_store_location},
+        reservation::check_not_reserved_now,
+        storage::check_not_in_waste_batch,
+    },
+    location_history::record_storage_move,
+    search,
+    utils::{enforce, enforce_store_location_storages, get_chimitheque_person_id_from_headers},
+    waste::{ArchiveReason, StorageArchive, insert_storage_archive},
+};
+
+#[derive(Deserialize, Debug, Default)]
+pub struct BulkQueryParameters {
+    // Report what would change without changing anything.
+    #[serde(default)]
+    pub(crate) dry_run: bool,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkItemStatus {
+    Updated,
+    WouldUpdate,
+    Unchanged,
+    PermissionDenied,
+    NotFound,
+    Rejected,
+}
+
+// The result of a bulk update for one item.
+#[derive(Serialize, Debug, Clone)]
+pub struct BulkItemResult {
+    id: u64,
+    status: BulkItemStatus,
+    changes: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+impl BulkItemResult {
+    fn new(id: u64, status: BulkItemStatus) -> Self {
+        BulkItemResult {
+            id,
+            status,
+            changes: vec![],
+            message: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ProductPatch {
+    #[serde(default)]
+    add_tags: Vec<Tag>,
+    // Tags are matched by label, case insensitive.
+    #[serde(default)]
+    remove_tags: Vec<Tag>,
+    category: Option<Category>,
+}
+
+// The items are the given ids, or the items matching the request filter
+// when no ids are given.
+#[derive(Deserialize, Debug, Default)]
+pub struct BulkUpdateProducts {
+    #[serde(default)]
+    ids: Vec<u64>,
+    patch: ProductPatch,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct StoragePatch {
+    store_location: Option<StoreLocation>,
+    supplier: Option<Supplier>,
+    #[serde(default)]
+    archive: bool,
+    // Required to archive the storages.
+    archive_reason: Option<ArchiveReason>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct BulkUpdateStorages {
+    #[serde(default)]
+    ids: Vec<u64>,
+    patch: StoragePatch,
+}
+
+impl BulkUpdateStorages {
+    // Archive the storages.
+    pub(crate) fn archive(ids: Vec<u64>, archive_reason: ArchiveReason) -> Self {
+        BulkUpdateStorages {
+            ids,
+            patch: StoragePatch {
+                archive: true,
+                archive_reason: Some(archive_reason),
+                ..Default::default()
+            },
+        }
+    }
+
+    // Move the storages to the store location.
+    pub(crate) fn store_location_move(ids: Vec<u64>, store_location_id: u64) -> Self {
+        BulkUpdateStorages {
+            ids,
+            patch: StoragePatch {
+                store_location: Some(StoreLocation {
+                    store_location_id: Some(store_location_id),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+fn same_label(label: &str, other_label: &str) -> bool {
+    label.trim().to_lowercase() == other_label.trim().to_lowercase()
+}
+
+// Apply the patch to a product, returning the changes made.
+fn patch_product(product: &mut Product, patch: &ProductPatch) -> Vec<String> {
+    let mut changes: Vec<String> = Vec::new();
+    let mut tags: Vec<Tag> = product.tags.clone().unwrap_or_default();
+
+    for remove_tag in patch.remove_tags.iter() {
+        let nb_tags = tags.len();
+        tags.retain(|tag| !same_label(&tag.tag_label, &remove_tag.tag_label));
+        if tags.len() != nb_tags {
+            changes.push(format!("tag {} removed", remove_tag.tag_label));
+        }
+    }
+    for add_tag in patch.add_tags.iter() {
+        if !tags
+            .iter()
+            .any(|tag| same_label(&tag.tag_label, &add_tag.tag_label))
+        {
+            tags.push(add_tag.clone());
+            changes.push(format!("tag {} added", add_tag.tag_label));
+        }
+    }
+    product.tags = if tags.is_empty() { None } else { Some(tags) };
+
+    if let Some(category) = &patch.category {
+        let current_label = product
+            .category
+            .as_ref()
+            .map(|category| category.category_label.clone())
+            .unwrap_or_default();
+        if !same_label(&current_label, &category.category_label) {
+            changes.push(format!(
+                "category {} -> {}",
+                current_label, category.category_label
+            ));
+            product.category = Some(category.clone());
+        }
+    }
+
+    changes
+}
+
+// Apply the patch to a storage, returning the changes made.
+fn patch_storage(storage: &mut Storage, patch: &StoragePatch) -> Vec<String> {
+    let mut changes: Vec<String> = Vec::new();
+
+    if let Some(store_location) = &patch.store_location {
+        if storage.store_location.store_location_id != store_location.store_location_id {
+            changes.push(format!(
+                "store location {} -> {}",
+                storage.store_location.store_location_name, store_location.store_location_name
+            ));
+            storage.store_location = store_location.clone();
+        }
+    }
+
+    if let Some(supplier) = &patch.supplier {
+        let current_label = storage
+            .supplier
+            .as_ref()
+            .map(|supplier| supplier.supplier_label.clone())
+            .unwrap_or_default();
+        if !same_label(&current_label, &supplier.supplier_label) {
+            changes.push(format!(
+                "supplier {} -> {}",
+                current_label, supplier.supplier_label
+            ));
+            storage.supplier = Some(supplier.clone());
+        }
+    }
+
+    if patch.archive {
+        changes.push(String::from("archived"));
+    }
+
+    changes
+}
+
+fn get_bulk_products(
+    db_connection: &Connection,
+    ids: &[u64],
+    request_filter: Option<RequestFilter>,
+    chimitheque_person_id: u64,
+) -> Result<(Vec<Product>, Vec<u64>), AppError> {
+    if ids.is_empty() {
+        let Some(request_filter) = request_filter else {
+            return Err(AppError::InputValidation(String::from(
+                "missing ids or request filter",
+            )));
+        };
+
+        return match chimitheque_db::product::get_products(
+            db_connection,
+            request_filter,
+            chimitheque_person_id,
+        ) {
+            Ok((products, _)) => Ok((products, vec![])),
+            Err(err) => Err(AppError::Database(err.to_string())),
+        };
+    }
+
+    let mut products: Vec<Product> = Vec::new();
+    let mut not_found_ids: Vec<u64> = Vec::new();
+    for id in ids.iter() {
+        match chimitheque_db::product::get_products(
+            db_connection,
+            RequestFilter {
+                id: Some(*id),
+                ..Default::default()
+            },
+            chimitheque_person_id,
+        ) {
+            Ok((found_products, _)) => match found_products.first() {
+                Some(product) => products.push(product.to_owned()),
+                None => not_found_ids.push(*id),
+            },
+            Err(err) => return Err(AppError::Database(err.to_string())),
+        }
+    }
+
+    Ok((products, not_found_ids))
+}
+
+fn get_bulk_storages(
+    db_connection: &Connection,
+    ids: &[u64],
+    request_filter: Option<RequestFilter>,
+    chimitheque_person_id: u64,
+) -> Result<(Vec<Storage>, Vec<u64>), AppError> {
+    if ids.is_empty() {
+        let Some(request_filter) = request_filter else {
+            return Err(AppError::InputValidation(String::from(
+                "missing ids or request filter",
+            )));
+        };
+
+        return match chimitheque_db::storage::get_storages(
+            db_connection,
+            request_filter,
+            chimitheque_person_id,
+        ) {
+            Ok((storages, _)) => Ok((storages, vec![])),
+            Err(err) => Err(AppError::Database(err.to_string())),
+        };
+    }
+
+    let mut storages: Vec<Storage> = Vec::new();
+    let mut not_found_ids: Vec<u64> = Vec::new();
+    for id in ids.iter() {
+        match chimitheque_db::storage::get_storages(
+            db_connection,
+            RequestFilter {
+                id: Some(*id),
+                ..Default::default()
+            },
+            chimitheque_person_id,
+        ) {
+            Ok((found_storages, _)) => match found_storages.first() {
+                Some(storage) => storages.push(storage.to_owned()),
+                None => not_found_ids.push(*id),
+            },
+            Err(err) => return Err(AppError::Database(err.to_string())),
+        }
+    }
+
+    Ok((storages, not_found_ids))
+}
+
+// The request filter of a bulk update, when the query string has other
+// parameters than the bulk ones. Without ids nor request filter, a bulk
+// update would change all the items and is refused.
+pub(crate) fn bulk_request_filter(
+    raw_query: Option<&str>,
+    request_filter: RequestFilter,
+) -> Option<RequestFilter> {
+    raw_query
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|parameter| parameter.split_once('='))
+        .any(|(name, value)| name != "dry_run" && !value.is_empty())
+        .then_some(request_filter)
+}
+
+// Write a patched product as the product update endpoint does, and
+// update its search index.
+fn write_product_patch(
+    db_connection: &mut Connection,
+    product: Product,
+    chimitheque_person_id: u64,
+) -> Result<(), AppError> {
+    let mut product = product;
+    if let Err(err) = product.sanitize_and_validate() {
+        return Err(AppError::InputValidation(err.to_string()));
+    };
+
+    match chimitheque_db::product::create_update_product(db_connection, product) {
+        Ok(product_id) => search::reindex_product(db_connection, product_id, chimitheque_person_id),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// A patched storage, with its store location before a move.
+struct PatchedStorage {
+    storage: Storage,
+    // Whether the storage changed, beside being archived.
+    updated: bool,
+    from_store_location: Option<StoreLocation>,
+}
+
+// Write a patched storage as the storage update and archive endpoints do,
+// and record its move.
+fn write_storage_patch(
+    db_connection: &mut Connection,
+    patched_storage: PatchedStorage,
+    archive_reason: Option<ArchiveReason>,
+    chimitheque_person_id: u64,
+) -> Result<(), AppError> {
+    let Some(storage_id) = patched_storage.storage.storage_id else {
+        return Ok(());
+    };
+    let to_store_location = patched_storage.storage.store_location.clone();
+
+    if patched_storage.updated {
+        let mut storage = patched_storage.storage;
+        if let Err(err) = storage.sanitize_and_validate() {
+            return Err(AppError::InputValidation(err.to_string()));
+        };
+
+        if let Err(err) =
+            chimitheque_db::storage::create_update_storage(db_connection, storage, 1, false)
+        {
+            return Err(AppError::Database(err.to_string()));
+        }
+    }
+
+    if let Some(from_store_location) = patched_storage.from_store_location {
+        record_storage_move(
+            db_connection,
+            storage_id,
+            &from_store_location,
+            &to_store_location,
+            chimitheque_person_id,
+        )?;
+    }
+
+    // Record why the storage is archived.
+    if let Some(archive_reason) = archive_reason {
+        check_not_in_waste_batch(db_connection, storage_id)?;
+
+        if let Err(err) = chimitheque_db::storage::archive_storage(db_connection, storage_id) {
+            return Err(AppError::Database(err.to_string()));
+        }
+
+        insert_storage_archive(
+            db_connection,
+            &StorageArchive::new(storage_id, archive_reason, None, chimitheque_person_id),
+        )?;
+    }
+
+    Ok(())
+}
+
+// Reject an item which could not be written. The items are written one
+// by one, the ones already written are kept.
+fn set_write_error(results: &mut [BulkItemResult], id: u64, err: AppError) {
+    if let Some(result) = results.iter_mut().find(|result| result.id == id) {
+        result.status = BulkItemStatus::Rejected;
+        result.message = Some(err.to_string());
+    }
+}
+
+// Set the final status of the items to update, the ones which could not
+// be written being already rejected.
+fn set_updated_status(results: &mut [BulkItemResult], dry_run: bool) {
+    for result in results.iter_mut() {
+        if result.status == BulkItemStatus::WouldUpdate && !dry_run {
+            result.status = BulkItemStatus::Updated;
+        }
+    }
+}
+
+pub async fn bulk_update_products(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query_params): Query<BulkQueryParameters>,
+    RawQuery(raw_query): RawQuery,
+    request_filter: RequestFilter,
+    Json(bulk_update): Json<BulkUpdateProducts>,
+) -> Result<Json<Vec<BulkItemResult>>, AppError> {
+    info!("bulk_update_products: dry_run={}", query_params.dry_run);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    let (products, not_found_ids) = {
+        // Get the connection from the database.
+        // It is released before the permission checks, the casbin
+        // matchers taking their own connections.
+        let db_connection_pool = state.db_connection_pool.clone();
+        let db_connection = db_connection_pool.get().unwrap();
+
+        get_bulk_products(
+            db_connection.deref(),
+            &bulk_update.ids,
+            bulk_request_filter(raw_query.as_deref(), request_filter),
+            chimitheque_person_id,
+        )?
+    };
+
+    let mut results: Vec<BulkItemResult> = not_found_ids
+        .into_iter()
+        .map(|id| BulkItemResult::new(id, BulkItemStatus::NotFound))
+        .collect();
+    let mut patched_products: Vec<Product> = Vec::new();
+
+    for mut product in products.into_iter() {
+        let Some(product_id) = product.product_id else {
+            continue;
+        };
+
+        if !enforce(&state, chimitheque_person_id, "u", "products", product_id).await? {
+            results.push(BulkItemResult::new(
+                product_id,
+                BulkItemStatus::PermissionDenied,
+            ));
+            continue;
+        }
+
+        let changes = patch_product(&mut product, &bulk_update.patch);
+        if changes.is_empty() {
+            results.push(BulkItemResult::new(product_id, BulkItemStatus::Unchanged));
+            continue;
+        }
+
+        results.push(BulkItemResult {
+            changes,
+            ..BulkItemResult::new(product_id, BulkItemStatus::WouldUpdate)
+        });
+        patched_products.push(product);
+    }
+
+    if !query_params.dry_run {
+        // Get the connection from the database.
+        let db_connection_pool = state.db_connection_pool.clone();
+        let mut db_connection = db_connection_pool.get().unwrap();
+
+        for product in patched_products.into_iter() {
+            let Some(product_id) = product.product_id else {
+                continue;
+            };
+
+            if let Err(err) =
+                write_product_patch(db_connection.deref_mut(), product, chimitheque_person_id)
+            {
+                set_write_error(&mut results, product_id, err);
+            }
+        }
+    }
+    set_updated_status(&mut results, query_params.dry_run);
+
+    Ok(Json(results))
+}
+
+pub async fn bulk_update_storages(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query_params): Query<BulkQueryParameters>,
+    RawQuery(raw_query): RawQuery,
+    request_filter: RequestFilter,
+    Json(bulk_update): Json<BulkUpdateStorages>,
+) -> Result<Json<Vec<BulkItemResult>>, AppError> {
+    info!("bulk_update_storages: dry_run={}", query_params.dry_run);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    Ok(Json(
+        update_storages(
+            &state,
+            chimitheque_person_id,
+            query_params.dry_run,
+            bulk_request_filter(raw_query.as_deref(), request_filter),
+            bulk_update,
+        )
+        .await?,
+    ))
+}
+
+// Apply a storage patch to several storages, recording the moves
+// in the location history. Without ids, the storages are the ones
+// matching the request filter.
+pub(crate) async fn update_storages(
+    state: &AppState,
+    chimitheque_person_id: u64,
+    dry_run: bool,
+    request_filter: Option<RequestFilter>,
+    bulk_update: BulkUpdateStorages,
+) -> Result<Vec<BulkItemResult>, AppError> {
+    // Archiving is a delete action for casbin, as for the archive endpoint.
+    let action = if bulk_update.patch.archive { "d" } else { "u" };
+    let archive_reason = match (bulk_update.patch.archive, bulk_update.patch.archive_reason) {
+        (true, None) => {
+            return Err(AppError::InputValidation(String::from(
+                "missing archive reason",
+            )));
+        }
+        (true, archive_reason) => archive_reason,
+        (false, _) => None,
+    };
+
+    // The person must be able to write storages in the target store location entity.
+    let mut patch = bulk_update.patch;
+    let target_store_location_id = match &patch.store_location {
+        Some(store_location) => match store_location.store_location_id {
+            Some(store_location_id) => Some(store_location_id),
+            None => {
+                return Err(AppError::InputValidation(String::from(
+                    "missing store location id",
+                )));
+            }
+        },
+        None => None,
+    };
+    let allowed = match target_store_location_id {
+        Some(store_location_id) => {
+            enforce_store_location_storages(state, chimitheque_person_id, "u", store_location_id)
+                .await?
+        }
+        None => true,
+    };
+    if !allowed {
+        return Err(AppError::PermissionDenied);
+    }
+
+    let (storages, not_found_ids) = {
+        // Get the connection from the database.
+        // It is released before the permission checks, the casbin
+        // matchers taking their own connections.
+        let db_connection_pool = state.db_connection_pool.clone();
+        let db_connection = db_connection_pool.get().unwrap();
+
+        get_bulk_storages(
+            db_connection.deref(),
+            &bulk_update.ids,
+            request_filter,
+            chimitheque_person_id,
+        )?
+    };
+
+    let mut results: Vec<BulkItemResult> = not_found_ids
+        .into_iter()
+        .map(|id| BulkItemResult::new(id, BulkItemStatus::NotFound))
+        .collect();
+
+    let mut allowed_storages: Vec<Storage> = Vec::new();
+    for storage in storages.into_iter() {
+        let Some(storage_id) = storage.storage_id else {
+            continue;
+        };
+
+        if !enforce(state, chimitheque_person_id, action, "storages", storage_id).await? {
+            results.push(BulkItemResult::new(
+                storage_id,
+                BulkItemStatus::PermissionDenied,
+            ));
+            continue;
+        }
+        allowed_storages.push(storage);
+    }
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let mut db_connection = db_connection_pool.get().unwrap();
+
+    if let Some(store_location_id) = target_store_location_id {
+        patch.store_location = Some(get_store_location(
+            db_connection.deref(),
+            store_location_id,
+            chimitheque_person_id,
+        )?);
+    }
+
+    let mut patched_storages: Vec<PatchedStorage> = Vec::new();
+
+    for mut storage in allowed_storages.into_iter() {
+        let Some(storage_id) = storage.storage_id else {
+            continue;
+        };
+
+        let store_location = storage.store_location.clone();
+        let changes = patch_storage(&mut storage, &patch);
+        // The archive is the last change.
+        let updated = changes.len() > usize::from(patch.archive);
+        if changes.is_empty() {
+            results.push(BulkItemResult::new(storage_id, BulkItemStatus::Unchanged));
+            continue;
+        }
+
+        // A moved storage is checked against its new store location storages.
+        let mut message: Option<String> = None;
+        let mut from_store_location: Option<StoreLocation> = None;
+        if storage.store_location.store_location_id != store_location.store_location_id {
+            // A reserved storage can only be moved by its reserver.
+            match check_not_reserved_now(db_connection.deref(), storage_id, chimitheque_person_id) {
+                Ok(()) => (),
+                Err(AppError::Reservation(reservation)) => {
+                    results.push(BulkItemResult {
+                        message: Some(reservation),
+                        ..BulkItemResult::new(storage_id, BulkItemStatus::Rejected)
+                    });
+                    continue;
+                }
+                Err(err) => return Err(err),
+            }
+
+            let (incompatibilities, strict) = check_storage_incompatibilities(
+                state,
+                db_connection.deref(),
+                &storage,
+                chimitheque_person_id,
+            )?;
+            if !incompatibilities.is_empty() {
+                let incompatibilities = incompatibilities
+                    .iter()
+                    .map(|incompatibility| incompatibility.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ");
+
+                if strict {
+                    results.push(BulkItemResult {
+                        message: Some(incompatibilities),
+                        ..BulkItemResult::new(storage_id, BulkItemStatus::Rejected)
+                    });
+                    continue;
+                }
+                message = Some(incompatibilities);
+            }
+
+            // Check the capacity limits of the new store location and its parents,
+            // the storages already patched counted with their new location.
+            let pending_storages: Vec<(&Storage, u64)> = patched_storages
+                .iter()
+                .map(|patched_storage| (&patched_storage.storage, 1))
+                .collect();
+            let (capacity_excesses, strict) = check_storage_capacity(
+                db_connection.deref(),
+                &storage,
+                1,
+                &pending_storages,
+                chimitheque_person_id,
+            )?;
+            if !capacity_excesses.is_empty() {
+                let capacity_excesses = capacity_excesses
+                    .iter()
+                    .map(|capacity_excess| capacity_excess.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ");
+
+                if strict {
+                    results.push(BulkItemResult {
+                        message: Some(capacity_excesses),
+                        ..BulkItemResult::new(storage_id, BulkItemStatus::Rejected)
+                    });
+                    continue;
+                }
+                message = Some(match message {
+                    Some(message) => format!("{}, {}", message, capacity_excesses),
+                    None => capacity_excesses,
+                });
+            }
+            from_store_location = Some(store_location);
+        }
+
+        results.push(BulkItemResult {
+            changes,
+            message,
+            ..BulkItemResult::new(storage_id, BulkItemStatus::WouldUpdate)
+        });
+        patched_storages.push(PatchedStorage {
+            storage,
+            updated,
+            from_store_location,
+        });
+    }
+
+    if !dry_run {
+        for patched_storage in patched_storages.into_iter() {
+            let Some(storage_id) = patched_storage.storage.storage_id else {
+                continue;
+            };
+
+            if let Err(err) = write_storage_patch(
+                db_connection.deref_mut(),
+                patched_storage,
+                archive_reason,
+                chimitheque_person_id,
+            ) {
+                set_write_error(&mut results, storage_id, err);
+            }
+        }
+    }
+    set_updated_status(&mut results, dry_run);
+
+    Ok(results)
+}
@@ -0,0 +1,424 @@
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::HeaderMap,
+};
+use axum_extra::extract::Query;
+use chimitheque_types::{
+    requestfilter::RequestFilter, storage::Storage, storelocation::StoreLocation,
+};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    ops::{Deref, DerefMut},
+};
+use tracing::info;
+
+use crate::{
+    appstate::AppState,
+    capacity::{
+        self, CapacityExcess, CapacityLimit, CapacityTotal, capacity_total,
+        get_all_capacity_limits, get_store_location_ancestors, get_store_location_subtree_ids,
+        get_stored_quantities, get_stored_quantities_by_hazard_class, set_capacity_limits,
+        storage_quantity_in, stored_hazard_class_totals,
+    },
+    errors::AppError,
+    handlers::incompatibility::{get_product, get_store_location},
+    incompatibility::Incompatibility,
+    utils::get_chimitheque_person_id_from_headers,
+    waste::HazardClassTotal,
+};
+
+#[derive(Deserialize, Debug, Default)]
+pub struct CapacityReportQueryParameters {
+    entity: Option<u64>,
+}
+
+// A warning returned when creating or moving a storage.
+#[derive(Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum StorageWarning {
+    Incompatibility(Incompatibility),
+    Capacity(CapacityExcess),
+}
+
+pub(crate) fn storage_warnings(
+    incompatibilities: Vec<Incompatibility>,
+    capacity_excesses: Vec<CapacityExcess>,
+) -> Vec<StorageWarning> {
+    incompatibilities
+        .into_iter()
+        .map(StorageWarning::Incompatibility)
+        .chain(capacity_excesses.into_iter().map(StorageWarning::Capacity))
+        .collect()
+}
+
+// The error returned for the strict limits exceeded.
+pub(crate) fn capacity_exceeded(capacity_excesses: &[CapacityExcess]) -> AppError {
+    AppError::CapacityExceeded(
+        capacity_excesses
+            .iter()
+            .filter(|capacity_excess| capacity_excess.strict)
+            .map(|capacity_excess| capacity_excess.to_string())
+            .collect::<Vec<String>>()
+            .join(", "),
+    )
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct StoreLocationCapacity {
+    store_location_id: u64,
+    store_location_name: String,
+    // The storages of the store location and its sub-locations.
+    nb_storages: usize,
+    hazard_class_totals: Vec<HazardClassTotal>,
+    capacity_totals: Vec<CapacityTotal>,
+}
+
+// The store locations visible by a person.
+struct VisibleStoreLocations {
+    store_locations: HashMap<u64, StoreLocation>,
+}
+
+impl VisibleStoreLocations {
+    fn load(db_connection: &Connection, chimitheque_person_id: u64) -> Result<Self, AppError> {
+        let store_locations = match chimitheque_db::storelocation::get_store_locations(
+            db_connection,
+            RequestFilter::default(),
+            chimitheque_person_id,
+        ) {
+            Ok((store_locations, _)) => store_locations,
+            Err(err) => return Err(AppError::Database(err.to_string())),
+        };
+
+        Ok(VisibleStoreLocations {
+            store_locations: store_locations
+                .into_iter()
+                .filter_map(|store_location| {
+                    store_location
+                        .store_location_id
+                        .map(|store_location_id| (store_location_id, store_location))
+                })
+                .collect(),
+        })
+    }
+
+    fn name(&self, store_location_id: u64) -> String {
+        self.store_locations
+            .get(&store_location_id)
+            .map(|store_location| store_location.store_location_name.clone())
+            .unwrap_or_else(|| store_location_id.to_string())
+    }
+}
+
+// Check nb_items storages to be created or moved against the capacity limits
+// of their store location and of its parents.
+// The pending storages are the ones of the same batch already checked, not
+// written yet, with their number of items. They are counted with their new
+// quantity and location, as the storage itself.
+// Return the limits that would be exceeded, and whether one of them is strict
+// (storage rejected).
+pub(crate) fn check_storage_capacity(
+    db_connection: &Connection,
+    storage: &Storage,
+    nb_items: u64,
+    pending_storages: &[(&Storage, u64)],
+    chimitheque_person_id: u64,
+) -> Result<(Vec<CapacityExcess>, bool), AppError> {
+    let Some(store_location_id) = storage.store_location.store_location_id else {
+        return Ok((vec![], false));
+    };
+
+    let capacity_limits = get_all_capacity_limits(db_connection)?;
+    if capacity_limits.is_empty() {
+        return Ok((vec![], false));
+    }
+
+    let Some(product) = get_product(
+        db_connection,
+        storage.product.product_id,
+        chimitheque_person_id,
+    )?
+    else {
+        return Ok((vec![], false));
+    };
+
+    let ancestors = get_store_location_ancestors(db_connection, store_location_id)?;
+    let capacity_limits: Vec<&CapacityLimit> = capacity_limits
+        .iter()
+        .filter(|capacity_limit| {
+            ancestors
+                .iter()
+                .any(|(ancestor_id, _)| *ancestor_id == capacity_limit.store_location_id)
+                && capacity_limit.applies_to(&product)
+        })
+        .collect();
+    if capacity_limits.is_empty() {
+        return Ok((vec![], false));
+    }
+
+    // The stored rows of the storage and of the pending storages
+    // are replaced by their new quantity and location.
+    let excluded_storage_ids: Vec<u64> = pending_storages
+        .iter()
+        .map(|(pending_storage, _)| *pending_storage)
+        .chain([storage])
+        .filter_map(|excluded_storage| excluded_storage.storage_id)
+        .collect();
+
+    let mut capacity_excesses: Vec<CapacityExcess> = Vec::new();
+    for capacity_limit in capacity_limits {
+        // A quantity that can not be converted can not be checked.
+        let Some(quantity) = storage_quantity_in(storage, &capacity_limit.unit_label) else {
+            continue;
+        };
+
+        let mut total = capacity_total(
+            capacity_limit,
+            &get_stored_quantities(
+                db_connection,
+                capacity_limit.store_location_id,
+                capacity_limit.hazard_class.as_deref(),
+                &excluded_storage_ids,
+            )?,
+        )
+        .total
+            + quantity * nb_items as f64;
+
+        if !pending_storages.is_empty() {
+            let subtree_ids =
+                get_store_location_subtree_ids(db_connection, capacity_limit.store_location_id)?;
+            for (pending_storage, pending_nb_items) in pending_storages {
+                let in_subtree = pending_storage
+                    .store_location
+                    .store_location_id
+                    .is_some_and(|store_location_id| subtree_ids.contains(&store_location_id));
+                // The storages of the same product share the hazard classes
+                // of the product loaded above.
+                let applies = pending_storage.product.product_id == product.product_id
+                    || capacity_limit.applies_to(&pending_storage.product);
+                if !in_subtree || !applies {
+                    continue;
+                }
+
+                if let Some(pending_quantity) =
+                    storage_quantity_in(pending_storage, &capacity_limit.unit_label)
+                {
+                    total += pending_quantity * *pending_nb_items as f64;
+                }
+            }
+        }
+
+        if total > capacity_limit.max_quantity {
+            let store_location_name = ancestors
+                .iter()
+                .find(|(ancestor_id, _)| *ancestor_id == capacity_limit.store_location_id)
+                .map(|(_, store_location_name)| store_location_name.clone())
+                .unwrap_or_default();
+
+            capacity_excesses.push(CapacityExcess {
+                store_location_id: capacity_limit.store_location_id,
+                store_location_name,
+                hazard_class: capacity_limit.hazard_class.clone(),
+                max_quantity: capacity_limit.max_quantity,
+                unit_label: capacity_limit.unit_label.clone(),
+                total,
+                strict: capacity_limit.strict,
+            });
+        }
+    }
+    let strict = capacity_excesses
+        .iter()
+        .any(|capacity_excess| capacity_excess.strict);
+
+    Ok((capacity_excesses, strict))
+}
+
+// The current totals of a store location and its sub-locations,
+// all the storages counted whoever can see them.
+fn store_location_capacity(
+    db_connection: &Connection,
+    store_location_id: u64,
+    store_location_name: String,
+    capacity_limits: &[CapacityLimit],
+) -> Result<StoreLocationCapacity, AppError> {
+    let mut capacity_totals: Vec<CapacityTotal> = Vec::new();
+    for capacity_limit in capacity_limits
+        .iter()
+        .filter(|capacity_limit| capacity_limit.store_location_id == store_location_id)
+    {
+        capacity_totals.push(capacity_total(
+            capacity_limit,
+            &get_stored_quantities(
+                db_connection,
+                store_location_id,
+                capacity_limit.hazard_class.as_deref(),
+                &[],
+            )?,
+        ));
+    }
+
+    Ok(StoreLocationCapacity {
+        store_location_id,
+        store_location_name,
+        nb_storages: get_stored_quantities(db_connection, store_location_id, None, &[])?
+            .iter()
+            .map(|stored_quantity| stored_quantity.nb_storages)
+            .sum(),
+        hazard_class_totals: stored_hazard_class_totals(&get_stored_quantities_by_hazard_class(
+            db_connection,
+            store_location_id,
+        )?),
+        capacity_totals,
+    })
+}
+
+pub async fn get_capacity_limits(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<Json<Vec<CapacityLimit>>, AppError> {
+    info!("get_capacity_limits: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    // Check that the store location exists.
+    get_store_location(db_connection.deref(), id, chimitheque_person_id)?;
+
+    Ok(Json(capacity::get_capacity_limits(
+        db_connection.deref(),
+        id,
+    )?))
+}
+
+// Replace the capacity limits of a store location, an empty list removing them.
+pub async fn update_capacity_limits(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+    Json(capacity_limits): Json<Vec<CapacityLimit>>,
+) -> Result<Json<Vec<CapacityLimit>>, AppError> {
+    info!("update_capacity_limits: {} {:?}", id, capacity_limits);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let mut db_connection = db_connection_pool.get().unwrap();
+
+    // Check that the store location exists.
+    get_store_location(db_connection.deref(), id, chimitheque_person_id)?;
+
+    let mut capacity_limits = capacity_limits;
+    for capacity_limit in capacity_limits.iter_mut() {
+        capacity_limit.sanitize_and_validate()?;
+    }
+
+    set_capacity_limits(db_connection.deref_mut(), id, &capacity_limits)?;
+
+    Ok(Json(capacity::get_capacity_limits(
+        db_connection.deref(),
+        id,
+    )?))
+}
+
+// The current totals of a store location and its sub-locations.
+pub async fn get_store_location_capacity(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<Json<StoreLocationCapacity>, AppError> {
+    info!("get_store_location_capacity: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    // Check that the store location exists.
+    let store_location = get_store_location(db_connection.deref(), id, chimitheque_person_id)?;
+
+    let capacity_limits = capacity::get_capacity_limits(db_connection.deref(), id)?;
+
+    Ok(Json(store_location_capacity(
+        db_connection.deref(),
+        id,
+        store_location.store_location_name,
+        &capacity_limits,
+    )?))
+}
+
+// The current totals of the store locations with capacity limits.
+pub async fn get_capacity_report(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query_params): Query<CapacityReportQueryParameters>,
+) -> Result<Json<Vec<StoreLocationCapacity>>, AppError> {
+    info!("get_capacity_report: {:?}", query_params);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    let visible_store_locations =
+        VisibleStoreLocations::load(db_connection.deref(), chimitheque_person_id)?;
+    let capacity_limits = get_all_capacity_limits(db_connection.deref())?;
+
+    // The visible store locations with limits, of the entity if given.
+    let mut store_location_ids: Vec<u64> = capacity_limits
+        .iter()
+        .map(|capacity_limit| capacity_limit.store_location_id)
+        .filter(|store_location_id| {
+            visible_store_locations
+                .store_locations
+                .get(store_location_id)
+                .is_some_and(|store_location| match query_params.entity {
+                    Some(entity_id) => store_location
+                        .entity
+                        .as_ref()
+                        .is_some_and(|entity| entity.entity_id == Some(entity_id)),
+                    None => true,
+                })
+        })
+        .collect();
+    store_location_ids.dedup();
+    if store_location_ids.is_empty() {
+        return Ok(Json(vec![]));
+    }
+
+    let mut store_location_capacities: Vec<StoreLocationCapacity> = Vec::new();
+    for store_location_id in store_location_ids {
+        store_location_capacities.push(store_location_capacity(
+            db_connection.deref(),
+            store_location_id,
+            visible_store_locations.name(store_location_id),
+            &capacity_limits,
+        )?);
+    }
+
+    Ok(Json(store_location_capacities))
+}
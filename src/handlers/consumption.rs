@@ -0,0 +1,263 @@
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::HeaderMap,
+};
+use chrono::Local;
+use rusqlite::TransactionBehavior;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    ops::{Deref, DerefMut},
+};
+use tracing::info;
+
+use crate::{
+    appstate::AppState,
+    consumption::{
+        StorageUsage, USAGE_DATETIME_FORMAT, archive_used_storage, convert_quantity,
+        get_product_usages, get_storage_quantity, get_storage_usages, insert_storage_usage,
+        set_storage_quantity,
+    },
+    errors::AppError,
+    handlers::storage::get_storage,
+    utils::{enforce, get_chimitheque_person_id_from_headers},
+    waste::{ArchiveReason, StorageArchive, set_storage_archive},
+};
+
+// Quantities below this value are considered as zero.
+const QUANTITY_EPSILON: f64 = 1e-9;
+
+#[derive(Deserialize, Debug, Default)]
+pub struct CreateStorageUsage {
+    quantity: f64,
+    // Defaults to the storage unit.
+    unit_label: Option<String>,
+    comment: Option<String>,
+    // Archive the storage if it is empty after the usage.
+    #[serde(default)]
+    archive_when_empty: bool,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct CreateStorageUsageResponse {
+    usage: StorageUsage,
+    // The storage is empty and can be archived.
+    empty: bool,
+    archived: bool,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct StorageUsagesResponse {
+    usages: Vec<StorageUsage>,
+    // The used quantities by unit.
+    total_quantities: BTreeMap<String, f64>,
+}
+
+impl From<Vec<StorageUsage>> for StorageUsagesResponse {
+    fn from(usages: Vec<StorageUsage>) -> Self {
+        let mut total_quantities: BTreeMap<String, f64> = BTreeMap::new();
+        for usage in usages.iter() {
+            *total_quantities
+                .entry(usage.unit_label.clone())
+                .or_default() += usage.quantity;
+        }
+
+        StorageUsagesResponse {
+            usages,
+            total_quantities,
+        }
+    }
+}
+
+// Record a quantity taken from a storage and decrement its quantity.
+pub async fn create_storage_usage(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+    Json(create_storage_usage): Json<CreateStorageUsage>,
+) -> Result<Json<CreateStorageUsageResponse>, AppError> {
+    info!("create_storage_usage: {} {:?}", id, create_storage_usage);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Recording a usage updates the storage.
+    if !enforce(&state, chimitheque_person_id, "u", "storages", id).await? {
+        return Err(AppError::PermissionDenied);
+    }
+
+    if !create_storage_usage.quantity.is_finite() || create_storage_usage.quantity <= 0.0 {
+        return Err(AppError::InputValidation(String::from(
+            "the quantity must be positive",
+        )));
+    }
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let mut db_connection = db_connection_pool.get().unwrap();
+
+    let storage = get_storage(db_connection.deref(), id, chimitheque_person_id)?;
+
+    let Some(unit_quantity) = storage.unit_quantity.as_ref() else {
+        return Err(AppError::InputValidation(format!(
+            "storage {} has no quantity",
+            id
+        )));
+    };
+    let storage_unit_label = unit_quantity.unit_label.clone();
+
+    let unit_label = create_storage_usage
+        .unit_label
+        .unwrap_or_else(|| storage_unit_label.clone());
+    let Some(quantity) = convert_quantity(
+        create_storage_usage.quantity,
+        &unit_label,
+        &storage_unit_label,
+    ) else {
+        return Err(AppError::InputValidation(format!(
+            "can not convert {} to {}",
+            unit_label, storage_unit_label
+        )));
+    };
+
+    // The quantity is read, decremented and logged in one immediate
+    // transaction, holding the write lock from the read.
+    let tx = match db_connection
+        .deref_mut()
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+    {
+        Ok(tx) => tx,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    let Some(storage_quantity) = get_storage_quantity(&tx, id)? else {
+        return Err(AppError::InputValidation(format!(
+            "storage {} has no quantity",
+            id
+        )));
+    };
+
+    let mut remaining_quantity = storage_quantity - quantity;
+    if remaining_quantity < -QUANTITY_EPSILON {
+        return Err(AppError::InputValidation(format!(
+            "only {} {} left in storage {}",
+            storage_quantity, storage_unit_label, id
+        )));
+    }
+    let empty = remaining_quantity <= QUANTITY_EPSILON;
+    if empty {
+        remaining_quantity = 0.0;
+    }
+    set_storage_quantity(&tx, id, remaining_quantity)?;
+
+    let mut usage = StorageUsage {
+        storage_id: id,
+        product_id: storage.product.product_id.unwrap_or_default(),
+        person_id: chimitheque_person_id,
+        quantity,
+        unit_label: storage_unit_label,
+        remaining_quantity,
+        comment: create_storage_usage
+            .comment
+            .map(|comment| comment.trim().to_string())
+            .filter(|comment| !comment.is_empty()),
+        used_at: Local::now().format(USAGE_DATETIME_FORMAT).to_string(),
+        ..Default::default()
+    };
+    usage.storage_usage_id = insert_storage_usage(&tx, &usage)?;
+
+    let archived = empty && create_storage_usage.archive_when_empty;
+    if archived {
+        archive_used_storage(&tx, id)?;
+        set_storage_archive(
+            &tx,
+            &StorageArchive::new(id, ArchiveReason::Consumed, None, chimitheque_person_id),
+        )?;
+    }
+
+    if let Err(err) = tx.commit() {
+        return Err(AppError::Database(err.to_string()));
+    }
+
+    Ok(Json(CreateStorageUsageResponse {
+        usage,
+        empty,
+        archived,
+    }))
+}
+
+// The usages of a storage, most recent first.
+pub async fn get_storage_usage_history(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<Json<StorageUsagesResponse>, AppError> {
+    info!("get_storage_usage_history: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    // Check that the storage exists.
+    get_storage(db_connection.deref(), id, chimitheque_person_id)?;
+
+    Ok(Json(get_storage_usages(db_connection.deref(), id)?.into()))
+}
+
+// The usages of the storages of a product the person can see,
+// most recent first.
+pub async fn get_product_usage_history(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<Json<StorageUsagesResponse>, AppError> {
+    info!("get_product_usage_history: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    // The visibility of each storage, checked once.
+    let mut visible_storages: BTreeMap<u64, bool> = BTreeMap::new();
+    let mut usages: Vec<StorageUsage> = Vec::new();
+    for usage in get_product_usages(db_connection.deref(), id)?.into_iter() {
+        let visible = match visible_storages.get(&usage.storage_id) {
+            Some(visible) => *visible,
+            None => {
+                let visible = match get_storage(
+                    db_connection.deref(),
+                    usage.storage_id,
+                    chimitheque_person_id,
+                ) {
+                    Ok(_) => true,
+                    Err(AppError::NotFound(_)) => false,
+                    Err(err) => return Err(err),
+                };
+                visible_storages.insert(usage.storage_id, visible);
+                visible
+            }
+        };
+
+        if visible {
+            usages.push(usage);
+        }
+    }
+
+    Ok(Json(usages.into()))
+}
@@ -0,0 +1,186 @@
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::HeaderMap,
+};
+use axum_extra::extract::Query;
+use chimitheque_types::{requestfilter::RequestFilter, storage::Storage};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, ops::Deref};
+use tracing::info;
+
+use crate::{
+    appstate::AppState,
+    errors::AppError,
+    expiry::{
+        ExpiryAlert, RecordedExpiryAlert, ShelfLife, get_expiry_alerts, get_recorded_expiry_alerts,
+        get_shelf_life, get_shelf_lives, set_shelf_life,
+    },
+    handlers::storage::get_storage,
+    utils::get_chimitheque_person_id_from_headers,
+};
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ExpiriesQueryParameters {
+    // Also list the storages expiring within this number of days,
+    // defaults to the configured warning delay.
+    days: Option<i64>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct StorageExpiry {
+    alert: ExpiryAlert,
+    storage: Storage,
+}
+
+pub async fn get_storage_shelf_life(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<Json<ShelfLife>, AppError> {
+    info!("get_storage_shelf_life: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    // Check that the storage exists.
+    get_storage(db_connection.deref(), id, chimitheque_person_id)?;
+
+    Ok(Json(get_shelf_life(db_connection.deref(), id)?.unwrap_or(
+        ShelfLife {
+            storage_id: id,
+            ..Default::default()
+        },
+    )))
+}
+
+pub async fn update_storage_shelf_life(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+    Json(mut shelf_life): Json<ShelfLife>,
+) -> Result<Json<ShelfLife>, AppError> {
+    info!("update_storage_shelf_life: {} {:?}", id, shelf_life);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    shelf_life.storage_id = id;
+    shelf_life.sanitize_and_validate()?;
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    // Check that the storage exists.
+    get_storage(db_connection.deref(), id, chimitheque_person_id)?;
+
+    set_shelf_life(db_connection.deref(), &shelf_life)?;
+
+    Ok(Json(shelf_life))
+}
+
+// The expired and soon to expire storages of an entity, soonest first.
+pub async fn get_entity_expiries(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+    Query(query_params): Query<ExpiriesQueryParameters>,
+) -> Result<Json<Vec<StorageExpiry>>, AppError> {
+    info!("get_entity_expiries: {} {:?}", id, query_params);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    let storages = match chimitheque_db::storage::get_storages(
+        db_connection.deref(),
+        RequestFilter {
+            entity: Some(id),
+            ..Default::default()
+        },
+        chimitheque_person_id,
+    ) {
+        Ok((storages, _)) => storages,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    let expiry_alerts = get_expiry_alerts(
+        &get_shelf_lives(db_connection.deref())?,
+        storages.iter().filter_map(|storage| storage.storage_id),
+        query_params.days.unwrap_or(state.expiry_warning_days),
+    );
+
+    Ok(Json(
+        expiry_alerts
+            .into_iter()
+            .filter_map(|alert| {
+                storages
+                    .iter()
+                    .find(|storage| storage.storage_id == Some(alert.storage_id))
+                    .map(|storage| StorageExpiry {
+                        alert,
+                        storage: storage.to_owned(),
+                    })
+            })
+            .collect(),
+    ))
+}
+
+// The expiry alerts of an entity recorded by the expiry alerts job,
+// soonest first, restricted to the storages the person can see.
+pub async fn get_entity_expiry_alerts(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<Json<Vec<RecordedExpiryAlert>>, AppError> {
+    info!("get_entity_expiry_alerts: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    let storage_ids: HashSet<u64> = match chimitheque_db::storage::get_storages(
+        db_connection.deref(),
+        RequestFilter {
+            entity: Some(id),
+            ..Default::default()
+        },
+        chimitheque_person_id,
+    ) {
+        Ok((storages, _)) => storages
+            .iter()
+            .filter_map(|storage| storage.storage_id)
+            .collect(),
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    Ok(Json(
+        get_recorded_expiry_alerts(db_connection.deref(), id)?
+            .into_iter()
+            .filter(|alert| storage_ids.contains(&alert.storage_id))
+            .collect(),
+    ))
+}
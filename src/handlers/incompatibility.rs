@@ -0,0 +1,300 @@
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::HeaderMap,
+};
+use chimitheque_types::{
+    product::Product, requestfilter::RequestFilter, storage::Storage, storelocation::StoreLocation,
+};
+use rusqlite::Connection;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, btree_map::Entry},
+    ops::Deref,
+};
+use tracing::info;
+
+use crate::{
+    appstate::AppState,
+    errors::AppError,
+    incompatibility::{
+        Incompatibility, StoreLocationCompatibility, get_store_location_compatibility,
+        get_store_location_storage_products, set_store_location_compatibility,
+    },
+    utils::get_chimitheque_person_id_from_headers,
+};
+
+pub(crate) fn get_product(
+    db_connection: &Connection,
+    product_id: Option<u64>,
+    chimitheque_person_id: u64,
+) -> Result<Option<Product>, AppError> {
+    let Some(product_id) = product_id else {
+        return Ok(None);
+    };
+
+    match chimitheque_db::product::get_products(
+        db_connection,
+        RequestFilter {
+            id: Some(product_id),
+            ..Default::default()
+        },
+        chimitheque_person_id,
+    ) {
+        Ok((products, _)) => Ok(products.first().cloned()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+pub(crate) fn get_store_location(
+    db_connection: &Connection,
+    store_location_id: u64,
+    chimitheque_person_id: u64,
+) -> Result<StoreLocation, AppError> {
+    match chimitheque_db::storelocation::get_store_locations(
+        db_connection,
+        RequestFilter {
+            id: Some(store_location_id),
+            ..Default::default()
+        },
+        chimitheque_person_id,
+    ) {
+        Ok((store_locations, _)) => match store_locations.first() {
+            Some(store_location) => Ok(store_location.to_owned()),
+            None => Err(AppError::NotFound(format!(
+                "store location {}",
+                store_location_id
+            ))),
+        },
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+fn incompatibilities_between(
+    state: &AppState,
+    storage: &Storage,
+    product: &Product,
+    other_storage_id: u64,
+    other_product: &Product,
+) -> Vec<Incompatibility> {
+    state
+        .incompatibility_rules
+        .check(product, other_product)
+        .into_iter()
+        .map(|(group, other_group)| Incompatibility {
+            storage_id: storage.storage_id,
+            product_id: product.product_id,
+            product_name: product.name.name_label.clone(),
+            group,
+            other_storage_id: Some(other_storage_id),
+            other_product_id: other_product.product_id,
+            other_product_name: other_product.name.name_label.clone(),
+            other_group,
+        })
+        .collect()
+}
+
+// Check a storage to be created or moved against the storages already in
+// its store location, whoever can see them, as for the capacity limits.
+// Return the incompatibilities found, one per incompatible product, and
+// whether the store location is in strict mode (incompatibilities rejected).
+pub(crate) fn check_storage_incompatibilities(
+    state: &AppState,
+    db_connection: &Connection,
+    storage: &Storage,
+    chimitheque_person_id: u64,
+) -> Result<(Vec<Incompatibility>, bool), AppError> {
+    let Some(store_location_id) = storage.store_location.store_location_id else {
+        return Ok((vec![], false));
+    };
+
+    let Some(product) = get_product(
+        db_connection,
+        storage.product.product_id,
+        chimitheque_person_id,
+    )?
+    else {
+        return Ok((vec![], false));
+    };
+
+    let store_location_compatibility =
+        get_store_location_compatibility(db_connection, store_location_id)?;
+
+    // The products are loaded once, the storages of a store location
+    // sharing few products.
+    let mut other_products: BTreeMap<u64, Option<Product>> = BTreeMap::new();
+    let mut incompatibilities: Vec<Incompatibility> = Vec::new();
+    for (other_storage_id, other_product_id) in
+        get_store_location_storage_products(db_connection, store_location_id)?
+    {
+        if storage.storage_id == Some(other_storage_id) {
+            continue;
+        }
+        if incompatibilities
+            .iter()
+            .any(|incompatibility| incompatibility.other_product_id == Some(other_product_id))
+        {
+            continue;
+        }
+
+        if let Entry::Vacant(entry) = other_products.entry(other_product_id) {
+            entry.insert(get_product(
+                db_connection,
+                Some(other_product_id),
+                chimitheque_person_id,
+            )?);
+        }
+        let Some(other_product) = other_products
+            .get(&other_product_id)
+            .and_then(|other_product| other_product.as_ref())
+        else {
+            continue;
+        };
+
+        incompatibilities.extend(incompatibilities_between(
+            state,
+            storage,
+            &product,
+            other_storage_id,
+            other_product,
+        ));
+    }
+
+    Ok((incompatibilities, store_location_compatibility.strict))
+}
+
+pub async fn get_compatibility(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<Json<StoreLocationCompatibility>, AppError> {
+    info!("get_compatibility: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    // Check that the store location exists.
+    get_store_location(db_connection.deref(), id, chimitheque_person_id)?;
+
+    Ok(Json(get_store_location_compatibility(
+        db_connection.deref(),
+        id,
+    )?))
+}
+
+// Set whether the incompatible storages are rejected from a store location.
+pub async fn update_compatibility(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+    Json(store_location_compatibility): Json<StoreLocationCompatibility>,
+) -> Result<Json<StoreLocationCompatibility>, AppError> {
+    info!(
+        "update_compatibility: {} {:?}",
+        id, store_location_compatibility
+    );
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    // Check that the store location exists.
+    get_store_location(db_connection.deref(), id, chimitheque_person_id)?;
+
+    set_store_location_compatibility(db_connection.deref(), id, &store_location_compatibility)?;
+
+    Ok(Json(store_location_compatibility))
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct StoreLocationIncompatibilities {
+    store_location_id: u64,
+    store_location_name: String,
+    incompatibilities: Vec<Incompatibility>,
+}
+
+// List every incompatible storage pair per store location.
+pub async fn get_store_location_incompatibilities(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    request_filter: RequestFilter,
+) -> Result<Json<Vec<StoreLocationIncompatibilities>>, AppError> {
+    info!("get_store_location_incompatibilities");
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    let storages = match chimitheque_db::storage::get_storages(
+        db_connection.deref(),
+        request_filter,
+        chimitheque_person_id,
+    ) {
+        Ok((storages, _)) => storages,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    // Group the storages by store location.
+    let mut storages_by_store_location: BTreeMap<u64, Vec<&Storage>> = BTreeMap::new();
+    for storage in storages.iter() {
+        if let Some(store_location_id) = storage.store_location.store_location_id {
+            storages_by_store_location
+                .entry(store_location_id)
+                .or_default()
+                .push(storage);
+        }
+    }
+
+    let mut report: Vec<StoreLocationIncompatibilities> = Vec::new();
+    for (store_location_id, storages) in storages_by_store_location {
+        let mut incompatibilities: Vec<Incompatibility> = Vec::new();
+
+        for (i, storage) in storages.iter().enumerate() {
+            for other_storage in storages.iter().skip(i + 1) {
+                // Report each incompatible product pair once.
+                if incompatibilities.iter().any(|incompatibility| {
+                    incompatibility.product_id == storage.product.product_id
+                        && incompatibility.other_product_id == other_storage.product.product_id
+                }) {
+                    continue;
+                }
+
+                incompatibilities.extend(incompatibilities_between(
+                    &state,
+                    storage,
+                    &storage.product,
+                    other_storage,
+                ));
+            }
+        }
+
+        if !incompatibilities.is_empty() {
+            report.push(StoreLocationIncompatibilities {
+                store_location_id,
+                store_location_name: storages[0].store_location.store_location_name.clone(),
+                incompatibilities,
+            });
+        }
+    }
+
+    Ok(Json(report))
+}
@@ -0,0 +1,553 @@
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::HeaderMap,
+};
+use axum_extra::extract::Query;
+use chimitheque_types::{requestfilter::RequestFilter, storage::Storage};
+use chrono::Local;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    ops::Deref,
+};
+use tracing::info;
+
+use crate::{
+    appstate::AppState,
+    errors::AppError,
+    handlers::{
+        barcode::find_storages_by_code,
+        bulk::{BulkItemResult, BulkQueryParameters, BulkUpdateStorages, update_storages},
+        incompatibility::get_store_location,
+        storage::get_storage,
+    },
+    inventory::{
+        self, INVENTORY_DATETIME_FORMAT, InventoryCampaign, InventoryScan, get_inventory_scans,
+        insert_inventory_campaign, insert_inventory_scan,
+    },
+    utils::{
+        enforce_entity_storages, enforce_store_location_storages,
+        get_chimitheque_person_id_from_headers,
+    },
+    waste::ArchiveReason,
+};
+
+#[derive(Deserialize, Debug)]
+pub struct Scan {
+    // A storage barcode, or a URL with the storage ID.
+    code: String,
+    // Where the storage was found, defaults to the campaign store location.
+    store_location_id: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct MisplacedStorage {
+    storage: Storage,
+    // Where the storage was found, if known.
+    found_store_location_id: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct InventoryReport {
+    campaign: InventoryCampaign,
+    nb_expected: usize,
+    nb_found: usize,
+    // Expected but not scanned.
+    missing: Vec<Storage>,
+    // Scanned but registered in another store location.
+    misplaced: Vec<MisplacedStorage>,
+    // Scanned codes matching no storage.
+    unknown: Vec<String>,
+}
+
+fn now() -> String {
+    Local::now().format(INVENTORY_DATETIME_FORMAT).to_string()
+}
+
+// The person must be able to write storages in the campaign entity or store location.
+async fn check_campaign_access(
+    state: &AppState,
+    chimitheque_person_id: u64,
+    campaign: &InventoryCampaign,
+) -> Result<(), AppError> {
+    let allowed = match (campaign.entity_id, campaign.store_location_id) {
+        (Some(entity_id), _) => {
+            enforce_entity_storages(state, chimitheque_person_id, "u", entity_id).await?
+        }
+        (None, Some(store_location_id)) => {
+            enforce_store_location_storages(state, chimitheque_person_id, "u", store_location_id)
+                .await?
+        }
+        (None, None) => false,
+    };
+
+    if allowed {
+        Ok(())
+    } else {
+        Err(AppError::PermissionDenied)
+    }
+}
+
+async fn get_campaign(
+    state: &AppState,
+    inventory_campaign_id: u64,
+    chimitheque_person_id: u64,
+) -> Result<InventoryCampaign, AppError> {
+    let maybe_campaign = {
+        // Get the connection from the database.
+        // It is released before the permission check, the casbin
+        // matchers taking their own connections.
+        let db_connection_pool = state.db_connection_pool.clone();
+        let db_connection = db_connection_pool.get().unwrap();
+
+        inventory::get_inventory_campaign(db_connection.deref(), inventory_campaign_id)?
+    };
+    let Some(campaign) = maybe_campaign else {
+        return Err(AppError::NotFound(format!(
+            "inventory campaign {}",
+            inventory_campaign_id
+        )));
+    };
+    check_campaign_access(state, chimitheque_person_id, &campaign).await?;
+
+    Ok(campaign)
+}
+
+// The storages the campaign expects to find.
+fn get_expected_storages(
+    db_connection: &Connection,
+    campaign: &InventoryCampaign,
+    chimitheque_person_id: u64,
+) -> Result<Vec<Storage>, AppError> {
+    match chimitheque_db::storage::get_storages(
+        db_connection,
+        RequestFilter {
+            entity: campaign.entity_id,
+            store_location: campaign.store_location_id,
+            ..Default::default()
+        },
+        chimitheque_person_id,
+    ) {
+        Ok((storages, _)) => Ok(storages),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+fn build_inventory_report(
+    db_connection: &Connection,
+    campaign: InventoryCampaign,
+    chimitheque_person_id: u64,
+) -> Result<InventoryReport, AppError> {
+    let expected_storages = get_expected_storages(db_connection, &campaign, chimitheque_person_id)?;
+    let scans = get_inventory_scans(db_connection, campaign.inventory_campaign_id)?;
+
+    // Where each scanned storage was last found.
+    let mut found: BTreeMap<u64, Option<u64>> = BTreeMap::new();
+    let mut unknown: Vec<String> = Vec::new();
+    for scan in scans.into_iter() {
+        match scan.storage_id {
+            Some(storage_id) => {
+                found.insert(storage_id, scan.store_location_id);
+            }
+            None => {
+                if !unknown.contains(&scan.code) {
+                    unknown.push(scan.code);
+                }
+            }
+        }
+    }
+
+    let expected_ids: HashSet<u64> = expected_storages
+        .iter()
+        .filter_map(|storage| storage.storage_id)
+        .collect();
+
+    let mut misplaced: Vec<MisplacedStorage> = Vec::new();
+    for (storage_id, found_store_location_id) in found.iter() {
+        let storage = match expected_storages
+            .iter()
+            .find(|storage| storage.storage_id == Some(*storage_id))
+        {
+            Some(storage) => storage.to_owned(),
+            // Registered outside of the campaign scope.
+            None => match get_storage(db_connection, *storage_id, chimitheque_person_id) {
+                Ok(storage) => storage,
+                // Archived or deleted since the scan.
+                Err(AppError::NotFound(_)) => continue,
+                Err(err) => return Err(err),
+            },
+        };
+
+        let registered_elsewhere = !expected_ids.contains(storage_id)
+            || found_store_location_id.is_some_and(|found_store_location_id| {
+                storage.store_location.store_location_id != Some(found_store_location_id)
+            });
+        if registered_elsewhere {
+            misplaced.push(MisplacedStorage {
+                storage,
+                found_store_location_id: *found_store_location_id,
+            });
+        }
+    }
+
+    let nb_expected = expected_storages.len();
+    let missing: Vec<Storage> = expected_storages
+        .into_iter()
+        .filter(|storage| {
+            storage
+                .storage_id
+                .is_some_and(|storage_id| !found.contains_key(&storage_id))
+        })
+        .collect();
+
+    Ok(InventoryReport {
+        campaign,
+        nb_expected,
+        nb_found: nb_expected - missing.len(),
+        missing,
+        misplaced,
+        unknown,
+    })
+}
+
+pub async fn create_inventory_campaign(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(mut campaign): Json<InventoryCampaign>,
+) -> Result<Json<InventoryCampaign>, AppError> {
+    info!("create_inventory_campaign: {:?}", campaign);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    campaign.sanitize_and_validate()?;
+    check_campaign_access(&state, chimitheque_person_id, &campaign).await?;
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    // Check that the store location exists.
+    if let Some(store_location_id) = campaign.store_location_id {
+        get_store_location(
+            db_connection.deref(),
+            store_location_id,
+            chimitheque_person_id,
+        )?;
+    }
+
+    campaign.opened_by = chimitheque_person_id;
+    campaign.opened_at = now();
+    campaign.closed_by = None;
+    campaign.closed_at = None;
+    campaign.inventory_campaign_id = insert_inventory_campaign(db_connection.deref(), &campaign)?;
+
+    Ok(Json(campaign))
+}
+
+// The inventory campaigns the connected user can take part in.
+pub async fn get_inventory_campaigns(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<InventoryCampaign>>, AppError> {
+    info!("get_inventory_campaigns");
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    let all_campaigns = {
+        // Get the connection from the database.
+        let db_connection_pool = state.db_connection_pool.clone();
+        let db_connection = db_connection_pool.get().unwrap();
+
+        inventory::get_inventory_campaigns(db_connection.deref())?
+    };
+
+    let mut campaigns: Vec<InventoryCampaign> = Vec::new();
+    for campaign in all_campaigns.into_iter() {
+        match check_campaign_access(&state, chimitheque_person_id, &campaign).await {
+            Ok(_) => campaigns.push(campaign),
+            Err(AppError::PermissionDenied) => (),
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(Json(campaigns))
+}
+
+pub async fn get_inventory_campaign(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<Json<InventoryCampaign>, AppError> {
+    info!("get_inventory_campaign: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    Ok(Json(get_campaign(&state, id, chimitheque_person_id).await?))
+}
+
+// Record a scanned code, returns the recorded scans.
+pub async fn create_inventory_scan(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+    Json(scan): Json<Scan>,
+) -> Result<Json<Vec<InventoryScan>>, AppError> {
+    info!("create_inventory_scan: {} {:?}", id, scan);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    let code = scan.code.trim().to_string();
+    if code.is_empty() {
+        return Err(AppError::InputValidation(String::from("empty code")));
+    }
+
+    let campaign = get_campaign(&state, id, chimitheque_person_id).await?;
+    if campaign.is_closed() {
+        return Err(AppError::Inventory(format!(
+            "inventory campaign {} is closed",
+            id
+        )));
+    }
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    let store_location_id = scan.store_location_id.or(campaign.store_location_id);
+    if let Some(store_location_id) = store_location_id {
+        get_store_location(
+            db_connection.deref(),
+            store_location_id,
+            chimitheque_person_id,
+        )?;
+    }
+
+    let (_, storages) = find_storages_by_code(db_connection.deref(), &code, chimitheque_person_id)?;
+    let storage_ids: Vec<Option<u64>> = if storages.is_empty() {
+        vec![None]
+    } else {
+        storages.iter().map(|storage| storage.storage_id).collect()
+    };
+
+    let scanned_at = now();
+    let mut inventory_scans: Vec<InventoryScan> = Vec::new();
+    for storage_id in storage_ids.into_iter() {
+        let mut inventory_scan = InventoryScan {
+            inventory_campaign_id: id,
+            code: code.clone(),
+            storage_id,
+            store_location_id,
+            person_id: chimitheque_person_id,
+            scanned_at: scanned_at.clone(),
+            ..Default::default()
+        };
+        inventory_scan.inventory_scan_id =
+            insert_inventory_scan(db_connection.deref(), &inventory_scan)?;
+        inventory_scans.push(inventory_scan);
+    }
+
+    Ok(Json(inventory_scans))
+}
+
+// The report of a campaign, a progress report while it is open.
+pub async fn get_inventory_report(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<Json<InventoryReport>, AppError> {
+    info!("get_inventory_report: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    let campaign = get_campaign(&state, id, chimitheque_person_id).await?;
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    Ok(Json(build_inventory_report(
+        db_connection.deref(),
+        campaign,
+        chimitheque_person_id,
+    )?))
+}
+
+// Close a campaign, returns its report.
+pub async fn close_inventory_campaign(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<Json<InventoryReport>, AppError> {
+    info!("close_inventory_campaign: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    let mut campaign = get_campaign(&state, id, chimitheque_person_id).await?;
+    if campaign.is_closed() {
+        return Err(AppError::Inventory(format!(
+            "inventory campaign {} is already closed",
+            id
+        )));
+    }
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    let closed_at = now();
+    inventory::close_inventory_campaign(
+        db_connection.deref(),
+        id,
+        &closed_at,
+        chimitheque_person_id,
+    )?;
+    campaign.closed_at = Some(closed_at);
+    campaign.closed_by = Some(chimitheque_person_id);
+
+    Ok(Json(build_inventory_report(
+        db_connection.deref(),
+        campaign,
+        chimitheque_person_id,
+    )?))
+}
+
+// Return the report of a closed campaign.
+async fn get_closed_campaign_report(
+    state: &AppState,
+    inventory_campaign_id: u64,
+    chimitheque_person_id: u64,
+) -> Result<InventoryReport, AppError> {
+    let campaign = get_campaign(state, inventory_campaign_id, chimitheque_person_id).await?;
+    if !campaign.is_closed() {
+        return Err(AppError::Inventory(format!(
+            "inventory campaign {} is not closed",
+            inventory_campaign_id
+        )));
+    }
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    build_inventory_report(db_connection.deref(), campaign, chimitheque_person_id)
+}
+
+// Archive the storages missing from a closed campaign, see bulk_update_storages.
+pub async fn archive_missing_storages(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+    Query(query_params): Query<BulkQueryParameters>,
+) -> Result<Json<Vec<BulkItemResult>>, AppError> {
+    info!(
+        "archive_missing_storages: {} dry_run={}",
+        id, query_params.dry_run
+    );
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    let report = get_closed_campaign_report(&state, id, chimitheque_person_id).await?;
+
+    let ids: Vec<u64> = report
+        .missing
+        .iter()
+        .filter_map(|storage| storage.storage_id)
+        .collect();
+    // No ids would mean all the storages matching the request filter.
+    if ids.is_empty() {
+        return Ok(Json(vec![]));
+    }
+
+    Ok(Json(
+        update_storages(
+            &state,
+            chimitheque_person_id,
+            query_params.dry_run,
+            None,
+            BulkUpdateStorages::archive(ids, ArchiveReason::Missing),
+        )
+        .await?,
+    ))
+}
+
+// Move the misplaced storages of a closed campaign to where they were found,
+// see bulk_update_storages.
+pub async fn relocate_misplaced_storages(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+    Query(query_params): Query<BulkQueryParameters>,
+) -> Result<Json<Vec<BulkItemResult>>, AppError> {
+    info!(
+        "relocate_misplaced_storages: {} dry_run={}",
+        id, query_params.dry_run
+    );
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    let report = get_closed_campaign_report(&state, id, chimitheque_person_id).await?;
+
+    // The storages found in an unknown store location are left untouched.
+    let mut moves: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+    for misplaced in report.misplaced.iter() {
+        if let (Some(storage_id), Some(found_store_location_id)) = (
+            misplaced.storage.storage_id,
+            misplaced.found_store_location_id,
+        ) {
+            moves
+                .entry(found_store_location_id)
+                .or_default()
+                .push(storage_id);
+        }
+    }
+
+    let mut results: Vec<BulkItemResult> = Vec::new();
+    for (store_location_id, ids) in moves.into_iter() {
+        results.extend(
+            update_storages(
+                &state,
+                chimitheque_person_id,
+                query_params.dry_run,
+                None,
+                BulkUpdateStorages::store_location_move(ids, store_location_id),
+            )
+            .await?,
+        );
+    }
+
+    Ok(Json(results))
+}
@@ -0,0 +1,246 @@
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{
+        HeaderMap,
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+    },
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::Query;
+use chimitheque_types::{product::Product, requestfilter::RequestFilter, storage::Storage};
+use serde::Deserialize;
+use std::ops::Deref;
+use tracing::info;
+
+use crate::{
+    appstate::AppState,
+    errors::AppError,
+    ghs_label::{LabelContent, LabelFormat, LabelSize, LabelStatement, render_svg, svg_to_pdf},
+    i18n::{Locale, StatementTranslations, request_locale},
+    utils::get_chimitheque_person_id_from_headers,
+};
+
+#[derive(Deserialize)]
+pub struct LabelQueryParameters {
+    #[serde(default)]
+    format: LabelFormat,
+    #[serde(default)]
+    size: LabelSize,
+    // Defaults to the request language.
+    #[serde(default)]
+    language: Option<Locale>,
+}
+
+fn label_content_from_product(product: &Product) -> LabelContent {
+    LabelContent {
+        product_name: product.name.name_label.clone(),
+        cas_number: product
+            .cas_number
+            .as_ref()
+            .map(|cas_number| cas_number.cas_number_label.clone()),
+        signal_word: product
+            .signal_word
+            .as_ref()
+            .map(|signal_word| signal_word.signal_word_label.clone()),
+        pictograms: product
+            .symbols
+            .iter()
+            .flatten()
+            .map(|symbol| symbol.symbol_label.clone())
+            .collect(),
+        hazard_statements: product
+            .hazard_statements
+            .iter()
+            .flatten()
+            .map(|hazard_statement| LabelStatement {
+                reference: hazard_statement.hazard_statement_reference.clone(),
+                label: hazard_statement.hazard_statement_label.clone(),
+            })
+            .collect(),
+        precautionary_statements: product
+            .precautionary_statements
+            .iter()
+            .flatten()
+            .map(|precautionary_statement| LabelStatement {
+                reference: precautionary_statement
+                    .precautionary_statement_reference
+                    .clone(),
+                label: precautionary_statement
+                    .precautionary_statement_label
+                    .clone(),
+            })
+            .collect(),
+        ..Default::default()
+    }
+}
+
+fn label_content_from_storage(storage: &Storage) -> LabelContent {
+    LabelContent {
+        storage_barecode: storage.storage_barecode.clone(),
+        entity_name: storage
+            .store_location
+            .entity
+            .as_ref()
+            .map(|entity| entity.entity_name.clone()),
+        ..label_content_from_product(&storage.product)
+    }
+}
+
+// Return the label language and localize the product statements in it.
+fn localize_label_product(
+    db_connection: &rusqlite::Connection,
+    headers: &HeaderMap,
+    chimitheque_person_id: u64,
+    query_params: &LabelQueryParameters,
+    product: &mut Product,
+) -> Result<Locale, AppError> {
+    let locale = match query_params.language {
+        Some(locale) => locale,
+        None => request_locale(db_connection, headers, chimitheque_person_id)?,
+    };
+
+    StatementTranslations::load(db_connection)?.localize_product(product, locale);
+
+    Ok(locale)
+}
+
+fn label_response(
+    state: &AppState,
+    content: &LabelContent,
+    query_params: &LabelQueryParameters,
+    locale: Locale,
+    filename: String,
+) -> Result<Response, AppError> {
+    let svg = render_svg(
+        content,
+        query_params.size,
+        locale,
+        state.ghs_pictograms_dir.as_deref(),
+    );
+
+    let body = match query_params.format {
+        LabelFormat::Svg => svg.into_bytes(),
+        LabelFormat::Pdf => svg_to_pdf(&svg, &state.font_database)?,
+    };
+
+    Ok((
+        [
+            (CONTENT_TYPE, query_params.format.content_type().to_string()),
+            (
+                CONTENT_DISPOSITION,
+                format!(
+                    "inline; filename=\"{}.{}\"",
+                    filename,
+                    query_params.format.extension()
+                ),
+            ),
+        ],
+        Body::from(body),
+    )
+        .into_response())
+}
+
+pub async fn get_product_label(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+    Query(query_params): Query<LabelQueryParameters>,
+) -> Result<Response, AppError> {
+    info!("get_product_label: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    let mayerr_products = chimitheque_db::product::get_products(
+        db_connection.deref(),
+        RequestFilter {
+            id: Some(id),
+            ..Default::default()
+        },
+        chimitheque_person_id,
+    );
+
+    let mut product = match mayerr_products {
+        Ok((products, _)) => match products.first() {
+            Some(product) => product.to_owned(),
+            None => return Err(AppError::NotFound(format!("product {}", id))),
+        },
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    let locale = localize_label_product(
+        db_connection.deref(),
+        &headers,
+        chimitheque_person_id,
+        &query_params,
+        &mut product,
+    )?;
+
+    label_response(
+        &state,
+        &label_content_from_product(&product),
+        &query_params,
+        locale,
+        format!("product_{}", id),
+    )
+}
+
+pub async fn get_storage_label(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+    Query(query_params): Query<LabelQueryParameters>,
+) -> Result<Response, AppError> {
+    info!("get_storage_label: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    let mayerr_storages = chimitheque_db::storage::get_storages(
+        db_connection.deref(),
+        RequestFilter {
+            id: Some(id),
+            ..Default::default()
+        },
+        chimitheque_person_id,
+    );
+
+    let mut storage = match mayerr_storages {
+        Ok((storages, _)) => match storages.first() {
+            Some(storage) => storage.to_owned(),
+            None => return Err(AppError::NotFound(format!("storage {}", id))),
+        },
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    let locale = localize_label_product(
+        db_connection.deref(),
+        &headers,
+        chimitheque_person_id,
+        &query_params,
+        &mut storage.product,
+    )?;
+
+    label_response(
+        &state,
+        &label_content_from_storage(&storage),
+        &query_params,
+        locale,
+        format!("storage_{}", id),
+    )
+}
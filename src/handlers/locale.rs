@@ -0,0 +1,129 @@
+use axum::{Json, extract::State, http::HeaderMap};
+use serde::{Deserialize, Serialize};
+use std::ops::{Deref, DerefMut};
+use tracing::info;
+
+use crate::{
+    appstate::AppState,
+    errors::AppError,
+    i18n::{
+        Locale, StatementTranslation, get_person_locale, import_statement_translations,
+        request_locale, set_person_locale,
+    },
+    utils::get_chimitheque_person_id_from_headers,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PersonLocale {
+    // The person preference, if any.
+    locale: Option<Locale>,
+    // The language used for the request, from the preference or Accept-Language.
+    #[serde(default, skip_deserializing)]
+    request_locale: Locale,
+}
+
+pub async fn get_connected_user_locale(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<PersonLocale>, AppError> {
+    info!("get_connected_user_locale");
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    Ok(Json(PersonLocale {
+        locale: get_person_locale(db_connection.deref(), chimitheque_person_id)?,
+        request_locale: request_locale(db_connection.deref(), &headers, chimitheque_person_id)?,
+    }))
+}
+
+pub async fn update_connected_user_locale(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(person_locale): Json<PersonLocale>,
+) -> Result<(), AppError> {
+    info!("update_connected_user_locale: {:?}", person_locale.locale);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    let Some(locale) = person_locale.locale else {
+        return Err(AppError::InputValidation(String::from("missing locale")));
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    set_person_locale(db_connection.deref(), chimitheque_person_id, locale)
+}
+
+// Import the official statement texts, admins only.
+// The body is a CSV file with the header: statement_type,reference,locale,label
+// where statement_type is "hazard" or "precautionary", for example:
+// hazard,H225,fr,Liquide et vapeurs très inflammables.
+pub async fn import_statement_translations_csv(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<Json<usize>, AppError> {
+    info!("import_statement_translations_csv");
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let mut db_connection = db_connection_pool.get().unwrap();
+
+    match chimitheque_db::casbin::match_person_is_admin(
+        db_connection.deref(),
+        chimitheque_person_id,
+    ) {
+        Ok(true) => (),
+        Ok(false) => return Err(AppError::PermissionDenied),
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    }
+
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(body.trim_start_matches('\u{feff}').as_bytes());
+
+    let mut translations: Vec<StatementTranslation> = Vec::new();
+    for (i, record) in reader.deserialize::<StatementTranslation>().enumerate() {
+        match record {
+            Ok(translation) if !translation.reference.is_empty() => translations.push(translation),
+            Ok(_) => {
+                return Err(AppError::InputValidation(format!(
+                    "line {}: missing reference",
+                    i + 2
+                )));
+            }
+            Err(err) => {
+                return Err(AppError::InputValidation(format!(
+                    "line {}: {}",
+                    i + 2,
+                    err
+                )));
+            }
+        }
+    }
+
+    Ok(Json(import_statement_translations(
+        db_connection.deref_mut(),
+        &translations,
+    )?))
+}
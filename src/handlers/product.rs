@@ -2,17 +2,118 @@ use axum::{
     Json,
     extract::{Path, State},
     http::HeaderMap,
+    response::Response,
 };
+use axum_extra::extract::Query;
 use chimitheque_types::{product::Product, requestfilter::RequestFilter};
+use chrono::Local;
+use rusqlite::Connection;
 use serde::{Deserialize, Serialize};
-use std::ops::{Deref, DerefMut};
-use tracing::info;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::{Deref, DerefMut},
+};
+use tracing::{error, info};
+
+use crate::{
+    AppState,
+    errors::AppError,
+    export::{ExportQueryParameters, ExportTable, export_response},
+    handlers::{
+        capacity::StorageWarning,
+        safety_data_sheet::get_outdated_safety_data_sheet_product_ids,
+        storage::{NewStorage, create_new_storages, validate_new_storages},
+    },
+    i18n::{StatementTranslations, request_locale},
+    regulatory::RegulatoryFlag,
+    search::{self, SEARCH_DEFAULT_LIMIT, SearchMatch},
+    structure::{self, STRUCTURE_DATETIME_FORMAT, StructureIdentifiers, set_structure_identifiers},
+    utils::{WithWarnings, enforce_items, get_chimitheque_person_id_from_headers},
+};
+
+#[derive(Deserialize, Debug, Default)]
+pub struct SearchQueryParameters {
+    // Full-text search query, switching get_products to the search mode.
+    q: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SearchProductsRow {
+    product: Product,
+    #[serde(flatten)]
+    search_match: SearchMatch,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct SearchProductsResponse {
+    rows: Vec<SearchProductsRow>,
+    total: usize,
+}
+
+// Full-text search of the products, best matches first.
+// The search index filters the restricted products and paginates the
+// matches, so that only the products of the page are fetched.
+fn search_products(
+    db_connection: &rusqlite::Connection,
+    q: &str,
+    with_restricted: bool,
+    request_filter: &RequestFilter,
+    chimitheque_person_id: u64,
+) -> Result<SearchProductsResponse, AppError> {
+    let (search_matches, total) = search::search_products(
+        db_connection,
+        q,
+        with_restricted,
+        request_filter.offset.unwrap_or(0),
+        request_filter.limit.unwrap_or(SEARCH_DEFAULT_LIMIT),
+    )?;
+
+    let mut rows: Vec<SearchProductsRow> = Vec::new();
+    for search_match in search_matches.into_iter() {
+        match chimitheque_db::product::get_products(
+            db_connection,
+            RequestFilter {
+                id: Some(search_match.product_id),
+                ..Default::default()
+            },
+            chimitheque_person_id,
+        ) {
+            Ok((products, _)) => {
+                if let Some(product) = products.first() {
+                    rows.push(SearchProductsRow {
+                        product: product.to_owned(),
+                        search_match,
+                    });
+                }
+            }
+            Err(err) => return Err(AppError::Database(err.to_string())),
+        }
+    }
 
-use crate::{AppState, errors::AppError, utils::get_chimitheque_person_id_from_headers};
+    Ok(SearchProductsResponse { rows, total })
+}
+
+// Localize the statements of the products in the request language.
+fn localize_products<'a>(
+    db_connection: &rusqlite::Connection,
+    headers: &HeaderMap,
+    chimitheque_person_id: u64,
+    products: impl Iterator<Item = &'a mut Product>,
+) -> Result<(), AppError> {
+    let locale = request_locale(db_connection, headers, chimitheque_person_id)?;
+    let statement_translations = StatementTranslations::load(db_connection)?;
+
+    for product in products {
+        statement_translations.localize_product(product, locale);
+    }
+
+    Ok(())
+}
 
 pub async fn get_products(
     State(state): State<AppState>,
     headers: HeaderMap,
+    Query(search_params): Query<SearchQueryParameters>,
     request_filter: RequestFilter,
 ) -> Result<Json<Box<dyn erased_serde::Serialize>>, AppError> {
     info!("get_products");
@@ -23,22 +124,66 @@ pub async fn get_products(
         Err(err) => return Err(err),
     };
 
+    // The restricted products are searched for the people allowed to read them.
+    // Checked before getting a connection, the casbin matchers taking their own.
+    let with_restricted = match (&search_params.q, request_filter.id) {
+        (Some(_), None) => enforce_items(&state, chimitheque_person_id, "r", "rproducts").await?,
+        _ => false,
+    };
+
     // Get the connection from the database.
     let db_connection_pool = state.db_connection_pool.clone();
     let db_connection = db_connection_pool.get().unwrap();
 
-    let mayerr_products = chimitheque_db::product::get_products(
+    if let (Some(q), None) = (&search_params.q, request_filter.id) {
+        let mut search_response = search_products(
+            db_connection.deref(),
+            q,
+            with_restricted,
+            &request_filter,
+            chimitheque_person_id,
+        )?;
+        localize_products(
+            db_connection.deref(),
+            &headers,
+            chimitheque_person_id,
+            search_response.rows.iter_mut().map(|row| &mut row.product),
+        )?;
+
+        return Ok(Json(Box::new(search_response)));
+    }
+
+    let mut mayerr_products = chimitheque_db::product::get_products(
         db_connection.deref(),
         request_filter.clone(),
         chimitheque_person_id,
     );
+    if let Ok((products, _)) = mayerr_products.as_mut() {
+        localize_products(
+            db_connection.deref(),
+            &headers,
+            chimitheque_person_id,
+            products.iter_mut(),
+        )?;
+    }
 
     if request_filter.id.is_none() {
         match mayerr_products {
-            Ok(products) => Ok(Json(Box::new(GetProductsOldResponse {
-                rows: products.0,
-                total: products.1,
-            }))),
+            Ok(products) => {
+                let outdated_safety_data_sheets = get_outdated_safety_data_sheet_product_ids(
+                    &state,
+                    db_connection.deref(),
+                    &product_ids(&products.0),
+                )?;
+                let regulatory_flags = regulatory_flags(&state, &products.0);
+
+                Ok(Json(Box::new(GetProductsOldResponse {
+                    rows: products.0,
+                    total: products.1,
+                    outdated_safety_data_sheets,
+                    regulatory_flags,
+                })))
+            }
             Err(err) => Err(AppError::Database(err.to_string())),
         }
     } else {
@@ -53,6 +198,33 @@ pub async fn get_products(
 pub struct GetProductsOldResponse {
     rows: Vec<Product>,
     total: usize,
+    // IDs of the products of rows with an outdated safety data sheet.
+    outdated_safety_data_sheets: Vec<u64>,
+    // Regulatory flags of the products of rows, products without flags omitted.
+    regulatory_flags: BTreeMap<u64, BTreeSet<RegulatoryFlag>>,
+}
+
+fn product_ids(products: &[Product]) -> Vec<u64> {
+    products
+        .iter()
+        .filter_map(|product| product.product_id)
+        .collect()
+}
+
+fn regulatory_flags(
+    state: &AppState,
+    products: &[Product],
+) -> BTreeMap<u64, BTreeSet<RegulatoryFlag>> {
+    products
+        .iter()
+        .filter_map(|product| {
+            let flags = state.regulatory_lists.classify(product);
+            match (product.product_id, flags.is_empty()) {
+                (Some(product_id), false) => Some((product_id, flags)),
+                _ => None,
+            }
+        })
+        .collect()
 }
 
 pub async fn get_products_old(
@@ -72,17 +244,36 @@ pub async fn get_products_old(
     let db_connection_pool = state.db_connection_pool.clone();
     let db_connection = db_connection_pool.get().unwrap();
 
-    let mayerr_products = chimitheque_db::product::get_products(
+    let mut mayerr_products = chimitheque_db::product::get_products(
         db_connection.deref(),
         request_filter,
         chimitheque_person_id,
     );
+    if let Ok((products, _)) = mayerr_products.as_mut() {
+        localize_products(
+            db_connection.deref(),
+            &headers,
+            chimitheque_person_id,
+            products.iter_mut(),
+        )?;
+    }
 
     match mayerr_products {
-        Ok(products) => Ok(Json(GetProductsOldResponse {
-            rows: products.0,
-            total: products.1,
-        })),
+        Ok(products) => {
+            let outdated_safety_data_sheets = get_outdated_safety_data_sheet_product_ids(
+                &state,
+                db_connection.deref(),
+                &product_ids(&products.0),
+            )?;
+            let regulatory_flags = regulatory_flags(&state, &products.0);
+
+            Ok(Json(GetProductsOldResponse {
+                rows: products.0,
+                total: products.1,
+                outdated_safety_data_sheets,
+                regulatory_flags,
+            }))
+        }
         Err(err) => Err(AppError::Database(err.to_string())),
     }
 }
@@ -95,11 +286,18 @@ pub struct CreateUpdateProductPathParameters {
 
 pub async fn create_update_product(
     State(state): State<AppState>,
+    headers: HeaderMap,
     Path(path_params): Path<CreateUpdateProductPathParameters>,
     Json(product): Json<Product>,
 ) -> Result<Json<u64>, AppError> {
     info!("create_update_product: {}", product);
 
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
     // Get the connection from the database.
     let db_connection_pool = state.db_connection_pool.clone();
     let mut db_connection = db_connection_pool.get().unwrap();
@@ -119,11 +317,194 @@ pub async fn create_update_product(
         chimitheque_db::product::create_update_product(db_connection.deref_mut(), product);
 
     match mayerr_product_id {
-        Ok(product_id) => Ok(Json(product_id)),
+        Ok(product_id) => {
+            search::reindex_product(db_connection.deref(), product_id, chimitheque_person_id)?;
+            Ok(Json(product_id))
+        }
         Err(err) => Err(AppError::Database(err.to_string())),
     }
 }
 
+#[derive(Deserialize, Debug)]
+pub struct ProductWithStorages {
+    product: Product,
+    storages: Vec<NewStorage>,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ProductWithStoragesIds {
+    pub product_id: u64,
+    pub storage_ids: Vec<u64>,
+}
+
+// Delete a product created with its storages, and the storages already created,
+// when the creation could not be completed. The storages are deleted first,
+// in reverse creation order, and the product is kept if one of them remains.
+// Returns the creation error, with the items left behind if any.
+fn delete_created_product(
+    db_connection: &mut Connection,
+    product_id: u64,
+    storage_ids: &[u64],
+    err: AppError,
+) -> AppError {
+    let mut left_storage_ids: Vec<u64> = Vec::new();
+    for storage_id in storage_ids.iter().rev() {
+        if let Err(err) = chimitheque_db::storage::delete_storage(db_connection, *storage_id) {
+            error!(
+                "delete_created_product: delete storage {}: {}",
+                storage_id, err
+            );
+            left_storage_ids.push(*storage_id);
+        }
+    }
+
+    if left_storage_ids.is_empty() {
+        match chimitheque_db::product::delete_product(db_connection, product_id) {
+            Ok(_) => return err,
+            Err(err) => error!("delete_created_product: {}: {}", product_id, err),
+        }
+    }
+
+    AppError::Database(format!(
+        "{}, product {} and storages {:?} left behind",
+        err, product_id, left_storage_ids
+    ))
+}
+
+// Store the structure identifiers of a newly created product and index it,
+// in one transaction with a single commit.
+fn index_created_product(
+    db_connection: &mut Connection,
+    product_id: u64,
+    structure_identifiers: Option<&StructureIdentifiers>,
+    chimitheque_person_id: u64,
+) -> Result<(), AppError> {
+    let tx = match db_connection.transaction() {
+        Ok(tx) => tx,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    if let Some(structure_identifiers) =
+        structure_identifiers.filter(|structure_identifiers| !structure_identifiers.is_empty())
+    {
+        set_structure_identifiers(
+            &tx,
+            product_id,
+            structure_identifiers,
+            &Local::now().format(STRUCTURE_DATETIME_FORMAT).to_string(),
+        )?;
+    }
+    search::reindex_product(&tx, product_id, chimitheque_person_id)?;
+
+    match tx.commit() {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// Create the storages of a newly created product, then store its structure
+// identifiers and index it.
+// chimitheque_db commits the product and each storage in its own transaction,
+// so it can not join an outer one: the product and its storages are deleted
+// when any later step fails, and nothing of this crate is written before the
+// storages are all created. Those deletes are not atomic either: the items
+// they leave behind are logged and reported in the returned error. A single
+// transaction needs chimitheque_db variants of create_update_product,
+// create_update_product_from_pubchem and create_update_storage taking a
+// rusqlite::Transaction.
+pub(crate) fn create_product_storages(
+    state: &AppState,
+    db_connection: &mut Connection,
+    product_id: u64,
+    new_storages: Vec<NewStorage>,
+    structure_identifiers: Option<&StructureIdentifiers>,
+    chimitheque_person_id: u64,
+) -> Result<Json<WithWarnings<ProductWithStoragesIds, StorageWarning>>, AppError> {
+    let mut storage_ids: Vec<u64> = Vec::new();
+    let warnings = match create_new_storages(
+        state,
+        db_connection,
+        product_id,
+        new_storages,
+        &mut storage_ids,
+        chimitheque_person_id,
+    )
+    .and_then(|warnings| {
+        index_created_product(
+            db_connection,
+            product_id,
+            structure_identifiers,
+            chimitheque_person_id,
+        )
+        .map(|_| warnings)
+    }) {
+        Ok(warnings) => warnings,
+        Err(err) => {
+            return Err(delete_created_product(
+                db_connection,
+                product_id,
+                &storage_ids,
+                err,
+            ));
+        }
+    };
+
+    Ok(Json(WithWarnings::new(
+        ProductWithStoragesIds {
+            product_id,
+            storage_ids,
+        },
+        warnings,
+    )))
+}
+
+// Create a product and its storages, all or nothing.
+pub async fn create_product_with_storages(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(product_with_storages): Json<ProductWithStorages>,
+) -> Result<Json<WithWarnings<ProductWithStoragesIds, StorageWarning>>, AppError> {
+    info!(
+        "create_product_with_storages: {}",
+        product_with_storages.product
+    );
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Sanitize and validate the product and the storages before creating anything.
+    let mut product = product_with_storages.product;
+    if let Err(err) = product.sanitize_and_validate() {
+        return Err(AppError::InputValidation(err.to_string()));
+    };
+    product.product_id = None;
+
+    let mut new_storages = product_with_storages.storages;
+    validate_new_storages(&state, chimitheque_person_id, &mut new_storages).await?;
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let mut db_connection = db_connection_pool.get().unwrap();
+
+    let product_id =
+        match chimitheque_db::product::create_update_product(db_connection.deref_mut(), product) {
+            Ok(product_id) => product_id,
+            Err(err) => return Err(AppError::Database(err.to_string())),
+        };
+
+    create_product_storages(
+        &state,
+        db_connection.deref_mut(),
+        product_id,
+        new_storages,
+        None,
+        chimitheque_person_id,
+    )
+}
+
 pub async fn delete_product(
     State(state): State<AppState>,
     Path(id): Path<u64>,
@@ -135,7 +516,10 @@ pub async fn delete_product(
     let mut db_connection = db_connection_pool.get().unwrap();
 
     match chimitheque_db::product::delete_product(db_connection.deref_mut(), id) {
-        Ok(_) => Ok(()),
+        Ok(_) => {
+            structure::delete_structure_identifiers(db_connection.deref(), id)?;
+            search::unindex_product(db_connection.deref(), id)
+        }
         Err(err) => Err(AppError::Database(err.to_string())),
     }
 }
@@ -143,8 +527,9 @@ pub async fn delete_product(
 pub async fn export_products(
     State(state): State<AppState>,
     headers: HeaderMap,
+    Query(export_params): Query<ExportQueryParameters>,
     request_filter: RequestFilter,
-) -> Result<String, AppError> {
+) -> Result<Response, AppError> {
     info!("export_products");
 
     // Get the chimitheque_person_id.
@@ -153,18 +538,38 @@ pub async fn export_products(
         Err(err) => return Err(err),
     };
 
-    // Get the connection from the database.
-    let db_connection_pool = state.db_connection_pool.clone();
-    let db_connection = db_connection_pool.get().unwrap();
+    // The statements are exported in the request language.
+    let (locale, statement_translations) = {
+        let db_connection_pool = state.db_connection_pool.clone();
+        let db_connection = db_connection_pool.get().unwrap();
 
-    let mayerr_products = chimitheque_db::product::export_products(
-        db_connection.deref(),
+        (
+            request_locale(db_connection.deref(), &headers, chimitheque_person_id)?,
+            StatementTranslations::load(db_connection.deref())?,
+        )
+    };
+
+    // The export is read page by page.
+    export_response(
+        state.db_connection_pool.clone(),
         request_filter,
-        chimitheque_person_id,
-    );
+        "products",
+        export_params,
+        move |db_connection, request_filter| {
+            chimitheque_db::product::export_products(
+                db_connection,
+                request_filter,
+                chimitheque_person_id,
+            )
+            .map_err(|err| AppError::Database(err.to_string()))
+            .and_then(|csv| ExportTable::from_db_csv(&csv))
+            .and_then(|mut table| {
+                // The statements are localized from their references.
+                statement_translations.localize_export(&mut table, locale);
 
-    match mayerr_products {
-        Ok(products) => Ok(products),
-        Err(err) => Err(AppError::Database(err.to_string())),
-    }
+                Ok(table)
+            })
+        },
+    )
+    .await
 }
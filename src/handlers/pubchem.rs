@@ -6,12 +6,24 @@ use chimitheque_pubchem::{
     pubchem_compound::Record,
 };
 use chimitheque_types::pubchemproduct::PubchemProduct;
+use chrono::Local;
 use http::HeaderMap;
 use serde::Deserialize;
 use std::ops::{Deref, DerefMut};
 use tracing::info;
 
-use crate::{appstate::AppState, errors::AppError, utils::get_chimitheque_person_id_from_headers};
+use crate::{
+    appstate::AppState,
+    errors::AppError,
+    handlers::{
+        capacity::StorageWarning,
+        product::{ProductWithStoragesIds, create_product_storages},
+        storage::{NewStorage, validate_new_storages},
+    },
+    search,
+    structure::{STRUCTURE_DATETIME_FORMAT, StructureIdentifiers, set_structure_identifiers},
+    utils::{WithWarnings, get_chimitheque_person_id_from_headers},
+};
 
 pub async fn pubchem_autocomplete(
     State(state): State<AppState>,
@@ -73,6 +85,9 @@ pub async fn pubchem_create_update_product(
     let db_connection_pool = state.db_connection_pool.clone();
     let mut db_connection = db_connection_pool.get().unwrap();
 
+    // The structure identifiers are not stored by chimitheque_db.
+    let structure_identifiers = StructureIdentifiers::from_pubchem_product(&pubchem_product);
+
     let mut product_id: Option<u64> = None;
     if path_params.id > 0 {
         product_id = Some(path_params.id);
@@ -86,7 +101,70 @@ pub async fn pubchem_create_update_product(
     );
 
     match mayerr_product_id {
-        Ok(product_id) => Ok(Json(product_id)),
+        Ok(product_id) => {
+            if !structure_identifiers.is_empty() {
+                set_structure_identifiers(
+                    db_connection.deref(),
+                    product_id,
+                    &structure_identifiers,
+                    &Local::now().format(STRUCTURE_DATETIME_FORMAT).to_string(),
+                )?;
+            }
+            search::reindex_product(db_connection.deref(), product_id, chimitheque_person_id)?;
+            Ok(Json(product_id))
+        }
         Err(err) => Err(AppError::Database(err.to_string())),
     }
 }
+
+#[derive(Deserialize, Debug)]
+pub struct PubchemProductWithStorages {
+    product: PubchemProduct,
+    storages: Vec<NewStorage>,
+}
+
+// Create a product from PubChem and its storages, all or nothing.
+pub async fn pubchem_create_product_with_storages(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(product_with_storages): Json<PubchemProductWithStorages>,
+) -> Result<Json<WithWarnings<ProductWithStoragesIds, StorageWarning>>, AppError> {
+    info!("pubchem_create_product_with_storages");
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Validate the storages before creating anything.
+    let mut new_storages = product_with_storages.storages;
+    validate_new_storages(&state, chimitheque_person_id, &mut new_storages).await?;
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let mut db_connection = db_connection_pool.get().unwrap();
+
+    // The structure identifiers are not stored by chimitheque_db.
+    let structure_identifiers =
+        StructureIdentifiers::from_pubchem_product(&product_with_storages.product);
+
+    let product_id = match chimitheque_db::pubchemproduct::create_update_product_from_pubchem(
+        db_connection.deref_mut(),
+        product_with_storages.product,
+        chimitheque_person_id,
+        None,
+    ) {
+        Ok(product_id) => product_id,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    create_product_storages(
+        &state,
+        db_connection.deref_mut(),
+        product_id,
+        new_storages,
+        Some(&structure_identifiers),
+        chimitheque_person_id,
+    )
+}
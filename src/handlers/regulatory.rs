@@ -0,0 +1,198 @@
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::HeaderMap,
+};
+use axum_extra::extract::Query;
+use chimitheque_types::{product::Product, requestfilter::RequestFilter, storage::Storage};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Deref,
+};
+use tracing::info;
+
+use crate::{
+    appstate::AppState, consumption::convert_quantity, errors::AppError,
+    regulatory::RegulatoryFlag, utils::get_chimitheque_person_id_from_headers,
+};
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ProductRegulatoryFlags {
+    product_id: u64,
+    flags: BTreeSet<RegulatoryFlag>,
+}
+
+pub async fn get_product_regulatory_flags(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<Json<ProductRegulatoryFlags>, AppError> {
+    info!("get_product_regulatory_flags: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    let product: Product = match chimitheque_db::product::get_products(
+        db_connection.deref(),
+        RequestFilter {
+            id: Some(id),
+            ..Default::default()
+        },
+        chimitheque_person_id,
+    ) {
+        Ok((products, _)) => match products.first() {
+            Some(product) => product.to_owned(),
+            None => return Err(AppError::NotFound(format!("product {}", id))),
+        },
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    Ok(Json(ProductRegulatoryFlags {
+        product_id: id,
+        flags: state.regulatory_lists.classify(&product),
+    }))
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct RegulatoryReportQueryParameters {
+    // Restrict the report to the products with this flag.
+    flag: Option<RegulatoryFlag>,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct RegulatoryReportStorage {
+    storage_id: Option<u64>,
+    storage_barecode: Option<String>,
+    store_location_id: Option<u64>,
+    store_location_name: String,
+    storage_quantity: Option<f64>,
+    unit: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct RegulatoryReportProduct {
+    product_id: Option<u64>,
+    product_name: String,
+    cas_number: Option<String>,
+    flags: BTreeSet<RegulatoryFlag>,
+    storages: Vec<RegulatoryReportStorage>,
+    // Total quantity per unit, masses in kg and volumes in L, storages
+    // without quantity or unit excluded.
+    total_quantities: BTreeMap<String, f64>,
+}
+
+// The unit a storage quantity is totalled in: kg for the masses, L for the
+// volumes, or the storage unit when it can not be converted.
+fn total_quantity_unit(unit_label: &str) -> &str {
+    if convert_quantity(1.0, unit_label, "kg").is_some() {
+        "kg"
+    } else if convert_quantity(1.0, unit_label, "L").is_some() {
+        "L"
+    } else {
+        unit_label
+    }
+}
+
+fn regulatory_report_storage(storage: &Storage) -> RegulatoryReportStorage {
+    RegulatoryReportStorage {
+        storage_id: storage.storage_id,
+        storage_barecode: storage.storage_barecode.clone(),
+        store_location_id: storage.store_location.store_location_id,
+        store_location_name: storage.store_location.store_location_name.clone(),
+        storage_quantity: storage.storage_quantity,
+        unit: storage
+            .unit_quantity
+            .as_ref()
+            .map(|unit| unit.unit_label.clone()),
+    }
+}
+
+// List the storages of an entity holding regulated products, grouped by product.
+pub async fn get_entity_regulatory_report(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+    Query(query_params): Query<RegulatoryReportQueryParameters>,
+) -> Result<Json<Vec<RegulatoryReportProduct>>, AppError> {
+    info!("get_entity_regulatory_report: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    let storages = match chimitheque_db::storage::get_storages(
+        db_connection.deref(),
+        RequestFilter {
+            entity: Some(id),
+            ..Default::default()
+        },
+        chimitheque_person_id,
+    ) {
+        Ok((storages, _)) => storages,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    let mut report: BTreeMap<u64, RegulatoryReportProduct> = BTreeMap::new();
+    for storage in storages.iter() {
+        let Some(product_id) = storage.product.product_id else {
+            continue;
+        };
+
+        if !report.contains_key(&product_id) {
+            let flags = state.regulatory_lists.classify(&storage.product);
+            if flags.is_empty() {
+                continue;
+            }
+            if let Some(flag) = query_params.flag {
+                if !flags.contains(&flag) {
+                    continue;
+                }
+            }
+
+            report.insert(
+                product_id,
+                RegulatoryReportProduct {
+                    product_id: Some(product_id),
+                    product_name: storage.product.name.name_label.clone(),
+                    cas_number: storage
+                        .product
+                        .cas_number
+                        .as_ref()
+                        .map(|cas_number| cas_number.cas_number_label.clone()),
+                    flags,
+                    ..Default::default()
+                },
+            );
+        }
+
+        let report_product = report.get_mut(&product_id).unwrap();
+        let report_storage = regulatory_report_storage(storage);
+
+        if let (Some(quantity), Some(unit)) =
+            (report_storage.storage_quantity, &report_storage.unit)
+        {
+            let total_unit = total_quantity_unit(unit);
+            *report_product
+                .total_quantities
+                .entry(total_unit.to_string())
+                .or_default() += convert_quantity(quantity, unit, total_unit).unwrap_or(quantity);
+        }
+        report_product.storages.push(report_storage);
+    }
+
+    Ok(Json(report.into_values().collect()))
+}
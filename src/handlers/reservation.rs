@@ -0,0 +1,512 @@
+use axum::{
+    Json,
+    extract::{Path, State},
+};
+use chimitheque_types::{requestfilter::RequestFilter, storage::Storage};
+use chrono::{Local, NaiveDate, NaiveDateTime};
+use http::HeaderMap;
+use rusqlite::{Connection, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    ops::{Deref, DerefMut},
+};
+use tracing::info;
+
+use crate::{
+    appstate::AppState,
+    errors::AppError,
+    handlers::{incompatibility::get_store_location, storage::get_storage},
+    reservation::{
+        self, RESERVATION_DATETIME_FORMAT, StorageReservation,
+        get_overlapping_storage_reservations, get_upcoming_storage_reservations,
+        insert_storage_reservation,
+    },
+    utils::{
+        enforce, enforce_entity_storages, enforce_store_location_storages,
+        get_chimitheque_person_id_from_headers,
+    },
+};
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Reserve {
+    // Defaults to the connected user. Another person must be a member
+    // of the storage entity.
+    person_id: Option<u64>,
+    #[serde(default)]
+    purpose: String,
+    // YYYY-MM-DD HH:MM[:SS], or YYYY-MM-DD for the whole day.
+    starts_at: String,
+    ends_at: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ReservedStorage {
+    reservation: StorageReservation,
+    storage: Storage,
+    // The reservation window includes the current time.
+    active: bool,
+}
+
+fn now() -> String {
+    Local::now().format(RESERVATION_DATETIME_FORMAT).to_string()
+}
+
+// Parse a reservation window bound, a date alone meaning
+// the start or the end of the day.
+fn parse_reservation_datetime(value: &str, end_of_day: bool) -> Result<String, AppError> {
+    let value = value.trim().replace('T', " ");
+
+    for format in [RESERVATION_DATETIME_FORMAT, "%Y-%m-%d %H:%M"] {
+        if let Ok(datetime) = NaiveDateTime::parse_from_str(&value, format) {
+            return Ok(datetime.format(RESERVATION_DATETIME_FORMAT).to_string());
+        }
+    }
+
+    match NaiveDate::parse_from_str(&value, "%Y-%m-%d") {
+        Ok(date) => {
+            let time = if end_of_day { "23:59:59" } else { "00:00:00" };
+            Ok(format!("{} {}", date.format("%Y-%m-%d"), time))
+        }
+        Err(err) => Err(AppError::InputValidation(format!(
+            "invalid reservation date {}: {}",
+            value, err
+        ))),
+    }
+}
+
+// Check that the person exists and is a member of the given entity.
+fn check_person_in_entity(
+    db_connection: &Connection,
+    person_id: u64,
+    maybe_entity_id: Option<u64>,
+    chimitheque_person_id: u64,
+) -> Result<(), AppError> {
+    let Some(entity_id) = maybe_entity_id else {
+        return Err(AppError::InputValidation(format!(
+            "person {} is not a member of the storage entity",
+            person_id
+        )));
+    };
+
+    match chimitheque_db::person::get_people(
+        db_connection,
+        RequestFilter {
+            id: Some(person_id),
+            entity: Some(entity_id),
+            ..Default::default()
+        },
+        chimitheque_person_id,
+    ) {
+        Ok((people, _)) if !people.is_empty() => Ok(()),
+        Ok(_) => Err(AppError::InputValidation(format!(
+            "person {} is not a member of entity {}",
+            person_id, entity_id
+        ))),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// Check that the storage is not reserved by another person than the given one
+// during the given time window.
+pub(crate) fn check_not_reserved(
+    db_connection: &Connection,
+    storage_id: u64,
+    person_id: u64,
+    starts_at: &str,
+    ends_at: &str,
+) -> Result<(), AppError> {
+    match get_overlapping_storage_reservations(db_connection, storage_id, starts_at, ends_at)?
+        .into_iter()
+        .find(|storage_reservation| storage_reservation.person_id != person_id)
+    {
+        Some(storage_reservation) => Err(AppError::Reservation(format!(
+            "storage {} is reserved by person {} from {} to {} ({})",
+            storage_id,
+            storage_reservation.person_id,
+            storage_reservation.starts_at,
+            storage_reservation.ends_at,
+            storage_reservation.purpose
+        ))),
+        None => Ok(()),
+    }
+}
+
+// Check that the storage is not currently reserved by another person.
+pub(crate) fn check_not_reserved_now(
+    db_connection: &Connection,
+    storage_id: u64,
+    person_id: u64,
+) -> Result<(), AppError> {
+    let now = now();
+
+    check_not_reserved(db_connection, storage_id, person_id, &now, &now)
+}
+
+// The person must be able to read the reserved storage.
+async fn check_reservation_access(
+    state: &AppState,
+    chimitheque_person_id: u64,
+    storage_id: u64,
+) -> Result<(), AppError> {
+    if enforce(state, chimitheque_person_id, "r", "storages", storage_id).await? {
+        Ok(())
+    } else {
+        Err(AppError::PermissionDenied)
+    }
+}
+
+// Load a reservation, checking that the person can read its storage.
+async fn get_reservation(
+    state: &AppState,
+    id: u64,
+    chimitheque_person_id: u64,
+) -> Result<StorageReservation, AppError> {
+    let maybe_storage_reservation = {
+        // Get the connection from the database.
+        let db_connection_pool = state.db_connection_pool.clone();
+        let db_connection = db_connection_pool.get().unwrap();
+
+        reservation::get_storage_reservation(db_connection.deref(), id)?
+    };
+
+    let Some(storage_reservation) = maybe_storage_reservation else {
+        return Err(AppError::NotFound(format!("reservation {}", id)));
+    };
+
+    check_reservation_access(state, chimitheque_person_id, storage_reservation.storage_id).await?;
+
+    Ok(storage_reservation)
+}
+
+// Attach the storages to the reservations, skipping the storages
+// the connected user can not see.
+fn reserved_storages(
+    db_connection: &Connection,
+    storage_reservations: Vec<StorageReservation>,
+    chimitheque_person_id: u64,
+) -> Result<Vec<ReservedStorage>, AppError> {
+    let now = now();
+
+    let mut reserved_storages: Vec<ReservedStorage> = Vec::new();
+    for storage_reservation in storage_reservations {
+        let storage = match get_storage(
+            db_connection,
+            storage_reservation.storage_id,
+            chimitheque_person_id,
+        ) {
+            Ok(storage) => storage,
+            Err(AppError::NotFound(_)) => continue,
+            Err(err) => return Err(err),
+        };
+        let active = storage_reservation.starts_at <= now && storage_reservation.ends_at >= now;
+
+        reserved_storages.push(ReservedStorage {
+            reservation: storage_reservation,
+            storage,
+            active,
+        });
+    }
+
+    Ok(reserved_storages)
+}
+
+// The current and upcoming reservations of the storages matching the filter.
+fn filtered_reserved_storages(
+    db_connection: &Connection,
+    request_filter: RequestFilter,
+    chimitheque_person_id: u64,
+) -> Result<Vec<ReservedStorage>, AppError> {
+    let storage_ids: HashSet<u64> = match chimitheque_db::storage::get_storages(
+        db_connection,
+        request_filter,
+        chimitheque_person_id,
+    ) {
+        Ok((storages, _)) => storages
+            .iter()
+            .filter_map(|storage| storage.storage_id)
+            .collect(),
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    let storage_reservations: Vec<StorageReservation> =
+        get_upcoming_storage_reservations(db_connection, &now())?
+            .into_iter()
+            .filter(|storage_reservation| storage_ids.contains(&storage_reservation.storage_id))
+            .collect();
+
+    reserved_storages(db_connection, storage_reservations, chimitheque_person_id)
+}
+
+// Reserve a storage for a time window.
+pub async fn create_storage_reservation(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+    Json(reserve): Json<Reserve>,
+) -> Result<Json<StorageReservation>, AppError> {
+    info!("create_storage_reservation: {} {:?}", id, reserve);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    check_reservation_access(&state, chimitheque_person_id, id).await?;
+
+    // Only the people who can update the storage may reserve it
+    // for someone else.
+    let person_id = reserve.person_id.unwrap_or(chimitheque_person_id);
+    if person_id != chimitheque_person_id
+        && !enforce(&state, chimitheque_person_id, "u", "storages", id).await?
+    {
+        return Err(AppError::PermissionDenied);
+    }
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let mut db_connection = db_connection_pool.get().unwrap();
+
+    // Check that the storage exists.
+    let storage = get_storage(db_connection.deref(), id, chimitheque_person_id)?;
+
+    if person_id != chimitheque_person_id {
+        check_person_in_entity(
+            db_connection.deref(),
+            person_id,
+            storage
+                .store_location
+                .entity
+                .as_ref()
+                .and_then(|entity| entity.entity_id),
+            chimitheque_person_id,
+        )?;
+    }
+
+    let starts_at = parse_reservation_datetime(&reserve.starts_at, false)?;
+    let ends_at = parse_reservation_datetime(&reserve.ends_at, true)?;
+    if starts_at > ends_at {
+        return Err(AppError::InputValidation(String::from(
+            "the reservation ends before it starts",
+        )));
+    }
+    if ends_at < now() {
+        return Err(AppError::InputValidation(String::from(
+            "the reservation is in the past",
+        )));
+    }
+
+    // The overlap check and the insert are done in one immediate transaction
+    // so that two concurrent requests can not reserve the same window.
+    let tx = match db_connection
+        .deref_mut()
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+    {
+        Ok(tx) => tx,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    // A storage can not be reserved twice at the same time.
+    if let Some(storage_reservation) =
+        get_overlapping_storage_reservations(&tx, id, &starts_at, &ends_at)?.first()
+    {
+        return Err(AppError::Reservation(format!(
+            "storage {} is already reserved from {} to {}",
+            id, storage_reservation.starts_at, storage_reservation.ends_at
+        )));
+    }
+
+    let mut storage_reservation = StorageReservation {
+        storage_id: id,
+        person_id,
+        purpose: reserve.purpose.trim().to_string(),
+        starts_at,
+        ends_at,
+        created_by: chimitheque_person_id,
+        created_at: now(),
+        ..Default::default()
+    };
+    storage_reservation.storage_reservation_id =
+        insert_storage_reservation(&tx, &storage_reservation)?;
+
+    if let Err(err) = tx.commit() {
+        return Err(AppError::Database(err.to_string()));
+    }
+
+    Ok(Json(storage_reservation))
+}
+
+// The current and upcoming reservations of a storage.
+pub async fn get_storage_reservations(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<Json<Vec<StorageReservation>>, AppError> {
+    info!("get_storage_reservations: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    check_reservation_access(&state, chimitheque_person_id, id).await?;
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    // Check that the storage exists.
+    get_storage(db_connection.deref(), id, chimitheque_person_id)?;
+
+    Ok(Json(reservation::get_storage_reservations(
+        db_connection.deref(),
+        id,
+        &now(),
+    )?))
+}
+
+pub async fn get_storage_reservation(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<Json<ReservedStorage>, AppError> {
+    info!("get_storage_reservation: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    let storage_reservation = get_reservation(&state, id, chimitheque_person_id).await?;
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    match reserved_storages(
+        db_connection.deref(),
+        vec![storage_reservation],
+        chimitheque_person_id,
+    )?
+    .pop()
+    {
+        Some(reserved_storage) => Ok(Json(reserved_storage)),
+        None => Err(AppError::NotFound(format!("reservation {}", id))),
+    }
+}
+
+// Cancel a reservation.
+// Allowed to the reserver, to the person who recorded it and to the people
+// who can update the storage.
+pub async fn delete_storage_reservation(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<(), AppError> {
+    info!("delete_storage_reservation: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    let storage_reservation = get_reservation(&state, id, chimitheque_person_id).await?;
+
+    if storage_reservation.person_id != chimitheque_person_id
+        && storage_reservation.created_by != chimitheque_person_id
+        && !enforce(
+            &state,
+            chimitheque_person_id,
+            "u",
+            "storages",
+            storage_reservation.storage_id,
+        )
+        .await?
+    {
+        return Err(AppError::PermissionDenied);
+    }
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    // Check that the storage exists.
+    get_storage(
+        db_connection.deref(),
+        storage_reservation.storage_id,
+        chimitheque_person_id,
+    )?;
+
+    reservation::delete_storage_reservation(db_connection.deref(), id)
+}
+
+// The current and upcoming reservations of the storages of an entity.
+pub async fn get_entity_reservations(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<Json<Vec<ReservedStorage>>, AppError> {
+    info!("get_entity_reservations: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    if !enforce_entity_storages(&state, chimitheque_person_id, "r", id).await? {
+        return Err(AppError::PermissionDenied);
+    }
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    Ok(Json(filtered_reserved_storages(
+        db_connection.deref(),
+        RequestFilter {
+            entity: Some(id),
+            ..Default::default()
+        },
+        chimitheque_person_id,
+    )?))
+}
+
+// The current and upcoming reservations of the storages of a store location.
+pub async fn get_store_location_reservations(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<Json<Vec<ReservedStorage>>, AppError> {
+    info!("get_store_location_reservations: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    if !enforce_store_location_storages(&state, chimitheque_person_id, "r", id).await? {
+        return Err(AppError::PermissionDenied);
+    }
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    // Check that the store location exists.
+    get_store_location(db_connection.deref(), id, chimitheque_person_id)?;
+
+    Ok(Json(filtered_reserved_storages(
+        db_connection.deref(),
+        RequestFilter {
+            store_location: Some(id),
+            ..Default::default()
+        },
+        chimitheque_person_id,
+    )?))
+}
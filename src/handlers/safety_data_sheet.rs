@@ -0,0 +1,419 @@
+use axum::{
+    Json,
+    body::Body,
+    extract::{Multipart, Path, State},
+    http::{
+        HeaderMap,
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+    },
+    response::{IntoResponse, Response},
+};
+use chimitheque_types::requestfilter::RequestFilter;
+use chrono::{Local, NaiveDate};
+use rusqlite::TransactionBehavior;
+use sha2::{Digest, Sha256};
+use std::{
+    ops::{Deref, DerefMut},
+    path::{Path as FilePath, PathBuf},
+};
+use tracing::info;
+
+use crate::{
+    appstate::AppState,
+    errors::AppError,
+    safety_data_sheet::{
+        SAFETY_DATA_SHEET_DATE_FORMAT, SAFETY_DATA_SHEET_DATETIME_FORMAT, SafetyDataSheet,
+        count_safety_data_sheets_with_hash, get_latest_revision_dates, get_safety_data_sheet,
+        insert_safety_data_sheet,
+    },
+    utils::{enforce, get_chimitheque_person_id_from_headers},
+};
+
+// Leading bytes of a PDF file.
+const PDF_MAGIC: &[u8] = b"%PDF-";
+
+// Safety data sheets are stored in the SDS directory by content hash,
+// so that the same file attached to several products is stored once.
+fn safety_data_sheet_path(state: &AppState, hash: &str) -> PathBuf {
+    state.sds_dir.join(format!("{}.pdf", hash))
+}
+
+// Write the file of a safety data sheet if it is not stored yet.
+// It is written to a temporary file then renamed, so that a reader never
+// sees a partially written file.
+// Called in the transaction inserting the safety data sheet, so that the file
+// can not be removed by a concurrent deletion between the check and the insert.
+fn store_safety_data_sheet_file(
+    sds_dir: &FilePath,
+    path: &FilePath,
+    content: &[u8],
+) -> Result<(), AppError> {
+    if path.exists() {
+        return Ok(());
+    }
+
+    let tmp_path = path.with_extension(format!("{}.tmp", uuid::Uuid::new_v4()));
+    if let Err(err) = std::fs::create_dir_all(sds_dir) {
+        return Err(AppError::SafetyDataSheetStorage(err.to_string()));
+    }
+    if let Err(err) = std::fs::write(&tmp_path, content) {
+        return Err(AppError::SafetyDataSheetStorage(err.to_string()));
+    }
+    if let Err(err) = std::fs::rename(&tmp_path, path) {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(AppError::SafetyDataSheetStorage(err.to_string()));
+    }
+
+    Ok(())
+}
+
+// Return the IDs of the given products whose most recent safety data sheet
+// revision is older than the configured maximum age.
+pub(crate) fn get_outdated_safety_data_sheet_product_ids(
+    state: &AppState,
+    db_connection: &rusqlite::Connection,
+    product_ids: &[u64],
+) -> Result<Vec<u64>, AppError> {
+    let latest_revision_dates = get_latest_revision_dates(db_connection, product_ids)?;
+
+    // The revision dates are formatted with SAFETY_DATA_SHEET_DATE_FORMAT
+    // and compare as strings.
+    let oldest_allowed_revision_date = (Local::now().date_naive()
+        - chrono::Duration::days(state.sds_max_age_days))
+    .format(SAFETY_DATA_SHEET_DATE_FORMAT)
+    .to_string();
+
+    let mut outdated_product_ids: Vec<u64> = latest_revision_dates
+        .into_iter()
+        .filter(|(_, revision_date)| *revision_date < oldest_allowed_revision_date)
+        .map(|(product_id, _)| product_id)
+        .collect();
+    outdated_product_ids.sort_unstable();
+
+    Ok(outdated_product_ids)
+}
+
+pub async fn get_safety_data_sheets(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<Json<Vec<SafetyDataSheet>>, AppError> {
+    info!("get_safety_data_sheets: {}", id);
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    Ok(Json(crate::safety_data_sheet::get_safety_data_sheets(
+        db_connection.deref(),
+        id,
+    )?))
+}
+
+// Upload a safety data sheet for a product as a multipart form with the fields:
+// - file: the PDF file
+// - revision_date: the SDS revision date (YYYY-MM-DD)
+// - supplier: the supplier label (optional)
+// - language: the SDS language code, such as "en" or "fr"
+pub async fn create_safety_data_sheet(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+    mut multipart: Multipart,
+) -> Result<Json<u64>, AppError> {
+    info!("create_safety_data_sheet: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Check that the product exists before reading the file.
+    {
+        // Get the connection from the database.
+        let db_connection_pool = state.db_connection_pool.clone();
+        let db_connection = db_connection_pool.get().unwrap();
+
+        match chimitheque_db::product::get_products(
+            db_connection.deref(),
+            RequestFilter {
+                id: Some(id),
+                ..Default::default()
+            },
+            chimitheque_person_id,
+        ) {
+            Ok((products, _)) if products.is_empty() => {
+                return Err(AppError::NotFound(format!("product {}", id)));
+            }
+            Ok(_) => (),
+            Err(err) => return Err(AppError::Database(err.to_string())),
+        }
+    }
+
+    let mut file: Option<(String, Vec<u8>)> = None;
+    let mut revision_date: Option<NaiveDate> = None;
+    let mut supplier: Option<String> = None;
+    let mut language: Option<String> = None;
+
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(err) => return Err(AppError::InputValidation(err.to_string())),
+        };
+
+        let field_name = field.name().unwrap_or_default().to_string();
+        match field_name.as_str() {
+            "file" => {
+                let filename = field.file_name().unwrap_or("sds.pdf").to_string();
+                match field.bytes().await {
+                    Ok(bytes) => file = Some((filename, bytes.to_vec())),
+                    Err(err) => return Err(AppError::InputValidation(err.to_string())),
+                }
+            }
+            "revision_date" | "supplier" | "language" => {
+                let value = match field.text().await {
+                    Ok(value) => value.trim().to_string(),
+                    Err(err) => return Err(AppError::InputValidation(err.to_string())),
+                };
+
+                match field_name.as_str() {
+                    "revision_date" => {
+                        match NaiveDate::parse_from_str(&value, SAFETY_DATA_SHEET_DATE_FORMAT) {
+                            Ok(date) => revision_date = Some(date),
+                            Err(err) => {
+                                return Err(AppError::InputValidation(format!(
+                                    "invalid revision date {}: {}",
+                                    value, err
+                                )));
+                            }
+                        }
+                    }
+                    "supplier" if !value.is_empty() => supplier = Some(value),
+                    "language" => language = Some(value.to_lowercase()),
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // Validate the form.
+    let Some((filename, content)) = file else {
+        return Err(AppError::InputValidation(String::from("missing file")));
+    };
+    if !content.starts_with(PDF_MAGIC) {
+        return Err(AppError::InputValidation(String::from(
+            "safety data sheet is not a PDF file",
+        )));
+    }
+    let Some(revision_date) = revision_date else {
+        return Err(AppError::InputValidation(String::from(
+            "missing revision date",
+        )));
+    };
+    let language = match language {
+        Some(language)
+            if language.len() == 2 && language.chars().all(|c| c.is_ascii_lowercase()) =>
+        {
+            language
+        }
+        _ => {
+            return Err(AppError::InputValidation(String::from(
+                "missing or invalid language",
+            )));
+        }
+    };
+
+    // Store the file by content hash, once.
+    let hash = format!("{:x}", Sha256::digest(&content));
+    let path = safety_data_sheet_path(&state, &hash);
+
+    let safety_data_sheet = SafetyDataSheet {
+        product_id: id,
+        safety_data_sheet_filename: filename,
+        safety_data_sheet_hash: hash,
+        safety_data_sheet_revision_date: revision_date
+            .format(SAFETY_DATA_SHEET_DATE_FORMAT)
+            .to_string(),
+        safety_data_sheet_supplier: supplier,
+        safety_data_sheet_language: language,
+        safety_data_sheet_created_at: Local::now()
+            .format(SAFETY_DATA_SHEET_DATETIME_FORMAT)
+            .to_string(),
+        ..Default::default()
+    };
+
+    // The file is stored and the safety data sheet inserted in one transaction,
+    // with blocking calls.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let sds_dir = state.sds_dir.clone();
+    let mayerr_safety_data_sheet_id = tokio::task::spawn_blocking(move || {
+        // Get the connection from the database.
+        let mut db_connection = db_connection_pool.get().unwrap();
+
+        let tx = match db_connection
+            .deref_mut()
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+        {
+            Ok(tx) => tx,
+            Err(err) => return Err(AppError::Database(err.to_string())),
+        };
+
+        store_safety_data_sheet_file(&sds_dir, &path, &content)?;
+        let safety_data_sheet_id = insert_safety_data_sheet(&tx, &safety_data_sheet)?;
+
+        match tx.commit() {
+            Ok(_) => Ok(safety_data_sheet_id),
+            Err(err) => Err(AppError::Database(err.to_string())),
+        }
+    })
+    .await;
+
+    match mayerr_safety_data_sheet_id {
+        Ok(Ok(safety_data_sheet_id)) => Ok(Json(safety_data_sheet_id)),
+        Ok(Err(err)) => Err(err),
+        Err(err) => Err(AppError::SafetyDataSheetStorage(err.to_string())),
+    }
+}
+
+// Return the safety data sheet if the person can perform the action
+// on its product: r to download it, u to delete it.
+// The connection is released before checking the permission.
+async fn get_permitted_safety_data_sheet(
+    state: &AppState,
+    safety_data_sheet_id: u64,
+    chimitheque_person_id: u64,
+    action: &str,
+) -> Result<SafetyDataSheet, AppError> {
+    let maybe_safety_data_sheet = {
+        // Get the connection from the database.
+        let db_connection_pool = state.db_connection_pool.clone();
+        let db_connection = db_connection_pool.get().unwrap();
+
+        get_safety_data_sheet(db_connection.deref(), safety_data_sheet_id)?
+    };
+
+    let Some(safety_data_sheet) = maybe_safety_data_sheet else {
+        return Err(AppError::NotFound(format!(
+            "safety data sheet {}",
+            safety_data_sheet_id
+        )));
+    };
+
+    if !enforce(
+        state,
+        chimitheque_person_id,
+        action,
+        "products",
+        safety_data_sheet.product_id,
+    )
+    .await?
+    {
+        return Err(AppError::PermissionDenied);
+    }
+
+    Ok(safety_data_sheet)
+}
+
+pub async fn download_safety_data_sheet(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<Response, AppError> {
+    info!("download_safety_data_sheet: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    let safety_data_sheet =
+        get_permitted_safety_data_sheet(&state, id, chimitheque_person_id, "r").await?;
+
+    let content = match tokio::fs::read(safety_data_sheet_path(
+        &state,
+        &safety_data_sheet.safety_data_sheet_hash,
+    ))
+    .await
+    {
+        Ok(content) => content,
+        Err(err) => return Err(AppError::SafetyDataSheetStorage(err.to_string())),
+    };
+
+    Ok((
+        [
+            (CONTENT_TYPE, String::from("application/pdf")),
+            (
+                CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"{}\"",
+                    safety_data_sheet
+                        .safety_data_sheet_filename
+                        .replace('"', "")
+                ),
+            ),
+        ],
+        Body::from(content),
+    )
+        .into_response())
+}
+
+pub async fn delete_safety_data_sheet(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<(), AppError> {
+    info!("delete_safety_data_sheet: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    let safety_data_sheet =
+        get_permitted_safety_data_sheet(&state, id, chimitheque_person_id, "u").await?;
+
+    // The safety data sheet is deleted, then its file is removed if no other
+    // safety data sheet references it once committed. The file is removed in
+    // a transaction, so that a concurrent upload of the same file either
+    // references it before, or writes it again after.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let path = safety_data_sheet_path(&state, &safety_data_sheet.safety_data_sheet_hash);
+    let mayerr_deleted = tokio::task::spawn_blocking(move || {
+        // Get the connection from the database.
+        let mut db_connection = db_connection_pool.get().unwrap();
+
+        crate::safety_data_sheet::delete_safety_data_sheet(db_connection.deref(), id)?;
+
+        let tx = match db_connection
+            .deref_mut()
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+        {
+            Ok(tx) => tx,
+            Err(err) => return Err(AppError::Database(err.to_string())),
+        };
+
+        if count_safety_data_sheets_with_hash(&tx, &safety_data_sheet.safety_data_sheet_hash)? == 0
+        {
+            match std::fs::remove_file(&path) {
+                Ok(_) => (),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+                Err(err) => return Err(AppError::SafetyDataSheetStorage(err.to_string())),
+            }
+        }
+
+        match tx.commit() {
+            Ok(_) => Ok(()),
+            Err(err) => Err(AppError::Database(err.to_string())),
+        }
+    })
+    .await;
+
+    match mayerr_deleted {
+        Ok(result) => result,
+        Err(err) => Err(AppError::SafetyDataSheetStorage(err.to_string())),
+    }
+}
@@ -1,10 +1,11 @@
-use axum::{Json, extract::State};
+use axum::{Json, extract::State, http::HeaderMap};
 use chimitheque_db::searchable::get_many;
 use chimitheque_traits::searchable::Searchable;
 use chimitheque_types::{
     casnumber::CasNumber, category::Category, cenumber::CeNumber, classofcompound::ClassOfCompound,
-    empiricalformula::EmpiricalFormula, linearformula::LinearFormula, name::Name,
-    physicalstate::PhysicalState, producer::Producer, producerref::ProducerRef,
+    empiricalformula::EmpiricalFormula, hazardstatement::HazardStatement,
+    linearformula::LinearFormula, name::Name, physicalstate::PhysicalState,
+    precautionarystatement::PrecautionaryStatement, producer::Producer, producerref::ProducerRef,
     requestfilter::RequestFilter, signalword::SignalWord, supplier::Supplier,
     supplierref::SupplierRef, symbol::Symbol, tag::Tag, unit::Unit,
 };
@@ -12,7 +13,12 @@ use chimitheque_utils::string::Transform;
 use serde::{Deserialize, Serialize};
 use std::ops::Deref;
 
-use crate::{appstate::AppState, errors::AppError};
+use crate::{
+    appstate::AppState,
+    errors::AppError,
+    i18n::{StatementTranslations, StatementType, request_locale},
+    utils::get_chimitheque_person_id_from_headers,
+};
 
 pub async fn get_cas_numbers(
     State(state): State<AppState>,
@@ -496,10 +502,40 @@ pub async fn get_signal_words_old(
     }
 }
 
+// Replace the statement labels by the ones of the request language.
+fn localize_hazard_statements(
+    db_connection: &rusqlite::Connection,
+    headers: &HeaderMap,
+    chimitheque_person_id: u64,
+    hazard_statements: &mut [HazardStatement],
+) -> Result<(), AppError> {
+    let locale = request_locale(db_connection, headers, chimitheque_person_id)?;
+    let statement_translations = StatementTranslations::load(db_connection)?;
+
+    for hazard_statement in hazard_statements.iter_mut() {
+        if let Some(label) = statement_translations.label(
+            StatementType::Hazard,
+            &hazard_statement.hazard_statement_reference,
+            locale,
+        ) {
+            hazard_statement.hazard_statement_label = label.to_string();
+        }
+    }
+
+    Ok(())
+}
+
 pub async fn get_hazard_statements(
     State(state): State<AppState>,
+    headers: HeaderMap,
     request_filter: RequestFilter,
-) -> Result<Json<(Vec<impl Searchable + Serialize>, usize)>, AppError> {
+) -> Result<Json<(Vec<HazardStatement>, usize)>, AppError> {
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
     // Get the connection from the database.
     let db_connection_pool = state.db_connection_pool.clone();
     let db_connection = db_connection_pool.get().unwrap();
@@ -508,15 +544,30 @@ pub async fn get_hazard_statements(
         db_connection.deref(),
         request_filter,
     ) {
-        Ok((hazard_statements, count)) => Ok(Json((hazard_statements, count))),
+        Ok((mut hazard_statements, count)) => {
+            localize_hazard_statements(
+                db_connection.deref(),
+                &headers,
+                chimitheque_person_id,
+                &mut hazard_statements,
+            )?;
+            Ok(Json((hazard_statements, count)))
+        }
         Err(err) => Err(AppError::Database(err.to_string())),
     }
 }
 
 pub async fn get_hazard_statements_old(
     State(state): State<AppState>,
+    headers: HeaderMap,
     request_filter: RequestFilter,
 ) -> Result<Json<Box<dyn erased_serde::Serialize>>, AppError> {
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
     // Get the connection from the database.
     let db_connection_pool = state.db_connection_pool.clone();
     let db_connection = db_connection_pool.get().unwrap();
@@ -525,18 +576,56 @@ pub async fn get_hazard_statements_old(
         db_connection.deref(),
         request_filter,
     ) {
-        Ok((hazard_statements, count)) => Ok(Json(Box::new(GetSearchableOldResponse {
-            rows: hazard_statements,
-            total: count,
-        }))),
+        Ok((mut hazard_statements, count)) => {
+            localize_hazard_statements(
+                db_connection.deref(),
+                &headers,
+                chimitheque_person_id,
+                &mut hazard_statements,
+            )?;
+            Ok(Json(Box::new(GetSearchableOldResponse {
+                rows: hazard_statements,
+                total: count,
+            })))
+        }
         Err(err) => Err(AppError::Database(err.to_string())),
     }
 }
 
+// Replace the statement labels by the ones of the request language.
+fn localize_precautionary_statements(
+    db_connection: &rusqlite::Connection,
+    headers: &HeaderMap,
+    chimitheque_person_id: u64,
+    precautionary_statements: &mut [PrecautionaryStatement],
+) -> Result<(), AppError> {
+    let locale = request_locale(db_connection, headers, chimitheque_person_id)?;
+    let statement_translations = StatementTranslations::load(db_connection)?;
+
+    for precautionary_statement in precautionary_statements.iter_mut() {
+        if let Some(label) = statement_translations.label(
+            StatementType::Precautionary,
+            &precautionary_statement.precautionary_statement_reference,
+            locale,
+        ) {
+            precautionary_statement.precautionary_statement_label = label.to_string();
+        }
+    }
+
+    Ok(())
+}
+
 pub async fn get_precautionary_statements(
     State(state): State<AppState>,
+    headers: HeaderMap,
     request_filter: RequestFilter,
-) -> Result<Json<(Vec<impl Searchable + Serialize>, usize)>, AppError> {
+) -> Result<Json<(Vec<PrecautionaryStatement>, usize)>, AppError> {
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
     // Get the connection from the database.
     let db_connection_pool = state.db_connection_pool.clone();
     let db_connection = db_connection_pool.get().unwrap();
@@ -545,15 +634,30 @@ pub async fn get_precautionary_statements(
         db_connection.deref(),
         request_filter,
     ) {
-        Ok((precautionary_statements, count)) => Ok(Json((precautionary_statements, count))),
+        Ok((mut precautionary_statements, count)) => {
+            localize_precautionary_statements(
+                db_connection.deref(),
+                &headers,
+                chimitheque_person_id,
+                &mut precautionary_statements,
+            )?;
+            Ok(Json((precautionary_statements, count)))
+        }
         Err(err) => Err(AppError::Database(err.to_string())),
     }
 }
 
 pub async fn get_precautionary_statements_old(
     State(state): State<AppState>,
+    headers: HeaderMap,
     request_filter: RequestFilter,
 ) -> Result<Json<Box<dyn erased_serde::Serialize>>, AppError> {
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
     // Get the connection from the database.
     let db_connection_pool = state.db_connection_pool.clone();
     let db_connection = db_connection_pool.get().unwrap();
@@ -562,10 +666,18 @@ pub async fn get_precautionary_statements_old(
         db_connection.deref(),
         request_filter,
     ) {
-        Ok((precautionary_statements, count)) => Ok(Json(Box::new(GetSearchableOldResponse {
-            rows: precautionary_statements,
-            total: count,
-        }))),
+        Ok((mut precautionary_statements, count)) => {
+            localize_precautionary_statements(
+                db_connection.deref(),
+                &headers,
+                chimitheque_person_id,
+                &mut precautionary_statements,
+            )?;
+            Ok(Json(Box::new(GetSearchableOldResponse {
+                rows: precautionary_statements,
+                total: count,
+            })))
+        }
         Err(err) => Err(AppError::Database(err.to_string())),
     }
 }
@@ -2,14 +2,57 @@ use axum::{
     Json,
     extract::{Path, State},
     http::HeaderMap,
+    response::Response,
 };
 use axum_extra::extract::Query;
 use chimitheque_types::{requestfilter::RequestFilter, storage::Storage};
+use rusqlite::Connection;
 use serde::{Deserialize, Serialize};
 use std::ops::{Deref, DerefMut};
 use tracing::info;
 
-use crate::{AppState, errors::AppError, utils::get_chimitheque_person_id_from_headers};
+use crate::{
+    AppState,
+    capacity::storage_quantity_increased,
+    errors::AppError,
+    export::{ExportQueryParameters, ExportTable, export_response},
+    handlers::{
+        capacity::{StorageWarning, capacity_exceeded, check_storage_capacity, storage_warnings},
+        incompatibility::check_storage_incompatibilities,
+        reservation::check_not_reserved_now,
+    },
+    i18n::{StatementTranslations, request_locale},
+    location_history::record_storage_move,
+    utils::{
+        enforce, enforce_store_location_storages, get_chimitheque_person_id_from_headers,
+        warnings_headers,
+    },
+    waste::{
+        ArchiveReason, StorageArchive, delete_storage_archive, get_storage_archive,
+        set_storage_archive,
+    },
+};
+
+pub(crate) fn get_storage(
+    db_connection: &Connection,
+    storage_id: u64,
+    chimitheque_person_id: u64,
+) -> Result<Storage, AppError> {
+    match chimitheque_db::storage::get_storages(
+        db_connection,
+        RequestFilter {
+            id: Some(storage_id),
+            ..Default::default()
+        },
+        chimitheque_person_id,
+    ) {
+        Ok((storages, _)) => match storages.first() {
+            Some(storage) => Ok(storage.to_owned()),
+            None => Err(AppError::NotFound(format!("storage {}", storage_id))),
+        },
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
 
 pub async fn get_storages(
     State(state): State<AppState>,
@@ -70,6 +113,8 @@ pub async fn get_storages_old(
     );
 
     // hack: request_filter.limit == Some(1) -> QRCode scanner
+    // Deprecated: kept until the scanners have migrated to scan_storage
+    // (GET /storages/scan), which has a stable response shape.
     if request_filter.id.is_none() || request_filter.limit == Some(1) {
         match mayerr_storages {
             Ok(storages) => Ok(Json(Box::new(GetStoragesOldResponse {
@@ -98,6 +143,129 @@ fn default_nb_items() -> u64 {
     1
 }
 
+// A storage to create with a new product, see create_product_with_storages.
+#[derive(Deserialize, Debug)]
+pub struct NewStorage {
+    storage: Storage,
+    #[serde(default = "default_nb_items")]
+    nb_items: u64,
+    #[serde(default)]
+    identical_barecode: bool,
+}
+
+// Validate the storages to create with a new product, before the product is created.
+pub(crate) async fn validate_new_storages(
+    state: &AppState,
+    chimitheque_person_id: u64,
+    new_storages: &mut [NewStorage],
+) -> Result<(), AppError> {
+    if new_storages.is_empty() {
+        return Err(AppError::InputValidation(String::from("no storages")));
+    }
+    if !enforce(state, chimitheque_person_id, "c", "storages", 0).await? {
+        return Err(AppError::PermissionDenied);
+    }
+
+    for new_storage in new_storages.iter_mut() {
+        if let Err(err) = new_storage.storage.sanitize_and_validate() {
+            return Err(AppError::InputValidation(err.to_string()));
+        };
+        new_storage.storage.storage_id = None;
+        if new_storage.nb_items == 0 {
+            new_storage.nb_items = 1;
+        }
+
+        // The person must be able to write storages in the store location entity.
+        let Some(store_location_id) = new_storage.storage.store_location.store_location_id else {
+            return Err(AppError::InputValidation(String::from(
+                "missing store location id",
+            )));
+        };
+        if !enforce_store_location_storages(state, chimitheque_person_id, "u", store_location_id)
+            .await?
+        {
+            return Err(AppError::PermissionDenied);
+        }
+    }
+
+    Ok(())
+}
+
+// Create the validated storages of a newly created product.
+// chimitheque_db commits each storage on its own: the ids of the storages
+// created are added to storage_ids as they are committed, for the caller
+// to delete them on failure.
+// Returns the incompatibilities and capacity warnings.
+pub(crate) fn create_new_storages(
+    state: &AppState,
+    db_connection: &mut Connection,
+    product_id: u64,
+    new_storages: Vec<NewStorage>,
+    storage_ids: &mut Vec<u64>,
+    chimitheque_person_id: u64,
+) -> Result<Vec<StorageWarning>, AppError> {
+    let mut warnings: Vec<StorageWarning> = Vec::new();
+    let mut new_storages = new_storages;
+    for new_storage in new_storages.iter_mut() {
+        new_storage.storage.product.product_id = Some(product_id);
+    }
+
+    for (index, new_storage) in new_storages.iter().enumerate() {
+        // Check the chemical incompatibilities in the store location.
+        let (storage_incompatibilities, strict) = check_storage_incompatibilities(
+            state,
+            db_connection,
+            &new_storage.storage,
+            chimitheque_person_id,
+        )?;
+        if strict && !storage_incompatibilities.is_empty() {
+            return Err(AppError::IncompatibleStorage(
+                storage_incompatibilities
+                    .iter()
+                    .map(|incompatibility| incompatibility.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", "),
+            ));
+        }
+
+        // Check the capacity limits of the store location and its parents,
+        // the previous storages of the request counted.
+        let pending_storages: Vec<(&Storage, u64)> = new_storages[..index]
+            .iter()
+            .map(|pending_storage| (&pending_storage.storage, pending_storage.nb_items))
+            .collect();
+        let (capacity_excesses, strict) = check_storage_capacity(
+            db_connection,
+            &new_storage.storage,
+            new_storage.nb_items,
+            &pending_storages,
+            chimitheque_person_id,
+        )?;
+        if strict {
+            return Err(capacity_exceeded(&capacity_excesses));
+        }
+
+        warnings.extend(storage_warnings(
+            storage_incompatibilities,
+            capacity_excesses,
+        ));
+    }
+
+    for new_storage in new_storages.into_iter() {
+        match chimitheque_db::storage::create_update_storage(
+            db_connection,
+            new_storage.storage,
+            new_storage.nb_items,
+            new_storage.identical_barecode,
+        ) {
+            Ok(ids) => storage_ids.extend(ids),
+            Err(err) => return Err(AppError::Database(err.to_string())),
+        }
+    }
+
+    Ok(warnings)
+}
+
 #[derive(Deserialize)]
 pub struct CreateUpdateStoragePathParameters {
     #[serde(default)]
@@ -106,15 +274,18 @@ pub struct CreateUpdateStoragePathParameters {
 
 pub async fn create_update_storage(
     State(state): State<AppState>,
+    headers: HeaderMap,
     Query(mut query_params): Query<CreateUpdateStorageQueryParameters>,
     Path(path_params): Path<CreateUpdateStoragePathParameters>,
     Json(storage): Json<Storage>,
-) -> Result<Json<Vec<u64>>, AppError> {
+) -> Result<(HeaderMap, Json<Vec<u64>>), AppError> {
     info!("create_update_storage: {}", storage);
 
-    // Get the connection from the database.
-    let db_connection_pool = state.db_connection_pool.clone();
-    let mut db_connection = db_connection_pool.get().unwrap();
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
 
     // Sanitize and validate the storage.
     let mut storage = storage.clone();
@@ -123,15 +294,127 @@ pub async fn create_update_storage(
     };
 
     // update?
+    // The previous storage is kept to record a move.
+    // The connection is released before checking the permissions.
+    let mut previous_storage = None;
     if path_params.id > 0 {
         storage.storage_id = Some(path_params.id);
+
+        // Get the connection from the database.
+        let db_connection_pool = state.db_connection_pool.clone();
+        let db_connection = db_connection_pool.get().unwrap();
+
+        previous_storage = Some(get_storage(
+            db_connection.deref(),
+            path_params.id,
+            chimitheque_person_id,
+        )?);
+    }
+    let previous_store_location = previous_storage
+        .as_ref()
+        .map(|previous_storage| previous_storage.store_location.clone());
+
+    // As for the move endpoint, the person must be able to write storages
+    // in the entity of the store location the storage is moved to.
+    match (
+        &previous_store_location,
+        storage.store_location.store_location_id,
+    ) {
+        (Some(previous_store_location), Some(store_location_id))
+            if previous_store_location.store_location_id != Some(store_location_id) =>
+        {
+            if !enforce_store_location_storages(
+                &state,
+                chimitheque_person_id,
+                "u",
+                store_location_id,
+            )
+            .await?
+            {
+                return Err(AppError::PermissionDenied);
+            }
+        }
+        _ => (),
     }
 
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let mut db_connection = db_connection_pool.get().unwrap();
+
     // Ensure nb_items not zero.
     if query_params.nb_items == 0 {
         query_params.nb_items = 1;
     }
 
+    // A reserved storage can only be moved by its reserver.
+    match &previous_store_location {
+        Some(previous_store_location)
+            if previous_store_location.store_location_id
+                != storage.store_location.store_location_id =>
+        {
+            check_not_reserved_now(db_connection.deref(), path_params.id, chimitheque_person_id)?
+        }
+        _ => (),
+    }
+
+    // A created or moved storage is checked against its store location,
+    // an update in place is not rejected by the store location settings.
+    let checked = match &previous_store_location {
+        Some(previous_store_location) => {
+            previous_store_location.store_location_id != storage.store_location.store_location_id
+        }
+        None => true,
+    };
+
+    // Check the chemical incompatibilities in the store location.
+    let (incompatibilities, strict) = if checked {
+        check_storage_incompatibilities(
+            &state,
+            db_connection.deref(),
+            &storage,
+            chimitheque_person_id,
+        )?
+    } else {
+        (vec![], false)
+    };
+    if strict && !incompatibilities.is_empty() {
+        return Err(AppError::IncompatibleStorage(
+            incompatibilities
+                .iter()
+                .map(|incompatibility| incompatibility.to_string())
+                .collect::<Vec<String>>()
+                .join(", "),
+        ));
+    }
+
+    // Check the capacity limits of the store location and its parents.
+    // An update changes a single storage.
+    let nb_items = if path_params.id > 0 {
+        1
+    } else {
+        query_params.nb_items
+    };
+    // An update in place is only checked when its quantity increases.
+    let capacity_checked = checked
+        || previous_storage
+            .as_ref()
+            .is_some_and(|previous_storage| storage_quantity_increased(previous_storage, &storage));
+    let (capacity_excesses, strict) = if capacity_checked {
+        check_storage_capacity(
+            db_connection.deref(),
+            &storage,
+            nb_items,
+            &[],
+            chimitheque_person_id,
+        )?
+    } else {
+        (vec![], false)
+    };
+    if strict {
+        return Err(capacity_exceeded(&capacity_excesses));
+    }
+
+    let store_location = storage.store_location.clone();
     let mayerr_storage_id = chimitheque_db::storage::create_update_storage(
         db_connection.deref_mut(),
         storage,
@@ -140,7 +423,29 @@ pub async fn create_update_storage(
     );
 
     match mayerr_storage_id {
-        Ok(storage_id) => Ok(Json(storage_id)),
+        Ok(storage_id) => {
+            match previous_store_location {
+                Some(previous_store_location)
+                    if previous_store_location.store_location_id
+                        != store_location.store_location_id =>
+                {
+                    record_storage_move(
+                        db_connection.deref(),
+                        path_params.id,
+                        &previous_store_location,
+                        &store_location,
+                        chimitheque_person_id,
+                    )?;
+                }
+                _ => (),
+            }
+            // The body is unchanged for the existing clients,
+            // the warnings are returned in headers.
+            Ok((
+                warnings_headers(&storage_warnings(incompatibilities, capacity_excesses)),
+                Json(storage_id),
+            ))
+        }
         Err(err) => Err(AppError::Database(err.to_string())),
     }
 }
@@ -164,8 +469,9 @@ pub async fn delete_storage(
 pub async fn export_storages(
     State(state): State<AppState>,
     headers: HeaderMap,
+    Query(export_params): Query<ExportQueryParameters>,
     request_filter: RequestFilter,
-) -> Result<String, AppError> {
+) -> Result<Response, AppError> {
     info!("export_storages");
 
     // Get the chimitheque_person_id.
@@ -174,36 +480,101 @@ pub async fn export_storages(
         Err(err) => return Err(err),
     };
 
-    // Get the connection from the database.
-    let db_connection_pool = state.db_connection_pool.clone();
-    let db_connection = db_connection_pool.get().unwrap();
+    // The statements are exported in the request language.
+    let (locale, statement_translations) = {
+        let db_connection_pool = state.db_connection_pool.clone();
+        let db_connection = db_connection_pool.get().unwrap();
 
-    let mayerr_storages = chimitheque_db::storage::export_storages(
-        db_connection.deref(),
+        (
+            request_locale(db_connection.deref(), &headers, chimitheque_person_id)?,
+            StatementTranslations::load(db_connection.deref())?,
+        )
+    };
+
+    // The export is read page by page.
+    export_response(
+        state.db_connection_pool.clone(),
         request_filter,
-        chimitheque_person_id,
-    );
+        "storages",
+        export_params,
+        move |db_connection, request_filter| {
+            chimitheque_db::storage::export_storages(
+                db_connection,
+                request_filter,
+                chimitheque_person_id,
+            )
+            .map_err(|err| AppError::Database(err.to_string()))
+            .and_then(|csv| ExportTable::from_db_csv(&csv))
+            .and_then(|mut table| {
+                // The statements are localized from their references.
+                statement_translations.localize_export(&mut table, locale);
+
+                Ok(table)
+            })
+        },
+    )
+    .await
+}
 
-    match mayerr_storages {
-        Ok(storages) => Ok(storages),
-        Err(err) => Err(AppError::Database(err.to_string())),
+// A storage in a waste batch is handed to the waste contractor,
+// its archive can not change anymore.
+pub(crate) fn check_not_in_waste_batch(
+    db_connection: &Connection,
+    storage_id: u64,
+) -> Result<(), AppError> {
+    match get_storage_archive(db_connection, storage_id)?
+        .and_then(|storage_archive| storage_archive.waste_batch_id)
+    {
+        Some(waste_batch_id) => Err(AppError::Waste(format!(
+            "storage {} is in the waste batch {}",
+            storage_id, waste_batch_id
+        ))),
+        None => Ok(()),
     }
 }
 
+#[derive(Deserialize, Debug)]
+pub struct ArchiveStorageQueryParameters {
+    // Disposed storages can be added to a waste batch.
+    // Unspecified for the clients archiving without a reason.
+    #[serde(default)]
+    reason: ArchiveReason,
+    comment: Option<String>,
+}
+
 pub async fn archive_storage(
     State(state): State<AppState>,
+    headers: HeaderMap,
     Path(id): Path<u64>,
+    Query(query_params): Query<ArchiveStorageQueryParameters>,
 ) -> Result<(), AppError> {
-    info!("archive_storage: {}", id);
+    info!("archive_storage: {} {:?}", id, query_params);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
 
     // Get the connection from the database.
     let db_connection_pool = state.db_connection_pool.clone();
     let mut db_connection = db_connection_pool.get().unwrap();
 
-    match chimitheque_db::storage::archive_storage(db_connection.deref_mut(), id) {
-        Ok(_) => Ok(()),
-        Err(err) => Err(AppError::Database(err.to_string())),
+    check_not_in_waste_batch(db_connection.deref(), id)?;
+
+    if let Err(err) = chimitheque_db::storage::archive_storage(db_connection.deref_mut(), id) {
+        return Err(AppError::Database(err.to_string()));
     }
+
+    set_storage_archive(
+        db_connection.deref(),
+        &StorageArchive::new(
+            id,
+            query_params.reason,
+            query_params.comment,
+            chimitheque_person_id,
+        ),
+    )
 }
 
 pub async fn unarchive_storage(
@@ -216,8 +587,11 @@ pub async fn unarchive_storage(
     let db_connection_pool = state.db_connection_pool.clone();
     let mut db_connection = db_connection_pool.get().unwrap();
 
-    match chimitheque_db::storage::unarchive_storage(db_connection.deref_mut(), id) {
-        Ok(_) => Ok(()),
-        Err(err) => Err(AppError::Database(err.to_string())),
+    check_not_in_waste_batch(db_connection.deref(), id)?;
+
+    if let Err(err) = chimitheque_db::storage::unarchive_storage(db_connection.deref_mut(), id) {
+        return Err(AppError::Database(err.to_string()));
     }
+
+    delete_storage_archive(db_connection.deref(), id)
 }
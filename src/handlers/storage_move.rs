@@ -0,0 +1,225 @@
+use axum::{
+    Json,
+    extract::{Path, RawQuery, State},
+    http::HeaderMap,
+};
+use axum_extra::extract::Query;
+use chimitheque_types::requestfilter::RequestFilter;
+use serde::Deserialize;
+use std::ops::{Deref, DerefMut};
+use tracing::info;
+
+use crate::{
+    appstate::AppState,
+    errors::AppError,
+    handlers::{
+        bulk::{
+            BulkItemResult, BulkQueryParameters, BulkUpdateStorages, bulk_request_filter,
+            update_storages,
+        },
+        capacity::{StorageWarning, capacity_exceeded, check_storage_capacity, storage_warnings},
+        incompatibility::{check_storage_incompatibilities, get_store_location},
+        reservation::check_not_reserved_now,
+        storage::get_storage,
+    },
+    location_history::{self, StorageMove, record_storage_move},
+    utils::{
+        WithWarnings, enforce_store_location_storages, get_chimitheque_person_id_from_headers,
+    },
+};
+
+#[derive(Deserialize, Debug)]
+pub struct MoveStorage {
+    store_location_id: u64,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct BulkMoveStorages {
+    // The storages to move, or the storages matching the request filter
+    // when no ids are given.
+    #[serde(default)]
+    ids: Vec<u64>,
+    store_location_id: u64,
+}
+
+// Move a storage to another store location.
+// Returns the recorded move, or null if the storage was already there.
+pub async fn move_storage(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+    Json(move_storage): Json<MoveStorage>,
+) -> Result<Json<WithWarnings<Option<StorageMove>, StorageWarning>>, AppError> {
+    info!("move_storage: {} {:?}", id, move_storage);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // The person must be able to write storages in the target store location entity.
+    if !enforce_store_location_storages(
+        &state,
+        chimitheque_person_id,
+        "u",
+        move_storage.store_location_id,
+    )
+    .await?
+    {
+        return Err(AppError::PermissionDenied);
+    }
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let mut db_connection = db_connection_pool.get().unwrap();
+
+    let mut storage = get_storage(db_connection.deref(), id, chimitheque_person_id)?;
+    let store_location = get_store_location(
+        db_connection.deref(),
+        move_storage.store_location_id,
+        chimitheque_person_id,
+    )?;
+
+    if storage.store_location.store_location_id == store_location.store_location_id {
+        return Ok(Json(WithWarnings::new(None, vec![])));
+    }
+
+    // A reserved storage can only be moved by its reserver.
+    check_not_reserved_now(db_connection.deref(), id, chimitheque_person_id)?;
+
+    let from_store_location = storage.store_location.clone();
+    storage.store_location = store_location.clone();
+
+    // Check the chemical incompatibilities in the target store location.
+    let (incompatibilities, strict) = check_storage_incompatibilities(
+        &state,
+        db_connection.deref(),
+        &storage,
+        chimitheque_person_id,
+    )?;
+    if strict && !incompatibilities.is_empty() {
+        return Err(AppError::IncompatibleStorage(
+            incompatibilities
+                .iter()
+                .map(|incompatibility| incompatibility.to_string())
+                .collect::<Vec<String>>()
+                .join(", "),
+        ));
+    }
+
+    // Check the capacity limits of the target store location and its parents.
+    let (capacity_excesses, strict) = check_storage_capacity(
+        db_connection.deref(),
+        &storage,
+        1,
+        &[],
+        chimitheque_person_id,
+    )?;
+    if strict {
+        return Err(capacity_exceeded(&capacity_excesses));
+    }
+
+    if let Err(err) =
+        chimitheque_db::storage::create_update_storage(db_connection.deref_mut(), storage, 1, false)
+    {
+        return Err(AppError::Database(err.to_string()));
+    }
+
+    let storage_move = record_storage_move(
+        db_connection.deref(),
+        id,
+        &from_store_location,
+        &store_location,
+        chimitheque_person_id,
+    )?;
+
+    Ok(Json(WithWarnings::new(
+        Some(storage_move),
+        storage_warnings(incompatibilities, capacity_excesses),
+    )))
+}
+
+// Move several storages to another store location, see bulk_update_storages.
+pub async fn bulk_move_storages(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query_params): Query<BulkQueryParameters>,
+    RawQuery(raw_query): RawQuery,
+    request_filter: RequestFilter,
+    Json(bulk_move): Json<BulkMoveStorages>,
+) -> Result<Json<Vec<BulkItemResult>>, AppError> {
+    info!(
+        "bulk_move_storages: {:?} dry_run={}",
+        bulk_move, query_params.dry_run
+    );
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    Ok(Json(
+        update_storages(
+            &state,
+            chimitheque_person_id,
+            query_params.dry_run,
+            bulk_request_filter(raw_query.as_deref(), request_filter),
+            BulkUpdateStorages::store_location_move(bulk_move.ids, bulk_move.store_location_id),
+        )
+        .await?,
+    ))
+}
+
+pub async fn get_storage_moves(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<Json<Vec<StorageMove>>, AppError> {
+    info!("get_storage_moves: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    // Check that the storage exists.
+    get_storage(db_connection.deref(), id, chimitheque_person_id)?;
+
+    Ok(Json(location_history::get_storage_moves(
+        db_connection.deref(),
+        id,
+    )?))
+}
+
+pub async fn get_store_location_moves(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<Json<Vec<StorageMove>>, AppError> {
+    info!("get_store_location_moves: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    // Check that the store location exists.
+    get_store_location(db_connection.deref(), id, chimitheque_person_id)?;
+
+    Ok(Json(location_history::get_store_location_moves(
+        db_connection.deref(),
+        id,
+    )?))
+}
@@ -3,9 +3,16 @@ use axum::{
     extract::{Path, State},
     http::HeaderMap,
 };
-use chimitheque_types::{requestfilter::RequestFilter, storelocation::StoreLocation};
+use axum_extra::extract::Query;
+use chimitheque_types::{
+    entity::Entity, requestfilter::RequestFilter, storelocation::StoreLocation,
+};
+use rusqlite::Connection;
 use serde::{Deserialize, Serialize};
-use std::ops::{Deref, DerefMut};
+use std::{
+    collections::{HashMap, HashSet},
+    ops::{Deref, DerefMut},
+};
 use tracing::info;
 
 use crate::{AppState, errors::AppError, utils::get_chimitheque_person_id_from_headers};
@@ -140,3 +147,318 @@ pub async fn delete_store_location(
         Err(err) => Err(AppError::Database(err.to_string())),
     }
 }
+
+#[derive(Deserialize, Debug, Default)]
+pub struct StoreLocationTreeQueryParameters {
+    entity: Option<u64>,
+    // Keep the store locations that can (or can not) store, and their parents.
+    can_store: Option<bool>,
+}
+
+// A store location with its sub-locations.
+// The total counts include the ones of all the sub-locations.
+#[derive(Serialize, Debug, Clone)]
+pub struct StoreLocationNode {
+    store_location: StoreLocation,
+    nb_direct_storages: usize,
+    nb_total_storages: usize,
+    nb_direct_children: usize,
+    nb_total_children: usize,
+    children: Vec<StoreLocationNode>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct EntityStoreLocationTree {
+    entity: Entity,
+    store_locations: Vec<StoreLocationNode>,
+}
+
+fn build_store_location_node(
+    store_location: StoreLocation,
+    children_by_parent: &HashMap<u64, Vec<StoreLocation>>,
+    nb_storages_by_store_location: &HashMap<u64, usize>,
+) -> StoreLocationNode {
+    let store_location_id = store_location.store_location_id.unwrap_or_default();
+
+    let children: Vec<StoreLocationNode> = children_by_parent
+        .get(&store_location_id)
+        .map(|children| {
+            children
+                .iter()
+                .map(|child| {
+                    build_store_location_node(
+                        child.clone(),
+                        children_by_parent,
+                        nb_storages_by_store_location,
+                    )
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let nb_direct_storages = nb_storages_by_store_location
+        .get(&store_location_id)
+        .copied()
+        .unwrap_or(0);
+
+    let mut node = StoreLocationNode {
+        store_location,
+        nb_direct_storages,
+        nb_total_storages: 0,
+        nb_direct_children: 0,
+        nb_total_children: 0,
+        children,
+    };
+    set_total_counts(&mut node);
+
+    node
+}
+
+// Compute the counts of a node from the ones of its children.
+fn set_total_counts(node: &mut StoreLocationNode) {
+    node.nb_direct_children = node.children.len();
+    node.nb_total_children = node.children.len()
+        + node
+            .children
+            .iter()
+            .map(|child| child.nb_total_children)
+            .sum::<usize>();
+    node.nb_total_storages = node.nb_direct_storages
+        + node
+            .children
+            .iter()
+            .map(|child| child.nb_total_storages)
+            .sum::<usize>();
+}
+
+// Keep the nodes matching can_store, and the ones with matching sub-locations.
+// The counts of the kept nodes only include the kept sub-locations.
+fn prune_store_location_nodes(
+    nodes: Vec<StoreLocationNode>,
+    can_store: bool,
+) -> Vec<StoreLocationNode> {
+    nodes
+        .into_iter()
+        .filter_map(|mut node| {
+            node.children = prune_store_location_nodes(node.children, can_store);
+            set_total_counts(&mut node);
+
+            if node.store_location.store_location_can_store == can_store
+                || !node.children.is_empty()
+            {
+                Some(node)
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+// The number of current storages of each store location,
+// the archived storages and the storage history excluded.
+fn count_storages_by_store_location(
+    db_connection: &Connection,
+) -> Result<HashMap<u64, usize>, AppError> {
+    let mut stmt = match db_connection.prepare(
+        "SELECT store_location, COUNT(*) FROM storage
+        WHERE storage_archive = 0 AND storage IS NULL
+        GROUP BY store_location",
+    ) {
+        Ok(stmt) => stmt,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    match stmt.query_map([], |row| {
+        Ok((row.get::<_, u64>(0)?, row.get::<_, usize>(1)?))
+    }) {
+        Ok(rows) => match rows.collect::<Result<HashMap<u64, usize>, rusqlite::Error>>() {
+            Ok(nb_storages_by_store_location) => Ok(nb_storages_by_store_location),
+            Err(err) => Err(AppError::Database(err.to_string())),
+        },
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// The store locations of the entities the connected user can see, as a tree per entity.
+pub async fn get_store_location_tree(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query_params): Query<StoreLocationTreeQueryParameters>,
+) -> Result<Json<Vec<EntityStoreLocationTree>>, AppError> {
+    info!("get_store_location_tree: {:?}", query_params);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    let entities = match chimitheque_db::entity::get_entities(
+        db_connection.deref(),
+        RequestFilter {
+            id: query_params.entity,
+            ..Default::default()
+        },
+        chimitheque_person_id,
+    ) {
+        Ok((entities, _)) => entities,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    let store_locations = match chimitheque_db::storelocation::get_store_locations(
+        db_connection.deref(),
+        RequestFilter {
+            entity: query_params.entity,
+            ..Default::default()
+        },
+        chimitheque_person_id,
+    ) {
+        Ok((store_locations, _)) => store_locations,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    let nb_storages_by_store_location = count_storages_by_store_location(db_connection.deref())?;
+
+    // A store location whose parent is not visible is a root.
+    let store_location_ids: HashSet<u64> = store_locations
+        .iter()
+        .filter_map(|store_location| store_location.store_location_id)
+        .collect();
+    let mut roots_by_entity: HashMap<u64, Vec<StoreLocation>> = HashMap::new();
+    let mut children_by_parent: HashMap<u64, Vec<StoreLocation>> = HashMap::new();
+    for store_location in store_locations {
+        match store_location
+            .store_location
+            .as_ref()
+            .and_then(|parent| parent.store_location_id)
+        {
+            Some(parent_id) if store_location_ids.contains(&parent_id) => children_by_parent
+                .entry(parent_id)
+                .or_default()
+                .push(store_location),
+            _ => {
+                let entity_id = store_location
+                    .entity
+                    .as_ref()
+                    .and_then(|entity| entity.entity_id)
+                    .unwrap_or_default();
+                roots_by_entity
+                    .entry(entity_id)
+                    .or_default()
+                    .push(store_location)
+            }
+        }
+    }
+
+    let mut trees: Vec<EntityStoreLocationTree> = Vec::new();
+    for entity in entities {
+        let roots = roots_by_entity
+            .remove(&entity.entity_id.unwrap_or_default())
+            .unwrap_or_default();
+
+        let mut nodes: Vec<StoreLocationNode> = roots
+            .into_iter()
+            .map(|root| {
+                build_store_location_node(root, &children_by_parent, &nb_storages_by_store_location)
+            })
+            .collect();
+        if let Some(can_store) = query_params.can_store {
+            nodes = prune_store_location_nodes(nodes, can_store);
+        }
+
+        trees.push(EntityStoreLocationTree {
+            entity,
+            store_locations: nodes,
+        });
+    }
+
+    Ok(Json(trees))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store_location(store_location_id: u64, can_store: bool) -> StoreLocation {
+        StoreLocation {
+            store_location_id: Some(store_location_id),
+            store_location_can_store: can_store,
+            ..Default::default()
+        }
+    }
+
+    // 1 (0 storage)
+    // ├── 2, can store (3 storages)
+    // └── 3 (5 storages)
+    //     └── 4 (1 storage)
+    fn tree() -> StoreLocationNode {
+        let children_by_parent = HashMap::from([
+            (1, vec![store_location(2, true), store_location(3, false)]),
+            (3, vec![store_location(4, false)]),
+        ]);
+        let nb_storages_by_store_location = HashMap::from([(2, 3), (3, 5), (4, 1)]);
+
+        build_store_location_node(
+            store_location(1, false),
+            &children_by_parent,
+            &nb_storages_by_store_location,
+        )
+    }
+
+    fn ids(nodes: &[StoreLocationNode]) -> Vec<u64> {
+        nodes
+            .iter()
+            .filter_map(|node| node.store_location.store_location_id)
+            .collect()
+    }
+
+    #[test]
+    fn build_counts_the_sub_locations() {
+        let root = tree();
+
+        assert_eq!(root.nb_direct_storages, 0);
+        assert_eq!(root.nb_total_storages, 9);
+        assert_eq!(root.nb_direct_children, 2);
+        assert_eq!(root.nb_total_children, 3);
+        assert_eq!(root.children[1].nb_total_storages, 6);
+        assert_eq!(root.children[1].nb_total_children, 1);
+    }
+
+    #[test]
+    fn prune_keeps_the_parents_of_matching_locations() {
+        let nodes = prune_store_location_nodes(vec![tree()], true);
+
+        assert_eq!(ids(&nodes), vec![1]);
+        assert_eq!(ids(&nodes[0].children), vec![2]);
+    }
+
+    #[test]
+    fn prune_recomputes_the_counts() {
+        let nodes = prune_store_location_nodes(vec![tree()], true);
+        assert_eq!(nodes[0].nb_direct_children, 1);
+        assert_eq!(nodes[0].nb_total_children, 1);
+        assert_eq!(nodes[0].nb_total_storages, 3);
+
+        let nodes = prune_store_location_nodes(vec![tree()], false);
+        assert_eq!(ids(&nodes[0].children), vec![3]);
+        assert_eq!(ids(&nodes[0].children[0].children), vec![4]);
+        assert_eq!(nodes[0].nb_direct_children, 1);
+        assert_eq!(nodes[0].nb_total_children, 2);
+        assert_eq!(nodes[0].nb_total_storages, 6);
+    }
+
+    #[test]
+    fn prune_removes_trees_without_matching_locations() {
+        let root = build_store_location_node(
+            store_location(1, false),
+            &HashMap::from([(1, vec![store_location(2, false)])]),
+            &HashMap::new(),
+        );
+
+        assert!(prune_store_location_nodes(vec![root], true).is_empty());
+    }
+}
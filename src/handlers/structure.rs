@@ -0,0 +1,207 @@
+use axum::{
+    Json,
+    body::Body,
+    extract::{Path, State},
+    http::{HeaderMap, header::CONTENT_TYPE},
+    response::{IntoResponse, Response},
+};
+use chimitheque_types::{product::Product, requestfilter::RequestFilter};
+use chrono::Local;
+use std::{ops::Deref, path::PathBuf};
+use tracing::info;
+
+use crate::{
+    appstate::AppState,
+    errors::AppError,
+    search,
+    structure::{
+        STRUCTURE_DATETIME_FORMAT, StructureIdentifiers, fetch_compound_record,
+        get_structure_identifiers, pubchem_get, set_structure_identifiers,
+    },
+    utils::get_chimitheque_person_id_from_headers,
+};
+
+// Size of the cached 2D structure images.
+const STRUCTURE_IMAGE_SIZE: &str = "300x300";
+
+fn get_product(
+    state: &AppState,
+    product_id: u64,
+    chimitheque_person_id: u64,
+) -> Result<Product, AppError> {
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    match chimitheque_db::product::get_products(
+        db_connection.deref(),
+        RequestFilter {
+            id: Some(product_id),
+            ..Default::default()
+        },
+        chimitheque_person_id,
+    ) {
+        Ok((products, _)) => match products.first() {
+            Some(product) => Ok(product.to_owned()),
+            None => Err(AppError::NotFound(format!("product {}", product_id))),
+        },
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+fn structure_image_path(state: &AppState, pubchem_cid: u64) -> PathBuf {
+    state
+        .structure_cache_dir
+        .join(format!("{}.png", pubchem_cid))
+}
+
+pub async fn get_product_structure_identifiers(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<Json<StructureIdentifiers>, AppError> {
+    info!("get_product_structure_identifiers: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Check the product is visible to the person.
+    get_product(&state, id, chimitheque_person_id)?;
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    let structure_identifiers = get_structure_identifiers(db_connection.deref(), id)?;
+
+    Ok(Json(structure_identifiers.unwrap_or_default()))
+}
+
+// Fetch the product structure identifiers from PubChem by product name
+// and store them in the product structure table.
+pub async fn update_product_structure_identifiers(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<Json<StructureIdentifiers>, AppError> {
+    info!("update_product_structure_identifiers: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    let product = get_product(&state, id, chimitheque_person_id)?;
+
+    // Fetched from the configured PubChem server.
+    let record = fetch_compound_record(
+        &state.pubchem_http_client,
+        &state.rate_limiter,
+        &state.pubchem_base_url,
+        &product.name.name_label,
+    )
+    .await?;
+
+    let structure_identifiers = StructureIdentifiers::from_compound_record(&record);
+
+    if structure_identifiers.is_empty() {
+        return Err(AppError::NotFound(format!(
+            "pubchem compound {}",
+            product.name.name_label
+        )));
+    }
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    set_structure_identifiers(
+        db_connection.deref(),
+        id,
+        &structure_identifiers,
+        &Local::now().format(STRUCTURE_DATETIME_FORMAT).to_string(),
+    )?;
+    search::reindex_product(db_connection.deref(), id, chimitheque_person_id)?;
+
+    Ok(Json(structure_identifiers))
+}
+
+// Serve the 2D structure image of a product.
+// The image is fetched from PubChem once, then served from the structure cache directory.
+pub async fn get_product_structure_image(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<Response, AppError> {
+    info!("get_product_structure_image: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Check the product is visible to the person.
+    get_product(&state, id, chimitheque_person_id)?;
+
+    let structure_identifiers = {
+        // Get the connection from the database.
+        let db_connection_pool = state.db_connection_pool.clone();
+        let db_connection = db_connection_pool.get().unwrap();
+
+        get_structure_identifiers(db_connection.deref(), id)?
+    };
+
+    let Some(pubchem_cid) = structure_identifiers
+        .and_then(|structure_identifiers| structure_identifiers.product_pubchem_cid)
+    else {
+        return Err(AppError::NotFound(format!(
+            "pubchem cid for product {}",
+            id
+        )));
+    };
+
+    let path = structure_image_path(&state, pubchem_cid);
+
+    let image = match tokio::fs::read(&path).await {
+        Ok(image) => image,
+        Err(_) => {
+            let response = pubchem_get(
+                &state.pubchem_http_client,
+                &state.rate_limiter,
+                &state.pubchem_base_url,
+                &format!(
+                    "/rest/pug/compound/cid/{}/PNG?record_type=2d&image_size={}",
+                    pubchem_cid, STRUCTURE_IMAGE_SIZE
+                ),
+            )
+            .await?;
+
+            let image = match response.bytes().await {
+                Ok(image) => image.to_vec(),
+                Err(err) => return Err(AppError::Pubchem(err.to_string())),
+            };
+
+            // Write to a temporary file then rename, so that concurrent
+            // requests never read a partially written image.
+            let tmp_path = path.with_extension(format!("{}.tmp", uuid::Uuid::new_v4()));
+            if let Err(err) = tokio::fs::create_dir_all(&state.structure_cache_dir).await {
+                return Err(AppError::StructureCache(err.to_string()));
+            }
+            if let Err(err) = tokio::fs::write(&tmp_path, &image).await {
+                return Err(AppError::StructureCache(err.to_string()));
+            }
+            if let Err(err) = tokio::fs::rename(&tmp_path, &path).await {
+                return Err(AppError::StructureCache(err.to_string()));
+            }
+
+            image
+        }
+    };
+
+    Ok(([(CONTENT_TYPE, "image/png")], Body::from(image)).into_response())
+}
@@ -0,0 +1,334 @@
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::HeaderMap,
+};
+use axum_extra::extract::Query;
+use chimitheque_types::{name::Name, product::Product, requestfilter::RequestFilter};
+use rusqlite::{Connection, TransactionBehavior};
+use serde::Deserialize;
+use std::ops::{Deref, DerefMut};
+use tracing::info;
+
+use crate::{
+    appstate::AppState,
+    errors::AppError,
+    search, synonym,
+    utils::{enforce, get_chimitheque_person_id_from_headers},
+};
+
+// The /synonyms/{name_id} routes take the product as a query parameter,
+// the authorization middleware handling a single path parameter.
+#[derive(Deserialize)]
+pub struct SynonymQueryParameters {
+    product_id: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AddSynonym {
+    name_label: String,
+}
+
+fn get_product(
+    db_connection: &Connection,
+    product_id: u64,
+    chimitheque_person_id: u64,
+) -> Result<Product, AppError> {
+    match chimitheque_db::product::get_products(
+        db_connection,
+        RequestFilter {
+            id: Some(product_id),
+            ..Default::default()
+        },
+        chimitheque_person_id,
+    ) {
+        Ok((products, _)) => match products.first() {
+            Some(product) => Ok(product.to_owned()),
+            None => Err(AppError::NotFound(format!("product {}", product_id))),
+        },
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// Update the names of a product and save them, with the names created
+// and the names no longer referenced deleted in the same transaction.
+fn update_product_names<F>(
+    db_connection: &mut Connection,
+    product_id: u64,
+    chimitheque_person_id: u64,
+    update: F,
+) -> Result<Json<Vec<Name>>, AppError>
+where
+    F: FnOnce(&mut Product) -> Result<(), AppError>,
+{
+    let tx = match db_connection.transaction_with_behavior(TransactionBehavior::Immediate) {
+        Ok(tx) => tx,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    let mut product = get_product(&tx, product_id, chimitheque_person_id)?;
+    update(&mut product)?;
+
+    // The new names have no ID yet.
+    let name_id = match product.name.name_id {
+        Some(name_id) => name_id,
+        None => synonym::get_or_create_name(&tx, &product.name.name_label)?,
+    };
+    let mut synonyms = product.synonyms.take().unwrap_or_default();
+    for synonym in synonyms.iter_mut() {
+        if synonym.name_id.is_none() {
+            synonym.name_id = Some(synonym::get_or_create_name(&tx, &synonym.name_label)?);
+        }
+    }
+    let synonym_name_ids: Vec<u64> = synonyms
+        .iter()
+        .filter_map(|synonym| synonym.name_id)
+        .collect();
+
+    synonym::set_product_names(&tx, product_id, name_id, &synonym_name_ids)?;
+    synonym::delete_orphan_names(&tx)?;
+    search::reindex_product(&tx, product_id, chimitheque_person_id)?;
+
+    if let Err(err) = tx.commit() {
+        return Err(AppError::Database(err.to_string()));
+    }
+
+    Ok(Json(synonyms))
+}
+
+// Check that the person can update the product, as the authorization
+// middleware does not for the /synonyms routes.
+async fn check_product_update(
+    state: &AppState,
+    chimitheque_person_id: u64,
+    product_id: u64,
+) -> Result<(), AppError> {
+    if enforce(state, chimitheque_person_id, "u", "products", product_id).await? {
+        Ok(())
+    } else {
+        Err(AppError::PermissionDenied)
+    }
+}
+
+fn find_synonym(product: &Product, name_id: u64) -> Result<usize, AppError> {
+    match product
+        .synonyms
+        .iter()
+        .flatten()
+        .position(|synonym| synonym.name_id == Some(name_id))
+    {
+        Some(position) => Ok(position),
+        None => Err(AppError::NotFound(format!(
+            "synonym {} of product {}",
+            name_id,
+            product.product_id.unwrap_or_default()
+        ))),
+    }
+}
+
+pub async fn get_synonyms(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<Json<Vec<Name>>, AppError> {
+    info!("get_synonyms: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    let product = get_product(db_connection.deref(), id, chimitheque_person_id)?;
+
+    Ok(Json(product.synonyms.unwrap_or_default()))
+}
+
+// Add a synonym to a product, reusing the existing name with the same label.
+pub async fn add_synonym(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+    Json(add_synonym): Json<AddSynonym>,
+) -> Result<Json<Vec<Name>>, AppError> {
+    info!("add_synonym: {} {}", id, add_synonym.name_label);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Validate the name.
+    let name_label = add_synonym.name_label.trim().to_uppercase();
+    if name_label.is_empty() {
+        return Err(AppError::InputValidation(String::from("empty name")));
+    }
+
+    check_product_update(&state, chimitheque_person_id, id).await?;
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let mut db_connection = db_connection_pool.get().unwrap();
+
+    update_product_names(
+        db_connection.deref_mut(),
+        id,
+        chimitheque_person_id,
+        |product| {
+            // Checked before creating the name, not to leave an unused one.
+            if product.name.name_label.trim().to_uppercase() == name_label {
+                return Err(AppError::InputValidation(format!(
+                    "{} is the product name",
+                    name_label
+                )));
+            }
+
+            let synonyms = product.synonyms.get_or_insert_with(Vec::new);
+            if !synonyms
+                .iter()
+                .any(|synonym| synonym.name_label.trim().to_uppercase() == name_label)
+            {
+                // The name is created or reused when the product is saved.
+                synonyms.push(Name {
+                    name_id: None,
+                    name_label,
+                    ..Default::default()
+                });
+            }
+
+            Ok(())
+        },
+    )
+}
+
+pub async fn delete_synonym(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(name_id): Path<u64>,
+    Query(query_params): Query<SynonymQueryParameters>,
+) -> Result<Json<Vec<Name>>, AppError> {
+    info!("delete_synonym: {} {}", query_params.product_id, name_id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    check_product_update(&state, chimitheque_person_id, query_params.product_id).await?;
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let mut db_connection = db_connection_pool.get().unwrap();
+
+    update_product_names(
+        db_connection.deref_mut(),
+        query_params.product_id,
+        chimitheque_person_id,
+        |product| {
+            let position = find_synonym(product, name_id)?;
+            if let Some(synonyms) = product.synonyms.as_mut() {
+                synonyms.remove(position);
+            }
+
+            Ok(())
+        },
+    )
+}
+
+// Reorder the synonyms of a product, given all its synonym name IDs in the new order.
+pub async fn reorder_synonyms(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+    Json(name_ids): Json<Vec<u64>>,
+) -> Result<Json<Vec<Name>>, AppError> {
+    info!("reorder_synonyms: {} {:?}", id, name_ids);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let mut db_connection = db_connection_pool.get().unwrap();
+
+    update_product_names(
+        db_connection.deref_mut(),
+        id,
+        chimitheque_person_id,
+        |product| {
+            let synonyms = product.synonyms.take().unwrap_or_default();
+            let mut sorted_name_ids: Vec<u64> = name_ids.clone();
+            sorted_name_ids.sort_unstable();
+            let mut synonym_name_ids: Vec<u64> = synonyms
+                .iter()
+                .filter_map(|synonym| synonym.name_id)
+                .collect();
+            synonym_name_ids.sort_unstable();
+            if sorted_name_ids != synonym_name_ids {
+                return Err(AppError::InputValidation(String::from(
+                    "the name IDs must be the product synonym IDs",
+                )));
+            }
+
+            product.synonyms = Some(
+                name_ids
+                    .iter()
+                    .filter_map(|name_id| {
+                        synonyms
+                            .iter()
+                            .find(|synonym| synonym.name_id == Some(*name_id))
+                            .cloned()
+                    })
+                    .collect(),
+            );
+
+            Ok(())
+        },
+    )
+}
+
+// Promote a synonym to the product main name, the former main name
+// taking its place in the synonyms.
+pub async fn promote_synonym(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(name_id): Path<u64>,
+    Query(query_params): Query<SynonymQueryParameters>,
+) -> Result<Json<Vec<Name>>, AppError> {
+    info!("promote_synonym: {} {}", query_params.product_id, name_id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    check_product_update(&state, chimitheque_person_id, query_params.product_id).await?;
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let mut db_connection = db_connection_pool.get().unwrap();
+
+    update_product_names(
+        db_connection.deref_mut(),
+        query_params.product_id,
+        chimitheque_person_id,
+        |product| {
+            let position = find_synonym(product, name_id)?;
+            if let Some(synonyms) = product.synonyms.as_mut() {
+                std::mem::swap(&mut synonyms[position], &mut product.name);
+            }
+
+            Ok(())
+        },
+    )
+}
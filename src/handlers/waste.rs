@@ -0,0 +1,481 @@
+use axum::{
+    Json,
+    body::Body,
+    extract::{Path, State},
+    http::{
+        HeaderMap,
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+    },
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::Query;
+use chimitheque_types::{requestfilter::RequestFilter, storage::Storage};
+use chrono::Local;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    ops::{Deref, DerefMut},
+};
+use tracing::info;
+
+use crate::{
+    appstate::AppState,
+    errors::AppError,
+    export::{ExportFormat, content_disposition},
+    ghs_label::svg_to_pdf,
+    utils::{enforce_entity_storages, get_chimitheque_person_id_from_headers},
+    waste::{
+        self, HazardClassTotal, WASTE_DATETIME_FORMAT, WasteBatch, WasteItem,
+        get_pending_disposed_storage_ids, get_waste_batch_storage_ids, hazard_class_totals,
+        insert_waste_batch, render_waste_manifest_svg, set_waste_batch_picked_up,
+        waste_manifest_table,
+    },
+};
+
+#[derive(Deserialize, Debug)]
+pub struct PendingWasteQueryParameters {
+    entity: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CreateWasteBatch {
+    entity_id: u64,
+    #[serde(default)]
+    name: String,
+    // The disposed storages of the entity to pick up,
+    // all the ones waiting for a pickup if empty.
+    #[serde(default)]
+    storage_ids: Vec<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ManifestFormat {
+    #[default]
+    Pdf,
+    Csv,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ManifestQueryParameters {
+    #[serde(default)]
+    format: ManifestFormat,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct WasteBatchDetail {
+    batch: WasteBatch,
+    items: Vec<WasteItem>,
+    totals: Vec<HazardClassTotal>,
+}
+
+pub(crate) fn waste_item_from_storage(storage: &Storage) -> WasteItem {
+    let product = &storage.product;
+
+    WasteItem {
+        storage_id: storage.storage_id.unwrap_or_default(),
+        storage_barecode: storage.storage_barecode.clone(),
+        product_name: product.name.name_label.clone(),
+        cas_number: product
+            .cas_number
+            .as_ref()
+            .map(|cas_number| cas_number.cas_number_label.clone()),
+        hazard_classes: product
+            .symbols
+            .iter()
+            .flatten()
+            .map(|symbol| symbol.symbol_label.clone())
+            .collect(),
+        hazard_statements: product
+            .hazard_statements
+            .iter()
+            .flatten()
+            .map(|hazard_statement| hazard_statement.hazard_statement_reference.clone())
+            .collect(),
+        quantity: storage.storage_quantity,
+        unit_label: storage
+            .unit_quantity
+            .as_ref()
+            .map(|unit| unit.unit_label.clone()),
+    }
+}
+
+// The person must be able to write storages in the entity.
+async fn check_entity_access(
+    state: &AppState,
+    chimitheque_person_id: u64,
+    entity_id: u64,
+) -> Result<(), AppError> {
+    if enforce_entity_storages(state, chimitheque_person_id, "u", entity_id).await? {
+        Ok(())
+    } else {
+        Err(AppError::PermissionDenied)
+    }
+}
+
+// The archived storages of an entity waiting for a waste pickup.
+fn get_pending_waste_storages(
+    db_connection: &Connection,
+    entity_id: u64,
+    chimitheque_person_id: u64,
+) -> Result<Vec<Storage>, AppError> {
+    let pending_ids: HashSet<u64> = get_pending_disposed_storage_ids(db_connection)?
+        .into_iter()
+        .collect();
+    if pending_ids.is_empty() {
+        return Ok(vec![]);
+    }
+
+    match chimitheque_db::storage::get_storages(
+        db_connection,
+        RequestFilter {
+            entity: Some(entity_id),
+            storage_archive: true,
+            ..Default::default()
+        },
+        chimitheque_person_id,
+    ) {
+        Ok((storages, _)) => Ok(storages
+            .into_iter()
+            .filter(|storage| {
+                storage
+                    .storage_id
+                    .is_some_and(|storage_id| pending_ids.contains(&storage_id))
+            })
+            .collect()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+async fn get_batch(
+    state: &AppState,
+    waste_batch_id: u64,
+    chimitheque_person_id: u64,
+) -> Result<WasteBatch, AppError> {
+    let maybe_waste_batch = {
+        // Get the connection from the database.
+        // It is released before the permission check, the casbin
+        // matchers taking their own connections.
+        let db_connection_pool = state.db_connection_pool.clone();
+        let db_connection = db_connection_pool.get().unwrap();
+
+        waste::get_waste_batch(db_connection.deref(), waste_batch_id)?
+    };
+    let Some(waste_batch) = maybe_waste_batch else {
+        return Err(AppError::NotFound(format!(
+            "waste batch {}",
+            waste_batch_id
+        )));
+    };
+    check_entity_access(state, chimitheque_person_id, waste_batch.entity_id).await?;
+
+    Ok(waste_batch)
+}
+
+fn get_batch_detail(
+    db_connection: &Connection,
+    waste_batch: WasteBatch,
+    chimitheque_person_id: u64,
+) -> Result<WasteBatchDetail, AppError> {
+    let mut items: Vec<WasteItem> = Vec::new();
+    for storage_id in get_waste_batch_storage_ids(db_connection, waste_batch.waste_batch_id)? {
+        match chimitheque_db::storage::get_storages(
+            db_connection,
+            RequestFilter {
+                id: Some(storage_id),
+                storage_archive: true,
+                ..Default::default()
+            },
+            chimitheque_person_id,
+        ) {
+            Ok((storages, _)) => items.extend(storages.iter().map(waste_item_from_storage)),
+            Err(err) => return Err(AppError::Database(err.to_string())),
+        }
+    }
+
+    Ok(WasteBatchDetail {
+        batch: waste_batch,
+        totals: hazard_class_totals(&items),
+        items,
+    })
+}
+
+// The disposed storages of an entity not in a waste batch yet.
+pub async fn get_pending_waste(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query_params): Query<PendingWasteQueryParameters>,
+) -> Result<Json<Vec<WasteItem>>, AppError> {
+    info!("get_pending_waste: {:?}", query_params);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    check_entity_access(&state, chimitheque_person_id, query_params.entity).await?;
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    Ok(Json(
+        get_pending_waste_storages(
+            db_connection.deref(),
+            query_params.entity,
+            chimitheque_person_id,
+        )?
+        .iter()
+        .map(waste_item_from_storage)
+        .collect(),
+    ))
+}
+
+pub async fn create_waste_batch(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(create_waste_batch): Json<CreateWasteBatch>,
+) -> Result<Json<WasteBatchDetail>, AppError> {
+    info!("create_waste_batch: {:?}", create_waste_batch);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    check_entity_access(&state, chimitheque_person_id, create_waste_batch.entity_id).await?;
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let mut db_connection = db_connection_pool.get().unwrap();
+
+    let pending_ids: Vec<u64> = get_pending_waste_storages(
+        db_connection.deref(),
+        create_waste_batch.entity_id,
+        chimitheque_person_id,
+    )?
+    .iter()
+    .filter_map(|storage| storage.storage_id)
+    .collect();
+
+    let storage_ids = if create_waste_batch.storage_ids.is_empty() {
+        pending_ids
+    } else {
+        for storage_id in create_waste_batch.storage_ids.iter() {
+            if !pending_ids.contains(storage_id) {
+                return Err(AppError::InputValidation(format!(
+                    "storage {} is not a disposed storage of the entity waiting for a pickup",
+                    storage_id
+                )));
+            }
+        }
+        create_waste_batch.storage_ids
+    };
+    if storage_ids.is_empty() {
+        return Err(AppError::InputValidation(String::from(
+            "no disposed storage waiting for a pickup",
+        )));
+    }
+
+    let created_at = Local::now().format(WASTE_DATETIME_FORMAT).to_string();
+    let name = match create_waste_batch.name.trim() {
+        "" => format!("waste {}", created_at),
+        name => name.to_string(),
+    };
+    let mut waste_batch = WasteBatch {
+        entity_id: create_waste_batch.entity_id,
+        name,
+        created_by: chimitheque_person_id,
+        created_at,
+        ..Default::default()
+    };
+    waste_batch.waste_batch_id =
+        insert_waste_batch(db_connection.deref_mut(), &waste_batch, &storage_ids)?;
+
+    Ok(Json(get_batch_detail(
+        db_connection.deref(),
+        waste_batch,
+        chimitheque_person_id,
+    )?))
+}
+
+// The waste batches of the entities the connected user can write storages in.
+pub async fn get_waste_batches(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<WasteBatch>>, AppError> {
+    info!("get_waste_batches");
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    let all_waste_batches = {
+        // Get the connection from the database.
+        let db_connection_pool = state.db_connection_pool.clone();
+        let db_connection = db_connection_pool.get().unwrap();
+
+        waste::get_waste_batches(db_connection.deref())?
+    };
+
+    let mut waste_batches: Vec<WasteBatch> = Vec::new();
+    for waste_batch in all_waste_batches.into_iter() {
+        match check_entity_access(&state, chimitheque_person_id, waste_batch.entity_id).await {
+            Ok(_) => waste_batches.push(waste_batch),
+            Err(AppError::PermissionDenied) => (),
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(Json(waste_batches))
+}
+
+pub async fn get_waste_batch(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<Json<WasteBatchDetail>, AppError> {
+    info!("get_waste_batch: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    let waste_batch = get_batch(&state, id, chimitheque_person_id).await?;
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    Ok(Json(get_batch_detail(
+        db_connection.deref(),
+        waste_batch,
+        chimitheque_person_id,
+    )?))
+}
+
+// Record that the waste contractor picked up the batch.
+pub async fn pickup_waste_batch(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+) -> Result<Json<WasteBatch>, AppError> {
+    info!("pickup_waste_batch: {}", id);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    let mut waste_batch = get_batch(&state, id, chimitheque_person_id).await?;
+    if waste_batch.picked_up_at.is_some() {
+        return Err(AppError::Waste(format!(
+            "waste batch {} is already picked up",
+            id
+        )));
+    }
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    let picked_up_at = Local::now().format(WASTE_DATETIME_FORMAT).to_string();
+    set_waste_batch_picked_up(
+        db_connection.deref(),
+        id,
+        &picked_up_at,
+        chimitheque_person_id,
+    )?;
+    waste_batch.picked_up_at = Some(picked_up_at);
+    waste_batch.picked_up_by = Some(chimitheque_person_id);
+
+    Ok(Json(waste_batch))
+}
+
+// Return the printable manifest of a waste batch, for the waste contractor.
+pub async fn get_waste_manifest(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u64>,
+    Query(query_params): Query<ManifestQueryParameters>,
+) -> Result<Response, AppError> {
+    info!("get_waste_manifest: {} {:?}", id, query_params);
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return Err(err),
+    };
+
+    let waste_batch = get_batch(&state, id, chimitheque_person_id).await?;
+
+    // Get the connection from the database.
+    let db_connection_pool = state.db_connection_pool.clone();
+    let db_connection = db_connection_pool.get().unwrap();
+
+    let detail = get_batch_detail(db_connection.deref(), waste_batch, chimitheque_person_id)?;
+    let basename = format!("waste_manifest_{}", id);
+
+    match query_params.format {
+        ManifestFormat::Csv => {
+            let table = waste_manifest_table(&detail.items);
+            let mut body: Vec<u8> = Vec::new();
+            table.write_csv(&mut body, ',', true)?;
+
+            Ok((
+                [
+                    (CONTENT_TYPE, ExportFormat::Csv.content_type().to_string()),
+                    (
+                        CONTENT_DISPOSITION,
+                        content_disposition(&basename, ExportFormat::Csv),
+                    ),
+                ],
+                Body::from(body),
+            )
+                .into_response())
+        }
+        ManifestFormat::Pdf => {
+            let entity_name = match chimitheque_db::entity::get_entities(
+                db_connection.deref(),
+                RequestFilter {
+                    id: Some(detail.batch.entity_id),
+                    ..Default::default()
+                },
+                chimitheque_person_id,
+            ) {
+                Ok((entities, _)) => entities
+                    .first()
+                    .map(|entity| entity.entity_name.clone())
+                    .unwrap_or_else(|| detail.batch.entity_id.to_string()),
+                Err(err) => return Err(AppError::Database(err.to_string())),
+            };
+
+            let svg = render_waste_manifest_svg(
+                &detail.batch,
+                &entity_name,
+                &detail.items,
+                &detail.totals,
+            );
+
+            Ok((
+                [
+                    (CONTENT_TYPE, String::from("application/pdf")),
+                    (
+                        CONTENT_DISPOSITION,
+                        format!("inline; filename=\"{}.pdf\"", basename),
+                    ),
+                ],
+                Body::from(svg_to_pdf(&svg, &state.font_database)?),
+            )
+                .into_response())
+        }
+    }
+}
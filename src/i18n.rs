@@ -0,0 +1,460 @@
+use chimitheque_types::product::Product;
+use http::{HeaderMap, header::ACCEPT_LANGUAGE};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::errors::AppError;
+use crate::export::ExportTable;
+
+// The columns of the chimitheque_db exports holding the statements.
+const EXPORT_STATEMENT_COLUMNS: [(&str, StatementType); 2] = [
+    ("hazard_statements", StatementType::Hazard),
+    ("precautionary_statements", StatementType::Precautionary),
+];
+
+// A statement reference of an export cell, such as "H225", "EUH066",
+// "H300+H310" or "P301+P310", at the start of the cell or after a list separator.
+static EXPORT_STATEMENT_REFERENCE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?:^|[,;|\n])\s*((?:EUH|H)\d{3}[A-Za-z]*(?:\+(?:EUH|H)\d{3}[A-Za-z]*)*|P\d{3}(?:\+P\d{3})*)\b").unwrap()
+});
+
+// The supported languages.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Locale {
+    #[default]
+    En,
+    Fr,
+}
+
+impl Locale {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Locale::En => "en",
+            Locale::Fr => "fr",
+        }
+    }
+
+    // Parse a language code such as "fr" or "fr-FR".
+    pub fn from_code(code: &str) -> Option<Locale> {
+        let language = code.trim().split(['-', '_']).next().unwrap_or_default();
+
+        match language.to_lowercase().as_str() {
+            "en" => Some(Locale::En),
+            "fr" => Some(Locale::Fr),
+            _ => None,
+        }
+    }
+
+    // Return the supported language with the highest weight of an
+    // Accept-Language header value such as "fr-CH, fr;q=0.9, en;q=0.8".
+    pub fn from_accept_language(accept_language: &str) -> Option<Locale> {
+        let mut locales: Vec<(Locale, f32)> = accept_language
+            .split(',')
+            .filter_map(|language_range| {
+                let mut parts = language_range.split(';');
+                let locale = Locale::from_code(parts.next()?)?;
+                let weight = parts
+                    .find_map(|part| part.trim().strip_prefix("q="))
+                    .and_then(|weight| weight.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+
+                Some((locale, weight))
+            })
+            .collect();
+        locales.sort_by(|(_, weight), (_, other_weight)| other_weight.total_cmp(weight));
+
+        locales.first().map(|(locale, _)| *locale)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum StatementType {
+    Hazard,
+    Precautionary,
+}
+
+impl StatementType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            StatementType::Hazard => "hazard",
+            StatementType::Precautionary => "precautionary",
+        }
+    }
+
+    fn from_str(statement_type: &str) -> Option<StatementType> {
+        match statement_type {
+            "hazard" => Some(StatementType::Hazard),
+            "precautionary" => Some(StatementType::Precautionary),
+            _ => None,
+        }
+    }
+}
+
+// The text of a hazard or precautionary statement in one language.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatementTranslation {
+    pub statement_type: StatementType,
+    pub reference: String,
+    pub locale: Locale,
+    pub label: String,
+}
+
+// Create the statement translations and person language tables.
+pub fn init_i18n(db_connection: &Connection) -> Result<(), AppError> {
+    match db_connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS statement_translation (
+            statement_translation_type TEXT NOT NULL,
+            statement_translation_reference TEXT NOT NULL,
+            statement_translation_locale TEXT NOT NULL,
+            statement_translation_label TEXT NOT NULL,
+            PRIMARY KEY (statement_translation_type, statement_translation_reference, statement_translation_locale)
+        );
+        CREATE TABLE IF NOT EXISTS person_locale (
+            person_locale_person_id INTEGER PRIMARY KEY,
+            person_locale_locale TEXT NOT NULL
+        );",
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// Insert or replace the given statement translations, in one transaction.
+pub fn import_statement_translations(
+    db_connection: &mut Connection,
+    translations: &[StatementTranslation],
+) -> Result<usize, AppError> {
+    let tx = match db_connection.transaction() {
+        Ok(tx) => tx,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    for translation in translations.iter() {
+        if let Err(err) = tx.execute(
+            "INSERT OR REPLACE INTO statement_translation (statement_translation_type, statement_translation_reference, statement_translation_locale, statement_translation_label)
+            VALUES (?1, ?2, ?3, ?4)",
+            params![
+                translation.statement_type.as_str(),
+                translation.reference.trim().to_uppercase(),
+                translation.locale.code(),
+                translation.label.trim()
+            ],
+        ) {
+            return Err(AppError::Database(err.to_string()));
+        }
+    }
+
+    match tx.commit() {
+        Ok(_) => Ok(translations.len()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// All the statement translations, by statement type and reference.
+#[derive(Debug, Clone, Default)]
+pub struct StatementTranslations {
+    labels: HashMap<(StatementType, String), HashMap<Locale, String>>,
+}
+
+impl StatementTranslations {
+    pub fn load(db_connection: &Connection) -> Result<Self, AppError> {
+        let mut stmt = match db_connection.prepare(
+            "SELECT statement_translation_type, statement_translation_reference, statement_translation_locale, statement_translation_label
+            FROM statement_translation",
+        ) {
+            Ok(stmt) => stmt,
+            Err(err) => return Err(AppError::Database(err.to_string())),
+        };
+
+        let rows = match stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+            ))
+        }) {
+            Ok(rows) => rows,
+            Err(err) => return Err(AppError::Database(err.to_string())),
+        };
+
+        let mut translations = StatementTranslations::default();
+        for row in rows {
+            let (statement_type, reference, locale, label) = match row {
+                Ok(row) => row,
+                Err(err) => return Err(AppError::Database(err.to_string())),
+            };
+            let (Some(statement_type), Some(locale)) = (
+                StatementType::from_str(&statement_type),
+                Locale::from_code(&locale),
+            ) else {
+                continue;
+            };
+
+            translations
+                .labels
+                .entry((statement_type, reference))
+                .or_default()
+                .insert(locale, label);
+        }
+
+        Ok(translations)
+    }
+
+    pub fn label(
+        &self,
+        statement_type: StatementType,
+        reference: &str,
+        locale: Locale,
+    ) -> Option<&str> {
+        self.labels
+            .get(&(statement_type, reference.trim().to_uppercase()))
+            .and_then(|labels| labels.get(&locale))
+            .map(|label| label.as_str())
+    }
+
+    // Replace the statement labels of a product by the ones of the locale,
+    // keeping the stored label when there is no translation.
+    pub fn localize_product(&self, product: &mut Product, locale: Locale) {
+        for hazard_statement in product.hazard_statements.iter_mut().flatten() {
+            if let Some(label) = self.label(
+                StatementType::Hazard,
+                &hazard_statement.hazard_statement_reference,
+                locale,
+            ) {
+                hazard_statement.hazard_statement_label = label.to_string();
+            }
+        }
+        for precautionary_statement in product.precautionary_statements.iter_mut().flatten() {
+            if let Some(label) = self.label(
+                StatementType::Precautionary,
+                &precautionary_statement.precautionary_statement_reference,
+                locale,
+            ) {
+                precautionary_statement.precautionary_statement_label = label.to_string();
+            }
+        }
+    }
+
+    // Replace, in the statement columns of the export rows, each statement
+    // by its reference and its label in the locale. The statements are
+    // found by their reference, a statement without translation is kept
+    // as exported.
+    pub fn localize_export(&self, table: &mut ExportTable, locale: Locale) {
+        let statement_columns: Vec<(usize, StatementType)> = table
+            .headers
+            .iter()
+            .enumerate()
+            .filter_map(|(index, header)| {
+                EXPORT_STATEMENT_COLUMNS
+                    .iter()
+                    .find(|(column, _)| column == header)
+                    .map(|(_, statement_type)| (index, *statement_type))
+            })
+            .collect();
+        if statement_columns.is_empty() {
+            return;
+        }
+
+        for row in table.rows.iter_mut() {
+            for (index, statement_type) in statement_columns.iter() {
+                if let Some(cell) = row.get_mut(*index) {
+                    *cell = self.localize_statements(*statement_type, cell, locale);
+                }
+            }
+        }
+    }
+
+    // Localize the statements of an export cell. A statement spans from
+    // its reference to the separator before the next reference.
+    fn localize_statements(
+        &self,
+        statement_type: StatementType,
+        cell: &str,
+        locale: Locale,
+    ) -> String {
+        // The start of each match, separator included, and its reference.
+        let references: Vec<(usize, regex::Match)> = EXPORT_STATEMENT_REFERENCE
+            .captures_iter(cell)
+            .filter_map(|captures| Some((captures.get(0)?.start(), captures.get(1)?)))
+            .collect();
+
+        let mut localized = String::with_capacity(cell.len());
+        let mut copied = 0;
+        for (i, (_, reference)) in references.iter().enumerate() {
+            let statement_end = references
+                .get(i + 1)
+                .map(|(next_start, _)| *next_start)
+                .unwrap_or(cell.len());
+
+            localized.push_str(&cell[copied..reference.start()]);
+            match self.label(statement_type, reference.as_str(), locale) {
+                Some(label) => {
+                    localized.push_str(reference.as_str());
+                    localized.push_str(": ");
+                    localized.push_str(label);
+                }
+                None => localized.push_str(&cell[reference.start()..statement_end]),
+            }
+            copied = statement_end;
+        }
+        localized.push_str(&cell[copied..]);
+
+        localized
+    }
+}
+
+pub fn get_person_locale(
+    db_connection: &Connection,
+    chimitheque_person_id: u64,
+) -> Result<Option<Locale>, AppError> {
+    match db_connection
+        .query_row(
+            "SELECT person_locale_locale FROM person_locale WHERE person_locale_person_id = ?1",
+            params![chimitheque_person_id],
+            |row| row.get::<_, String>(0),
+        )
+        .optional()
+    {
+        Ok(locale) => Ok(locale.and_then(|locale| Locale::from_code(&locale))),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+pub fn set_person_locale(
+    db_connection: &Connection,
+    chimitheque_person_id: u64,
+    locale: Locale,
+) -> Result<(), AppError> {
+    match db_connection.execute(
+        "INSERT OR REPLACE INTO person_locale (person_locale_person_id, person_locale_locale) VALUES (?1, ?2)",
+        params![chimitheque_person_id, locale.code()],
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// The request language: the person preference, else the Accept-Language
+// header, else the default language.
+pub fn request_locale(
+    db_connection: &Connection,
+    headers: &HeaderMap,
+    chimitheque_person_id: u64,
+) -> Result<Locale, AppError> {
+    if let Some(locale) = get_person_locale(db_connection, chimitheque_person_id)? {
+        return Ok(locale);
+    }
+
+    Ok(headers
+        .get(ACCEPT_LANGUAGE)
+        .and_then(|accept_language| accept_language.to_str().ok())
+        .and_then(Locale::from_accept_language)
+        .unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_accept_language_picks_the_highest_weight() {
+        assert_eq!(
+            Locale::from_accept_language("fr-FR,fr;q=0.9,en;q=0.8"),
+            Some(Locale::Fr)
+        );
+        assert_eq!(
+            Locale::from_accept_language("fr;q=0.5, en-GB;q=0.7"),
+            Some(Locale::En)
+        );
+        assert_eq!(
+            Locale::from_accept_language("de-DE, fr;q=0.3"),
+            Some(Locale::Fr)
+        );
+    }
+
+    #[test]
+    fn from_accept_language_without_supported_language() {
+        assert_eq!(Locale::from_accept_language("de, it;q=0.9"), None);
+        assert_eq!(Locale::from_accept_language("*"), None);
+        assert_eq!(Locale::from_accept_language(""), None);
+    }
+
+    #[test]
+    fn localize_export_replaces_the_statements_by_reference() {
+        let mut statement_translations = StatementTranslations::default();
+        for (statement_type, reference, label) in [
+            (StatementType::Hazard, "H300", "Mortel en cas d'ingestion"),
+            (
+                StatementType::Hazard,
+                "H225",
+                "Liquide et vapeurs très inflammables",
+            ),
+            (
+                StatementType::Precautionary,
+                "P301+P310",
+                "EN CAS D'INGESTION: appeler immédiatement un CENTRE ANTIPOISON",
+            ),
+        ] {
+            statement_translations.labels.insert(
+                (statement_type, String::from(reference)),
+                HashMap::from([(Locale::Fr, String::from(label))]),
+            );
+        }
+        let mut table = ExportTable {
+            headers: vec![
+                String::from("product_name"),
+                String::from("hazard_statements"),
+                String::from("precautionary_statements"),
+            ],
+            rows: vec![
+                vec![
+                    String::from("H225"),
+                    String::from(
+                        "H225: Highly flammable liquid and vapour, H300+H310: Fatal if swallowed or in contact with skin, H300: Fatal if swallowed",
+                    ),
+                    String::from("P301+P310, P301"),
+                ],
+                vec![String::from("acetone"), String::from("H225"), String::new()],
+            ],
+        };
+
+        statement_translations.localize_export(&mut table, Locale::Fr);
+
+        // Only the statement columns are localized, the untranslated
+        // statements are kept as exported.
+        assert_eq!(
+            table.rows[0],
+            vec![
+                String::from("H225"),
+                String::from(
+                    "H225: Liquide et vapeurs très inflammables, H300+H310: Fatal if swallowed or in contact with skin, H300: Mortel en cas d'ingestion"
+                ),
+                String::from(
+                    "P301+P310: EN CAS D'INGESTION: appeler immédiatement un CENTRE ANTIPOISON, P301"
+                ),
+            ]
+        );
+        assert_eq!(
+            table.rows[1],
+            vec![
+                String::from("acetone"),
+                String::from("H225: Liquide et vapeurs très inflammables"),
+                String::new(),
+            ]
+        );
+    }
+
+    #[test]
+    fn from_accept_language_ignores_invalid_weights() {
+        assert_eq!(
+            Locale::from_accept_language("en;q=abc, fr;q=0.9"),
+            Some(Locale::En)
+        );
+    }
+}
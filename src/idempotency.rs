@@ -0,0 +1,322 @@
+use rusqlite::{Connection, OptionalExtension, Row, params};
+
+use crate::errors::AppError;
+
+pub const IDEMPOTENCY_DATETIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+// A request sent with an idempotency key, and its response once processed.
+// The key is scoped to the person who sent it.
+#[derive(Debug, Clone, Default)]
+pub struct IdempotencyRecord {
+    pub idempotency_key: String,
+    pub person_id: u64,
+    // Fingerprint of the method, URI and body of the request.
+    pub request_hash: String,
+    // Not set while the request is still being processed.
+    pub response_status: Option<u16>,
+    // JSON array of the [name, value] response headers.
+    pub response_headers: Option<String>,
+    pub response_body: Option<Vec<u8>>,
+    // The date the key was reserved, reset when a stale pending key is taken over.
+    pub created_at: String,
+}
+
+impl IdempotencyRecord {
+    pub fn is_pending(&self) -> bool {
+        self.response_status.is_none()
+    }
+}
+
+const IDEMPOTENCY_KEY_COLUMNS: &str = "idempotency_key, idempotency_key_person_id, idempotency_key_request_hash, idempotency_key_response_status, idempotency_key_response_headers, idempotency_key_response_body, idempotency_key_created_at";
+
+fn idempotency_record_from_row(row: &Row) -> Result<IdempotencyRecord, rusqlite::Error> {
+    Ok(IdempotencyRecord {
+        idempotency_key: row.get(0)?,
+        person_id: row.get(1)?,
+        request_hash: row.get(2)?,
+        response_status: row.get(3)?,
+        response_headers: row.get(4)?,
+        response_body: row.get(5)?,
+        created_at: row.get(6)?,
+    })
+}
+
+// Create the idempotency keys table.
+pub fn init_idempotency(db_connection: &Connection) -> Result<(), AppError> {
+    match db_connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS idempotency_key (
+            idempotency_key TEXT NOT NULL,
+            idempotency_key_person_id INTEGER NOT NULL,
+            idempotency_key_request_hash TEXT NOT NULL,
+            idempotency_key_response_status INTEGER,
+            idempotency_key_response_headers TEXT,
+            idempotency_key_response_body BLOB,
+            idempotency_key_created_at TEXT NOT NULL,
+            PRIMARY KEY (idempotency_key, idempotency_key_person_id)
+        );
+        CREATE INDEX IF NOT EXISTS idx_idempotency_key_created_at ON idempotency_key(idempotency_key_created_at);",
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// Delete the keys created before the given date, they can be reused.
+pub fn purge_idempotency_keys(
+    db_connection: &Connection,
+    created_before: &str,
+) -> Result<(), AppError> {
+    match db_connection.execute(
+        "DELETE FROM idempotency_key WHERE idempotency_key_created_at < ?1",
+        params![created_before],
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// Reserve the key for a new request.
+// Return the existing record instead if the key was already used by the person,
+// unless it is still pending and was reserved before pending_before: the request
+// that reserved it is considered lost and the key is taken over.
+pub fn reserve_idempotency_key(
+    db_connection: &Connection,
+    idempotency_key: &str,
+    person_id: u64,
+    request_hash: &str,
+    created_at: &str,
+    pending_before: &str,
+) -> Result<Option<IdempotencyRecord>, AppError> {
+    let nb_inserted = match db_connection.execute(
+        "INSERT OR IGNORE INTO idempotency_key (idempotency_key, idempotency_key_person_id, idempotency_key_request_hash, idempotency_key_created_at)
+        VALUES (?1, ?2, ?3, ?4)",
+        params![idempotency_key, person_id, request_hash, created_at],
+    ) {
+        Ok(nb_inserted) => nb_inserted,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    if nb_inserted == 1 {
+        return Ok(None);
+    }
+
+    // A single statement, so that a stale key is taken over only once.
+    let nb_taken_over = match db_connection.execute(
+        "UPDATE idempotency_key SET idempotency_key_request_hash = ?1, idempotency_key_created_at = ?2
+        WHERE idempotency_key = ?3 AND idempotency_key_person_id = ?4
+        AND idempotency_key_response_status IS NULL AND idempotency_key_created_at < ?5",
+        params![
+            request_hash,
+            created_at,
+            idempotency_key,
+            person_id,
+            pending_before
+        ],
+    ) {
+        Ok(nb_taken_over) => nb_taken_over,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    if nb_taken_over == 1 {
+        return Ok(None);
+    }
+
+    let sql = format!(
+        "SELECT {} FROM idempotency_key WHERE idempotency_key = ?1 AND idempotency_key_person_id = ?2",
+        IDEMPOTENCY_KEY_COLUMNS
+    );
+
+    match db_connection
+        .query_row(
+            &sql,
+            params![idempotency_key, person_id],
+            idempotency_record_from_row,
+        )
+        .optional()
+    {
+        Ok(idempotency_record) => Ok(idempotency_record),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// Record the response of the request reserved with the key.
+pub fn set_idempotency_key_response(
+    db_connection: &Connection,
+    idempotency_key: &str,
+    person_id: u64,
+    response_status: u16,
+    response_headers: &str,
+    response_body: &[u8],
+) -> Result<(), AppError> {
+    match db_connection.execute(
+        "UPDATE idempotency_key SET idempotency_key_response_status = ?1, idempotency_key_response_headers = ?2, idempotency_key_response_body = ?3
+        WHERE idempotency_key = ?4 AND idempotency_key_person_id = ?5",
+        params![
+            response_status,
+            response_headers,
+            response_body,
+            idempotency_key,
+            person_id
+        ],
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// Release the key of a failed request so that it can be retried.
+pub fn delete_idempotency_key(
+    db_connection: &Connection,
+    idempotency_key: &str,
+    person_id: u64,
+) -> Result<(), AppError> {
+    match db_connection.execute(
+        "DELETE FROM idempotency_key WHERE idempotency_key = ?1 AND idempotency_key_person_id = ?2",
+        params![idempotency_key, person_id],
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn init_test_db() -> Connection {
+        let db_connection = Connection::open_in_memory().unwrap();
+        init_idempotency(&db_connection).unwrap();
+        db_connection
+    }
+
+    #[test]
+    fn reserve_idempotency_key_returns_the_existing_record() {
+        let db_connection = init_test_db();
+
+        assert!(
+            reserve_idempotency_key(
+                &db_connection,
+                "key",
+                1,
+                "hash",
+                "2025-01-01 10:00:00",
+                "2025-01-01 09:50:00"
+            )
+            .unwrap()
+            .is_none()
+        );
+
+        // Still pending.
+        let idempotency_record = reserve_idempotency_key(
+            &db_connection,
+            "key",
+            1,
+            "hash",
+            "2025-01-01 10:01:00",
+            "2025-01-01 09:51:00",
+        )
+        .unwrap()
+        .unwrap();
+        assert!(idempotency_record.is_pending());
+        assert_eq!(idempotency_record.created_at, "2025-01-01 10:00:00");
+
+        set_idempotency_key_response(&db_connection, "key", 1, 201, "[]", b"[1]").unwrap();
+        let idempotency_record = reserve_idempotency_key(
+            &db_connection,
+            "key",
+            1,
+            "hash",
+            "2025-01-01 10:02:00",
+            "2025-01-01 09:52:00",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(idempotency_record.response_status, Some(201));
+        assert_eq!(idempotency_record.response_body, Some(b"[1]".to_vec()));
+
+        // The key is scoped to the person.
+        assert!(
+            reserve_idempotency_key(
+                &db_connection,
+                "key",
+                2,
+                "hash",
+                "2025-01-01 10:02:00",
+                "2025-01-01 09:52:00"
+            )
+            .unwrap()
+            .is_none()
+        );
+    }
+
+    #[test]
+    fn reserve_idempotency_key_takes_over_a_stale_pending_key() {
+        let db_connection = init_test_db();
+        reserve_idempotency_key(
+            &db_connection,
+            "key",
+            1,
+            "hash",
+            "2025-01-01 10:00:00",
+            "2025-01-01 09:50:00",
+        )
+        .unwrap();
+
+        // The pending key was reserved before pending_before.
+        assert!(
+            reserve_idempotency_key(
+                &db_connection,
+                "key",
+                1,
+                "other hash",
+                "2025-01-01 10:20:00",
+                "2025-01-01 10:10:00"
+            )
+            .unwrap()
+            .is_none()
+        );
+
+        // The key is taken over only once, with the new request.
+        let idempotency_record = reserve_idempotency_key(
+            &db_connection,
+            "key",
+            1,
+            "hash",
+            "2025-01-01 10:21:00",
+            "2025-01-01 10:11:00",
+        )
+        .unwrap()
+        .unwrap();
+        assert!(idempotency_record.is_pending());
+        assert_eq!(idempotency_record.request_hash, "other hash");
+        assert_eq!(idempotency_record.created_at, "2025-01-01 10:20:00");
+    }
+
+    #[test]
+    fn reserve_idempotency_key_keeps_a_stale_response() {
+        let db_connection = init_test_db();
+        reserve_idempotency_key(
+            &db_connection,
+            "key",
+            1,
+            "hash",
+            "2025-01-01 10:00:00",
+            "2025-01-01 09:50:00",
+        )
+        .unwrap();
+        set_idempotency_key_response(&db_connection, "key", 1, 200, "[]", b"").unwrap();
+
+        let idempotency_record = reserve_idempotency_key(
+            &db_connection,
+            "key",
+            1,
+            "other hash",
+            "2025-01-01 10:20:00",
+            "2025-01-01 10:10:00",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(idempotency_record.response_status, Some(200));
+        assert_eq!(idempotency_record.request_hash, "hash");
+    }
+}
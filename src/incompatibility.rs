@@ -0,0 +1,272 @@
+use chimitheque_types::product::Product;
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::errors::AppError;
+
+// A compatibility group, such as "oxidizers" or "acids".
+// A product belongs to a group when one of its hazard statements starts with
+// one of the group hazard statements (so "H22" matches H220 to H229), or when
+// one of its classes of compounds is one of the group classes of compounds.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct CompatibilityGroup {
+    #[serde(default)]
+    pub hazard_statements: Vec<String>,
+    #[serde(default)]
+    pub classes_of_compounds: Vec<String>,
+}
+
+// The incompatibility rules: the compatibility groups and the pairs of
+// groups that must not be stored in the same store location.
+//
+// They can be overridden with a JSON file such as:
+// {
+//   "groups": {
+//     "oxidizers": { "hazard_statements": ["H270", "H271", "H272"] },
+//     "flammables": { "hazard_statements": ["H22"] }
+//   },
+//   "incompatibilities": [["oxidizers", "flammables"]]
+// }
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct IncompatibilityRules {
+    pub groups: BTreeMap<String, CompatibilityGroup>,
+    pub incompatibilities: Vec<(String, String)>,
+}
+
+impl Default for IncompatibilityRules {
+    fn default() -> Self {
+        let group =
+            |hazard_statements: &[&str], classes_of_compounds: &[&str]| CompatibilityGroup {
+                hazard_statements: hazard_statements.iter().map(|s| s.to_string()).collect(),
+                classes_of_compounds: classes_of_compounds.iter().map(|s| s.to_string()).collect(),
+            };
+
+        IncompatibilityRules {
+            groups: BTreeMap::from([
+                (String::from("explosives"), group(&["H20"], &[])),
+                (
+                    String::from("oxidizers"),
+                    group(&["H270", "H271", "H272"], &[]),
+                ),
+                (
+                    String::from("flammables"),
+                    group(&["H22", "H242", "H250", "H251", "H252"], &[]),
+                ),
+                (
+                    String::from("water_reactives"),
+                    group(&["H260", "H261", "EUH014"], &[]),
+                ),
+                (
+                    String::from("acids"),
+                    group(&[], &["acid", "acids", "acide", "acides"]),
+                ),
+                (String::from("bases"), group(&[], &["base", "bases"])),
+                (
+                    String::from("cyanides"),
+                    group(&[], &["cyanide", "cyanides", "cyanure", "cyanures"]),
+                ),
+            ]),
+            incompatibilities: vec![
+                (String::from("oxidizers"), String::from("flammables")),
+                (String::from("explosives"), String::from("flammables")),
+                (String::from("explosives"), String::from("oxidizers")),
+                (String::from("acids"), String::from("cyanides")),
+                (String::from("acids"), String::from("bases")),
+                (String::from("acids"), String::from("water_reactives")),
+            ],
+        }
+    }
+}
+
+impl IncompatibilityRules {
+    pub fn from_file(path: &str) -> Result<Self, AppError> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) => return Err(AppError::IncompatibilityRules(err.to_string())),
+        };
+
+        let rules: IncompatibilityRules = match serde_json::from_str(&content) {
+            Ok(rules) => rules,
+            Err(err) => return Err(AppError::IncompatibilityRules(err.to_string())),
+        };
+
+        for (group, other_group) in rules.incompatibilities.iter() {
+            for group in [group, other_group] {
+                if !rules.groups.contains_key(group) {
+                    return Err(AppError::IncompatibilityRules(format!(
+                        "unknown compatibility group: {}",
+                        group
+                    )));
+                }
+            }
+        }
+
+        Ok(rules)
+    }
+
+    // Return the compatibility groups of a product.
+    pub fn groups_of(&self, product: &Product) -> BTreeSet<String> {
+        let hazard_statements: Vec<String> = product
+            .hazard_statements
+            .iter()
+            .flatten()
+            .map(|hazard_statement| hazard_statement.hazard_statement_reference.to_uppercase())
+            .collect();
+        let classes_of_compounds: Vec<String> = product
+            .classes_of_compounds
+            .iter()
+            .flatten()
+            .map(|class_of_compound| class_of_compound.class_of_compound_label.to_lowercase())
+            .collect();
+
+        self.groups
+            .iter()
+            .filter(|(_, group)| {
+                group
+                    .hazard_statements
+                    .iter()
+                    .any(|group_hazard_statement| {
+                        let group_hazard_statement = group_hazard_statement.to_uppercase();
+                        hazard_statements.iter().any(|hazard_statement| {
+                            hazard_statement.starts_with(&group_hazard_statement)
+                        })
+                    })
+                    || group
+                        .classes_of_compounds
+                        .iter()
+                        .any(|group_class_of_compound| {
+                            classes_of_compounds.contains(&group_class_of_compound.to_lowercase())
+                        })
+            })
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    // Return the incompatible group pairs between two products,
+    // the first group being the one of the first product.
+    pub fn check(&self, product: &Product, other_product: &Product) -> Vec<(String, String)> {
+        let groups = self.groups_of(product);
+        let other_groups = self.groups_of(other_product);
+
+        // A set, as a pair can be found twice with symmetric or repeated rules.
+        let mut pairs: BTreeSet<(String, String)> = BTreeSet::new();
+        for (group, other_group) in self.incompatibilities.iter() {
+            if groups.contains(group) && other_groups.contains(other_group) {
+                pairs.insert((group.clone(), other_group.clone()));
+            }
+            if groups.contains(other_group) && other_groups.contains(group) {
+                pairs.insert((other_group.clone(), group.clone()));
+            }
+        }
+
+        pairs.into_iter().collect()
+    }
+}
+
+// An incompatibility between two storages of the same store location.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Incompatibility {
+    pub storage_id: Option<u64>,
+    pub product_id: Option<u64>,
+    pub product_name: String,
+    pub group: String,
+    pub other_storage_id: Option<u64>,
+    pub other_product_id: Option<u64>,
+    pub other_product_name: String,
+    pub other_group: String,
+}
+
+impl std::fmt::Display for Incompatibility {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({}) is incompatible with {} ({})",
+            self.product_name, self.group, self.other_product_name, self.other_group
+        )
+    }
+}
+
+// The compatibility setting of a store location.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct StoreLocationCompatibility {
+    // Reject the storages incompatible with the store location ones
+    // instead of warning.
+    pub strict: bool,
+}
+
+// Create the store location compatibility settings table.
+pub fn init_incompatibility(db_connection: &Connection) -> Result<(), AppError> {
+    match db_connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS store_location_compatibility (
+            store_location_compatibility_store_location_id INTEGER PRIMARY KEY,
+            store_location_compatibility_strict INTEGER NOT NULL DEFAULT 0
+        );",
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// The compatibility setting of a store location, not strict by default.
+pub fn get_store_location_compatibility(
+    db_connection: &Connection,
+    store_location_id: u64,
+) -> Result<StoreLocationCompatibility, AppError> {
+    match db_connection
+        .query_row(
+            "SELECT store_location_compatibility_strict FROM store_location_compatibility
+            WHERE store_location_compatibility_store_location_id = ?1",
+            params![store_location_id],
+            |row| row.get(0),
+        )
+        .optional()
+    {
+        Ok(strict) => Ok(StoreLocationCompatibility {
+            strict: strict.unwrap_or_default(),
+        }),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// The current storages of a store location, whoever can see them, with
+// their product id. The archived storages and the storage history are
+// excluded.
+pub fn get_store_location_storage_products(
+    db_connection: &Connection,
+    store_location_id: u64,
+) -> Result<Vec<(u64, u64)>, AppError> {
+    let mut stmt = match db_connection.prepare(
+        "SELECT storage_id, product FROM storage
+        WHERE store_location = ?1 AND storage_archive = 0 AND storage IS NULL
+        ORDER BY storage_id",
+    ) {
+        Ok(stmt) => stmt,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    match stmt.query_map(params![store_location_id], |row| {
+        Ok((row.get(0)?, row.get(1)?))
+    }) {
+        Ok(rows) => match rows.collect::<Result<Vec<(u64, u64)>, rusqlite::Error>>() {
+            Ok(storage_products) => Ok(storage_products),
+            Err(err) => Err(AppError::Database(err.to_string())),
+        },
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+pub fn set_store_location_compatibility(
+    db_connection: &Connection,
+    store_location_id: u64,
+    store_location_compatibility: &StoreLocationCompatibility,
+) -> Result<(), AppError> {
+    match db_connection.execute(
+        "INSERT OR REPLACE INTO store_location_compatibility (store_location_compatibility_store_location_id, store_location_compatibility_strict)
+        VALUES (?1, ?2)",
+        params![store_location_id, store_location_compatibility.strict],
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
@@ -0,0 +1,243 @@
+use rusqlite::{Connection, OptionalExtension, Row, params};
+use serde::{Deserialize, Serialize};
+
+use crate::errors::AppError;
+
+pub const INVENTORY_DATETIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+// A physical inventory of the storages of an entity or of a store location,
+// open until closed.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct InventoryCampaign {
+    #[serde(default)]
+    pub inventory_campaign_id: u64,
+    // Exactly one of the entity or the store location is set.
+    pub entity_id: Option<u64>,
+    pub store_location_id: Option<u64>,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub opened_by: u64,
+    #[serde(default)]
+    pub opened_at: String,
+    #[serde(default)]
+    pub closed_by: Option<u64>,
+    #[serde(default)]
+    pub closed_at: Option<String>,
+}
+
+impl InventoryCampaign {
+    pub fn sanitize_and_validate(&mut self) -> Result<(), AppError> {
+        self.name = self.name.trim().to_string();
+
+        match (self.entity_id, self.store_location_id) {
+            (Some(_), None) | (None, Some(_)) => Ok(()),
+            _ => Err(AppError::InputValidation(String::from(
+                "an inventory campaign is for either an entity or a store location",
+            ))),
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed_at.is_some()
+    }
+}
+
+// A code scanned during an inventory campaign. A code matching no storage
+// is recorded without storage, a code matching a batch of storages
+// sharing the same barcode is recorded once per storage.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct InventoryScan {
+    pub inventory_scan_id: u64,
+    pub inventory_campaign_id: u64,
+    pub code: String,
+    pub storage_id: Option<u64>,
+    // Where the storage was found, if known.
+    pub store_location_id: Option<u64>,
+    pub person_id: u64,
+    pub scanned_at: String,
+}
+
+const INVENTORY_CAMPAIGN_COLUMNS: &str = "inventory_campaign_id, inventory_campaign_entity_id, inventory_campaign_store_location_id, inventory_campaign_name, inventory_campaign_opened_by, inventory_campaign_opened_at, inventory_campaign_closed_by, inventory_campaign_closed_at";
+
+const INVENTORY_SCAN_COLUMNS: &str = "inventory_scan_id, inventory_scan_campaign_id, inventory_scan_code, inventory_scan_storage_id, inventory_scan_store_location_id, inventory_scan_person_id, inventory_scan_scanned_at";
+
+fn inventory_campaign_from_row(row: &Row) -> Result<InventoryCampaign, rusqlite::Error> {
+    Ok(InventoryCampaign {
+        inventory_campaign_id: row.get(0)?,
+        entity_id: row.get(1)?,
+        store_location_id: row.get(2)?,
+        name: row.get(3)?,
+        opened_by: row.get(4)?,
+        opened_at: row.get(5)?,
+        closed_by: row.get(6)?,
+        closed_at: row.get(7)?,
+    })
+}
+
+fn inventory_scan_from_row(row: &Row) -> Result<InventoryScan, rusqlite::Error> {
+    Ok(InventoryScan {
+        inventory_scan_id: row.get(0)?,
+        inventory_campaign_id: row.get(1)?,
+        code: row.get(2)?,
+        storage_id: row.get(3)?,
+        store_location_id: row.get(4)?,
+        person_id: row.get(5)?,
+        scanned_at: row.get(6)?,
+    })
+}
+
+// Create the inventory campaigns and scans tables.
+pub fn init_inventory(db_connection: &Connection) -> Result<(), AppError> {
+    match db_connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS inventory_campaign (
+            inventory_campaign_id INTEGER PRIMARY KEY,
+            inventory_campaign_entity_id INTEGER,
+            inventory_campaign_store_location_id INTEGER,
+            inventory_campaign_name TEXT NOT NULL,
+            inventory_campaign_opened_by INTEGER NOT NULL,
+            inventory_campaign_opened_at TEXT NOT NULL,
+            inventory_campaign_closed_by INTEGER,
+            inventory_campaign_closed_at TEXT
+        );
+        CREATE TABLE IF NOT EXISTS inventory_scan (
+            inventory_scan_id INTEGER PRIMARY KEY,
+            inventory_scan_campaign_id INTEGER NOT NULL,
+            inventory_scan_code TEXT NOT NULL,
+            inventory_scan_storage_id INTEGER,
+            inventory_scan_store_location_id INTEGER,
+            inventory_scan_person_id INTEGER NOT NULL,
+            inventory_scan_scanned_at TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS idx_inventory_scan_campaign ON inventory_scan(inventory_scan_campaign_id);",
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+pub fn insert_inventory_campaign(
+    db_connection: &Connection,
+    inventory_campaign: &InventoryCampaign,
+) -> Result<u64, AppError> {
+    match db_connection.execute(
+        "INSERT INTO inventory_campaign (inventory_campaign_entity_id, inventory_campaign_store_location_id, inventory_campaign_name, inventory_campaign_opened_by, inventory_campaign_opened_at)
+        VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            inventory_campaign.entity_id,
+            inventory_campaign.store_location_id,
+            inventory_campaign.name,
+            inventory_campaign.opened_by,
+            inventory_campaign.opened_at
+        ],
+    ) {
+        Ok(_) => Ok(db_connection.last_insert_rowid() as u64),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+pub fn close_inventory_campaign(
+    db_connection: &Connection,
+    inventory_campaign_id: u64,
+    closed_at: &str,
+    closed_by: u64,
+) -> Result<(), AppError> {
+    match db_connection.execute(
+        "UPDATE inventory_campaign SET inventory_campaign_closed_at = ?1, inventory_campaign_closed_by = ?2
+        WHERE inventory_campaign_id = ?3 AND inventory_campaign_closed_at IS NULL",
+        params![closed_at, closed_by, inventory_campaign_id],
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+pub fn get_inventory_campaign(
+    db_connection: &Connection,
+    inventory_campaign_id: u64,
+) -> Result<Option<InventoryCampaign>, AppError> {
+    let sql = format!(
+        "SELECT {} FROM inventory_campaign WHERE inventory_campaign_id = ?1",
+        INVENTORY_CAMPAIGN_COLUMNS
+    );
+
+    match db_connection
+        .query_row(
+            &sql,
+            params![inventory_campaign_id],
+            inventory_campaign_from_row,
+        )
+        .optional()
+    {
+        Ok(inventory_campaign) => Ok(inventory_campaign),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// The inventory campaigns, most recent first.
+pub fn get_inventory_campaigns(
+    db_connection: &Connection,
+) -> Result<Vec<InventoryCampaign>, AppError> {
+    let sql = format!(
+        "SELECT {} FROM inventory_campaign ORDER BY inventory_campaign_opened_at DESC, inventory_campaign_id DESC",
+        INVENTORY_CAMPAIGN_COLUMNS
+    );
+
+    let mut stmt = match db_connection.prepare(&sql) {
+        Ok(stmt) => stmt,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    match stmt.query_map([], inventory_campaign_from_row) {
+        Ok(rows) => match rows.collect::<Result<Vec<InventoryCampaign>, rusqlite::Error>>() {
+            Ok(inventory_campaigns) => Ok(inventory_campaigns),
+            Err(err) => Err(AppError::Database(err.to_string())),
+        },
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+pub fn insert_inventory_scan(
+    db_connection: &Connection,
+    inventory_scan: &InventoryScan,
+) -> Result<u64, AppError> {
+    match db_connection.execute(
+        "INSERT INTO inventory_scan (inventory_scan_campaign_id, inventory_scan_code, inventory_scan_storage_id, inventory_scan_store_location_id, inventory_scan_person_id, inventory_scan_scanned_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            inventory_scan.inventory_campaign_id,
+            inventory_scan.code,
+            inventory_scan.storage_id,
+            inventory_scan.store_location_id,
+            inventory_scan.person_id,
+            inventory_scan.scanned_at
+        ],
+    ) {
+        Ok(_) => Ok(db_connection.last_insert_rowid() as u64),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// The scans of a campaign, in scan order.
+pub fn get_inventory_scans(
+    db_connection: &Connection,
+    inventory_campaign_id: u64,
+) -> Result<Vec<InventoryScan>, AppError> {
+    let sql = format!(
+        "SELECT {} FROM inventory_scan WHERE inventory_scan_campaign_id = ?1 ORDER BY inventory_scan_scanned_at, inventory_scan_id",
+        INVENTORY_SCAN_COLUMNS
+    );
+
+    let mut stmt = match db_connection.prepare(&sql) {
+        Ok(stmt) => stmt,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    match stmt.query_map(params![inventory_campaign_id], inventory_scan_from_row) {
+        Ok(rows) => match rows.collect::<Result<Vec<InventoryScan>, rusqlite::Error>>() {
+            Ok(inventory_scans) => Ok(inventory_scans),
+            Err(err) => Err(AppError::Database(err.to_string())),
+        },
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
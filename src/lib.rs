@@ -1,29 +1,101 @@
 pub mod appstate;
+pub mod barcode;
+pub mod bookmark;
+pub mod borrowing;
+pub mod capacity;
 pub mod constants;
+pub mod consumption;
 pub mod errors;
+pub mod expiry;
+pub mod export;
+pub mod ghs_label;
 pub mod handlers;
+pub mod i18n;
+pub mod idempotency;
+pub mod incompatibility;
+pub mod inventory;
+pub mod location_history;
+pub mod regulatory;
+pub mod reservation;
+pub mod safety_data_sheet;
+pub mod search;
+pub mod structure;
+pub mod synonym;
 pub mod utils;
+pub mod waste;
 
 use crate::{
     appstate::{AppState, init_casbin_enforcer},
-    constants::{CHIMITHEQUE_PERSON_EMAIL_HEADER, CHIMITHEQUE_PERSON_ID_HEADER, REQUEST_ID_HEADER},
+    bookmark::init_bookmark,
+    borrowing::init_borrowing_log,
+    capacity::init_capacity,
+    constants::{
+        CHIMITHEQUE_IDEMPOTENT_REPLAY_HEADER, CHIMITHEQUE_PERSON_EMAIL_HEADER,
+        CHIMITHEQUE_PERSON_ID_HEADER, CHIMITHEQUE_WARNINGS_COUNT_HEADER,
+        CHIMITHEQUE_WARNINGS_HEADER, DEFAULT_EXPIRY_ALERTS_INTERVAL_HOURS,
+        DEFAULT_EXPIRY_WARNING_DAYS, DEFAULT_IDEMPOTENCY_WINDOW_HOURS, DEFAULT_PUBCHEM_BASE_URL,
+        DEFAULT_SDS_MAX_AGE_DAYS, IDEMPOTENCY_KEY_HEADER, IDEMPOTENCY_MAX_REQUEST_SIZE,
+        IDEMPOTENCY_MAX_RESPONSE_SIZE, IDEMPOTENCY_PENDING_TIMEOUT_MINUTES, REQUEST_ID_HEADER,
+        SDS_MAX_SIZE,
+    },
+    consumption::init_consumption_log,
     errors::AppError,
+    expiry::{init_expiry, run_expiry_alerts},
+    ghs_label::load_font_database,
     handlers::{
-        bookmark::toogle_bookmark,
-        borrowing::toogle_borrowing,
+        barcode::{get_storage_barcode, get_storage_label_sheet, scan_storage},
+        bookmark::{create_update_bookmark, delete_bookmark, get_bookmark_labels, get_bookmarks},
+        borrowing::{
+            create_borrowing, delete_borrowing, get_borrowing_history,
+            get_connected_user_borrowings, get_connected_user_lendings,
+            get_entity_overdue_borrowings,
+        },
+        bulk::{bulk_update_products, bulk_update_storages},
+        capacity::{
+            get_capacity_limits, get_capacity_report, get_store_location_capacity,
+            update_capacity_limits,
+        },
+        consumption::{create_storage_usage, get_product_usage_history, get_storage_usage_history},
         entity::{
             create_update_entity, delete_entity, get_entities, get_entities_old, get_entity_stock,
         },
+        expiry::{
+            get_entity_expiries, get_entity_expiry_alerts, get_storage_shelf_life,
+            update_storage_shelf_life,
+        },
         fake::fake,
+        incompatibility::{
+            get_compatibility, get_store_location_incompatibilities, update_compatibility,
+        },
+        inventory::{
+            archive_missing_storages, close_inventory_campaign, create_inventory_campaign,
+            create_inventory_scan, get_inventory_campaign, get_inventory_campaigns,
+            get_inventory_report, relocate_misplaced_storages,
+        },
+        label::{get_product_label, get_storage_label},
+        locale::{
+            get_connected_user_locale, import_statement_translations_csv,
+            update_connected_user_locale,
+        },
         person::{
             create_update_person, delete_person, get_connected_user, get_people, get_people_old,
         },
         product::{
-            create_update_product, delete_product, export_products, get_products, get_products_old,
+            create_product_with_storages, create_update_product, delete_product, export_products,
+            get_products, get_products_old,
         },
         pubchem::{
-            pubchem_autocomplete, pubchem_create_update_product, pubchem_getcompoundbyname,
-            pubchem_getproductbyname,
+            pubchem_autocomplete, pubchem_create_product_with_storages,
+            pubchem_create_update_product, pubchem_getcompoundbyname, pubchem_getproductbyname,
+        },
+        regulatory::{get_entity_regulatory_report, get_product_regulatory_flags},
+        reservation::{
+            create_storage_reservation, delete_storage_reservation, get_entity_reservations,
+            get_storage_reservation, get_storage_reservations, get_store_location_reservations,
+        },
+        safety_data_sheet::{
+            create_safety_data_sheet, delete_safety_data_sheet, download_safety_data_sheet,
+            get_safety_data_sheets,
         },
         searchable::{
             create_producer, create_supplier, get_cas_numbers, get_cas_numbers_old, get_categories,
@@ -41,21 +113,48 @@ use crate::{
             archive_storage, create_update_storage, delete_storage, export_storages, get_storages,
             get_storages_old, unarchive_storage,
         },
+        storage_move::{
+            bulk_move_storages, get_storage_moves, get_store_location_moves, move_storage,
+        },
         store_location::{
-            create_update_store_location, delete_store_location, get_store_locations,
-            get_store_locations_old,
+            create_update_store_location, delete_store_location, get_store_location_tree,
+            get_store_locations, get_store_locations_old,
         },
+        structure::{
+            get_product_structure_identifiers, get_product_structure_image,
+            update_product_structure_identifiers,
+        },
+        synonym::{add_synonym, delete_synonym, get_synonyms, promote_synonym, reorder_synonyms},
         validate::{
             validate_cas_number, validate_ce_number, validate_email, validate_empirical_formula,
         },
+        waste::{
+            create_waste_batch, get_pending_waste, get_waste_batch, get_waste_batches,
+            get_waste_manifest, pickup_waste_batch,
+        },
+    },
+    i18n::init_i18n,
+    idempotency::{
+        IDEMPOTENCY_DATETIME_FORMAT, delete_idempotency_key, init_idempotency,
+        purge_idempotency_keys, reserve_idempotency_key, set_idempotency_key_response,
     },
+    incompatibility::{IncompatibilityRules, init_incompatibility},
+    inventory::init_inventory,
+    location_history::init_location_history,
+    regulatory::RegulatoryLists,
+    reservation::init_reservation,
+    safety_data_sheet::init_safety_data_sheet,
+    search::init_product_index,
+    structure::init_structure,
     utils::get_chimitheque_person_id_from_headers,
+    waste::init_waste,
 };
 
 use axum::{
     Extension, Router,
-    extract::{Request, State},
-    http::HeaderMap,
+    body::{Body, Bytes, HttpBody},
+    extract::{DefaultBodyLimit, Request, State},
+    http::{HeaderMap, StatusCode},
     middleware::{self, Next},
     response::{IntoResponse, Response},
     routing::{delete, get, post, put},
@@ -66,9 +165,10 @@ use chimitheque_db::{
     person::{get_admins, set_person_admin, unset_person_admin},
 };
 use chimitheque_types::{person::Person, requestfilter::RequestFilter};
+use chrono::Local;
 use dashmap::DashMap;
 use governor::{Quota, RateLimiter};
-use http::{HeaderValue, Method};
+use http::{HeaderName, HeaderValue, Method};
 use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode, decode_header};
 use once_cell::sync::OnceCell;
 use opentelemetry::KeyValue;
@@ -78,14 +178,17 @@ use r2d2_sqlite::SqliteConnectionManager;
 use regex::Regex;
 use rusqlite::Connection;
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use std::{
     env,
     num::NonZeroU32,
     ops::{Deref, DerefMut},
+    path::PathBuf,
     sync::Arc,
 };
 use tokio::net::TcpListener;
 use tokio::sync::Mutex;
+use tokio_stream::StreamExt;
 use tower_http::{
     cors::{Any, CorsLayer},
     trace::TraceLayer,
@@ -95,7 +198,7 @@ use tower_sessions::{
     cookie::{SameSite, time::Duration},
 };
 use tracing::{Span, info_span};
-use tracing::{debug, info};
+use tracing::{debug, error, info};
 use tracing_opentelemetry::OpenTelemetryLayer;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
@@ -438,7 +541,6 @@ async fn authorize_middleware(
         String::from("pubchemgetproductbyname"),
         String::from("pubchemproduct"),
         String::from("bookmarks"),
-        String::from("borrows"),
     ]
     .contains(&item)
     {
@@ -491,6 +593,222 @@ async fn authorize_middleware(
     next.run(request).await
 }
 
+// Delete an idempotency key so that its request can be sent again.
+fn release_idempotency_key(state: &AppState, idempotency_key: &str, chimitheque_person_id: u64) {
+    let db_connection = state.db_connection_pool.get().unwrap();
+
+    if let Err(err) = delete_idempotency_key(
+        db_connection.deref(),
+        idempotency_key,
+        chimitheque_person_id,
+    ) {
+        error!("delete idempotency key: {}", err);
+    }
+}
+
+// Replay the response of an already processed creation request.
+// POST requests sent with an idempotency key are recorded with their response
+// so that a retried request returns the original response instead of
+// creating the records again.
+async fn idempotency_middleware(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    request: Request,
+    next: Next,
+) -> Response {
+    debug!("idempotency_middleware");
+
+    if request.method() != Method::POST {
+        return next.run(request).await;
+    }
+
+    // Get the idempotency key, if any.
+    let idempotency_key = match headers
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim())
+    {
+        Some(idempotency_key) if !idempotency_key.is_empty() => idempotency_key.to_string(),
+        _ => return next.run(request).await,
+    };
+
+    // Get the chimitheque_person_id.
+    let chimitheque_person_id = match get_chimitheque_person_id_from_headers(&headers) {
+        Ok(chimitheque_person_id) => chimitheque_person_id,
+        Err(err) => return err.into_response(),
+    };
+
+    // Fingerprint the request, hashing its body while buffering it.
+    // A body too large to be buffered is streamed back to the handler,
+    // and the request is processed without the key.
+    let (parts, body) = request.into_parts();
+
+    let mut hasher = Sha256::new();
+    hasher.update(parts.method.as_str());
+    hasher.update(parts.uri.to_string());
+
+    let mut body_stream = body.into_data_stream();
+    let mut body_chunks: Vec<Bytes> = Vec::new();
+    let mut body_size: usize = 0;
+    while let Some(body_chunk) = body_stream.next().await {
+        let body_chunk = match body_chunk {
+            Ok(body_chunk) => body_chunk,
+            Err(err) => return AppError::InputValidation(err.to_string()).into_response(),
+        };
+        body_size += body_chunk.len();
+        hasher.update(&body_chunk);
+        body_chunks.push(body_chunk);
+
+        if body_size > IDEMPOTENCY_MAX_REQUEST_SIZE {
+            debug!(
+                "request too large for the idempotency key {}",
+                idempotency_key
+            );
+            let body = Body::from_stream(
+                tokio_stream::iter(body_chunks.into_iter().map(Ok)).chain(body_stream),
+            );
+            return next.run(Request::from_parts(parts, body)).await;
+        }
+    }
+
+    let request_hash = format!("{:x}", hasher.finalize());
+
+    let request = Request::from_parts(parts, Body::from(body_chunks.concat()));
+
+    // Reserve the key, or get the request already sent with it.
+    // The connection is released before running the request.
+    let maybe_idempotency_record = {
+        let db_connection = state.db_connection_pool.get().unwrap();
+
+        let now = Local::now();
+        let created_before = (now - chrono::Duration::hours(state.idempotency_window_hours))
+            .format(IDEMPOTENCY_DATETIME_FORMAT)
+            .to_string();
+        if let Err(err) = purge_idempotency_keys(db_connection.deref(), &created_before) {
+            return err.into_response();
+        }
+
+        let pending_before = (now - chrono::Duration::minutes(IDEMPOTENCY_PENDING_TIMEOUT_MINUTES))
+            .format(IDEMPOTENCY_DATETIME_FORMAT)
+            .to_string();
+
+        match reserve_idempotency_key(
+            db_connection.deref(),
+            &idempotency_key,
+            chimitheque_person_id,
+            &request_hash,
+            &now.format(IDEMPOTENCY_DATETIME_FORMAT).to_string(),
+            &pending_before,
+        ) {
+            Ok(maybe_idempotency_record) => maybe_idempotency_record,
+            Err(err) => return err.into_response(),
+        }
+    };
+
+    match maybe_idempotency_record {
+        Some(idempotency_record) if idempotency_record.request_hash != request_hash => {
+            return AppError::Idempotency(format!(
+                "key {} already used for another request",
+                idempotency_key
+            ))
+            .into_response();
+        }
+        Some(idempotency_record) if idempotency_record.is_pending() => {
+            return AppError::Idempotency(format!(
+                "request with key {} still in progress",
+                idempotency_key
+            ))
+            .into_response();
+        }
+        Some(idempotency_record) => {
+            debug!("replaying idempotency key {}", idempotency_key);
+
+            let status = StatusCode::from_u16(idempotency_record.response_status.unwrap())
+                .unwrap_or(StatusCode::OK);
+            let response_headers: Vec<(String, String)> = idempotency_record
+                .response_headers
+                .and_then(|response_headers| serde_json::from_str(&response_headers).ok())
+                .unwrap_or_default();
+
+            let mut response = Response::new(Body::from(
+                idempotency_record.response_body.unwrap_or_default(),
+            ));
+            *response.status_mut() = status;
+            for (name, value) in response_headers {
+                if let (Ok(name), Ok(value)) = (
+                    HeaderName::from_bytes(name.as_bytes()),
+                    HeaderValue::from_str(&value),
+                ) {
+                    response.headers_mut().append(name, value);
+                }
+            }
+            response.headers_mut().insert(
+                CHIMITHEQUE_IDEMPOTENT_REPLAY_HEADER,
+                HeaderValue::from_static("true"),
+            );
+
+            return response;
+        }
+        None => (),
+    }
+
+    let response = next.run(request).await;
+
+    // Release the key of a failed request so that it can be retried.
+    if !response.status().is_success() {
+        release_idempotency_key(&state, &idempotency_key, chimitheque_person_id);
+        return response;
+    }
+
+    // A response too large to be recorded, or of unknown size, is returned
+    // as is and its key released.
+    let (parts, body) = response.into_parts();
+    match body.size_hint().upper() {
+        Some(size) if size <= IDEMPOTENCY_MAX_RESPONSE_SIZE as u64 => (),
+        _ => {
+            release_idempotency_key(&state, &idempotency_key, chimitheque_person_id);
+            return Response::from_parts(parts, body);
+        }
+    }
+
+    // Record the response to replay it.
+    let body_bytes = match axum::body::to_bytes(body, IDEMPOTENCY_MAX_RESPONSE_SIZE).await {
+        Ok(body_bytes) => body_bytes,
+        Err(err) => {
+            release_idempotency_key(&state, &idempotency_key, chimitheque_person_id);
+            return AppError::Idempotency(err.to_string()).into_response();
+        }
+    };
+
+    let response_headers: Vec<(String, String)> = parts
+        .headers
+        .iter()
+        .filter_map(|(name, value)| {
+            value
+                .to_str()
+                .ok()
+                .map(|value| (name.to_string(), value.to_string()))
+        })
+        .collect();
+
+    {
+        let db_connection = state.db_connection_pool.get().unwrap();
+
+        if let Err(err) = set_idempotency_key_response(
+            db_connection.deref(),
+            &idempotency_key,
+            chimitheque_person_id,
+            parts.status.as_u16(),
+            &serde_json::to_string(&response_headers).unwrap_or_default(),
+            &body_bytes,
+        ) {
+            error!("set idempotency key response: {}", err);
+        }
+    }
+
+    Response::from_parts(parts, Body::from(body_bytes))
+}
+
 // A debug middleware.
 async fn _debug_middleware(
     State(_state): State<AppState>,
@@ -660,6 +978,67 @@ pub async fn run(
         }
     }
 
+    // Initialize the product full-text search index, as the default admin
+    // so that every product is indexed.
+    let default_admin_id = chimitheque_db::person::get_people(
+        db_connection.deref_mut(),
+        RequestFilter {
+            person_email: Some(String::from("admin@chimitheque.fr")),
+            ..Default::default()
+        },
+        1,
+    )
+    .unwrap()
+    .0
+    .first()
+    .unwrap()
+    .person_id
+    .unwrap();
+
+    init_product_index(db_connection.deref(), default_admin_id).unwrap();
+
+    // Initialize the statement translations and person languages tables.
+    init_i18n(db_connection.deref()).unwrap();
+
+    // Initialize the safety data sheets table.
+    init_safety_data_sheet(db_connection.deref()).unwrap();
+
+    // Initialize the product structure identifiers table.
+    init_structure(db_connection.deref()).unwrap();
+
+    // Initialize the bookmark labels table.
+    init_bookmark(db_connection.deref()).unwrap();
+
+    // Initialize the borrowing history table.
+    init_borrowing_log(db_connection.deref()).unwrap();
+
+    // Initialize the storage usages table.
+    init_consumption_log(db_connection.deref()).unwrap();
+
+    // Initialize the storage shelf lives and expiry alerts tables.
+    init_expiry(db_connection.deref()).unwrap();
+
+    // Initialize the storage moves table.
+    init_location_history(db_connection.deref()).unwrap();
+
+    // Initialize the inventory campaigns and scans tables.
+    init_inventory(db_connection.deref()).unwrap();
+
+    // Initialize the storage archives and waste batches tables.
+    init_waste(db_connection.deref()).unwrap();
+
+    // Initialize the storage reservations table.
+    init_reservation(db_connection.deref()).unwrap();
+
+    // Initialize the store location compatibility settings table.
+    init_incompatibility(db_connection.deref()).unwrap();
+
+    // Initialize the store location capacity limits table.
+    init_capacity(db_connection.deref()).unwrap();
+
+    // Initialize the idempotency keys table.
+    init_idempotency(db_connection.deref()).unwrap();
+
     let session_store = MemoryStore::default();
     let session_layer = SessionManagerLayer::new(session_store)
         .with_secure(false)
@@ -671,6 +1050,55 @@ pub async fn run(
 
     let rate_limiter = RateLimiter::direct(Quota::per_second(NonZeroU32::new(5).unwrap()));
 
+    // Optional GHS pictograms directory for the labels.
+    let ghs_pictograms_dir = env::var("GHS_PICTOGRAMS_DIR").ok();
+
+    // Safety data sheets storage directory and maximum age.
+    let sds_dir = PathBuf::from(env::var("SDS_DIR").unwrap_or(String::from("sds")));
+    let sds_max_age_days: i64 = env::var("SDS_MAX_AGE_DAYS")
+        .ok()
+        .and_then(|sds_max_age_days| sds_max_age_days.parse().ok())
+        .unwrap_or(DEFAULT_SDS_MAX_AGE_DAYS);
+
+    // Storage expiry alerts delay and job interval.
+    let expiry_warning_days: i64 = env::var("EXPIRY_WARNING_DAYS")
+        .ok()
+        .and_then(|expiry_warning_days| expiry_warning_days.parse().ok())
+        .unwrap_or(DEFAULT_EXPIRY_WARNING_DAYS);
+    let expiry_alerts_interval_hours: u64 = env::var("EXPIRY_ALERTS_INTERVAL_HOURS")
+        .ok()
+        .and_then(|expiry_alerts_interval_hours| expiry_alerts_interval_hours.parse().ok())
+        .filter(|expiry_alerts_interval_hours| *expiry_alerts_interval_hours > 0)
+        .unwrap_or(DEFAULT_EXPIRY_ALERTS_INTERVAL_HOURS);
+
+    // Idempotency keys replay window.
+    let idempotency_window_hours: i64 = env::var("IDEMPOTENCY_WINDOW_HOURS")
+        .ok()
+        .and_then(|idempotency_window_hours| idempotency_window_hours.parse().ok())
+        .filter(|idempotency_window_hours| *idempotency_window_hours > 0)
+        .unwrap_or(DEFAULT_IDEMPOTENCY_WINDOW_HOURS);
+
+    // PubChem base URL and 2D structure images cache directory.
+    let pubchem_base_url =
+        env::var("PUBCHEM_BASE_URL").unwrap_or(String::from(DEFAULT_PUBCHEM_BASE_URL));
+    let structure_cache_dir =
+        PathBuf::from(env::var("STRUCTURE_CACHE_DIR").unwrap_or(String::from("structures")));
+
+    // Chemical incompatibility rules, built-in unless a rules file is given.
+    let incompatibility_rules = match env::var("INCOMPATIBILITY_RULES_FILE") {
+        Ok(incompatibility_rules_file) => {
+            IncompatibilityRules::from_file(&incompatibility_rules_file).unwrap()
+        }
+        Err(_) => IncompatibilityRules::default(),
+    };
+
+    // Drug and explosives precursors CAS number lists, built-in unless list files are given.
+    let regulatory_lists = RegulatoryLists::from_files(
+        env::var("DRUG_PRECURSORS_FILE").ok().as_deref(),
+        env::var("EXPLOSIVES_PRECURSORS_FILE").ok().as_deref(),
+    )
+    .unwrap();
+
     // Temporary Casbin model and adapter for state initialization.
     let empty_casbin_model = DefaultModel::from_str("").await.unwrap();
     let empty_casbin_adapter = NullAdapter;
@@ -682,6 +1110,17 @@ pub async fn run(
         keycloak_realm,
         keycloak_base_url,
         pkce_store: Arc::new(Mutex::new(DashMap::new())),
+        ghs_pictograms_dir,
+        font_database: Arc::new(load_font_database()),
+        sds_dir,
+        sds_max_age_days,
+        expiry_warning_days,
+        idempotency_window_hours,
+        pubchem_base_url,
+        pubchem_http_client: reqwest::Client::new(),
+        structure_cache_dir,
+        incompatibility_rules: Arc::new(incompatibility_rules),
+        regulatory_lists: Arc::new(regulatory_lists),
         casbin_enforcer: Arc::new(Mutex::new(
             Enforcer::new(empty_casbin_model, empty_casbin_adapter)
                 .await
@@ -698,6 +1137,14 @@ pub async fn run(
     .await
     .unwrap();
 
+    // Start the storage expiry alerts job.
+    tokio::spawn(run_expiry_alerts(
+        state.db_connection_pool.clone(),
+        default_admin_id,
+        expiry_warning_days,
+        expiry_alerts_interval_hours,
+    ));
+
     //     requests
     //        |
     //        v
@@ -728,21 +1175,62 @@ pub async fn run(
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods(Any)
-        .allow_headers(Any);
+        .allow_headers(Any)
+        .expose_headers([
+            HeaderName::from_static(CHIMITHEQUE_IDEMPOTENT_REPLAY_HEADER),
+            HeaderName::from_static(CHIMITHEQUE_WARNINGS_HEADER),
+            HeaderName::from_static(CHIMITHEQUE_WARNINGS_COUNT_HEADER),
+        ]);
 
     info!("initialize routes");
 
     let app = Router::new()
         //
         .route("/getconnecteduser", get(get_connected_user))
+        .route("/getconnecteduser/locale", get(get_connected_user_locale))
+        .route(
+            "/getconnecteduser/locale",
+            put(update_connected_user_locale),
+        )
         //
         .route("/store_locations", get(get_store_locations))
+        .route(
+            "/store_locations/incompatibilities",
+            get(get_store_location_incompatibilities),
+        )
+        .route("/store_locations/tree", get(get_store_location_tree))
+        .route("/store_locations/capacity", get(get_capacity_report))
         .route("/store_locations/{id}", get(get_store_locations))
         .route("/store_locations_old", get(get_store_locations_old))
         .route("/store_locations_old/{id}", get(get_store_locations_old))
         .route("/store_locations/{id}", put(create_update_store_location))
         .route("/store_locations", post(create_update_store_location))
         .route("/store_locations/{id}", delete(delete_store_location))
+        .route("/store_locations/{id}/moves", get(get_store_location_moves))
+        .route(
+            "/store_locations/{id}/compatibility",
+            get(get_compatibility),
+        )
+        .route(
+            "/store_locations/{id}/compatibility",
+            put(update_compatibility),
+        )
+        .route(
+            "/store_locations/{id}/capacity",
+            get(get_store_location_capacity),
+        )
+        .route(
+            "/store_locations/{id}/capacity_limits",
+            get(get_capacity_limits),
+        )
+        .route(
+            "/store_locations/{id}/capacity_limits",
+            put(update_capacity_limits),
+        )
+        .route(
+            "/store_locations/{id}/reservations",
+            get(get_store_location_reservations),
+        )
         //
         .route("/f/store_locations", get(fake))
         .route("/f/store_locations/{id}", get(fake))
@@ -771,6 +1259,20 @@ pub async fn run(
         .route("/entities/{id}", put(create_update_entity))
         .route("/entities", post(create_update_entity))
         .route("/entities/{id}", delete(delete_entity))
+        .route(
+            "/entities/{id}/regulatory",
+            get(get_entity_regulatory_report),
+        )
+        .route(
+            "/entities/{id}/borrows/overdue",
+            get(get_entity_overdue_borrowings),
+        )
+        .route("/entities/{id}/expiries", get(get_entity_expiries))
+        .route(
+            "/entities/{id}/expiries/alerts",
+            get(get_entity_expiry_alerts),
+        )
+        .route("/entities/{id}/reservations", get(get_entity_reservations))
         //
         .route("/f/entities", get(fake))
         .route("/f/entities/{id}", get(fake))
@@ -781,13 +1283,41 @@ pub async fn run(
         .route("/stocks/{id}", get(get_entity_stock))
         //
         .route("/products/export", get(export_products))
+        .route("/products/bulk", post(bulk_update_products))
         .route("/products", get(get_products))
         .route("/products/{id}", get(get_products))
         .route("/products_old", get(get_products_old))
         .route("/products_old/{id}", get(get_products_old))
         .route("/products/{id}", put(create_update_product))
         .route("/products", post(create_update_product))
+        .route("/products/withstorages", post(create_product_with_storages))
         .route("/products/{id}", delete(delete_product))
+        .route("/products/{id}/label", get(get_product_label))
+        .route(
+            "/products/{id}/regulatory",
+            get(get_product_regulatory_flags),
+        )
+        .route("/products/{id}/sds", get(get_safety_data_sheets))
+        .route(
+            "/products/{id}/sds",
+            post(create_safety_data_sheet).layer(DefaultBodyLimit::max(SDS_MAX_SIZE)),
+        )
+        .route(
+            "/products/{id}/structure",
+            get(get_product_structure_identifiers),
+        )
+        .route(
+            "/products/{id}/structure",
+            put(update_product_structure_identifiers),
+        )
+        .route(
+            "/products/{id}/structure/image",
+            get(get_product_structure_image),
+        )
+        .route("/products/{id}/usages", get(get_product_usage_history))
+        .route("/products/{id}/synonyms", get(get_synonyms))
+        .route("/products/{id}/synonyms", post(add_synonym))
+        .route("/products/{id}/synonyms", put(reorder_synonyms))
         //
         .route("/f/products", get(fake))
         .route("/f/products/{id}", get(fake))
@@ -795,6 +1325,11 @@ pub async fn run(
         .route("/f/products", post(fake))
         .route("/f/products/{id}", delete(fake))
         //
+        .route("/sds/{id}", get(download_safety_data_sheet))
+        .route("/sds/{id}", delete(delete_safety_data_sheet))
+        .route("/synonyms/{id}", delete(delete_synonym))
+        .route("/synonyms/{id}/promote", put(promote_synonym))
+        //
         .route("/storages", get(get_storages))
         .route("/storages/{id}", get(get_storages))
         .route("/storages_old", get(get_storages_old))
@@ -803,8 +1338,25 @@ pub async fn run(
         .route("/storages", post(create_update_storage))
         .route("/storages/{id}", delete(delete_storage))
         .route("/storages/export", get(export_storages))
+        .route("/storages/scan", get(scan_storage))
+        .route("/storages/bulk", post(bulk_update_storages))
+        .route("/storages/move", post(bulk_move_storages))
+        .route("/storages/{id}/move", put(move_storage))
+        .route("/storages/{id}/moves", get(get_storage_moves))
+        .route("/storages/{id}/reservations", get(get_storage_reservations))
+        .route(
+            "/storages/{id}/reservations",
+            post(create_storage_reservation),
+        )
         .route("/storages/{id}/archive", delete(archive_storage))
         .route("/storages/{id}/unarchive", put(unarchive_storage))
+        .route("/storages/{id}/label", get(get_storage_label))
+        .route("/storages/{id}/barcode", get(get_storage_barcode))
+        .route("/storages/labelsheet", get(get_storage_label_sheet))
+        .route("/storages/{id}/usages", get(get_storage_usage_history))
+        .route("/storages/{id}/usages", post(create_storage_usage))
+        .route("/storages/{id}/shelflife", get(get_storage_shelf_life))
+        .route("/storages/{id}/shelflife", put(update_storage_shelf_life))
         //
         .route("/f/storages", get(fake))
         .route("/f/storages/{id}", get(fake))
@@ -832,6 +1384,10 @@ pub async fn run(
             "/products/pubchemproduct/{id}",
             post(pubchem_create_update_product),
         )
+        .route(
+            "/products/pubchemproduct/withstorages",
+            post(pubchem_create_product_with_storages),
+        )
         //
         .route("/storages/units", get(get_units))
         .route("/storages/units_old", get(get_units_old))
@@ -889,10 +1445,46 @@ pub async fn run(
         .route("/products/supplierrefs_old", get(get_supplier_refs_old))
         .route("/products/producers", post(create_producer))
         .route("/products/suppliers", post(create_supplier))
+        .route(
+            "/products/statementtranslations",
+            post(import_statement_translations_csv),
+        )
         //
-        .route("/bookmarks/{id}", get(toogle_bookmark))
+        .route("/bookmarks", get(get_bookmarks))
+        .route("/bookmarks/labels", get(get_bookmark_labels))
+        .route("/bookmarks/{id}", put(create_update_bookmark))
+        .route("/bookmarks/{id}", delete(delete_bookmark))
         //
-        .route("/borrows/{id}", get(toogle_borrowing))
+        .route("/borrows", get(get_connected_user_borrowings))
+        .route("/borrows/lent", get(get_connected_user_lendings))
+        .route("/borrows/{id}", post(create_borrowing))
+        .route("/borrows/{id}", delete(delete_borrowing))
+        .route("/borrows/{id}/history", get(get_borrowing_history))
+        //
+        .route("/inventories", get(get_inventory_campaigns))
+        .route("/inventories", post(create_inventory_campaign))
+        .route("/inventories/{id}", get(get_inventory_campaign))
+        .route("/inventories/{id}/scans", post(create_inventory_scan))
+        .route("/inventories/{id}/report", get(get_inventory_report))
+        .route("/inventories/{id}/close", put(close_inventory_campaign))
+        .route(
+            "/inventories/{id}/archive_missing",
+            post(archive_missing_storages),
+        )
+        .route(
+            "/inventories/{id}/relocate_misplaced",
+            post(relocate_misplaced_storages),
+        )
+        //
+        .route("/reservations/{id}", get(get_storage_reservation))
+        .route("/reservations/{id}", delete(delete_storage_reservation))
+        //
+        .route("/wastebatches", get(get_waste_batches))
+        .route("/wastebatches", post(create_waste_batch))
+        .route("/wastebatches/pending", get(get_pending_waste))
+        .route("/wastebatches/{id}", get(get_waste_batch))
+        .route("/wastebatches/{id}/pickup", put(pickup_waste_batch))
+        .route("/wastebatches/{id}/manifest", get(get_waste_manifest))
         //
         .route("/validate/email/{email}", get(validate_email))
         .route("/validate/casnumber/{cas_number}", get(validate_cas_number))
@@ -902,6 +1494,10 @@ pub async fn run(
             get(validate_empirical_formula),
         )
         //
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            idempotency_middleware,
+        ))
         .layer(middleware::from_fn_with_state(
             state.clone(),
             authorize_middleware,
@@ -0,0 +1,141 @@
+use chimitheque_types::storelocation::StoreLocation;
+use chrono::Local;
+use rusqlite::{Connection, Row, params};
+use serde::Serialize;
+
+use crate::errors::AppError;
+
+pub const MOVE_DATETIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+// A storage move from a store location to another.
+// The store location names are the ones at the time of the move.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct StorageMove {
+    pub storage_move_id: u64,
+    pub storage_id: u64,
+    pub from_store_location_id: Option<u64>,
+    pub from_store_location_name: String,
+    pub to_store_location_id: Option<u64>,
+    pub to_store_location_name: String,
+    pub person_id: u64,
+    pub moved_at: String,
+}
+
+const STORAGE_MOVE_COLUMNS: &str = "storage_move_id, storage_move_storage_id, storage_move_from_store_location_id, storage_move_from_store_location_name, storage_move_to_store_location_id, storage_move_to_store_location_name, storage_move_person_id, storage_move_moved_at";
+
+fn storage_move_from_row(row: &Row) -> Result<StorageMove, rusqlite::Error> {
+    Ok(StorageMove {
+        storage_move_id: row.get(0)?,
+        storage_id: row.get(1)?,
+        from_store_location_id: row.get(2)?,
+        from_store_location_name: row.get(3)?,
+        to_store_location_id: row.get(4)?,
+        to_store_location_name: row.get(5)?,
+        person_id: row.get(6)?,
+        moved_at: row.get(7)?,
+    })
+}
+
+// Create the storage moves table.
+pub fn init_location_history(db_connection: &Connection) -> Result<(), AppError> {
+    match db_connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS storage_move (
+            storage_move_id INTEGER PRIMARY KEY,
+            storage_move_storage_id INTEGER NOT NULL,
+            storage_move_from_store_location_id INTEGER,
+            storage_move_from_store_location_name TEXT NOT NULL,
+            storage_move_to_store_location_id INTEGER,
+            storage_move_to_store_location_name TEXT NOT NULL,
+            storage_move_person_id INTEGER NOT NULL,
+            storage_move_moved_at TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS idx_storage_move_storage ON storage_move(storage_move_storage_id);",
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// Record that a storage was moved now by the given person.
+pub fn record_storage_move(
+    db_connection: &Connection,
+    storage_id: u64,
+    from_store_location: &StoreLocation,
+    to_store_location: &StoreLocation,
+    chimitheque_person_id: u64,
+) -> Result<StorageMove, AppError> {
+    let mut storage_move = StorageMove {
+        storage_id,
+        from_store_location_id: from_store_location.store_location_id,
+        from_store_location_name: from_store_location.store_location_name.clone(),
+        to_store_location_id: to_store_location.store_location_id,
+        to_store_location_name: to_store_location.store_location_name.clone(),
+        person_id: chimitheque_person_id,
+        moved_at: Local::now().format(MOVE_DATETIME_FORMAT).to_string(),
+        ..Default::default()
+    };
+
+    match db_connection.execute(
+        "INSERT INTO storage_move (storage_move_storage_id, storage_move_from_store_location_id, storage_move_from_store_location_name, storage_move_to_store_location_id, storage_move_to_store_location_name, storage_move_person_id, storage_move_moved_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            storage_move.storage_id,
+            storage_move.from_store_location_id,
+            storage_move.from_store_location_name,
+            storage_move.to_store_location_id,
+            storage_move.to_store_location_name,
+            storage_move.person_id,
+            storage_move.moved_at
+        ],
+    ) {
+        Ok(_) => {
+            storage_move.storage_move_id = db_connection.last_insert_rowid() as u64;
+            Ok(storage_move)
+        }
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+fn query_storage_moves(
+    db_connection: &Connection,
+    filter: &str,
+    id: u64,
+) -> Result<Vec<StorageMove>, AppError> {
+    let sql = format!(
+        "SELECT {} FROM storage_move WHERE {} ORDER BY storage_move_moved_at DESC, storage_move_id DESC",
+        STORAGE_MOVE_COLUMNS, filter
+    );
+
+    let mut stmt = match db_connection.prepare(&sql) {
+        Ok(stmt) => stmt,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    match stmt.query_map(params![id], storage_move_from_row) {
+        Ok(rows) => match rows.collect::<Result<Vec<StorageMove>, rusqlite::Error>>() {
+            Ok(storage_moves) => Ok(storage_moves),
+            Err(err) => Err(AppError::Database(err.to_string())),
+        },
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// The moves of a storage, most recent first.
+pub fn get_storage_moves(
+    db_connection: &Connection,
+    storage_id: u64,
+) -> Result<Vec<StorageMove>, AppError> {
+    query_storage_moves(db_connection, "storage_move_storage_id = ?1", storage_id)
+}
+
+// The moves from or to a store location, most recent first.
+pub fn get_store_location_moves(
+    db_connection: &Connection,
+    store_location_id: u64,
+) -> Result<Vec<StorageMove>, AppError> {
+    query_storage_moves(
+        db_connection,
+        "storage_move_from_store_location_id = ?1 OR storage_move_to_store_location_id = ?1",
+        store_location_id,
+    )
+}
@@ -0,0 +1,163 @@
+use chimitheque_types::product::Product;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+
+use crate::errors::AppError;
+
+// Hazard statement prefixes of the CMR (carcinogenic, mutagenic, reprotoxic)
+// category 1A and 1B products.
+const CMR_HAZARD_STATEMENTS: [&str; 3] = ["H340", "H350", "H360"];
+
+// Hazard statement prefixes of the suspected CMR (category 2) products.
+const SUSPECTED_CMR_HAZARD_STATEMENTS: [&str; 3] = ["H341", "H351", "H361"];
+
+// Default EU drug precursors CAS numbers (regulation (EC) No 273/2004, annex I).
+const DEFAULT_DRUG_PRECURSORS: [&str; 24] = [
+    "299-42-3",   // ephedrine
+    "90-82-4",    // pseudoephedrine
+    "14838-15-4", // norephedrine
+    "60-79-7",    // ergometrine
+    "113-15-5",   // ergotamine
+    "82-58-6",    // lysergic acid
+    "103-79-7",   // 1-phenyl-2-propanone
+    "4676-39-5",  // 3,4-methylenedioxyphenylpropan-2-one
+    "4468-48-8",  // alpha-phenylacetoacetonitrile
+    "94-59-7",    // safrole
+    "120-58-1",   // isosafrole
+    "120-57-0",   // piperonal
+    "89-52-1",    // N-acetylanthranilic acid
+    "108-24-7",   // acetic anhydride
+    "103-82-2",   // phenylacetic acid
+    "118-92-3",   // anthranilic acid
+    "110-89-4",   // piperidine
+    "7722-64-7",  // potassium permanganate
+    "67-64-1",    // acetone
+    "60-29-7",    // ethyl ether
+    "78-93-3",    // methyl ethyl ketone
+    "108-88-3",   // toluene
+    "7664-93-9",  // sulphuric acid
+    "7647-01-0",  // hydrochloric acid
+];
+
+// Default EU explosives precursors CAS numbers (regulation (EU) 2019/1148, annexes I and II).
+const DEFAULT_EXPLOSIVES_PRECURSORS: [&str; 18] = [
+    "7722-84-1",  // hydrogen peroxide
+    "75-52-5",    // nitromethane
+    "7697-37-2",  // nitric acid
+    "3811-04-9",  // potassium chlorate
+    "7778-74-7",  // potassium perchlorate
+    "7775-09-9",  // sodium chlorate
+    "7601-89-0",  // sodium perchlorate
+    "7664-93-9",  // sulphuric acid
+    "100-97-0",   // hexamine
+    "67-64-1",    // acetone
+    "7757-79-1",  // potassium nitrate
+    "7631-99-4",  // sodium nitrate
+    "10124-37-5", // calcium nitrate
+    "15245-12-2", // calcium ammonium nitrate
+    "6484-52-2",  // ammonium nitrate
+    "7429-90-5",  // aluminium powder
+    "7439-95-4",  // magnesium powder
+    "13446-18-9", // magnesium nitrate hexahydrate
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum RegulatoryFlag {
+    Cmr,
+    SuspectedCmr,
+    DrugPrecursor,
+    ExplosivesPrecursor,
+}
+
+// The CAS number lists used to classify the products.
+#[derive(Debug, Clone)]
+pub struct RegulatoryLists {
+    pub drug_precursors: HashSet<String>,
+    pub explosives_precursors: HashSet<String>,
+}
+
+impl Default for RegulatoryLists {
+    fn default() -> Self {
+        RegulatoryLists {
+            drug_precursors: DEFAULT_DRUG_PRECURSORS
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            explosives_precursors: DEFAULT_EXPLOSIVES_PRECURSORS
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+        }
+    }
+}
+
+// Read a CAS number list file: one CAS number per line, "#" starts a comment.
+fn read_cas_number_list(path: &str) -> Result<HashSet<String>, AppError> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) => return Err(AppError::RegulatoryLists(format!("{}: {}", path, err))),
+    };
+
+    Ok(content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| line.to_string())
+        .collect())
+}
+
+impl RegulatoryLists {
+    // Build the lists, replacing the default ones by the given files if any.
+    pub fn from_files(
+        drug_precursors_file: Option<&str>,
+        explosives_precursors_file: Option<&str>,
+    ) -> Result<Self, AppError> {
+        let mut regulatory_lists = RegulatoryLists::default();
+
+        if let Some(drug_precursors_file) = drug_precursors_file {
+            regulatory_lists.drug_precursors = read_cas_number_list(drug_precursors_file)?;
+        }
+        if let Some(explosives_precursors_file) = explosives_precursors_file {
+            regulatory_lists.explosives_precursors =
+                read_cas_number_list(explosives_precursors_file)?;
+        }
+
+        Ok(regulatory_lists)
+    }
+
+    // Classify a product from its hazard statements and CAS number.
+    pub fn classify(&self, product: &Product) -> BTreeSet<RegulatoryFlag> {
+        let mut flags: BTreeSet<RegulatoryFlag> = BTreeSet::new();
+
+        for hazard_statement in product.hazard_statements.iter().flatten() {
+            let reference = hazard_statement.hazard_statement_reference.to_uppercase();
+
+            if CMR_HAZARD_STATEMENTS
+                .iter()
+                .any(|prefix| reference.starts_with(prefix))
+            {
+                flags.insert(RegulatoryFlag::Cmr);
+            }
+            if SUSPECTED_CMR_HAZARD_STATEMENTS
+                .iter()
+                .any(|prefix| reference.starts_with(prefix))
+            {
+                flags.insert(RegulatoryFlag::SuspectedCmr);
+            }
+        }
+
+        if let Some(cas_number) = &product.cas_number {
+            let cas_number = cas_number.cas_number_label.trim();
+
+            if self.drug_precursors.contains(cas_number) {
+                flags.insert(RegulatoryFlag::DrugPrecursor);
+            }
+            if self.explosives_precursors.contains(cas_number) {
+                flags.insert(RegulatoryFlag::ExplosivesPrecursor);
+            }
+        }
+
+        flags
+    }
+}
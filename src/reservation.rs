@@ -0,0 +1,251 @@
+use rusqlite::{Connection, OptionalExtension, Row, params};
+use serde::Serialize;
+
+use crate::errors::AppError;
+
+pub const RESERVATION_DATETIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+// A storage reserved by a person for a time window,
+// formatted with RESERVATION_DATETIME_FORMAT.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct StorageReservation {
+    pub storage_reservation_id: u64,
+    pub storage_id: u64,
+    // The person the storage is reserved for.
+    pub person_id: u64,
+    pub purpose: String,
+    pub starts_at: String,
+    pub ends_at: String,
+    // The person who recorded the reservation.
+    pub created_by: u64,
+    pub created_at: String,
+}
+
+const STORAGE_RESERVATION_COLUMNS: &str = "storage_reservation_id, storage_reservation_storage_id, storage_reservation_person_id, storage_reservation_purpose, storage_reservation_starts_at, storage_reservation_ends_at, storage_reservation_created_by, storage_reservation_created_at";
+
+fn storage_reservation_from_row(row: &Row) -> Result<StorageReservation, rusqlite::Error> {
+    Ok(StorageReservation {
+        storage_reservation_id: row.get(0)?,
+        storage_id: row.get(1)?,
+        person_id: row.get(2)?,
+        purpose: row.get(3)?,
+        starts_at: row.get(4)?,
+        ends_at: row.get(5)?,
+        created_by: row.get(6)?,
+        created_at: row.get(7)?,
+    })
+}
+
+fn query_storage_reservations(
+    db_connection: &Connection,
+    filter: &str,
+    params: impl rusqlite::Params,
+) -> Result<Vec<StorageReservation>, AppError> {
+    let sql = format!(
+        "SELECT {} FROM storage_reservation WHERE {} ORDER BY storage_reservation_starts_at, storage_reservation_id",
+        STORAGE_RESERVATION_COLUMNS, filter
+    );
+
+    let mut stmt = match db_connection.prepare(&sql) {
+        Ok(stmt) => stmt,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    match stmt.query_map(params, storage_reservation_from_row) {
+        Ok(rows) => match rows.collect::<Result<Vec<StorageReservation>, rusqlite::Error>>() {
+            Ok(storage_reservations) => Ok(storage_reservations),
+            Err(err) => Err(AppError::Database(err.to_string())),
+        },
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// Create the storage reservations table.
+pub fn init_reservation(db_connection: &Connection) -> Result<(), AppError> {
+    match db_connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS storage_reservation (
+            storage_reservation_id INTEGER PRIMARY KEY,
+            storage_reservation_storage_id INTEGER NOT NULL,
+            storage_reservation_person_id INTEGER NOT NULL,
+            storage_reservation_purpose TEXT NOT NULL,
+            storage_reservation_starts_at TEXT NOT NULL,
+            storage_reservation_ends_at TEXT NOT NULL,
+            storage_reservation_created_by INTEGER NOT NULL,
+            storage_reservation_created_at TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS idx_storage_reservation_storage ON storage_reservation(storage_reservation_storage_id);",
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+pub fn insert_storage_reservation(
+    db_connection: &Connection,
+    storage_reservation: &StorageReservation,
+) -> Result<u64, AppError> {
+    match db_connection.execute(
+        "INSERT INTO storage_reservation (storage_reservation_storage_id, storage_reservation_person_id, storage_reservation_purpose, storage_reservation_starts_at, storage_reservation_ends_at, storage_reservation_created_by, storage_reservation_created_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            storage_reservation.storage_id,
+            storage_reservation.person_id,
+            storage_reservation.purpose,
+            storage_reservation.starts_at,
+            storage_reservation.ends_at,
+            storage_reservation.created_by,
+            storage_reservation.created_at
+        ],
+    ) {
+        Ok(_) => Ok(db_connection.last_insert_rowid() as u64),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+pub fn delete_storage_reservation(
+    db_connection: &Connection,
+    storage_reservation_id: u64,
+) -> Result<(), AppError> {
+    match db_connection.execute(
+        "DELETE FROM storage_reservation WHERE storage_reservation_id = ?1",
+        params![storage_reservation_id],
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+pub fn get_storage_reservation(
+    db_connection: &Connection,
+    storage_reservation_id: u64,
+) -> Result<Option<StorageReservation>, AppError> {
+    let sql = format!(
+        "SELECT {} FROM storage_reservation WHERE storage_reservation_id = ?1",
+        STORAGE_RESERVATION_COLUMNS
+    );
+
+    match db_connection
+        .query_row(
+            &sql,
+            params![storage_reservation_id],
+            storage_reservation_from_row,
+        )
+        .optional()
+    {
+        Ok(storage_reservation) => Ok(storage_reservation),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// The reservations of a storage ending after the given date.
+pub fn get_storage_reservations(
+    db_connection: &Connection,
+    storage_id: u64,
+    ending_after: &str,
+) -> Result<Vec<StorageReservation>, AppError> {
+    query_storage_reservations(
+        db_connection,
+        "storage_reservation_storage_id = ?1 AND storage_reservation_ends_at >= ?2",
+        params![storage_id, ending_after],
+    )
+}
+
+// The reservations of all the storages ending after the given date.
+pub fn get_upcoming_storage_reservations(
+    db_connection: &Connection,
+    ending_after: &str,
+) -> Result<Vec<StorageReservation>, AppError> {
+    query_storage_reservations(
+        db_connection,
+        "storage_reservation_ends_at >= ?1",
+        params![ending_after],
+    )
+}
+
+// The reservations of a storage overlapping the given time window.
+pub fn get_overlapping_storage_reservations(
+    db_connection: &Connection,
+    storage_id: u64,
+    starts_at: &str,
+    ends_at: &str,
+) -> Result<Vec<StorageReservation>, AppError> {
+    query_storage_reservations(
+        db_connection,
+        "storage_reservation_storage_id = ?1 AND storage_reservation_starts_at <= ?3 AND storage_reservation_ends_at >= ?2",
+        params![storage_id, starts_at, ends_at],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage_reservation(storage_id: u64, starts_at: &str, ends_at: &str) -> StorageReservation {
+        StorageReservation {
+            storage_id,
+            person_id: 1,
+            purpose: String::from("experiment"),
+            starts_at: starts_at.to_string(),
+            ends_at: ends_at.to_string(),
+            created_by: 1,
+            created_at: String::from("2025-01-01 08:00:00"),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn get_overlapping_storage_reservations_of_a_window() {
+        let db_connection = Connection::open_in_memory().unwrap();
+        init_reservation(&db_connection).unwrap();
+
+        let morning_id = insert_storage_reservation(
+            &db_connection,
+            &storage_reservation(1, "2025-01-02 08:00:00", "2025-01-02 12:00:00"),
+        )
+        .unwrap();
+        let afternoon_id = insert_storage_reservation(
+            &db_connection,
+            &storage_reservation(1, "2025-01-02 14:00:00", "2025-01-02 18:00:00"),
+        )
+        .unwrap();
+        // Another storage.
+        insert_storage_reservation(
+            &db_connection,
+            &storage_reservation(2, "2025-01-02 08:00:00", "2025-01-02 18:00:00"),
+        )
+        .unwrap();
+
+        let overlapping_ids = |starts_at: &str, ends_at: &str| -> Vec<u64> {
+            get_overlapping_storage_reservations(&db_connection, 1, starts_at, ends_at)
+                .unwrap()
+                .iter()
+                .map(|storage_reservation| storage_reservation.storage_reservation_id)
+                .collect()
+        };
+
+        assert_eq!(
+            overlapping_ids("2025-01-02 11:00:00", "2025-01-02 15:00:00"),
+            vec![morning_id, afternoon_id]
+        );
+        assert_eq!(
+            overlapping_ids("2025-01-02 12:30:00", "2025-01-02 13:30:00"),
+            Vec::<u64>::new()
+        );
+        // A window inside a reservation.
+        assert_eq!(
+            overlapping_ids("2025-01-02 15:00:00", "2025-01-02 16:00:00"),
+            vec![afternoon_id]
+        );
+        // The bounds are included.
+        assert_eq!(
+            overlapping_ids("2025-01-02 12:00:00", "2025-01-02 14:00:00"),
+            vec![morning_id, afternoon_id]
+        );
+
+        delete_storage_reservation(&db_connection, morning_id).unwrap();
+        assert_eq!(
+            overlapping_ids("2025-01-02 00:00:00", "2025-01-03 00:00:00"),
+            vec![afternoon_id]
+        );
+    }
+}
@@ -0,0 +1,190 @@
+use rusqlite::{Connection, OptionalExtension, Row, params};
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::errors::AppError;
+
+pub const SAFETY_DATA_SHEET_DATE_FORMAT: &str = "%Y-%m-%d";
+pub const SAFETY_DATA_SHEET_DATETIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+// A safety data sheet revision of a product. The PDF file is stored in
+// the SDS directory under its content hash.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct SafetyDataSheet {
+    pub safety_data_sheet_id: u64,
+    pub product_id: u64,
+    pub safety_data_sheet_filename: String,
+    pub safety_data_sheet_hash: String,
+    // Formatted with SAFETY_DATA_SHEET_DATE_FORMAT.
+    pub safety_data_sheet_revision_date: String,
+    pub safety_data_sheet_supplier: Option<String>,
+    // ISO 639-1 language code, such as "en" or "fr".
+    pub safety_data_sheet_language: String,
+    pub safety_data_sheet_created_at: String,
+}
+
+const SAFETY_DATA_SHEET_COLUMNS: &str = "safety_data_sheet_id, safety_data_sheet_product_id, safety_data_sheet_filename, safety_data_sheet_hash, safety_data_sheet_revision_date, safety_data_sheet_supplier, safety_data_sheet_language, safety_data_sheet_created_at";
+
+fn safety_data_sheet_from_row(row: &Row) -> Result<SafetyDataSheet, rusqlite::Error> {
+    Ok(SafetyDataSheet {
+        safety_data_sheet_id: row.get(0)?,
+        product_id: row.get(1)?,
+        safety_data_sheet_filename: row.get(2)?,
+        safety_data_sheet_hash: row.get(3)?,
+        safety_data_sheet_revision_date: row.get(4)?,
+        safety_data_sheet_supplier: row.get(5)?,
+        safety_data_sheet_language: row.get(6)?,
+        safety_data_sheet_created_at: row.get(7)?,
+    })
+}
+
+// Create the safety data sheets table.
+pub fn init_safety_data_sheet(db_connection: &Connection) -> Result<(), AppError> {
+    match db_connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS safety_data_sheet (
+            safety_data_sheet_id INTEGER PRIMARY KEY,
+            safety_data_sheet_product_id INTEGER NOT NULL,
+            safety_data_sheet_filename TEXT NOT NULL,
+            safety_data_sheet_hash TEXT NOT NULL,
+            safety_data_sheet_revision_date TEXT NOT NULL,
+            safety_data_sheet_supplier TEXT,
+            safety_data_sheet_language TEXT NOT NULL,
+            safety_data_sheet_created_at TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS idx_safety_data_sheet_product ON safety_data_sheet(safety_data_sheet_product_id);
+        CREATE INDEX IF NOT EXISTS idx_safety_data_sheet_hash ON safety_data_sheet(safety_data_sheet_hash);",
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+pub fn insert_safety_data_sheet(
+    db_connection: &Connection,
+    safety_data_sheet: &SafetyDataSheet,
+) -> Result<u64, AppError> {
+    match db_connection.execute(
+        "INSERT INTO safety_data_sheet (safety_data_sheet_product_id, safety_data_sheet_filename, safety_data_sheet_hash, safety_data_sheet_revision_date, safety_data_sheet_supplier, safety_data_sheet_language, safety_data_sheet_created_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            safety_data_sheet.product_id,
+            safety_data_sheet.safety_data_sheet_filename,
+            safety_data_sheet.safety_data_sheet_hash,
+            safety_data_sheet.safety_data_sheet_revision_date,
+            safety_data_sheet.safety_data_sheet_supplier,
+            safety_data_sheet.safety_data_sheet_language,
+            safety_data_sheet.safety_data_sheet_created_at
+        ],
+    ) {
+        Ok(_) => Ok(db_connection.last_insert_rowid() as u64),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+pub fn delete_safety_data_sheet(
+    db_connection: &Connection,
+    safety_data_sheet_id: u64,
+) -> Result<(), AppError> {
+    match db_connection.execute(
+        "DELETE FROM safety_data_sheet WHERE safety_data_sheet_id = ?1",
+        params![safety_data_sheet_id],
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+pub fn get_safety_data_sheet(
+    db_connection: &Connection,
+    safety_data_sheet_id: u64,
+) -> Result<Option<SafetyDataSheet>, AppError> {
+    let sql = format!(
+        "SELECT {} FROM safety_data_sheet WHERE safety_data_sheet_id = ?1",
+        SAFETY_DATA_SHEET_COLUMNS
+    );
+
+    match db_connection
+        .query_row(
+            &sql,
+            params![safety_data_sheet_id],
+            safety_data_sheet_from_row,
+        )
+        .optional()
+    {
+        Ok(safety_data_sheet) => Ok(safety_data_sheet),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// The safety data sheets of a product, most recent revision first.
+pub fn get_safety_data_sheets(
+    db_connection: &Connection,
+    product_id: u64,
+) -> Result<Vec<SafetyDataSheet>, AppError> {
+    let sql = format!(
+        "SELECT {} FROM safety_data_sheet WHERE safety_data_sheet_product_id = ?1
+        ORDER BY safety_data_sheet_revision_date DESC, safety_data_sheet_id DESC",
+        SAFETY_DATA_SHEET_COLUMNS
+    );
+
+    let mut stmt = match db_connection.prepare(&sql) {
+        Ok(stmt) => stmt,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    match stmt.query_map(params![product_id], safety_data_sheet_from_row) {
+        Ok(rows) => match rows.collect::<Result<Vec<SafetyDataSheet>, rusqlite::Error>>() {
+            Ok(safety_data_sheets) => Ok(safety_data_sheets),
+            Err(err) => Err(AppError::Database(err.to_string())),
+        },
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// The number of safety data sheets referencing the file with the given hash.
+pub fn count_safety_data_sheets_with_hash(
+    db_connection: &Connection,
+    hash: &str,
+) -> Result<u64, AppError> {
+    match db_connection.query_row(
+        "SELECT COUNT(*) FROM safety_data_sheet WHERE safety_data_sheet_hash = ?1",
+        params![hash],
+        |row| row.get(0),
+    ) {
+        Ok(count) => Ok(count),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// The most recent revision date of the safety data sheets of the given
+// products, for the products having at least one.
+pub fn get_latest_revision_dates(
+    db_connection: &Connection,
+    product_ids: &[u64],
+) -> Result<HashMap<u64, String>, AppError> {
+    if product_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let sql = format!(
+        "SELECT safety_data_sheet_product_id, MAX(safety_data_sheet_revision_date) FROM safety_data_sheet
+        WHERE safety_data_sheet_product_id IN ({})
+        GROUP BY safety_data_sheet_product_id",
+        vec!["?"; product_ids.len()].join(", ")
+    );
+
+    let mut stmt = match db_connection.prepare(&sql) {
+        Ok(stmt) => stmt,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    match stmt.query_map(rusqlite::params_from_iter(product_ids.iter()), |row| {
+        Ok((row.get::<_, u64>(0)?, row.get::<_, String>(1)?))
+    }) {
+        Ok(rows) => match rows.collect::<Result<HashMap<u64, String>, rusqlite::Error>>() {
+            Ok(latest_revision_dates) => Ok(latest_revision_dates),
+            Err(err) => Err(AppError::Database(err.to_string())),
+        },
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
@@ -0,0 +1,490 @@
+use chimitheque_types::{product::Product, requestfilter::RequestFilter};
+use rusqlite::{Connection, params};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use tracing::info;
+
+use crate::{errors::AppError, ghs_label::xml_escape, structure::get_structure_identifiers};
+
+// Number of matches returned when the request has no limit.
+pub const SEARCH_DEFAULT_LIMIT: u64 = 100;
+
+// Products are read by pages when the index is rebuilt.
+const REBUILD_PAGE_SIZE: u64 = 1000;
+
+// The matches are delimited by private use characters in the highlighted
+// text, replaced by the HTML marks once the text is escaped.
+const HIGHLIGHT_START: char = '\u{E000}';
+const HIGHLIGHT_END: char = '\u{E001}';
+const HIGHLIGHT_START_HTML: &str = "<mark>";
+const HIGHLIGHT_END_HTML: &str = "</mark>";
+
+// The indexed columns, in the product_fts table order.
+// The product ID is the rowid of the table.
+const INDEXED_COLUMNS: [&str; 8] = [
+    "name",
+    "synonyms",
+    "cas_number",
+    "ce_number",
+    "formulas",
+    "structure",
+    "tags",
+    "comments",
+];
+
+// A full-text search match: the product ID, its rank (lower is better)
+// and the highlighted text of the matching columns, HTML escaped.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct SearchMatch {
+    pub product_id: u64,
+    pub rank: f64,
+    pub highlights: BTreeMap<String, String>,
+}
+
+// Join the labels, without the highlight delimiters.
+fn join_labels<'a>(labels: impl Iterator<Item = &'a str>) -> String {
+    labels
+        .filter(|label| !label.is_empty())
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .replace([HIGHLIGHT_START, HIGHLIGHT_END], "")
+}
+
+// Escape a highlighted text for HTML, then mark its matches.
+fn highlight_html(text: &str) -> String {
+    xml_escape(text)
+        .replace(HIGHLIGHT_START, HIGHLIGHT_START_HTML)
+        .replace(HIGHLIGHT_END, HIGHLIGHT_END_HTML)
+}
+
+// Create the full-text search index, and fill it when it is empty.
+pub fn init_product_index(
+    db_connection: &Connection,
+    chimitheque_person_id: u64,
+) -> Result<(), AppError> {
+    // An index created without the structure column, or with the product ID
+    // in a column instead of the rowid, is created again.
+    if db_connection
+        .prepare("SELECT structure FROM product_fts LIMIT 0")
+        .is_err()
+        || db_connection
+            .prepare("SELECT product_id FROM product_fts LIMIT 0")
+            .is_ok()
+    {
+        match db_connection.execute_batch("DROP TABLE IF EXISTS product_fts;") {
+            Ok(_) => (),
+            Err(err) => return Err(AppError::Database(err.to_string())),
+        }
+    }
+
+    if let Err(err) = db_connection.execute_batch(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS product_fts USING fts5(
+            name,
+            synonyms,
+            cas_number,
+            ce_number,
+            formulas,
+            structure,
+            tags,
+            comments,
+            tokenize = 'unicode61 remove_diacritics 2'
+        );",
+    ) {
+        return Err(AppError::Database(err.to_string()));
+    }
+
+    let nb_indexed: u64 =
+        match db_connection.query_row("SELECT count(*) FROM product_fts", [], |row| row.get(0)) {
+            Ok(nb_indexed) => nb_indexed,
+            Err(err) => return Err(AppError::Database(err.to_string())),
+        };
+
+    if nb_indexed == 0 {
+        rebuild_product_index(db_connection, chimitheque_person_id)?;
+    }
+
+    Ok(())
+}
+
+// Index all the products again.
+pub fn rebuild_product_index(
+    db_connection: &Connection,
+    chimitheque_person_id: u64,
+) -> Result<(), AppError> {
+    info!("rebuilding the product full-text search index");
+
+    if let Err(err) = db_connection.execute("DELETE FROM product_fts", []) {
+        return Err(AppError::Database(err.to_string()));
+    }
+
+    let mut offset: u64 = 0;
+    loop {
+        let products = match chimitheque_db::product::get_products(
+            db_connection,
+            RequestFilter {
+                offset: Some(offset),
+                limit: Some(REBUILD_PAGE_SIZE),
+                ..Default::default()
+            },
+            chimitheque_person_id,
+        ) {
+            Ok((products, _)) => products,
+            Err(err) => return Err(AppError::Database(err.to_string())),
+        };
+
+        for product in products.iter() {
+            index_product(db_connection, product)?;
+        }
+
+        if (products.len() as u64) < REBUILD_PAGE_SIZE {
+            break;
+        }
+        offset += REBUILD_PAGE_SIZE;
+    }
+
+    Ok(())
+}
+
+// Add or replace a product in the index.
+pub fn index_product(db_connection: &Connection, product: &Product) -> Result<(), AppError> {
+    let Some(product_id) = product.product_id else {
+        return Ok(());
+    };
+
+    unindex_product(db_connection, product_id)?;
+
+    let synonyms = join_labels(
+        product
+            .synonyms
+            .iter()
+            .flatten()
+            .map(|synonym| synonym.name_label.as_str()),
+    );
+    let cas_number = product
+        .cas_number
+        .as_ref()
+        .map(|cas_number| cas_number.cas_number_label.clone())
+        .unwrap_or_default();
+    let ce_number = product
+        .ce_number
+        .as_ref()
+        .map(|ce_number| ce_number.ce_number_label.clone())
+        .unwrap_or_default();
+    let formulas = join_labels(
+        [
+            product
+                .empirical_formula
+                .as_ref()
+                .map(|empirical_formula| empirical_formula.empirical_formula_label.as_str()),
+            product
+                .linear_formula
+                .as_ref()
+                .map(|linear_formula| linear_formula.linear_formula_label.as_str()),
+        ]
+        .into_iter()
+        .flatten(),
+    );
+    // The structure identifiers stored locally, see the structure module.
+    let structure_identifiers =
+        get_structure_identifiers(db_connection, product_id)?.unwrap_or_default();
+    let pubchem_cid = structure_identifiers
+        .product_pubchem_cid
+        .map(|pubchem_cid| pubchem_cid.to_string());
+    let structure = join_labels(
+        [
+            pubchem_cid.as_deref(),
+            structure_identifiers.product_smiles.as_deref(),
+            structure_identifiers.product_inchi.as_deref(),
+            structure_identifiers.product_inchikey.as_deref(),
+        ]
+        .into_iter()
+        .flatten(),
+    );
+    let tags = join_labels(
+        product
+            .tags
+            .iter()
+            .flatten()
+            .map(|tag| tag.tag_label.as_str()),
+    );
+    let comments = join_labels(
+        [
+            product.product_remark.as_deref(),
+            product.product_disposal_comment.as_deref(),
+        ]
+        .into_iter()
+        .flatten(),
+    );
+
+    let name = join_labels(std::iter::once(product.name.name_label.as_str()));
+
+    match db_connection.execute(
+        "INSERT INTO product_fts (rowid, name, synonyms, cas_number, ce_number, formulas, structure, tags, comments)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            product_id,
+            name,
+            synonyms,
+            cas_number,
+            ce_number,
+            formulas,
+            structure,
+            tags,
+            comments
+        ],
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// Index a product again from its stored version.
+pub fn reindex_product(
+    db_connection: &Connection,
+    product_id: u64,
+    chimitheque_person_id: u64,
+) -> Result<(), AppError> {
+    match chimitheque_db::product::get_products(
+        db_connection,
+        RequestFilter {
+            id: Some(product_id),
+            ..Default::default()
+        },
+        chimitheque_person_id,
+    ) {
+        Ok((products, _)) => match products.first() {
+            Some(product) => index_product(db_connection, product),
+            None => unindex_product(db_connection, product_id),
+        },
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+pub fn unindex_product(db_connection: &Connection, product_id: u64) -> Result<(), AppError> {
+    match db_connection.execute(
+        "DELETE FROM product_fts WHERE rowid = ?1",
+        params![product_id],
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// Build a FTS5 query from the user query: every word must match,
+// as a prefix, the FTS5 syntax characters being quoted.
+fn fts_query(q: &str) -> Option<String> {
+    let terms: Vec<String> = q
+        .split_whitespace()
+        .map(|term| format!("\"{}\"*", term.replace('"', "\"\"")))
+        .collect();
+
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" "))
+    }
+}
+
+// Return a page of the products matching the query, best first, and the
+// total number of matches.
+// The restricted products are only matched for the people allowed to
+// read them, so that the total and the pages only count visible products.
+pub fn search_products(
+    db_connection: &Connection,
+    q: &str,
+    with_restricted: bool,
+    offset: u64,
+    limit: u64,
+) -> Result<(Vec<SearchMatch>, usize), AppError> {
+    let Some(fts_query) = fts_query(q) else {
+        return Err(AppError::InputValidation(String::from(
+            "empty search query",
+        )));
+    };
+
+    let from_where = "FROM product_fts JOIN product ON product.product_id = product_fts.rowid
+        WHERE product_fts MATCH ?1 AND (?2 OR NOT product.product_restricted)";
+
+    let total: usize = match db_connection.query_row(
+        &format!("SELECT count(*) {}", from_where),
+        params![fts_query, with_restricted],
+        |row| row.get(0),
+    ) {
+        Ok(total) => total,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    let highlight_columns: Vec<String> = (0..INDEXED_COLUMNS.len())
+        .map(|column| {
+            format!(
+                "highlight(product_fts, {}, '{}', '{}')",
+                column, HIGHLIGHT_START, HIGHLIGHT_END
+            )
+        })
+        .collect();
+
+    let sql = format!(
+        "SELECT product_fts.rowid, product_fts.rank, {} {} ORDER BY product_fts.rank LIMIT ?3 OFFSET ?4",
+        highlight_columns.join(", "),
+        from_where
+    );
+
+    let mut stmt = match db_connection.prepare(&sql) {
+        Ok(stmt) => stmt,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    let rows = stmt.query_map(params![fts_query, with_restricted, limit, offset], |row| {
+        let mut highlights: BTreeMap<String, String> = BTreeMap::new();
+        for (i, column) in INDEXED_COLUMNS.iter().enumerate() {
+            let text: String = row.get(i + 2)?;
+            if text.contains(HIGHLIGHT_START) {
+                highlights.insert(column.to_string(), highlight_html(&text));
+            }
+        }
+
+        Ok(SearchMatch {
+            product_id: row.get(0)?,
+            rank: row.get(1)?,
+            highlights,
+        })
+    });
+
+    match rows {
+        Ok(rows) => match rows.collect::<Result<Vec<SearchMatch>, rusqlite::Error>>() {
+            Ok(search_matches) => Ok((search_matches, total)),
+            Err(err) => Err(AppError::Database(err.to_string())),
+        },
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structure::init_structure;
+    use chimitheque_types::name::Name;
+
+    fn init_test_db() -> Connection {
+        let db_connection = Connection::open_in_memory().unwrap();
+        db_connection
+            .execute_batch(
+                "CREATE TABLE product (
+                    product_id INTEGER PRIMARY KEY,
+                    product_restricted BOOLEAN NOT NULL DEFAULT 0
+                );",
+            )
+            .unwrap();
+        init_structure(&db_connection).unwrap();
+        init_product_index(&db_connection, 1).unwrap();
+        db_connection
+    }
+
+    fn add_product(db_connection: &Connection, product: Product, restricted: bool) {
+        db_connection
+            .execute(
+                "INSERT INTO product (product_id, product_restricted) VALUES (?1, ?2)",
+                params![product.product_id, restricted],
+            )
+            .unwrap();
+        index_product(db_connection, &product).unwrap();
+    }
+
+    fn product(product_id: u64, name_label: &str, product_remark: Option<&str>) -> Product {
+        Product {
+            product_id: Some(product_id),
+            name: Name {
+                name_label: name_label.to_string(),
+                ..Default::default()
+            },
+            product_remark: product_remark.map(|product_remark| product_remark.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn product_ids(search_matches: &[SearchMatch]) -> Vec<u64> {
+        search_matches
+            .iter()
+            .map(|search_match| search_match.product_id)
+            .collect()
+    }
+
+    #[test]
+    fn search_products_ranks_the_best_matches_first() {
+        let db_connection = init_test_db();
+        add_product(
+            &db_connection,
+            product(
+                1,
+                "sodium chloride",
+                Some("cleaned with acetone after use, then dried in the oven"),
+            ),
+            false,
+        );
+        add_product(
+            &db_connection,
+            product(2, "acetone", Some("acetone")),
+            false,
+        );
+        add_product(&db_connection, product(3, "ethanol", None), false);
+
+        let (search_matches, total) =
+            search_products(&db_connection, "aceto", false, 0, 10).unwrap();
+        assert_eq!(total, 2);
+        assert_eq!(product_ids(&search_matches), vec![2, 1]);
+        assert!(search_matches[0].rank <= search_matches[1].rank);
+        assert_eq!(
+            search_matches[1]
+                .highlights
+                .get("comments")
+                .map(String::as_str),
+            Some("cleaned with <mark>acetone</mark> after use, then dried in the oven")
+        );
+
+        // The pages do not change the total.
+        let (search_matches, total) =
+            search_products(&db_connection, "aceto", false, 1, 10).unwrap();
+        assert_eq!(total, 2);
+        assert_eq!(product_ids(&search_matches), vec![1]);
+    }
+
+    #[test]
+    fn search_products_filters_the_restricted_products() {
+        let db_connection = init_test_db();
+        add_product(&db_connection, product(1, "acetic acid", None), true);
+        add_product(&db_connection, product(2, "acetone", None), false);
+
+        let (search_matches, total) =
+            search_products(&db_connection, "acet", false, 0, 10).unwrap();
+        assert_eq!(total, 1);
+        assert_eq!(product_ids(&search_matches), vec![2]);
+
+        let (search_matches, total) = search_products(&db_connection, "acet", true, 0, 10).unwrap();
+        assert_eq!(total, 2);
+        let mut product_ids = product_ids(&search_matches);
+        product_ids.sort();
+        assert_eq!(product_ids, vec![1, 2]);
+    }
+
+    #[test]
+    fn search_products_quotes_the_query() {
+        let db_connection = init_test_db();
+        add_product(&db_connection, product(1, "acetone <b>", None), false);
+
+        let (search_matches, total) =
+            search_products(&db_connection, "\"acetone OR", false, 0, 10).unwrap();
+        assert_eq!(total, 0);
+        assert!(search_matches.is_empty());
+
+        let (search_matches, _) = search_products(&db_connection, "acetone", false, 0, 10).unwrap();
+        assert_eq!(
+            search_matches[0].highlights.get("name").map(String::as_str),
+            Some("<mark>acetone</mark> &lt;b&gt;")
+        );
+
+        assert!(matches!(
+            search_products(&db_connection, "  ", false, 0, 10),
+            Err(AppError::InputValidation(_))
+        ));
+    }
+}
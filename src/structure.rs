@@ -0,0 +1,398 @@
+use chimitheque_types::pubchemproduct::PubchemProduct;
+use governor::DefaultDirectRateLimiter;
+use reqwest::StatusCode;
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::errors::AppError;
+
+pub const STRUCTURE_DATETIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+// PubChem PUG View section headings of the SMILES, by preference.
+// PubChem renamed the canonical SMILES to connectivity SMILES.
+const SMILES_HEADINGS: [&str; 4] = [
+    "Connectivity SMILES",
+    "Canonical SMILES",
+    "SMILES",
+    "Isomeric SMILES",
+];
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct StructureIdentifiers {
+    pub product_pubchem_cid: Option<u64>,
+    pub product_smiles: Option<String>,
+    pub product_inchi: Option<String>,
+    pub product_inchikey: Option<String>,
+}
+
+// Return the first string of the information of a PUG View section with
+// the given heading, searching the sub-sections depth first.
+fn record_section_string(section: &Value, heading: &str) -> Option<String> {
+    if section.get("TOCHeading").and_then(Value::as_str) == Some(heading) {
+        let string = section
+            .get("Information")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(|information| {
+                information
+                    .pointer("/Value/StringWithMarkup/0/String")
+                    .and_then(Value::as_str)
+            })
+            .next();
+        if let Some(string) = string {
+            return Some(string.to_string());
+        }
+    }
+
+    section
+        .get("Section")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .find_map(|sub_section| record_section_string(sub_section, heading))
+}
+
+impl StructureIdentifiers {
+    // Extract the identifiers of a PubChem PUG View compound record,
+    // as returned by chimitheque_pubchem::pubchem::get_compound_by_name.
+    pub fn from_compound_record(record: &Value) -> Self {
+        let record = record.get("Record").unwrap_or(record);
+
+        StructureIdentifiers {
+            product_pubchem_cid: record.get("RecordNumber").and_then(Value::as_u64),
+            product_smiles: SMILES_HEADINGS
+                .iter()
+                .find_map(|heading| record_section_string(record, heading)),
+            product_inchi: record_section_string(record, "InChI"),
+            product_inchikey: record_section_string(record, "InChIKey"),
+        }
+    }
+
+    // Extract the identifiers of a product created from PubChem.
+    // A PubChem product has no CID, it is set by a later structure update.
+    pub fn from_pubchem_product(pubchem_product: &PubchemProduct) -> Self {
+        let non_empty = |identifier: &Option<String>| {
+            identifier
+                .as_ref()
+                .map(|identifier| identifier.trim().to_string())
+                .filter(|identifier| !identifier.is_empty())
+        };
+
+        StructureIdentifiers {
+            product_pubchem_cid: None,
+            product_smiles: non_empty(&pubchem_product.canonical_smiles),
+            product_inchi: non_empty(&pubchem_product.inchi),
+            product_inchikey: non_empty(&pubchem_product.inchi_key),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == StructureIdentifiers::default()
+    }
+}
+
+// Send a GET request to the PubChem server at base_url, waiting for the
+// rate limiter first.
+pub async fn pubchem_get(
+    http_client: &reqwest::Client,
+    rate_limiter: &DefaultDirectRateLimiter,
+    base_url: &str,
+    path: &str,
+) -> Result<reqwest::Response, AppError> {
+    rate_limiter.until_ready().await;
+
+    let url = format!("{}{}", base_url.trim_end_matches('/'), path);
+    match http_client.get(url).send().await {
+        Ok(response) if response.status().is_success() => Ok(response),
+        Ok(response) if response.status() == StatusCode::NOT_FOUND => {
+            Err(AppError::NotFound(format!("pubchem {}", path)))
+        }
+        Ok(response) => Err(AppError::Pubchem(format!(
+            "unexpected status: {}",
+            response.status()
+        ))),
+        Err(err) => Err(AppError::Pubchem(err.to_string())),
+    }
+}
+
+async fn pubchem_get_json(
+    http_client: &reqwest::Client,
+    rate_limiter: &DefaultDirectRateLimiter,
+    base_url: &str,
+    path: &str,
+) -> Result<Value, AppError> {
+    match pubchem_get(http_client, rate_limiter, base_url, path)
+        .await?
+        .json::<Value>()
+        .await
+    {
+        Ok(value) => Ok(value),
+        Err(err) => Err(AppError::Pubchem(err.to_string())),
+    }
+}
+
+// Fetch the PUG View record of the first PubChem compound with the given name,
+// to be read by StructureIdentifiers::from_compound_record.
+pub async fn fetch_compound_record(
+    http_client: &reqwest::Client,
+    rate_limiter: &DefaultDirectRateLimiter,
+    base_url: &str,
+    name: &str,
+) -> Result<Value, AppError> {
+    let not_found = || AppError::NotFound(format!("pubchem compound {}", name));
+
+    let cids = match pubchem_get_json(
+        http_client,
+        rate_limiter,
+        base_url,
+        &format!(
+            "/rest/pug/compound/name/{}/cids/JSON",
+            urlencoding::encode(name)
+        ),
+    )
+    .await
+    {
+        Ok(cids) => cids,
+        Err(AppError::NotFound(_)) => return Err(not_found()),
+        Err(err) => return Err(err),
+    };
+
+    let Some(cid) = cids
+        .pointer("/IdentifierList/CID/0")
+        .and_then(Value::as_u64)
+    else {
+        return Err(not_found());
+    };
+
+    pubchem_get_json(
+        http_client,
+        rate_limiter,
+        base_url,
+        &format!("/rest/pug_view/data/compound/{}/JSON", cid),
+    )
+    .await
+}
+
+// Create the product structure identifiers table.
+pub fn init_structure(db_connection: &Connection) -> Result<(), AppError> {
+    match db_connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS product_structure (
+            product_structure_product_id INTEGER PRIMARY KEY,
+            product_structure_pubchem_cid INTEGER,
+            product_structure_smiles TEXT,
+            product_structure_inchi TEXT,
+            product_structure_inchikey TEXT,
+            product_structure_updated_at TEXT NOT NULL
+        );",
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+pub fn get_structure_identifiers(
+    db_connection: &Connection,
+    product_id: u64,
+) -> Result<Option<StructureIdentifiers>, AppError> {
+    match db_connection
+        .query_row(
+            "SELECT product_structure_pubchem_cid, product_structure_smiles, product_structure_inchi, product_structure_inchikey
+            FROM product_structure WHERE product_structure_product_id = ?1",
+            params![product_id],
+            |row| {
+                Ok(StructureIdentifiers {
+                    product_pubchem_cid: row.get(0)?,
+                    product_smiles: row.get(1)?,
+                    product_inchi: row.get(2)?,
+                    product_inchikey: row.get(3)?,
+                })
+            },
+        )
+        .optional()
+    {
+        Ok(structure_identifiers) => Ok(structure_identifiers),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// Insert or replace the structure identifiers of a product.
+pub fn set_structure_identifiers(
+    db_connection: &Connection,
+    product_id: u64,
+    structure_identifiers: &StructureIdentifiers,
+    updated_at: &str,
+) -> Result<(), AppError> {
+    match db_connection.execute(
+        "INSERT OR REPLACE INTO product_structure (product_structure_product_id, product_structure_pubchem_cid, product_structure_smiles, product_structure_inchi, product_structure_inchikey, product_structure_updated_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            product_id,
+            structure_identifiers.product_pubchem_cid,
+            structure_identifiers.product_smiles,
+            structure_identifiers.product_inchi,
+            structure_identifiers.product_inchikey,
+            updated_at
+        ],
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+pub fn delete_structure_identifiers(
+    db_connection: &Connection,
+    product_id: u64,
+) -> Result<(), AppError> {
+    match db_connection.execute(
+        "DELETE FROM product_structure WHERE product_structure_product_id = ?1",
+        params![product_id],
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn from_compound_record_reads_computed_descriptors() {
+        let record = json!({
+            "Record": {
+                "RecordType": "CID",
+                "RecordNumber": 180,
+                "Section": [{
+                    "TOCHeading": "Names and Identifiers",
+                    "Section": [{
+                        "TOCHeading": "Computed Descriptors",
+                        "Section": [
+                            {
+                                "TOCHeading": "InChI",
+                                "Information": [{"Value": {"StringWithMarkup": [{"String": "InChI=1S/C3H6O/c1-3(2)4/h1-2H3"}]}}]
+                            },
+                            {
+                                "TOCHeading": "InChIKey",
+                                "Information": [{"Value": {"StringWithMarkup": [{"String": "CSCPPACGZOOCGX-UHFFFAOYSA-N"}]}}]
+                            },
+                            {
+                                "TOCHeading": "SMILES",
+                                "Information": [{"Value": {"StringWithMarkup": [{"String": "CC(C)=O"}]}}]
+                            },
+                            {
+                                "TOCHeading": "Connectivity SMILES",
+                                "Information": [{"Value": {"StringWithMarkup": [{"String": "CC(=O)C"}]}}]
+                            }
+                        ]
+                    }]
+                }]
+            }
+        });
+
+        assert_eq!(
+            StructureIdentifiers::from_compound_record(&record),
+            StructureIdentifiers {
+                product_pubchem_cid: Some(180),
+                product_smiles: Some(String::from("CC(=O)C")),
+                product_inchi: Some(String::from("InChI=1S/C3H6O/c1-3(2)4/h1-2H3")),
+                product_inchikey: Some(String::from("CSCPPACGZOOCGX-UHFFFAOYSA-N")),
+            }
+        );
+    }
+
+    #[test]
+    fn from_compound_record_without_descriptors() {
+        let structure_identifiers =
+            StructureIdentifiers::from_compound_record(&json!({"RecordNumber": 7}));
+
+        assert_eq!(structure_identifiers.product_pubchem_cid, Some(7));
+        assert_eq!(structure_identifiers.product_smiles, None);
+        assert!(!structure_identifiers.is_empty());
+    }
+
+    #[test]
+    fn from_pubchem_product_maps_the_identifiers() {
+        let pubchem_product = PubchemProduct {
+            inchi: Some(String::from("InChI=1S/C3H6O/c1-3(2)4/h1-2H3")),
+            inchi_key: Some(String::from(" CSCPPACGZOOCGX-UHFFFAOYSA-N ")),
+            canonical_smiles: Some(String::new()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            StructureIdentifiers::from_pubchem_product(&pubchem_product),
+            StructureIdentifiers {
+                product_pubchem_cid: None,
+                product_smiles: None,
+                product_inchi: Some(String::from("InChI=1S/C3H6O/c1-3(2)4/h1-2H3")),
+                product_inchikey: Some(String::from("CSCPPACGZOOCGX-UHFFFAOYSA-N")),
+            }
+        );
+        assert!(StructureIdentifiers::from_pubchem_product(&PubchemProduct::default()).is_empty());
+    }
+
+    // A local PubChem stand-in knowing a single compound.
+    fn pubchem_stand_in() -> axum::Router {
+        axum::Router::new()
+            .route(
+                "/rest/pug/compound/name/{name}/cids/JSON",
+                axum::routing::get(|axum::extract::Path(name): axum::extract::Path<String>| async move {
+                    if name == "caffeine" {
+                        Ok(axum::Json(json!({"IdentifierList": {"CID": [2519]}})))
+                    } else {
+                        Err(axum::http::StatusCode::NOT_FOUND)
+                    }
+                }),
+            )
+            .route(
+                "/rest/pug_view/data/compound/{cid}/JSON",
+                axum::routing::get(|axum::extract::Path(cid): axum::extract::Path<u64>| async move {
+                    axum::Json(json!({
+                        "Record": {
+                            "RecordNumber": cid,
+                            "Section": [{
+                                "TOCHeading": "InChIKey",
+                                "Information": [{"Value": {"StringWithMarkup": [{"String": "RYYVLZVUVIJVGH-UHFFFAOYSA-N"}]}}]
+                            }]
+                        }
+                    }))
+                }),
+            )
+    }
+
+    #[test]
+    fn fetch_compound_record_from_a_local_pubchem() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let base_url = format!("http://{}/", listener.local_addr().unwrap());
+            tokio::spawn(async move { axum::serve(listener, pubchem_stand_in()).await });
+
+            let http_client = reqwest::Client::new();
+            let rate_limiter = governor::RateLimiter::direct(governor::Quota::per_second(
+                std::num::NonZeroU32::new(5).unwrap(),
+            ));
+
+            let record = fetch_compound_record(&http_client, &rate_limiter, &base_url, "caffeine")
+                .await
+                .unwrap();
+            let structure_identifiers = StructureIdentifiers::from_compound_record(&record);
+            assert_eq!(structure_identifiers.product_pubchem_cid, Some(2519));
+            assert_eq!(
+                structure_identifiers.product_inchikey.as_deref(),
+                Some("RYYVLZVUVIJVGH-UHFFFAOYSA-N")
+            );
+
+            match fetch_compound_record(&http_client, &rate_limiter, &base_url, "unobtainium").await
+            {
+                Err(AppError::NotFound(message)) => {
+                    assert_eq!(message, "pubchem compound unobtainium")
+                }
+                other => panic!("unexpected result: {:?}", other.map(|_| ())),
+            }
+        });
+    }
+}
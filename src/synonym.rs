@@ -0,0 +1,170 @@
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::errors::AppError;
+
+// chimitheque_db writes a product in its own transaction, which can not
+// be nested: the names of a product are written below, in one transaction
+// with the cleanup of the names no product refers to anymore.
+
+// The ID of the name with the given label, created if it does not exist.
+pub fn get_or_create_name(db_connection: &Connection, name_label: &str) -> Result<u64, AppError> {
+    match db_connection
+        .query_row(
+            "SELECT name_id FROM name WHERE name_label = ?1",
+            params![name_label],
+            |row| row.get::<_, u64>(0),
+        )
+        .optional()
+    {
+        Ok(Some(name_id)) => return Ok(name_id),
+        Ok(None) => (),
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    }
+
+    match db_connection.execute(
+        "INSERT INTO name (name_label) VALUES (?1)",
+        params![name_label],
+    ) {
+        Ok(_) => Ok(db_connection.last_insert_rowid() as u64),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// Set the main name and the synonyms of a product, in the given order.
+pub fn set_product_names(
+    db_connection: &Connection,
+    product_id: u64,
+    name_id: u64,
+    synonym_name_ids: &[u64],
+) -> Result<(), AppError> {
+    if let Err(err) = db_connection.execute(
+        "UPDATE product SET name = ?1 WHERE product_id = ?2",
+        params![name_id, product_id],
+    ) {
+        return Err(AppError::Database(err.to_string()));
+    }
+
+    if let Err(err) = db_connection.execute(
+        "DELETE FROM productsynonyms WHERE productsynonyms_product_id = ?1",
+        params![product_id],
+    ) {
+        return Err(AppError::Database(err.to_string()));
+    }
+
+    for synonym_name_id in synonym_name_ids {
+        if let Err(err) = db_connection.execute(
+            "INSERT INTO productsynonyms (productsynonyms_product_id, productsynonyms_name_id) VALUES (?1, ?2)",
+            params![product_id, synonym_name_id],
+        ) {
+            return Err(AppError::Database(err.to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+// Delete the names that are neither a product name nor a synonym.
+pub fn delete_orphan_names(db_connection: &Connection) -> Result<usize, AppError> {
+    match db_connection.execute(
+        "DELETE FROM name
+        WHERE name_id NOT IN (SELECT name FROM product WHERE name IS NOT NULL)
+        AND name_id NOT IN (SELECT productsynonyms_name_id FROM productsynonyms)",
+        [],
+    ) {
+        Ok(nb_deleted) => Ok(nb_deleted),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn init_test_db() -> Connection {
+        let db_connection = Connection::open_in_memory().unwrap();
+        db_connection
+            .execute_batch(
+                "CREATE TABLE name (
+                    name_id INTEGER PRIMARY KEY,
+                    name_label TEXT NOT NULL UNIQUE
+                );
+                CREATE TABLE product (
+                    product_id INTEGER PRIMARY KEY,
+                    name INTEGER REFERENCES name(name_id)
+                );
+                CREATE TABLE productsynonyms (
+                    productsynonyms_product_id INTEGER NOT NULL,
+                    productsynonyms_name_id INTEGER NOT NULL,
+                    PRIMARY KEY (productsynonyms_product_id, productsynonyms_name_id)
+                );
+                INSERT INTO name (name_id, name_label) VALUES (1, 'ETHANOL'), (2, 'ALCOHOL');
+                INSERT INTO product (product_id, name) VALUES (1, 1);
+                INSERT INTO productsynonyms VALUES (1, 2);",
+            )
+            .unwrap();
+        db_connection
+    }
+
+    fn synonym_name_ids(db_connection: &Connection, product_id: u64) -> Vec<u64> {
+        let mut stmt = db_connection
+            .prepare(
+                "SELECT productsynonyms_name_id FROM productsynonyms
+                WHERE productsynonyms_product_id = ?1 ORDER BY rowid",
+            )
+            .unwrap();
+        stmt.query_map(params![product_id], |row| row.get(0))
+            .unwrap()
+            .map(|name_id| name_id.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn get_or_create_name_reuses_the_label() {
+        let db_connection = init_test_db();
+
+        assert_eq!(get_or_create_name(&db_connection, "ALCOHOL").unwrap(), 2);
+        let name_id = get_or_create_name(&db_connection, "ETHYL ALCOHOL").unwrap();
+        assert_eq!(name_id, 3);
+        assert_eq!(
+            get_or_create_name(&db_connection, "ETHYL ALCOHOL").unwrap(),
+            name_id
+        );
+    }
+
+    #[test]
+    fn set_product_names_keeps_the_order() {
+        let db_connection = init_test_db();
+        let name_id = get_or_create_name(&db_connection, "ETHYL ALCOHOL").unwrap();
+
+        set_product_names(&db_connection, 1, 1, &[name_id, 2]).unwrap();
+        assert_eq!(synonym_name_ids(&db_connection, 1), vec![name_id, 2]);
+
+        // Promote a synonym.
+        set_product_names(&db_connection, 1, 2, &[name_id, 1]).unwrap();
+        let product_name_id: u64 = db_connection
+            .query_row("SELECT name FROM product WHERE product_id = 1", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(product_name_id, 2);
+        assert_eq!(synonym_name_ids(&db_connection, 1), vec![name_id, 1]);
+    }
+
+    #[test]
+    fn delete_orphan_names_keeps_the_referenced_names() {
+        let db_connection = init_test_db();
+        let name_id = get_or_create_name(&db_connection, "ETHYL ALCOHOL").unwrap();
+        set_product_names(&db_connection, 1, 1, &[name_id]).unwrap();
+
+        // ALCOHOL is not referenced anymore.
+        assert_eq!(delete_orphan_names(&db_connection).unwrap(), 1);
+        let name_labels: Vec<String> = db_connection
+            .prepare("SELECT name_label FROM name ORDER BY name_id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .map(|name_label| name_label.unwrap())
+            .collect();
+        assert_eq!(name_labels, vec!["ETHANOL", "ETHYL ALCOHOL"]);
+    }
+}
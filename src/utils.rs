@@ -1,6 +1,17 @@
-use http::HeaderMap;
+use casbin::{CoreApi, MgmtApi};
+use chimitheque_types::requestfilter::RequestFilter;
+use http::{HeaderMap, HeaderValue};
+use serde::Serialize;
+use std::ops::Deref;
 
-use crate::{constants::CHIMITHEQUE_PERSON_ID_HEADER, errors::AppError};
+use crate::{
+    appstate::AppState,
+    constants::{
+        CHIMITHEQUE_PERSON_ID_HEADER, CHIMITHEQUE_WARNINGS_COUNT_HEADER,
+        CHIMITHEQUE_WARNINGS_HEADER, CHIMITHEQUE_WARNINGS_HEADER_MAX_SIZE,
+    },
+    errors::AppError,
+};
 
 pub(crate) fn get_chimitheque_person_id_from_headers(headers: &HeaderMap) -> Result<u64, AppError> {
     let Some(chimitheque_person_id_header) = headers.get(CHIMITHEQUE_PERSON_ID_HEADER) else {
@@ -19,3 +30,224 @@ pub(crate) fn get_chimitheque_person_id_from_headers(headers: &HeaderMap) -> Res
 
     Ok(chimitheque_person_id_u64)
 }
+
+// A response body carrying the warnings of a request that succeeded,
+// such as the chemical incompatibilities of a created storage.
+// The endpoints existing before the warnings return them in headers
+// instead, see warnings_headers.
+#[derive(Serialize, Debug, Clone)]
+pub struct WithWarnings<T, W> {
+    pub data: T,
+    pub warnings: Vec<W>,
+}
+
+impl<T, W> WithWarnings<T, W> {
+    pub fn new(data: T, warnings: Vec<W>) -> Self {
+        WithWarnings { data, warnings }
+    }
+}
+
+// Escape the non ASCII characters of a JSON string, as header values
+// must be visible ASCII.
+fn ascii_json(json: &str) -> String {
+    let mut ascii_json = String::with_capacity(json.len());
+    for c in json.chars() {
+        if c.is_ascii() {
+            ascii_json.push(c);
+        } else {
+            let mut buffer = [0u16; 2];
+            for unit in c.encode_utf16(&mut buffer) {
+                ascii_json.push_str(&format!("\\u{:04x}", unit));
+            }
+        }
+    }
+
+    ascii_json
+}
+
+// Return the headers carrying the given warnings as a JSON array, for the
+// endpoints whose response body can not change.
+// The value is bounded by CHIMITHEQUE_WARNINGS_HEADER_MAX_SIZE: the warnings
+// that do not fit are left out, the count header giving the number of warnings.
+pub(crate) fn warnings_headers<T: Serialize>(warnings: &[T]) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if warnings.is_empty() {
+        return headers;
+    }
+
+    let mut json_warnings: Vec<String> = Vec::new();
+    let mut json_size = "[]".len();
+    for warning in warnings {
+        let Ok(json_warning) = serde_json::to_string(warning) else {
+            continue;
+        };
+        let json_warning = ascii_json(&json_warning);
+
+        // The warning and its separator.
+        json_size += json_warning.len() + usize::from(!json_warnings.is_empty());
+        if json_size > CHIMITHEQUE_WARNINGS_HEADER_MAX_SIZE {
+            break;
+        }
+        json_warnings.push(json_warning);
+    }
+
+    if let Ok(header_value) = HeaderValue::from_str(&format!("[{}]", json_warnings.join(","))) {
+        headers.insert(CHIMITHEQUE_WARNINGS_HEADER, header_value);
+    }
+    headers.insert(
+        CHIMITHEQUE_WARNINGS_COUNT_HEADER,
+        HeaderValue::from(warnings.len()),
+    );
+
+    headers
+}
+
+// Check a casbin permission from within a handler, for the endpoints acting
+// on several items at once that the authorization middleware can not check.
+pub(crate) async fn enforce(
+    state: &AppState,
+    chimitheque_person_id: u64,
+    action: &str,
+    item: &str,
+    item_id: u64,
+) -> Result<bool, AppError> {
+    enforce_item_id(
+        state,
+        chimitheque_person_id,
+        action,
+        item,
+        item_id.to_string(),
+    )
+    .await
+}
+
+// Check a casbin permission on the items of a kind, such as reading the
+// restricted products.
+pub(crate) async fn enforce_items(
+    state: &AppState,
+    chimitheque_person_id: u64,
+    action: &str,
+    item: &str,
+) -> Result<bool, AppError> {
+    enforce_item_id(state, chimitheque_person_id, action, item, String::new()).await
+}
+
+async fn enforce_item_id(
+    state: &AppState,
+    chimitheque_person_id: u64,
+    action: &str,
+    item: &str,
+    item_id: String,
+) -> Result<bool, AppError> {
+    let casbin_enforcer = state.casbin_enforcer.lock().await;
+
+    match casbin_enforcer.enforce((chimitheque_person_id.to_string(), action, item, item_id)) {
+        Ok(allowed) => Ok(allowed),
+        Err(err) => Err(AppError::CasbinError(err.to_string())),
+    }
+}
+
+// Check a permission on the storages of an entity, for the endpoints acting
+// on all the storages of an entity or store location. The casbin matchers,
+// generated by chimitheque_utils/src/casbin.rs, only scope the storage
+// permissions by storage id, so the policies of the person are checked as
+// the matchers do: a permission on the storages or on all the items, in the
+// entity or in all the entities.
+pub(crate) async fn enforce_entity_storages(
+    state: &AppState,
+    chimitheque_person_id: u64,
+    action: &str,
+    entity_id: u64,
+) -> Result<bool, AppError> {
+    let perms: &[&str] = match action {
+        "r" => &["r", "w", "all"],
+        "c" | "u" | "d" => &["w", "all"],
+        _ => &["all"],
+    };
+    let entity_id = entity_id.to_string();
+
+    let casbin_enforcer = state.casbin_enforcer.lock().await;
+    let policies = casbin_enforcer.get_filtered_policy(0, vec![chimitheque_person_id.to_string()]);
+
+    Ok(policies.iter().any(|policy| match policy.as_slice() {
+        [_, perm, item, policy_entity_id] => {
+            perms.contains(&perm.as_str())
+                && (item == "storages" || item == "all")
+                && (policy_entity_id == "-1" || *policy_entity_id == entity_id)
+        }
+        _ => false,
+    }))
+}
+
+// Check a permission on the storages of the entity of a store location.
+pub(crate) async fn enforce_store_location_storages(
+    state: &AppState,
+    chimitheque_person_id: u64,
+    action: &str,
+    store_location_id: u64,
+) -> Result<bool, AppError> {
+    let maybe_entity_id = {
+        // Get the connection from the database.
+        let db_connection_pool = state.db_connection_pool.clone();
+        let db_connection = db_connection_pool.get().unwrap();
+
+        match chimitheque_db::storelocation::get_store_locations(
+            db_connection.deref(),
+            RequestFilter {
+                id: Some(store_location_id),
+                ..Default::default()
+            },
+            chimitheque_person_id,
+        ) {
+            Ok((store_locations, _)) => store_locations
+                .first()
+                .and_then(|store_location| store_location.entity.as_ref())
+                .and_then(|entity| entity.entity_id),
+            Err(err) => return Err(AppError::Database(err.to_string())),
+        }
+    };
+
+    match maybe_entity_id {
+        Some(entity_id) => {
+            enforce_entity_storages(state, chimitheque_person_id, action, entity_id).await
+        }
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warnings_headers_escape_non_ascii() {
+        let headers = warnings_headers(&["acide sulfurique / éthanol"]);
+
+        assert_eq!(
+            headers.get(CHIMITHEQUE_WARNINGS_HEADER).unwrap(),
+            r#"["acide sulfurique / \u00e9thanol"]"#
+        );
+        assert_eq!(headers.get(CHIMITHEQUE_WARNINGS_COUNT_HEADER).unwrap(), "1");
+    }
+
+    #[test]
+    fn warnings_headers_are_bounded() {
+        let warnings: Vec<String> = (0..1000).map(|i| format!("warning {}", i)).collect();
+        let headers = warnings_headers(&warnings);
+
+        let header_value = headers.get(CHIMITHEQUE_WARNINGS_HEADER).unwrap();
+        assert!(header_value.len() <= CHIMITHEQUE_WARNINGS_HEADER_MAX_SIZE);
+        let header_warnings: Vec<String> = serde_json::from_slice(header_value.as_bytes()).unwrap();
+        assert!(!header_warnings.is_empty());
+        assert_eq!(header_warnings, warnings[..header_warnings.len()]);
+        assert_eq!(
+            headers.get(CHIMITHEQUE_WARNINGS_COUNT_HEADER).unwrap(),
+            "1000"
+        );
+    }
+
+    #[test]
+    fn warnings_headers_without_warnings() {
+        assert!(warnings_headers::<String>(&[]).is_empty());
+    }
+}
@@ -0,0 +1,631 @@
+use chrono::Local;
+use rusqlite::{Connection, OptionalExtension, Row, params};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::{
+    consumption::convert_quantity,
+    errors::AppError,
+    export::ExportTable,
+    ghs_label::{GLYPH_WIDTH_RATIO, LINE_HEIGHT_RATIO, wrap, xml_escape},
+};
+
+pub const WASTE_DATETIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+// Hazard class of the storages without GHS pictogram.
+pub const UNCLASSIFIED_HAZARD_CLASS: &str = "unclassified";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveReason {
+    Consumed,
+    Disposed,
+    Transferred,
+    Broken,
+    // Not found by an inventory campaign.
+    Missing,
+    // Archived by a client not giving the reason.
+    #[default]
+    Unspecified,
+}
+
+impl ArchiveReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ArchiveReason::Consumed => "consumed",
+            ArchiveReason::Disposed => "disposed",
+            ArchiveReason::Transferred => "transferred",
+            ArchiveReason::Broken => "broken",
+            ArchiveReason::Missing => "missing",
+            ArchiveReason::Unspecified => "unspecified",
+        }
+    }
+
+    fn from_str(reason: &str) -> Option<Self> {
+        match reason {
+            "consumed" => Some(ArchiveReason::Consumed),
+            "disposed" => Some(ArchiveReason::Disposed),
+            "transferred" => Some(ArchiveReason::Transferred),
+            "broken" => Some(ArchiveReason::Broken),
+            "missing" => Some(ArchiveReason::Missing),
+            "unspecified" => Some(ArchiveReason::Unspecified),
+            _ => None,
+        }
+    }
+}
+
+// Why and when a storage was archived. Disposed storages wait for
+// a waste pickup batch.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct StorageArchive {
+    pub storage_id: u64,
+    pub reason: Option<ArchiveReason>,
+    pub comment: Option<String>,
+    pub person_id: u64,
+    pub archived_at: String,
+    pub waste_batch_id: Option<u64>,
+}
+
+impl StorageArchive {
+    // A storage archived now by the person.
+    pub fn new(
+        storage_id: u64,
+        reason: ArchiveReason,
+        comment: Option<String>,
+        person_id: u64,
+    ) -> Self {
+        StorageArchive {
+            storage_id,
+            reason: Some(reason),
+            comment: comment
+                .map(|comment| comment.trim().to_string())
+                .filter(|comment| !comment.is_empty()),
+            person_id,
+            archived_at: Local::now().format(WASTE_DATETIME_FORMAT).to_string(),
+            waste_batch_id: None,
+        }
+    }
+}
+
+// The disposed storages of an entity handed together to the waste contractor.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct WasteBatch {
+    pub waste_batch_id: u64,
+    pub entity_id: u64,
+    pub name: String,
+    pub created_by: u64,
+    pub created_at: String,
+    pub picked_up_by: Option<u64>,
+    pub picked_up_at: Option<String>,
+}
+
+// A storage of a waste batch, as listed on the manifest.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct WasteItem {
+    pub storage_id: u64,
+    pub storage_barecode: Option<String>,
+    pub product_name: String,
+    pub cas_number: Option<String>,
+    // GHS pictogram codes such as SGH02.
+    pub hazard_classes: Vec<String>,
+    pub hazard_statements: Vec<String>,
+    pub quantity: Option<f64>,
+    pub unit_label: Option<String>,
+}
+
+// The storages and quantities of a waste batch for a hazard class.
+// The quantities are in kg and L when the unit can be converted,
+// in their unit otherwise.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct HazardClassTotal {
+    pub hazard_class: String,
+    pub nb_storages: usize,
+    pub quantities: BTreeMap<String, f64>,
+}
+
+const STORAGE_ARCHIVE_COLUMNS: &str = "storage_archive_storage_id, storage_archive_reason, storage_archive_comment, storage_archive_person_id, storage_archive_archived_at, storage_archive_waste_batch_id";
+
+const WASTE_BATCH_COLUMNS: &str = "waste_batch_id, waste_batch_entity_id, waste_batch_name, waste_batch_created_by, waste_batch_created_at, waste_batch_picked_up_by, waste_batch_picked_up_at";
+
+fn storage_archive_from_row(row: &Row) -> Result<StorageArchive, rusqlite::Error> {
+    let reason: Option<String> = row.get(1)?;
+
+    Ok(StorageArchive {
+        storage_id: row.get(0)?,
+        reason: reason.as_deref().and_then(ArchiveReason::from_str),
+        comment: row.get(2)?,
+        person_id: row.get(3)?,
+        archived_at: row.get(4)?,
+        waste_batch_id: row.get(5)?,
+    })
+}
+
+fn waste_batch_from_row(row: &Row) -> Result<WasteBatch, rusqlite::Error> {
+    Ok(WasteBatch {
+        waste_batch_id: row.get(0)?,
+        entity_id: row.get(1)?,
+        name: row.get(2)?,
+        created_by: row.get(3)?,
+        created_at: row.get(4)?,
+        picked_up_by: row.get(5)?,
+        picked_up_at: row.get(6)?,
+    })
+}
+
+// Create the storage archives and waste batches tables.
+pub fn init_waste(db_connection: &Connection) -> Result<(), AppError> {
+    match db_connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS storage_archive (
+            storage_archive_storage_id INTEGER PRIMARY KEY,
+            storage_archive_reason TEXT,
+            storage_archive_comment TEXT,
+            storage_archive_person_id INTEGER NOT NULL,
+            storage_archive_archived_at TEXT NOT NULL,
+            storage_archive_waste_batch_id INTEGER
+        );
+        CREATE INDEX IF NOT EXISTS idx_storage_archive_waste_batch ON storage_archive(storage_archive_waste_batch_id);
+        CREATE TABLE IF NOT EXISTS waste_batch (
+            waste_batch_id INTEGER PRIMARY KEY,
+            waste_batch_entity_id INTEGER NOT NULL,
+            waste_batch_name TEXT NOT NULL,
+            waste_batch_created_by INTEGER NOT NULL,
+            waste_batch_created_at TEXT NOT NULL,
+            waste_batch_picked_up_by INTEGER,
+            waste_batch_picked_up_at TEXT
+        );",
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// Record why a storage was archived, replacing a previous archive.
+pub fn set_storage_archive(
+    db_connection: &Connection,
+    storage_archive: &StorageArchive,
+) -> Result<(), AppError> {
+    match db_connection.execute(
+        "INSERT OR REPLACE INTO storage_archive (storage_archive_storage_id, storage_archive_reason, storage_archive_comment, storage_archive_person_id, storage_archive_archived_at, storage_archive_waste_batch_id)
+        VALUES (?1, ?2, ?3, ?4, ?5, NULL)",
+        params![
+            storage_archive.storage_id,
+            storage_archive.reason.map(|reason| reason.as_str()),
+            storage_archive.comment,
+            storage_archive.person_id,
+            storage_archive.archived_at
+        ],
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// Record why a storage was archived, keeping a previous archive such as
+// the one of a storage in a waste batch.
+pub fn insert_storage_archive(
+    db_connection: &Connection,
+    storage_archive: &StorageArchive,
+) -> Result<(), AppError> {
+    match db_connection.execute(
+        "INSERT OR IGNORE INTO storage_archive (storage_archive_storage_id, storage_archive_reason, storage_archive_comment, storage_archive_person_id, storage_archive_archived_at, storage_archive_waste_batch_id)
+        VALUES (?1, ?2, ?3, ?4, ?5, NULL)",
+        params![
+            storage_archive.storage_id,
+            storage_archive.reason.map(|reason| reason.as_str()),
+            storage_archive.comment,
+            storage_archive.person_id,
+            storage_archive.archived_at
+        ],
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+pub fn delete_storage_archive(db_connection: &Connection, storage_id: u64) -> Result<(), AppError> {
+    match db_connection.execute(
+        "DELETE FROM storage_archive WHERE storage_archive_storage_id = ?1",
+        params![storage_id],
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+pub fn get_storage_archive(
+    db_connection: &Connection,
+    storage_id: u64,
+) -> Result<Option<StorageArchive>, AppError> {
+    let sql = format!(
+        "SELECT {} FROM storage_archive WHERE storage_archive_storage_id = ?1",
+        STORAGE_ARCHIVE_COLUMNS
+    );
+
+    match db_connection
+        .query_row(&sql, params![storage_id], storage_archive_from_row)
+        .optional()
+    {
+        Ok(storage_archive) => Ok(storage_archive),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+fn query_storage_ids(
+    db_connection: &Connection,
+    sql: &str,
+    params: impl rusqlite::Params,
+) -> Result<Vec<u64>, AppError> {
+    let mut stmt = match db_connection.prepare(sql) {
+        Ok(stmt) => stmt,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    match stmt.query_map(params, |row| row.get(0)) {
+        Ok(rows) => match rows.collect::<Result<Vec<u64>, rusqlite::Error>>() {
+            Ok(storage_ids) => Ok(storage_ids),
+            Err(err) => Err(AppError::Database(err.to_string())),
+        },
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// The disposed storages not in a waste batch yet.
+pub fn get_pending_disposed_storage_ids(db_connection: &Connection) -> Result<Vec<u64>, AppError> {
+    query_storage_ids(
+        db_connection,
+        "SELECT storage_archive_storage_id FROM storage_archive
+        WHERE storage_archive_reason = ?1 AND storage_archive_waste_batch_id IS NULL
+        ORDER BY storage_archive_storage_id",
+        params![ArchiveReason::Disposed.as_str()],
+    )
+}
+
+pub fn get_waste_batch_storage_ids(
+    db_connection: &Connection,
+    waste_batch_id: u64,
+) -> Result<Vec<u64>, AppError> {
+    query_storage_ids(
+        db_connection,
+        "SELECT storage_archive_storage_id FROM storage_archive
+        WHERE storage_archive_waste_batch_id = ?1
+        ORDER BY storage_archive_storage_id",
+        params![waste_batch_id],
+    )
+}
+
+// Create a waste batch with the given disposed storages.
+pub fn insert_waste_batch(
+    db_connection: &mut Connection,
+    waste_batch: &WasteBatch,
+    storage_ids: &[u64],
+) -> Result<u64, AppError> {
+    let tx = match db_connection.transaction() {
+        Ok(tx) => tx,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    if let Err(err) = tx.execute(
+        "INSERT INTO waste_batch (waste_batch_entity_id, waste_batch_name, waste_batch_created_by, waste_batch_created_at)
+        VALUES (?1, ?2, ?3, ?4)",
+        params![
+            waste_batch.entity_id,
+            waste_batch.name,
+            waste_batch.created_by,
+            waste_batch.created_at
+        ],
+    ) {
+        return Err(AppError::Database(err.to_string()));
+    }
+    let waste_batch_id = tx.last_insert_rowid() as u64;
+
+    for storage_id in storage_ids.iter() {
+        if let Err(err) = tx.execute(
+            "UPDATE storage_archive SET storage_archive_waste_batch_id = ?1 WHERE storage_archive_storage_id = ?2",
+            params![waste_batch_id, storage_id],
+        ) {
+            return Err(AppError::Database(err.to_string()));
+        }
+    }
+
+    match tx.commit() {
+        Ok(_) => Ok(waste_batch_id),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+pub fn set_waste_batch_picked_up(
+    db_connection: &Connection,
+    waste_batch_id: u64,
+    picked_up_at: &str,
+    picked_up_by: u64,
+) -> Result<(), AppError> {
+    match db_connection.execute(
+        "UPDATE waste_batch SET waste_batch_picked_up_at = ?1, waste_batch_picked_up_by = ?2
+        WHERE waste_batch_id = ?3 AND waste_batch_picked_up_at IS NULL",
+        params![picked_up_at, picked_up_by, waste_batch_id],
+    ) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+pub fn get_waste_batch(
+    db_connection: &Connection,
+    waste_batch_id: u64,
+) -> Result<Option<WasteBatch>, AppError> {
+    let sql = format!(
+        "SELECT {} FROM waste_batch WHERE waste_batch_id = ?1",
+        WASTE_BATCH_COLUMNS
+    );
+
+    match db_connection
+        .query_row(&sql, params![waste_batch_id], waste_batch_from_row)
+        .optional()
+    {
+        Ok(waste_batch) => Ok(waste_batch),
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// The waste batches, most recent first.
+pub fn get_waste_batches(db_connection: &Connection) -> Result<Vec<WasteBatch>, AppError> {
+    let sql = format!(
+        "SELECT {} FROM waste_batch ORDER BY waste_batch_created_at DESC, waste_batch_id DESC",
+        WASTE_BATCH_COLUMNS
+    );
+
+    let mut stmt = match db_connection.prepare(&sql) {
+        Ok(stmt) => stmt,
+        Err(err) => return Err(AppError::Database(err.to_string())),
+    };
+
+    match stmt.query_map([], waste_batch_from_row) {
+        Ok(rows) => match rows.collect::<Result<Vec<WasteBatch>, rusqlite::Error>>() {
+            Ok(waste_batches) => Ok(waste_batches),
+            Err(err) => Err(AppError::Database(err.to_string())),
+        },
+        Err(err) => Err(AppError::Database(err.to_string())),
+    }
+}
+
+// Sum the waste items quantities by hazard class, a storage with
+// several pictograms being counted in each of their classes.
+// Normalize a quantity to kg or L when possible, keeping its unit otherwise.
+pub fn normalize_quantity(quantity: f64, unit_label: &str) -> (String, f64) {
+    match (
+        convert_quantity(quantity, unit_label, "kg"),
+        convert_quantity(quantity, unit_label, "L"),
+    ) {
+        (Some(quantity), _) => (String::from("kg"), quantity),
+        (_, Some(quantity)) => (String::from("L"), quantity),
+        _ => (unit_label.to_string(), quantity),
+    }
+}
+
+pub fn hazard_class_totals(items: &[WasteItem]) -> Vec<HazardClassTotal> {
+    let mut totals: BTreeMap<String, HazardClassTotal> = BTreeMap::new();
+
+    for item in items.iter() {
+        let hazard_classes = if item.hazard_classes.is_empty() {
+            vec![UNCLASSIFIED_HAZARD_CLASS.to_string()]
+        } else {
+            item.hazard_classes.clone()
+        };
+
+        let quantity = match (item.quantity, item.unit_label.as_deref()) {
+            (Some(quantity), Some(unit_label)) => Some(normalize_quantity(quantity, unit_label)),
+            _ => None,
+        };
+
+        for hazard_class in hazard_classes.into_iter() {
+            let total = totals
+                .entry(hazard_class.clone())
+                .or_insert_with(|| HazardClassTotal {
+                    hazard_class,
+                    ..Default::default()
+                });
+            total.nb_storages += 1;
+            if let Some((unit_label, quantity)) = &quantity {
+                *total.quantities.entry(unit_label.clone()).or_default() += quantity;
+            }
+        }
+    }
+
+    totals.into_values().collect()
+}
+
+fn format_quantity(quantity: Option<f64>, unit_label: Option<&str>) -> String {
+    match quantity {
+        Some(quantity) => format!("{} {}", quantity, unit_label.unwrap_or_default())
+            .trim()
+            .to_string(),
+        None => String::new(),
+    }
+}
+
+fn format_quantities(quantities: &BTreeMap<String, f64>) -> String {
+    quantities
+        .iter()
+        .map(|(unit_label, quantity)| format!("{:.3} {}", quantity, unit_label))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+// The manifest as a table, one row per storage.
+pub fn waste_manifest_table(items: &[WasteItem]) -> ExportTable {
+    ExportTable {
+        headers: [
+            "storage_id",
+            "storage_barecode",
+            "product_name",
+            "cas_number",
+            "hazard_classes",
+            "hazard_statements",
+            "quantity",
+            "unit_label",
+        ]
+        .iter()
+        .map(|header| header.to_string())
+        .collect(),
+        rows: items
+            .iter()
+            .map(|item| {
+                vec![
+                    item.storage_id.to_string(),
+                    item.storage_barecode.clone().unwrap_or_default(),
+                    item.product_name.clone(),
+                    item.cas_number.clone().unwrap_or_default(),
+                    item.hazard_classes.join(" "),
+                    item.hazard_statements.join(" "),
+                    item.quantity
+                        .map(|quantity| quantity.to_string())
+                        .unwrap_or_default(),
+                    item.unit_label.clone().unwrap_or_default(),
+                ]
+            })
+            .collect(),
+    }
+}
+
+// Manifest layout, in millimeters.
+const MANIFEST_WIDTH: f64 = 210.0;
+const MANIFEST_MIN_HEIGHT: f64 = 297.0;
+const MANIFEST_MARGIN: f64 = 15.0;
+const MANIFEST_FONT_SIZE: f64 = 3.0;
+const MANIFEST_TITLE_FONT_SIZE: f64 = 6.0;
+// Columns of the storages table: header and width.
+const MANIFEST_COLUMNS: [(&str, f64); 5] = [
+    ("Barcode", 30.0),
+    ("Product", 60.0),
+    ("CAS", 22.0),
+    ("Hazard classes", 38.0),
+    ("Quantity", 30.0),
+];
+
+fn manifest_text(svg: &mut String, x: f64, y: f64, bold: bool, content: &str) {
+    let _ = write!(
+        svg,
+        r#"<text x="{x:.2}" y="{y:.2}" font-size="{MANIFEST_FONT_SIZE:.2}" font-family="sans-serif"{}>{}</text>"#,
+        if bold { r#" font-weight="bold""# } else { "" },
+        xml_escape(content)
+    );
+}
+
+// Render the waste manifest of a batch as an SVG document in millimeters.
+// The document is one A4 wide page, as long as needed for the storages.
+pub fn render_waste_manifest_svg(
+    waste_batch: &WasteBatch,
+    entity_name: &str,
+    items: &[WasteItem],
+    totals: &[HazardClassTotal],
+) -> String {
+    let line_height = MANIFEST_FONT_SIZE * LINE_HEIGHT_RATIO;
+    let mut body = String::new();
+    let mut y = MANIFEST_MARGIN + MANIFEST_TITLE_FONT_SIZE;
+
+    let _ = write!(
+        body,
+        r#"<text x="{MANIFEST_MARGIN:.2}" y="{y:.2}" font-size="{MANIFEST_TITLE_FONT_SIZE:.2}" font-family="sans-serif" font-weight="bold">Waste manifest</text>"#
+    );
+    y += MANIFEST_TITLE_FONT_SIZE * LINE_HEIGHT_RATIO;
+
+    for line in [
+        format!(
+            "Batch: {} ({})",
+            waste_batch.name, waste_batch.waste_batch_id
+        ),
+        format!("Entity: {}", entity_name),
+        format!("Created: {}", waste_batch.created_at),
+        format!(
+            "Picked up: {}",
+            waste_batch.picked_up_at.as_deref().unwrap_or("-")
+        ),
+    ] {
+        manifest_text(&mut body, MANIFEST_MARGIN, y, false, &line);
+        y += line_height;
+    }
+    y += line_height;
+
+    // Storages table.
+    let mut x = MANIFEST_MARGIN;
+    for (header, width) in MANIFEST_COLUMNS.iter() {
+        manifest_text(&mut body, x, y, true, header);
+        x += width;
+    }
+    let _ = write!(
+        body,
+        r#"<line x1="{MANIFEST_MARGIN:.2}" y1="{:.2}" x2="{:.2}" y2="{:.2}" stroke="black" stroke-width="0.2"/>"#,
+        y + line_height * 0.3,
+        MANIFEST_WIDTH - MANIFEST_MARGIN,
+        y + line_height * 0.3
+    );
+    y += line_height;
+
+    for item in items.iter() {
+        let cells = [
+            item.storage_barecode
+                .clone()
+                .unwrap_or_else(|| item.storage_id.to_string()),
+            item.product_name.clone(),
+            item.cas_number.clone().unwrap_or_default(),
+            item.hazard_classes.join(" "),
+            format_quantity(item.quantity, item.unit_label.as_deref()),
+        ];
+
+        let mut x = MANIFEST_MARGIN;
+        let mut nb_lines = 1;
+        for (cell, (_, width)) in cells.iter().zip(MANIFEST_COLUMNS.iter()) {
+            let max_chars = (width / (MANIFEST_FONT_SIZE * GLYPH_WIDTH_RATIO)).floor() as usize;
+            let lines = wrap(cell, max_chars.max(1));
+            for (i, line) in lines.iter().enumerate() {
+                manifest_text(&mut body, x, y + i as f64 * line_height, false, line);
+            }
+            nb_lines = nb_lines.max(lines.len());
+            x += width;
+        }
+        y += nb_lines as f64 * line_height;
+    }
+    y += line_height;
+
+    // Totals by hazard class.
+    manifest_text(
+        &mut body,
+        MANIFEST_MARGIN,
+        y,
+        true,
+        "Totals by hazard class",
+    );
+    y += line_height;
+    for total in totals.iter() {
+        manifest_text(
+            &mut body,
+            MANIFEST_MARGIN,
+            y,
+            false,
+            &format!(
+                "{}: {} storage(s) {}",
+                total.hazard_class,
+                total.nb_storages,
+                format_quantities(&total.quantities)
+            ),
+        );
+        y += line_height;
+    }
+    y += 3.0 * line_height;
+
+    // Signatures.
+    manifest_text(&mut body, MANIFEST_MARGIN, y, false, "Producer signature:");
+    manifest_text(
+        &mut body,
+        MANIFEST_WIDTH / 2.0,
+        y,
+        false,
+        "Contractor signature:",
+    );
+    y += 6.0 * line_height;
+
+    let height = (y + MANIFEST_MARGIN).max(MANIFEST_MIN_HEIGHT);
+    format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{MANIFEST_WIDTH}mm" height="{height:.2}mm" viewBox="0 0 {MANIFEST_WIDTH} {height:.2}"><rect width="{MANIFEST_WIDTH}" height="{height:.2}" fill="white"/>{body}</svg>"#
+    )
+}